@@ -0,0 +1,35 @@
+#ifndef PROM2JSON_H
+#define PROM2JSON_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// What `prom2json_parse` returns: either `json` or `error` is set, the other one is null. Both
+// belong to the caller, who hands the result back to `prom2json_result_free`.
+typedef struct Prom2JsonResult {
+  // The parsed text, as the command line tool prints it
+  char *json;
+  // Why the text could not be parsed
+  char *error;
+} Prom2JsonResult;
+
+// Parse the `len` bytes of exposition text at `text` into JSON.
+//
+// # Safety
+//
+// `text` must point to `len` readable bytes, or be null with `len` 0.
+struct Prom2JsonResult prom2json_parse(const char *text, size_t len);
+
+// Free the strings of a result of `prom2json_parse`.
+//
+// # Safety
+//
+// `result` must come from `prom2json_parse` and not have been freed already.
+void prom2json_result_free(struct Prom2JsonResult result);
+
+#endif  /* PROM2JSON_H */
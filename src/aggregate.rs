@@ -0,0 +1,552 @@
+//! Aggregation of the series of a family, as PromQL's `sum by(...)` and `avg by(...)` do, to get
+//! cluster-level numbers out of the scrapes of several targets
+
+use crate::PrometheusData;
+use crate::{descending, sorted_labels, MetricFamily, SortBy, Summary};
+use crate::{format_float, parse_float, Histogram, Labels, Metric, MetricLike, MetricType};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The label of the series the others of a capped family are summed into
+pub const OVERFLOW_LABEL: &str = "__overflow__";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregationOp {
+    Sum,
+    Avg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How to aggregate series: their values summed or averaged over the series sharing the values
+/// of the `by` labels, which are the only labels kept
+pub struct Aggregation {
+    pub op: AggregationOp,
+    pub by: Vec<String>,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    /// `sum`, `avg`, or either followed by `by(label, ...)`
+    fn from_str(s: &str) -> Result<Aggregation, String> {
+        let s = s.trim();
+        let (name, rest) = s.split_at(
+            s.find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(s.len()),
+        );
+        let op = match name {
+            "sum" => AggregationOp::Sum,
+            "avg" => AggregationOp::Avg,
+            other => {
+                return Err(format!(
+                    "Unknown aggregation {}, expected sum or avg",
+                    other
+                ))
+            }
+        };
+        let rest = rest.trim();
+        let by = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.strip_prefix("by")
+                .map(str::trim_start)
+                .and_then(|r| r.strip_prefix('('))
+                .and_then(|r| r.strip_suffix(')'))
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid aggregation {}, expected `{} by(label, ...)`",
+                        s, name
+                    )
+                })?
+                .split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        Ok(Aggregation { op, by })
+    }
+}
+
+/// The series sharing the values of the `by` labels, with the total and number of each of
+/// their samples by sample name and `le` or `quantile` label
+struct Group {
+    labels: Labels,
+    samples: Vec<(String, Option<String>, f64, usize)>,
+}
+
+impl Group {
+    fn add(&mut self, name: String, extra: Option<String>, value: f64) {
+        match self
+            .samples
+            .iter_mut()
+            .find(|(n, e, _, _)| *n == name && *e == extra)
+        {
+            Some((_, _, total, count)) => {
+                *total += value;
+                *count += 1;
+            }
+            None => self.samples.push((name, extra, value, 1)),
+        }
+    }
+
+    /// Add the samples of a series of `family`, leaving out those whose value is not a number
+    fn add_series(&mut self, family: &MetricFamily, series: &dyn MetricLike) {
+        for sample in series.samples(&family.metric_name) {
+            let extra = match family.metric_type {
+                MetricType::Histogram => sample.labels.get("le").cloned(),
+                MetricType::Summary => sample.labels.get("quantile").cloned(),
+                _ => None,
+            };
+            if let Some(value) = sample.float_value() {
+                self.add(sample.name, extra, value);
+            }
+        }
+    }
+
+    /// The aggregated series, built back from its samples
+    fn series(self, family: &MetricFamily, op: AggregationOp) -> Box<dyn MetricLike> {
+        let name = &family.metric_name;
+        let labels = Some(self.labels).filter(|l| !l.is_empty());
+        let mut value = String::new();
+        let (mut sum, mut count) = (String::new(), String::new());
+        let mut by_bound = Labels::new();
+        for (sample, extra, total, n) in self.samples {
+            let aggregated = match op {
+                AggregationOp::Sum => format_float(total),
+                AggregationOp::Avg => format_float(total / n as f64),
+            };
+            match extra {
+                // Quantiles do not add up, only their average means something
+                Some(_)
+                    if family.metric_type == MetricType::Summary && op == AggregationOp::Sum => {}
+                Some(bound) => {
+                    by_bound.insert(bound, aggregated);
+                }
+                None if sample == format!("{}_sum", name) => sum = aggregated,
+                None if sample == format!("{}_count", name) => count = aggregated,
+                None => value = aggregated,
+            }
+        }
+        match family.metric_type {
+            MetricType::Histogram => Box::new(Histogram {
+                labels,
+                buckets: by_bound,
+                count,
+                sum,
+                percentiles: None,
+            }),
+            MetricType::Summary => Box::new(Summary {
+                labels,
+                quantiles: by_bound,
+                count,
+                sum,
+            }),
+            MetricType::Gauge | MetricType::Untyped => Box::new(Metric {
+                labels,
+                value,
+                timestamp: None,
+            }),
+        }
+    }
+}
+
+/// Bounds for messages, e.g. `0.1, 1, +Inf`
+fn format_bounds(bounds: &[f64]) -> String {
+    let bounds: Vec<String> = bounds.iter().map(|b| format_float(*b)).collect();
+    bounds.join(", ")
+}
+
+impl Histogram {
+    /// The bounds of the buckets in order, leaving out those that are not numbers
+    pub fn bounds(&self) -> Vec<f64> {
+        let mut bounds: Vec<f64> = self
+            .buckets
+            .keys()
+            .filter_map(|le| parse_float(le))
+            .collect();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        bounds
+    }
+
+    /// Only keep the buckets whose bound is one of `bounds`, their cumulative counts staying
+    /// exact, with their `le` labels written the same way (`1.0` becoming `1`)
+    pub fn rebucket(&mut self, bounds: &[f64]) {
+        self.buckets = self
+            .buckets
+            .drain()
+            .filter_map(|(le, count)| {
+                let bound = parse_float(&le).filter(|b| bounds.contains(b))?;
+                Some((format_float(bound), count))
+            })
+            .collect();
+    }
+
+    /// Sum histograms with the same bucket bounds into one, e.g. the per-instance latency
+    /// histograms of a service, with the labels all of them share. Fails on histograms with
+    /// different bounds, whose buckets do not add up, see `merge_rebucketed`.
+    pub fn merge(histograms: &[&Histogram]) -> Result<Histogram, String> {
+        let first = histograms.first().ok_or("No histograms to merge")?;
+        let bounds = first.bounds();
+        if let Some(other) = histograms.iter().find(|h| h.bounds() != bounds) {
+            return Err(format!(
+                "Histograms with different buckets: {} and {}",
+                format_bounds(&bounds),
+                format_bounds(&other.bounds())
+            ));
+        }
+        Ok(Histogram::sum(histograms))
+    }
+
+    /// Sum histograms into one like `merge`, first re-bucketing them onto the bounds all of
+    /// them have, which keeps the counts exact at the cost of the finer buckets. Fails when
+    /// they do not all have a `+Inf` bucket.
+    pub fn merge_rebucketed(histograms: &[&Histogram]) -> Result<Histogram, String> {
+        let first = histograms.first().ok_or("No histograms to merge")?;
+        let mut bounds = first.bounds();
+        for histogram in &histograms[1..] {
+            let other = histogram.bounds();
+            bounds.retain(|b| other.contains(b));
+        }
+        if bounds.last() != Some(&f64::INFINITY) {
+            return Err("Histograms without a +Inf bucket cannot be re-bucketed".to_string());
+        }
+        let rebucketed: Vec<Histogram> = histograms
+            .iter()
+            .map(|h| {
+                let mut h = (*h).clone();
+                h.rebucket(&bounds);
+                h
+            })
+            .collect();
+        Ok(Histogram::sum(&rebucketed.iter().collect::<Vec<_>>()))
+    }
+
+    /// The sum of the buckets, counts and sums of histograms, leaving out values that are not
+    /// numbers
+    fn sum(histograms: &[&Histogram]) -> Histogram {
+        let mut labels = histograms[0].labels.clone().unwrap_or_default();
+        for histogram in &histograms[1..] {
+            let other = histogram.labels.as_ref();
+            labels.retain(|k, v| other.and_then(|o| o.get(k)) == Some(v));
+        }
+        let mut buckets: HashMap<String, f64> = HashMap::new();
+        let (mut count, mut sum) = (0.0, 0.0);
+        for histogram in histograms {
+            for (le, value) in &histogram.buckets {
+                if let (Some(bound), Some(value)) = (parse_float(le), parse_float(value)) {
+                    *buckets.entry(format_float(bound)).or_default() += value;
+                }
+            }
+            count += parse_float(&histogram.count).unwrap_or(0.0);
+            sum += parse_float(&histogram.sum).unwrap_or(0.0);
+        }
+        Histogram {
+            labels: Some(labels),
+            buckets: buckets
+                .into_iter()
+                .map(|(le, v)| (le, format_float(v)))
+                .collect(),
+            count: format_float(count),
+            sum: format_float(sum),
+            percentiles: None,
+        }
+    }
+}
+
+impl PrometheusData {
+    /// Fail when the series of a histogram family have different bucket bounds, whose buckets
+    /// would not add up when aggregated
+    pub fn check_histogram_buckets(&self) -> Result<(), String> {
+        for family in &self.metrics {
+            let mut series = family.data.iter().map(|s| s.bucket_bounds());
+            let bounds = match series.next() {
+                Some(bounds) if family.metric_type == MetricType::Histogram => bounds,
+                _ => continue,
+            };
+            if let Some(other) = series.find(|b| *b != bounds) {
+                return Err(format!(
+                    "Histogram {} has series with different buckets: {} and {}",
+                    family.metric_name,
+                    format_bounds(&bounds),
+                    format_bounds(&other)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-bucket the series of every histogram family onto the bounds all of them have (see
+    /// `Histogram::merge_rebucketed`), so that they add up when aggregated
+    pub fn rebucket_histograms(&mut self) {
+        for family in self.metrics.iter_mut() {
+            if family.metric_type != MetricType::Histogram {
+                continue;
+            }
+            let mut series = family.data.iter().map(|s| s.bucket_bounds());
+            let mut bounds = series.next().unwrap_or_default();
+            for other in series {
+                bounds.retain(|b| other.contains(b));
+            }
+            for series in family.data.iter_mut() {
+                series.rebucket(&bounds);
+            }
+        }
+    }
+
+    /// Aggregate the series of every family, e.g. those of several targets merged together
+    /// into cluster-level ones. Gauges, counters, histogram buckets and the counts and sums of
+    /// summaries are summed or averaged; summary quantiles are averaged, and left out of sums.
+    /// Samples whose value is not a number are left out.
+    pub fn aggregate(&self, aggregation: &Aggregation) -> PrometheusData {
+        let mut metrics = Vec::with_capacity(self.metrics.len());
+        for family in &self.metrics {
+            let mut groups: Vec<Group> = Vec::new();
+            let mut index: HashMap<Vec<(&str, &str)>, usize> = HashMap::new();
+            for series in &family.data {
+                let series_labels = series.labels();
+                let key: Vec<(&str, &str)> = aggregation
+                    .by
+                    .iter()
+                    .filter_map(|l| Some((l.as_str(), series_labels?.get(l)?.as_str())))
+                    .collect();
+                let group = *index.entry(key.clone()).or_insert_with(|| {
+                    groups.push(Group {
+                        labels: key
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .collect(),
+                        samples: Vec::new(),
+                    });
+                    groups.len() - 1
+                });
+                groups[group].add_series(family, series.as_ref());
+            }
+            metrics.push(MetricFamily {
+                metric_type: family.metric_type,
+                metric_name: family.metric_name.clone(),
+                help: family.help.clone(),
+                data: groups
+                    .into_iter()
+                    .map(|g| g.series(family, aggregation.op))
+                    .collect(),
+            });
+        }
+        PrometheusData { metrics }
+    }
+
+    /// Keep at most `n` series in every family, the largest ones (by `rank_value`) or the first
+    /// by their sorted labels, and sum the others into one more series labeled
+    /// `__overflow__="true"`, so that a label explosion cannot flood what takes the output.
+    /// Series keep their order, the overflow one coming last.
+    pub fn cap_series(&mut self, n: usize, by: SortBy) {
+        for family in self.metrics.iter_mut() {
+            if family.data.len() <= n {
+                continue;
+            }
+            let mut ranked: Vec<usize> = (0..family.data.len()).collect();
+            match by {
+                SortBy::Name => {
+                    ranked.sort_by_cached_key(|&i| sorted_labels(family.data[i].labels()))
+                }
+                SortBy::Value => ranked.sort_by(|&a, &b| {
+                    descending(family.data[a].rank_value(), family.data[b].rank_value())
+                }),
+            }
+            let mut keep = vec![false; family.data.len()];
+            for i in ranked.into_iter().take(n) {
+                keep[i] = true;
+            }
+            let mut overflow = Group {
+                labels: Labels::from([(OVERFLOW_LABEL.to_string(), "true".to_string())]),
+                samples: Vec::new(),
+            };
+            let mut kept = Vec::with_capacity(n + 1);
+            for (series, keep) in std::mem::take(&mut family.data).into_iter().zip(keep) {
+                if keep {
+                    kept.push(series);
+                } else {
+                    overflow.add_series(family, series.as_ref());
+                }
+            }
+            kept.push(overflow.series(family, AggregationOp::Sum));
+            family.data = kept;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aggregation_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE http_requests_total counter
+http_requests_total{instance=\"a\",code=\"200\"} 10
+http_requests_total{instance=\"a\",code=\"500\"} 1
+http_requests_total{instance=\"b\",code=\"200\"} 30
+# TYPE latency histogram
+latency_bucket{instance=\"a\",le=\"1\"} 2
+latency_bucket{instance=\"a\",le=\"+Inf\"} 3
+latency_sum{instance=\"a\"} 1.5
+latency_count{instance=\"a\"} 3",
+        );
+        data.merge(PrometheusData::from_string(
+            "# TYPE latency histogram
+latency_bucket{instance=\"b\",le=\"1\"} 4
+latency_bucket{instance=\"b\",le=\"+Inf\"} 5
+latency_sum{instance=\"b\"} 2
+latency_count{instance=\"b\"} 5",
+        ));
+
+        let summed = data.aggregate(&"sum by(code)".parse().unwrap());
+        let samples: Vec<String> = summed
+            .samples()
+            .into_iter()
+            .map(|s| format!("{}{:?} {}", s.name, s.labels, s.value))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                "http_requests_total{\"code\": \"200\"} 40",
+                "http_requests_total{\"code\": \"500\"} 1",
+                "latency_bucket{\"le\": \"1\"} 6",
+                "latency_bucket{\"le\": \"+Inf\"} 8",
+                "latency_sum{} 3.5",
+                "latency_count{} 8",
+            ]
+        );
+
+        let averaged = data.aggregate(&"avg".parse().unwrap());
+        assert_eq!(averaged.metrics[0].data[0].rank_value(), Some(41.0 / 3.0));
+        assert_eq!(
+            "avg by (job, zone)".parse(),
+            Ok(Aggregation {
+                op: AggregationOp::Avg,
+                by: vec!["job".to_string(), "zone".to_string()],
+            })
+        );
+        assert!("max".parse::<Aggregation>().is_err());
+        assert!("sum job".parse::<Aggregation>().is_err());
+    }
+
+    #[test]
+    fn histogram_merging_works() {
+        let histogram = |instance: &str, buckets: &[(&str, u32)], sum: f64| {
+            let mut labels = Labels::new();
+            labels.insert("instance".to_string(), instance.to_string());
+            labels.insert("job".to_string(), "api".to_string());
+            Histogram {
+                labels: Some(labels),
+                buckets: buckets
+                    .iter()
+                    .map(|(le, v)| (le.to_string(), v.to_string()))
+                    .collect(),
+                count: buckets.last().unwrap().1.to_string(),
+                sum: sum.to_string(),
+                percentiles: None,
+            }
+        };
+        let a = histogram("a", &[("0.1", 2), ("1", 5), ("+Inf", 6)], 2.5);
+        let b = histogram("b", &[("0.1", 1), ("1.0", 1), ("+Inf", 3)], 4.0);
+        let c = histogram("c", &[("0.5", 4), ("1", 5), ("+Inf", 5)], 1.5);
+
+        let merged = Histogram::merge(&[&a, &b]).unwrap();
+        assert_eq!(merged.labels.as_ref().unwrap().len(), 1);
+        assert_eq!(merged.labels.as_ref().unwrap()["job"], "api");
+        assert_eq!(
+            merged.bucket_counts(),
+            vec![(0.1, 3.0), (1.0, 3.0), (f64::INFINITY, 3.0)]
+        );
+        assert_eq!((merged.count.as_str(), merged.sum.as_str()), ("9", "6.5"));
+
+        let err = Histogram::merge(&[&a, &c]).unwrap_err();
+        assert_eq!(
+            err,
+            "Histograms with different buckets: 0.1, 1, +Inf and 0.5, 1, +Inf"
+        );
+        let merged = Histogram::merge_rebucketed(&[&a, &b, &c]).unwrap();
+        assert_eq!(merged.bounds(), vec![1.0, f64::INFINITY]);
+        assert_eq!(merged.buckets["1"], "11");
+        assert_eq!(merged.count, "14");
+        assert!(Histogram::merge(&[]).is_err());
+        let d = histogram("d", &[("1", 1)], 0.5);
+        assert!(Histogram::merge_rebucketed(&[&a, &d]).is_err());
+
+        let mut data = PrometheusData::from_string(
+            "# TYPE latency histogram
+latency_bucket{instance=\"a\",le=\"0.1\"} 2
+latency_bucket{instance=\"a\",le=\"1\"} 5
+latency_bucket{instance=\"a\",le=\"+Inf\"} 6
+latency_sum{instance=\"a\"} 1
+latency_count{instance=\"a\"} 6
+latency_bucket{instance=\"c\",le=\"0.5\"} 4
+latency_bucket{instance=\"c\",le=\"1\"} 5
+latency_bucket{instance=\"c\",le=\"+Inf\"} 5
+latency_sum{instance=\"c\"} 2
+latency_count{instance=\"c\"} 5",
+        );
+        assert!(data.check_histogram_buckets().is_err());
+        data.rebucket_histograms();
+        assert!(data.check_histogram_buckets().is_ok());
+        let summed = data.aggregate(&"sum".parse().unwrap());
+        let values: Vec<String> = summed.samples().into_iter().map(|s| s.value).collect();
+        assert_eq!(values, vec!["10", "11", "3", "11"]);
+    }
+
+    #[test]
+    fn series_capping_works() {
+        let raw = "# TYPE http_requests_total counter
+http_requests_total{path=\"/c\"} 5
+http_requests_total{path=\"/a\"} 1
+http_requests_total{path=\"/d\"} 7
+http_requests_total{path=\"/b\"} 2
+# TYPE up gauge
+up 1";
+        let ids = |data: &PrometheusData| -> Vec<String> {
+            data.samples()
+                .iter()
+                .map(|s| format!("{} {}", s.series_id(), s.value))
+                .collect()
+        };
+        let mut data = PrometheusData::from_string(raw);
+        data.cap_series(2, SortBy::Value);
+        assert_eq!(
+            ids(&data),
+            vec![
+                "http_requests_total{path=\"/c\"} 5",
+                "http_requests_total{path=\"/d\"} 7",
+                "http_requests_total{__overflow__=\"true\"} 3",
+                "up 1",
+            ]
+        );
+
+        let mut data = PrometheusData::from_string(raw);
+        data.cap_series(1, SortBy::Name);
+        assert_eq!(
+            ids(&data)[..2],
+            [
+                "http_requests_total{path=\"/a\"} 1",
+                "http_requests_total{__overflow__=\"true\"} 14",
+            ]
+        );
+
+        let mut data = PrometheusData::from_string(
+            "# TYPE latency histogram
+latency_bucket{pod=\"a\",le=\"+Inf\"} 3
+latency_sum{pod=\"a\"} 1.5
+latency_count{pod=\"a\"} 3
+latency_bucket{pod=\"b\",le=\"+Inf\"} 1
+latency_sum{pod=\"b\"} 0.5
+latency_count{pod=\"b\"} 1
+latency_bucket{pod=\"c\",le=\"+Inf\"} 2
+latency_sum{pod=\"c\"} 1
+latency_count{pod=\"c\"} 2",
+        );
+        data.cap_series(1, SortBy::Value);
+        assert_eq!(data.metrics[0].data.len(), 2);
+        assert_eq!(data.metrics[0].data[1].rank_value(), Some(3.0));
+    }
+}
@@ -0,0 +1,205 @@
+//! Flagging of samples that stray from the recent values of their series, across the scrapes of
+//! `watch`
+
+use crate::diff::{counter_increase, is_counter};
+use crate::{Labels, PrometheusData, Sample};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// A sample whose value deviates from the history of its series
+pub struct Anomaly {
+    pub name: String,
+    pub labels: Labels,
+    /// The value checked: that of the sample, or its increase since the previous scrape for
+    /// counters
+    pub value: f64,
+    /// The mean of the previous values of the series
+    pub mean: f64,
+    /// How many standard deviations the value is from the mean, when it exceeds the threshold
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub z_score: Option<f64>,
+    /// The change from the previous value in percent, when it exceeds the threshold
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub change_percent: Option<f64>,
+}
+
+impl Anomaly {
+    /// Canonical `name{label="value",...}` identity of the series
+    pub fn series_id(&self) -> String {
+        Sample {
+            name: self.name.clone(),
+            labels: self.labels.clone(),
+            value: String::new(),
+        }
+        .series_id()
+    }
+}
+
+/// The recent values of a series, and its last raw value for counters
+#[derive(Debug, Default)]
+struct History {
+    values: VecDeque<f64>,
+    last: Option<f64>,
+}
+
+#[derive(Debug)]
+/// The recent values of every series, kept across scrapes to flag the samples that deviate
+/// beyond a z-score or a percent change
+pub struct AnomalyDetector {
+    z_score: Option<f64>,
+    change_percent: Option<f64>,
+    window: usize,
+    history: HashMap<String, History>,
+}
+
+impl AnomalyDetector {
+    /// Flag the values more than `z_score` standard deviations from the mean of the last
+    /// `window` values of their series, or that changed by more than `change_percent` percent
+    /// since the previous one
+    pub fn new(
+        z_score: Option<f64>,
+        change_percent: Option<f64>,
+        window: usize,
+    ) -> AnomalyDetector {
+        AnomalyDetector {
+            z_score,
+            change_percent,
+            window: window.max(1),
+            history: HashMap::new(),
+        }
+    }
+
+    /// The anomalies of a scrape, remembering its values for the next ones. Counters (families
+    /// named `*_total`, and the buckets, counts and sums of histograms and summaries) are
+    /// checked by their increase since the previous scrape, a reset counting as an increase by
+    /// the whole new value as in Prometheus. The
+    /// z-score needs two previous values, and values that are not numbers are skipped.
+    pub fn check(&mut self, data: &PrometheusData) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        for family in &data.metrics {
+            for sample in family.samples() {
+                let raw = match sample.float_value().filter(|v| v.is_finite()) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let id = sample.series_id();
+                let history = self.history.entry(id.clone()).or_default();
+                let value = if is_counter(family.metric_type, &family.metric_name, &sample.name) {
+                    match history.last.replace(raw) {
+                        Some(last) => counter_increase(last, raw),
+                        None => continue,
+                    }
+                } else {
+                    raw
+                };
+                let stats = history_stats(history);
+                if let Some(anomaly) = self.flag(stats, value) {
+                    anomalies.push(Anomaly {
+                        name: sample.name,
+                        labels: sample.labels,
+                        ..anomaly
+                    });
+                }
+                let history = self.history.get_mut(&id).unwrap();
+                history.values.push_back(value);
+                if history.values.len() > self.window {
+                    history.values.pop_front();
+                }
+            }
+        }
+        anomalies
+    }
+
+    fn flag(&self, stats: Option<Stats>, value: f64) -> Option<Anomaly> {
+        let stats = stats?;
+        let z_score = match (self.z_score, stats.std_dev) {
+            (Some(threshold), Some(std_dev)) if std_dev > 0.0 => {
+                Some((value - stats.mean) / std_dev).filter(|z| z.abs() > threshold)
+            }
+            _ => None,
+        };
+        let change_percent = match self.change_percent {
+            Some(threshold) if stats.last != 0.0 => {
+                Some((value - stats.last) / stats.last.abs() * 100.0)
+                    .filter(|change| change.abs() > threshold)
+            }
+            _ => None,
+        };
+        if z_score.is_none() && change_percent.is_none() {
+            return None;
+        }
+        Some(Anomaly {
+            name: String::new(),
+            labels: Labels::new(),
+            value,
+            mean: stats.mean,
+            z_score,
+            change_percent,
+        })
+    }
+}
+
+struct Stats {
+    mean: f64,
+    /// None with less than two values
+    std_dev: Option<f64>,
+    last: f64,
+}
+
+fn history_stats(history: &History) -> Option<Stats> {
+    let last = *history.values.back()?;
+    let n = history.values.len() as f64;
+    let mean = history.values.iter().sum::<f64>() / n;
+    let std_dev = (history.values.len() >= 2).then(|| {
+        let variance = history
+            .values
+            .iter()
+            .map(|v| (v - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        variance.sqrt()
+    });
+    Some(Stats {
+        mean,
+        std_dev,
+        last,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn anomaly_detection_works() {
+        let mut detector = AnomalyDetector::new(Some(3.0), Some(100.0), 5);
+        let scrape = |load: f64, requests: f64| {
+            PrometheusData::from_string(&format!(
+                "# TYPE load gauge\nload {}\n# TYPE requests_total counter\nrequests_total {}",
+                load, requests
+            ))
+        };
+        for (load, requests) in [(1.0, 0.0), (1.2, 10.0), (1.0, 20.0), (0.8, 30.0)] {
+            assert!(detector.check(&scrape(load, requests)).is_empty());
+        }
+
+        let anomalies = detector.check(&scrape(4.0, 40.0));
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].series_id(), "load");
+        assert_eq!(anomalies[0].value, 4.0);
+        assert_eq!(anomalies[0].mean, 1.0);
+        assert!(anomalies[0].z_score.unwrap() > 3.0);
+        assert_eq!(anomalies[0].change_percent, Some(400.0));
+
+        // The increase of the counter jumps, and a reset is no large negative change
+        let anomalies = detector.check(&scrape(1.0, 100.0));
+        let names: Vec<&str> = anomalies.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["requests_total"]);
+        assert_eq!(anomalies[0].value, 60.0);
+        assert!(detector.check(&scrape(1.0, 110.0)).is_empty());
+        assert!(detector.check(&scrape(1.0, 15.0)).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+//! Scratch memory for parsing. The lines of each series, the labels they repeat and the
+//! `_sum`/`_count` names derived from family names only live while a family is parsed; with the
+//! `arena` feature they come from a bump arena, freed at once when the family is done rather
+//! than one allocation at a time. Without it they come from the heap as usual.
+
+#[cfg(feature = "arena")]
+pub(crate) type Arena = bumpalo::Bump;
+#[cfg(feature = "arena")]
+pub(crate) type ArenaVec<'a, T> = bumpalo::collections::Vec<'a, T>;
+#[cfg(feature = "arena")]
+pub(crate) type ArenaString<'a> = bumpalo::collections::String<'a>;
+
+#[cfg(not(feature = "arena"))]
+#[derive(Default)]
+pub(crate) struct Arena {}
+#[cfg(not(feature = "arena"))]
+pub(crate) type ArenaVec<'a, T> = Vec<T>;
+#[cfg(not(feature = "arena"))]
+pub(crate) type ArenaString<'a> = String;
+
+#[cfg(not(feature = "arena"))]
+impl Arena {
+    /// Nothing to free, each allocation went back to the heap on its own
+    pub(crate) fn reset(&mut self) {}
+}
+
+/// An empty list in `arena`
+pub(crate) fn vec<T>(arena: &Arena) -> ArenaVec<'_, T> {
+    #[cfg(feature = "arena")]
+    return ArenaVec::new_in(arena);
+    #[cfg(not(feature = "arena"))]
+    {
+        let _ = arena;
+        Vec::new()
+    }
+}
+
+/// `name` followed by `suffix`, in `arena`
+pub(crate) fn concat<'a>(arena: &'a Arena, name: &str, suffix: &str) -> ArenaString<'a> {
+    #[cfg(feature = "arena")]
+    let mut joined = ArenaString::with_capacity_in(name.len() + suffix.len(), arena);
+    #[cfg(not(feature = "arena"))]
+    let mut joined = {
+        let _ = arena;
+        String::with_capacity(name.len() + suffix.len())
+    };
+    joined.push_str(name);
+    joined.push_str(suffix);
+    joined
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arena_works() {
+        let mut arena = Arena::default();
+        {
+            let name = concat(&arena, "rpc_duration_seconds", "_count");
+            let mut lines = vec(&arena);
+            lines.push(name.as_str());
+            assert_eq!(lines.as_slice(), ["rpc_duration_seconds_count"]);
+        }
+        arena.reset();
+        assert_eq!(concat(&arena, "up", "").as_str(), "up");
+    }
+}
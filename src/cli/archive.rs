@@ -0,0 +1,385 @@
+//! Timestamped files recording every conversion of `watch`, with rotation, and optionally only
+//! the changes since the previous one between periodic full snapshots
+
+use super::compress::{maybe_compress, with_extension, Compression};
+use super::config::parse_duration;
+use super::delta;
+use super::Error;
+use prom2jsonrs::PrometheusData;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+use tracing::{debug, warn};
+
+#[derive(StructOpt)]
+pub struct ArchiveOpts {
+    /// Write every conversion to a timestamped file in this directory instead of printing it
+    #[structopt(long)]
+    archive_dir: Option<PathBuf>,
+    /// Delete archived files older than this, e.g. `24h`
+    #[structopt(long, parse(try_from_str = parse_duration), requires = "archive-dir")]
+    keep: Option<Duration>,
+    /// Keep at most this many archived files, deleting the oldest
+    #[structopt(long, requires = "archive-dir")]
+    max_files: Option<usize>,
+    /// Write a full document every N conversions, and only the series that changed since the
+    /// previous one in between
+    #[structopt(long, requires = "archive-dir")]
+    snapshot_every: Option<usize>,
+}
+
+/// A directory of `<time>.json` (or `.json.gz`, `.json.zst`) documents, and of
+/// `<time>.delta.json` ones with the changes since the previous document when writing deltas
+pub struct Archive {
+    dir: PathBuf,
+    keep: Option<Duration>,
+    max_files: Option<usize>,
+    compression: Option<Compression>,
+    snapshot_every: Option<usize>,
+    /// The previous document, for deltas
+    previous: Mutex<Option<Previous>>,
+}
+
+struct Previous {
+    document: Value,
+    time: SystemTime,
+    /// The documents written since the last snapshot, included
+    written: usize,
+}
+
+impl ArchiveOpts {
+    pub fn archive(&self, compression: Option<Compression>) -> Result<Option<Archive>, Error> {
+        let dir = match &self.archive_dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+        Ok(Some(Archive {
+            dir: dir.clone(),
+            keep: self.keep,
+            max_files: self.max_files,
+            compression,
+            snapshot_every: self.snapshot_every.map(|n| n.max(1)),
+            previous: Mutex::new(None),
+        }))
+    }
+}
+
+/// File name of a document written at `time`, which sorts chronologically and avoids the
+/// colons some filesystems reject
+fn file_name(time: SystemTime, compression: Option<Compression>, delta: bool) -> String {
+    let stamp = format_time(time).replace(':', "-");
+    let kind = if delta { ".delta" } else { "" };
+    with_extension(format!("{}{}.json", stamp, kind), compression)
+}
+
+/// The time of a document as in its file name, to the millisecond
+pub fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// When a document was written, from its file name
+fn archived_at(name: &str) -> Option<SystemTime> {
+    let stamp = name.split(".json").next()?.trim_end_matches(".delta");
+    let (date, time) = stamp.split_once('T')?;
+    humantime::parse_rfc3339(&format!("{}T{}", date, time.replace('-', ":"))).ok()
+}
+
+fn is_archived(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    [".json", ".json.gz", ".json.zst"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+fn is_delta(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|name| name.contains(".delta.json"))
+}
+
+impl Archive {
+    /// Write a document, or its changes since the previous one between snapshots, then apply
+    /// the retention
+    pub async fn write(&self, body: &str) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let delta = match self.snapshot_every {
+            Some(every) => self.delta(serde_json::from_str(body)?, now, every),
+            None => None,
+        };
+        let path = self
+            .dir
+            .join(file_name(now, self.compression, delta.is_some()));
+        let body = match delta {
+            Some(delta) => serde_json::to_string(&delta)?,
+            None => body.to_string(),
+        };
+        let contents = maybe_compress(body.as_bytes(), self.compression)?;
+        // Readers of the directory never see a partial file
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, contents).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        debug!(path = %path.display(), "archived");
+        self.rotate().await
+    }
+
+    /// The changes of `document` written at `time` since the previous one, with the time of
+    /// that one as `after`, or none when a snapshot is due. The first document written is always
+    /// a snapshot.
+    fn delta(&self, document: Value, time: SystemTime, every: usize) -> Option<Value> {
+        let mut previous = self.previous.lock().unwrap_or_else(|e| e.into_inner());
+        let (delta, written) = match previous.take() {
+            Some(before) if before.written < every => {
+                let mut delta = delta::encode(&before.document, &document);
+                delta["delta"]["after"] = format_time(before.time).into();
+                (Some(delta), before.written + 1)
+            }
+            _ => (None, 1),
+        };
+        *previous = Some(Previous {
+            document,
+            time,
+            written,
+        });
+        delta
+    }
+
+    /// Delete the files beyond `max_files` or older than `keep`
+    async fn rotate(&self) -> Result<(), Error> {
+        if self.keep.is_none() && self.max_files.is_none() {
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if is_archived(&path) {
+                files.push(path);
+            }
+        }
+        // Newest first, the names being timestamps
+        files.sort_by(|a, b| b.cmp(a));
+        let now = SystemTime::now();
+        for (i, path) in files.iter().enumerate() {
+            let expired = match self.keep {
+                Some(keep) => {
+                    let modified = tokio::fs::metadata(path).await?.modified()?;
+                    now.duration_since(modified).unwrap_or_default() > keep
+                }
+                None => false,
+            };
+            if expired || self.max_files.is_some_and(|max| i >= max) {
+                debug!(path = %path.display(), "rotated out");
+                if let Err(err) = tokio::fs::remove_file(path).await {
+                    warn!(path = %path.display(), error = %err, "cannot delete archived file");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn read_document(path: &Path) -> Result<Value, Error> {
+    let raw = tokio::fs::read(path).await?;
+    let raw = match Compression::from_path(path) {
+        Some(compression) => compression.decompress(&raw)?,
+        None => raw,
+    };
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+/// The full documents archived in `dir` from `start` to `end` included, oldest first, with the
+/// time they were written, deltas replayed from the snapshot before them. Unreadable documents
+/// are skipped, as are the deltas whose snapshot is gone.
+pub async fn replay(
+    dir: &Path,
+    start: SystemTime,
+    end: SystemTime,
+) -> Result<Vec<(SystemTime, Value)>, Error> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", dir.display(), e))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let time = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if is_archived(&path) => archived_at(name),
+            _ => None,
+        };
+        match time {
+            Some(time) if time <= end => files.push((time, path)),
+            _ => continue,
+        }
+    }
+    files.sort();
+    // Deltas need the documents from the last snapshot up to `start`
+    let first = files
+        .iter()
+        .rposition(|(time, path)| *time <= start && !is_delta(path))
+        .unwrap_or(0);
+    let mut documents = Vec::new();
+    // The document replayed so far and when it was written
+    let mut current: Option<(Value, SystemTime)> = None;
+    for (time, path) in files.drain(first..) {
+        // Rotation may delete it in the meantime
+        let document = match read_document(&path).await {
+            Ok(document) => document,
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "cannot read archived document");
+                // The deltas after it cannot be replayed
+                current = None;
+                continue;
+            }
+        };
+        if is_delta(&path) {
+            let after = document["delta"]["after"].as_str();
+            let replayed = match current.as_mut() {
+                Some((replayed, before)) if after == Some(&format_time(*before)) => {
+                    delta::apply(replayed, &document)
+                }
+                _ => Err("the document before it is gone".to_string()),
+            };
+            if let Err(err) = replayed {
+                warn!(path = %path.display(), error = %err, "cannot replay archived delta");
+                current = None;
+                continue;
+            }
+        } else {
+            current = Some((document, time));
+        }
+        if let Some((document, before)) = current.as_mut() {
+            *before = time;
+            if start <= time {
+                documents.push((time, document.clone()));
+            }
+        }
+    }
+    Ok(documents)
+}
+
+/// The data of the documents archived in `dir` from `start` to `end` included, see `replay`.
+/// Documents that are not data are skipped.
+pub async fn read(
+    dir: &Path,
+    start: SystemTime,
+    end: SystemTime,
+) -> Result<Vec<(SystemTime, PrometheusData)>, Error> {
+    let mut documents = Vec::new();
+    for (time, document) in replay(dir, start, end).await? {
+        match serde_json::from_value(document) {
+            Ok(data) => documents.push((time, data)),
+            Err(err) => warn!(error = %err, "archived document is no data"),
+        }
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn archive_rotation_works() {
+        assert_eq!(
+            file_name(
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+                Some(Compression::Gzip),
+                false
+            ),
+            "2023-11-14T22-13-20.123Z.json.gz"
+        );
+        assert_eq!(
+            archived_at("2023-11-14T22-13-20.123Z.delta.json"),
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123))
+        );
+
+        let dir = std::env::temp_dir().join(format!("prom2jsonrs-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let opts = ArchiveOpts {
+            archive_dir: Some(dir.clone()),
+            keep: None,
+            max_files: Some(2),
+            snapshot_every: None,
+        };
+        let archive = opts.archive(None).unwrap().unwrap();
+        std::fs::write(dir.join("notes.txt"), "kept").unwrap();
+        for i in 0..4 {
+            archive.write(&format!("{{\"n\":{}}}", i)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[2], "notes.txt");
+        assert_eq!(
+            std::fs::read_to_string(dir.join(&names[1])).unwrap(),
+            "{\"n\":3}"
+        );
+        assert_eq!(
+            archived_at("2023-11-14T22-13-20.123Z.json.gz"),
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123))
+        );
+        assert_eq!(archived_at("notes.txt"), None);
+
+        archive.write("{\"metrics\":[]}").await.unwrap();
+        let documents = read(&dir, UNIX_EPOCH, SystemTime::now()).await.unwrap();
+        // The `{"n":..}` documents are no data
+        assert_eq!(documents.len(), 1);
+        assert!(documents[0].1.metrics.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let opts = ArchiveOpts {
+            archive_dir: Some(dir.clone()),
+            keep: None,
+            max_files: None,
+            snapshot_every: Some(2),
+        };
+        let archive = opts.archive(Some(Compression::Gzip)).unwrap().unwrap();
+        for value in 1..=5 {
+            let data = PrometheusData::from_string(&format!(
+                "# TYPE up gauge\nup{{job=\"a\"}} 1\nup{{job=\"b\"}} {}",
+                value
+            ));
+            archive
+                .write(&serde_json::to_string(&data).unwrap())
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let deltas: Vec<bool> = names.iter().map(|n| n.contains(".delta.")).collect();
+        assert_eq!(deltas, vec![false, true, false, true, false]);
+        let start = archived_at(&names[0]).unwrap();
+        let values = |documents: Vec<(SystemTime, PrometheusData)>| -> Vec<String> {
+            documents
+                .iter()
+                .map(|(_, data)| data.samples()[1].value.clone())
+                .collect()
+        };
+        let documents = read(&dir, start, SystemTime::now()).await.unwrap();
+        assert_eq!(values(documents), vec!["1", "2", "3", "4", "5"]);
+        // Starting at a delta replays it from the snapshot before it
+        let second = archived_at(&names[1]).unwrap();
+        let documents = read(&dir, second, SystemTime::now()).await.unwrap();
+        assert_eq!(values(documents), vec!["2", "3", "4", "5"]);
+        // Without its snapshot, a delta is skipped
+        std::fs::remove_file(dir.join(&names[2])).unwrap();
+        let documents = read(&dir, start, SystemTime::now()).await.unwrap();
+        assert_eq!(values(documents), vec!["1", "2", "5"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Measuring parse throughput, memory and allocations on a payload
+
+use super::config::Settings;
+use super::Error;
+use prom2jsonrs::PrometheusData;
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// The system allocator, counting allocations and the bytes in use
+struct CountingAllocator {
+    allocations: AtomicUsize,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl CountingAllocator {
+    const fn new() -> CountingAllocator {
+        CountingAllocator {
+            allocations: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn grew(&self, size: usize) {
+        let in_use = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    /// Start measuring a peak from the bytes in use now
+    fn reset_peak(&self) -> usize {
+        let in_use = self.in_use.load(Ordering::Relaxed);
+        self.peak.store(in_use, Ordering::Relaxed);
+        in_use
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.grew(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.grew(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        if new_size > layout.size() {
+            self.grew(new_size - layout.size());
+        } else {
+            self.in_use
+                .fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+
+#[derive(StructOpt)]
+pub struct BenchOpts {
+    /// url, file or `-` for stdin to parse, fetched once
+    source: String,
+    /// Number of times to parse it
+    #[structopt(long, default_value = "10")]
+    iterations: usize,
+    /// Lint the payload before parsing it, as `PrometheusData::from_string_strict` does
+    #[structopt(long)]
+    strict: bool,
+    /// Print the figures as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+/// Figures of parsing a payload, per parse
+struct Report {
+    payload_bytes: usize,
+    samples: usize,
+    iterations: usize,
+    mean_ms: f64,
+    mb_per_second: f64,
+    samples_per_second: f64,
+    /// The most memory allocated by a parse, beyond what was in use before it
+    peak_bytes: usize,
+    allocations: usize,
+}
+
+impl Report {
+    fn new(payload_bytes: usize, samples: usize, runs: &[Run]) -> Report {
+        let iterations = runs.len().max(1);
+        let total: Duration = runs.iter().map(|run| run.elapsed).sum();
+        let seconds = total.as_secs_f64() / iterations as f64;
+        let per_second = |n: usize| match seconds {
+            s if s > 0.0 => n as f64 / s,
+            _ => 0.0,
+        };
+        Report {
+            payload_bytes,
+            samples,
+            iterations: runs.len(),
+            mean_ms: seconds * 1000.0,
+            mb_per_second: per_second(payload_bytes) / 1e6,
+            samples_per_second: per_second(samples),
+            peak_bytes: runs.iter().map(|run| run.peak_bytes).max().unwrap_or(0),
+            allocations: runs.iter().map(|run| run.allocations).sum::<usize>() / iterations,
+        }
+    }
+}
+
+/// The measures of one parse
+struct Run {
+    elapsed: Duration,
+    peak_bytes: usize,
+    allocations: usize,
+}
+
+fn parse(raw: &str, strict: bool) -> Result<PrometheusData, Error> {
+    if !strict {
+        return Ok(PrometheusData::from_string(raw));
+    }
+    PrometheusData::from_string_strict(raw).map_err(|issues| {
+        let first = issues.first().map(|i| i.to_string()).unwrap_or_default();
+        format!("{} lint errors, the first {}", issues.len(), first).into()
+    })
+}
+
+fn measure(raw: &str, strict: bool) -> Result<(Run, PrometheusData), Error> {
+    let baseline = ALLOCATOR.reset_peak();
+    let allocations = ALLOCATOR.allocations.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let data = parse(raw, strict)?;
+    let run = Run {
+        elapsed: start.elapsed(),
+        peak_bytes: ALLOCATOR
+            .peak
+            .load(Ordering::Relaxed)
+            .saturating_sub(baseline),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed) - allocations,
+    };
+    Ok((run, data))
+}
+
+pub async fn run(opts: BenchOpts, settings: &Settings) -> Result<(), Error> {
+    let raw = settings.read_source(&opts.source).await?;
+    let mut runs = Vec::with_capacity(opts.iterations);
+    let mut samples = 0;
+    for _ in 0..opts.iterations.max(1) {
+        let (run, data) = measure(&raw, opts.strict)?;
+        runs.push(run);
+        samples = data.samples().len();
+    }
+    let report = Report::new(raw.len(), samples, &runs);
+    if opts.json {
+        println!("{}", settings.render(&report));
+        return Ok(());
+    }
+    println!(
+        "payload:     {} bytes, {} samples",
+        report.payload_bytes, report.samples
+    );
+    println!("iterations:  {}", report.iterations);
+    println!("mean:        {:.3} ms", report.mean_ms);
+    println!("throughput:  {:.1} MB/s", report.mb_per_second);
+    println!("samples:     {:.0} /s", report.samples_per_second);
+    println!("peak memory: {} bytes", report.peak_bytes);
+    println!("allocations: {} per parse", report.allocations);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measuring_works() {
+        let raw = "# TYPE up gauge\nup{job=\"a\"} 1\nup{job=\"b\"} 0\n";
+        let (run, data) = measure(raw, false).unwrap();
+        assert_eq!(data.samples().len(), 2);
+        assert!(run.allocations > 0);
+        assert!(run.peak_bytes > 0);
+        assert!(measure("up{job=\"a} 1\n", true).is_err());
+
+        let report = Report::new(raw.len(), 2, &[run]);
+        assert_eq!(report.iterations, 1);
+        assert_eq!(report.payload_bytes, raw.len());
+    }
+}
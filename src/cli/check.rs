@@ -0,0 +1,181 @@
+use super::config::Settings;
+use prom2jsonrs::{Labels, Selector};
+use serde::Serialize;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct CheckOpts {
+    /// url to query for prom metrics
+    url: String,
+    /// Name of the metric to check
+    #[structopt(long)]
+    metric: String,
+    /// Only check the series matching this selector, e.g. `{job="api"}`
+    #[structopt(long)]
+    selector: Option<Selector>,
+    /// Warning threshold, e.g. `<1`, `>=100` (a bare number means `>`)
+    #[structopt(long)]
+    warn: Option<Threshold>,
+    /// Critical threshold, e.g. `<1`, `>=100` (a bare number means `>`)
+    #[structopt(long)]
+    crit: Option<Threshold>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+/// Nagios plugin states, ordered by severity
+enum Status {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl Status {
+    fn exit_code(self) -> i32 {
+        match self {
+            Status::Ok => 0,
+            Status::Warning => 1,
+            Status::Critical => 2,
+            Status::Unknown => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Threshold {
+    op: &'static str,
+    value: f64,
+}
+
+impl Threshold {
+    /// Whether `value` is in the alerting range
+    fn breached(&self, value: f64) -> bool {
+        match self.op {
+            "<" => value < self.value,
+            "<=" => value <= self.value,
+            ">=" => value >= self.value,
+            "==" => value == self.value,
+            "!=" => value != self.value,
+            _ => value > self.value,
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Threshold, String> {
+        let s = s.trim();
+        let op = ["<=", ">=", "==", "!=", "<", ">"]
+            .iter()
+            .find(|op| s.starts_with(*op))
+            .copied()
+            .unwrap_or(">");
+        let value = s.trim_start_matches(op).trim();
+        let value =
+            prom2jsonrs::parse_float(value).ok_or_else(|| format!("Invalid threshold {}", s))?;
+        Ok(Threshold { op, value })
+    }
+}
+
+#[derive(Serialize)]
+struct SeriesStatus {
+    labels: Labels,
+    value: String,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    status: Status,
+    code: i32,
+    metric: String,
+    message: String,
+    series: Vec<SeriesStatus>,
+}
+
+fn evaluate(opts: &CheckOpts, value: &str) -> Status {
+    let value = match prom2jsonrs::parse_float(value) {
+        Some(v) if !v.is_nan() => v,
+        _ => return Status::Unknown,
+    };
+    if opts.crit.is_some_and(|t| t.breached(value)) {
+        Status::Critical
+    } else if opts.warn.is_some_and(|t| t.breached(value)) {
+        Status::Warning
+    } else {
+        Status::Ok
+    }
+}
+
+async fn check(opts: &CheckOpts, settings: &Settings) -> CheckResult {
+    let data = match settings.scrape_url(&opts.url).await {
+        Ok(data) => data,
+        Err(err) => {
+            return CheckResult {
+                status: Status::Unknown,
+                code: Status::Unknown.exit_code(),
+                metric: opts.metric.clone(),
+                message: format!("scrape failed: {}", err),
+                series: Vec::new(),
+            }
+        }
+    };
+    let selector = opts.selector.clone().unwrap_or_default();
+    let series: Vec<SeriesStatus> = data
+        .samples()
+        .into_iter()
+        .filter(|s| s.name == opts.metric && selector.matches(s))
+        .map(|s| SeriesStatus {
+            status: evaluate(opts, &s.value),
+            labels: s.labels,
+            value: s.value,
+        })
+        .collect();
+    let (status, message) = if series.is_empty() {
+        (Status::Unknown, "no matching series".to_string())
+    } else {
+        let status = series
+            .iter()
+            .map(|s| s.status)
+            .fold(Status::Ok, |a, b| if b > a { b } else { a });
+        let breached = series.iter().filter(|s| s.status != Status::Ok).count();
+        (
+            status,
+            format!("{} of {} series not ok", breached, series.len()),
+        )
+    };
+    CheckResult {
+        status,
+        code: status.exit_code(),
+        metric: opts.metric.clone(),
+        message,
+        series,
+    }
+}
+
+/// Run the check, printing a JSON summary and exiting with the Nagios status code
+pub async fn run(opts: CheckOpts, settings: &Settings) -> ! {
+    let result = check(&opts, settings).await;
+    println!("{}", serde_json::to_string(&result).unwrap());
+    std::process::exit(result.code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn threshold_parsing_works() {
+        let t: Threshold = "<1".parse().unwrap();
+        assert!(t.breached(0.0));
+        assert!(!t.breached(1.0));
+        let t: Threshold = ">= 100".parse().unwrap();
+        assert!(t.breached(100.0));
+        let t: Threshold = "90".parse().unwrap();
+        assert!(t.breached(91.0) && !t.breached(90.0));
+        assert!("<abc".parse::<Threshold>().is_err());
+    }
+}
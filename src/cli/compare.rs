@@ -0,0 +1,49 @@
+use super::config::Settings;
+use super::diff;
+use super::Error;
+use prom2jsonrs::{CompareOptions, PrometheusData};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct CompareOpts {
+    /// url or file to convert
+    source: String,
+    /// JSON document the conversion is expected to match, e.g. saved from `convert --format json`
+    expected: PathBuf,
+    /// Only compare the families and series, not their values
+    #[structopt(long)]
+    ignore_values: bool,
+    /// Comma separated labels left out of the comparison, e.g. `pod,instance`
+    #[structopt(long, use_delimiter = true)]
+    ignore_labels: Vec<String>,
+    /// Print the report as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+/// Compare the conversion of the source with the expected document, exiting 1 on any drift
+pub async fn run(opts: CompareOpts, settings: &Settings) -> Result<(), Error> {
+    let raw = tokio::fs::read(&opts.expected)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", opts.expected.display(), e))?;
+    let expected: PrometheusData = serde_json::from_slice(&raw)
+        .map_err(|e| format!("Cannot parse {}: {}", opts.expected.display(), e))?;
+    let actual = settings.load(&opts.source).await?;
+    let options = CompareOptions {
+        ignore_values: opts.ignore_values,
+        ignore_labels: opts.ignore_labels,
+    };
+    let drift = prom2jsonrs::compare(&expected, &actual, &options);
+    if opts.json {
+        println!("{}", settings.render(&drift));
+    } else if drift.is_empty() {
+        println!("matches {}", opts.expected.display());
+    } else {
+        diff::print(&drift, "");
+    }
+    if !drift.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
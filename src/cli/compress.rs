@@ -0,0 +1,243 @@
+//! Compression of the files written by `--output`, `--split-by` and archives, and decompression
+//! of the `.gz`, `.zst` and tarball inputs
+
+use super::Error;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            other => Err(format!(
+                "Unknown compression {}, expected gzip or zstd",
+                other
+            )),
+        }
+    }
+}
+
+impl Compression {
+    /// The compression a file name asks for, from its `.gz` or `.zst` extension
+    pub fn from_path(path: &Path) -> Option<Compression> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        match self {
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            }
+            Compression::Zstd => decompressed = zstd::decode_all(data)?,
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Decompresses a file a chunk at a time as it is read, so that it never needs to be held whole
+pub enum StreamDecoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl StreamDecoder {
+    pub fn new(compression: Compression) -> Result<StreamDecoder, Error> {
+        Ok(match compression {
+            Compression::Gzip => StreamDecoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            Compression::Zstd => {
+                StreamDecoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?)
+            }
+        })
+    }
+
+    /// What the next compressed chunk decompresses to
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        let decompressed = match self {
+            StreamDecoder::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            StreamDecoder::Zstd(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(decompressed))
+    }
+
+    /// What is left once the whole file was fed
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        match self {
+            StreamDecoder::Gzip(decoder) => Ok(decoder.finish()?),
+            StreamDecoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+/// Whether a file name is that of a tarball: `.tar.gz`, `.tgz` or `.tar.zst`
+pub fn is_tarball(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    [".tar.gz", ".tgz", ".tar.zst"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+/// The name and contents of every file of a tarball, in the order they were archived
+pub async fn read_tarball(path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let cannot_read = |e: std::io::Error| format!("Cannot read {}: {}", path.display(), e);
+        let file = std::fs::File::open(&path).map_err(cannot_read)?;
+        let reader: Box<dyn Read> = match Compression::from_path(&path) {
+            Some(Compression::Zstd) => Box::new(zstd::Decoder::new(file).map_err(cannot_read)?),
+            _ => Box::new(flate2::read::GzDecoder::new(file)),
+        };
+        let mut members = Vec::new();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(cannot_read)? {
+            let mut entry = entry.map_err(cannot_read)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path().map_err(cannot_read)?.display().to_string();
+            let mut contents = Vec::new();
+            entry
+                .read_to_end(&mut contents)
+                .map_err(|e| format!("Cannot read {} in {}: {}", name, path.display(), e))?;
+            members.push((name, contents));
+        }
+        Ok(members)
+    })
+    .await?
+}
+
+/// `name` with the extension of the compression appended, if any
+pub fn with_extension(name: String, compression: Option<Compression>) -> String {
+    match compression {
+        Some(compression) => format!("{}.{}", name, compression.extension()),
+        None => name,
+    }
+}
+
+/// Compress `data` if asked to
+pub fn maybe_compress(data: &[u8], compression: Option<Compression>) -> Result<Vec<u8>, Error> {
+    match compression {
+        Some(compression) => compression.compress(data),
+        None => Ok(data.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_works() {
+        assert_eq!(
+            Compression::from_path(Path::new("out.json.zst")),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_path(Path::new("out.json")), None);
+        assert!("lz4".parse::<Compression>().is_err());
+
+        let data = b"{\"metrics\":[]}".repeat(100);
+        let gzipped = Compression::Gzip.compress(&data).unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzipped[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(Compression::Gzip.decompress(&gzipped).unwrap(), data);
+        let zstded = Compression::Zstd.compress(&data).unwrap();
+        assert!(zstded.len() < data.len());
+        assert_eq!(zstd::decode_all(&zstded[..]).unwrap(), data);
+        assert_eq!(Compression::Zstd.decompress(&zstded).unwrap(), data);
+        assert_eq!(
+            with_extension("a.json".to_string(), Some(Compression::Gzip)),
+            "a.json.gz"
+        );
+
+        for (compression, compressed) in [(Compression::Gzip, gzipped), (Compression::Zstd, zstded)]
+        {
+            let mut decoder = StreamDecoder::new(compression).unwrap();
+            let mut decoded = Vec::new();
+            for chunk in compressed.chunks(7) {
+                decoded.extend(decoder.feed(chunk).unwrap());
+            }
+            decoded.extend(decoder.finish().unwrap());
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn tarballs_work() {
+        assert!(is_tarball(Path::new("dumps/2024.tar.gz")));
+        assert!(is_tarball(Path::new("dumps.tgz")));
+        assert!(!is_tarball(Path::new("dump.prom.gz")));
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, text) in [("a.prom", "up 1\n"), ("nested/b.prom", "up 0\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, text.as_bytes())
+                .unwrap();
+        }
+        let tarball = Compression::Gzip
+            .compress(&builder.into_inner().unwrap())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("prom2jsonrs-{}.tar.gz", std::process::id()));
+        std::fs::write(&path, tarball).unwrap();
+        let members = read_tarball(&path).await.unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("a.prom".to_string(), b"up 1\n".to_vec()),
+                ("nested/b.prom".to_string(), b"up 0\n".to_vec()),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1599 @@
+use super::compress::{is_tarball, maybe_compress, Compression, StreamDecoder};
+use super::integrity::{self, Integrity};
+use super::record::Recorder;
+use super::scraper::{ScrapeMetadata, Scraper};
+use super::sink::{Document, Sink, SinkOpts};
+use super::split::{self, SplitBy};
+use super::summary;
+use super::vector;
+use super::Error;
+use lazy_static::lazy_static;
+use prom2jsonrs::{
+    lint, openmetrics_to_text, sha256_hex, CounterState, Derivation, HashAlgorithm, InfoJoin,
+    InputFormat, Labels, MetricFamily, ParseError, ParseLimits, PrivacyConfig, PrometheusData,
+    RelabelConfig, Relabeler, Selector, Severity, SloThreshold, StreamParser, UnitConversion,
+    UnitConverter,
+};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// File holding the password, instead of `password`
+    #[serde(default)]
+    pub password_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// OAuth2 client credentials, exchanged for access tokens sent as bearer tokens
+pub struct OAuth2 {
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String,
+    /// File holding the client secret, instead of `client_secret`
+    #[serde(default)]
+    pub client_secret_file: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Extra parameters of the token request, e.g. `audience`
+    #[serde(default)]
+    pub endpoint_params: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    #[serde(default)]
+    pub basic: Option<BasicAuth>,
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    /// File holding the bearer token, instead of `bearer_token`
+    #[serde(default)]
+    pub bearer_token_file: Option<String>,
+    /// Only used for scrapes
+    #[serde(default)]
+    pub oauth2: Option<OAuth2>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// PEM file of an extra CA to trust
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub url: String,
+    /// Labels added to every series scraped from the target
+    #[serde(default)]
+    pub labels: Labels,
+    /// Overrides the global auth for this target
+    #[serde(default)]
+    pub auth: Option<Auth>,
+}
+
+/// Read a secret from a file, without the trailing newline editors and `echo` leave
+fn read_secret(path: &str) -> Result<String, String> {
+    let secret =
+        std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl Auth {
+    /// Replace the `_file` variants with the secrets they hold
+    fn read_secret_files(&mut self) -> Result<(), String> {
+        if let Some(path) = self.bearer_token_file.take() {
+            self.bearer_token = Some(read_secret(&path)?);
+        }
+        if let Some(basic) = &mut self.basic {
+            if let Some(path) = basic.password_file.take() {
+                basic.password = read_secret(&path)?;
+            }
+        }
+        if let Some(oauth2) = &mut self.oauth2 {
+            if let Some(path) = oauth2.client_secret_file.take() {
+                oauth2.client_secret = read_secret(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    pub fn from_url(url: &str) -> Target {
+        Target {
+            name: None,
+            url: url.to_string(),
+            labels: Labels::new(),
+            auth: None,
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filters {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Json,
+    Pretty,
+    /// The metric events of Vector's `prometheus_scrape` source, one a line
+    Vector,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "pretty" => Ok(OutputFormat::Pretty),
+            "vector" => Ok(OutputFormat::Vector),
+            other => Err(format!("Unknown output format {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The issues of exposition text (see `lint`) that make a conversion fail
+pub enum FailOn {
+    /// Lines the parser skips or cannot read as they are: malformed lines, unknown types,
+    /// duplicate series...
+    Error,
+    /// Any issue, lint warnings such as a missing HELP included
+    Warning,
+    Never,
+}
+
+impl FromStr for FailOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FailOn, String> {
+        match s {
+            "error" => Ok(FailOn::Error),
+            "warning" => Ok(FailOn::Warning),
+            "never" => Ok(FailOn::Never),
+            other => Err(format!(
+                "Unknown fail-on policy {}, expected error, warning or never",
+                other
+            )),
+        }
+    }
+}
+
+impl FailOn {
+    fn fails(self, severity: Severity) -> bool {
+        match self {
+            FailOn::Error => severity == Severity::Error,
+            FailOn::Warning => true,
+            FailOn::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+    /// Quantiles estimated from the buckets of every histogram, e.g. `[0.5, 0.9, 0.99]`
+    #[serde(default)]
+    pub percentiles: Vec<f64>,
+    /// Histogram thresholds whose attainment is added, e.g.
+    /// `[http_request_duration_seconds=0.3]`
+    #[serde(default)]
+    pub slo_thresholds: Vec<SloThreshold>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A webhook receiving every converted document
+pub struct PostConfig {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// Defaults to `application/json`
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An endpoint receiving pushed samples
+pub struct PushConfig {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    /// Protobuf POSTed to the url, e.g. `http://collector:4318/v1/metrics`
+    #[default]
+    Http,
+    /// The gRPC metrics service at the url, e.g. `http://collector:4317`
+    Grpc,
+}
+
+#[cfg(feature = "otlp")]
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OtlpProtocol, String> {
+        match s {
+            "http" => Ok(OtlpProtocol::Http),
+            "grpc" => Ok(OtlpProtocol::Grpc),
+            _ => Err(format!(
+                "Unknown OTLP protocol {}, expected http or grpc",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An OpenTelemetry collector receiving every scrape as OTLP metrics
+pub struct OtlpConfig {
+    pub url: String,
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    /// Sent as headers, or gRPC metadata
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Attributes of the resource the metrics come from, `service.name` defaulting to
+    /// `prom2jsonrs`
+    #[serde(default)]
+    pub resource: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A Pushgateway receiving every scrape under a job (and optional instance) grouping key
+pub struct PushgatewayConfig {
+    pub url: String,
+    /// Defaults to `prom2jsonrs`
+    #[serde(default)]
+    pub job: Option<String>,
+    #[serde(default)]
+    pub instance: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The New Relic Metric API, receiving every scrape as gauge, count and summary metrics
+pub struct NewRelicConfig {
+    /// Defaults to the US endpoint, `https://metric-api.newrelic.com/metric/v1`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// License or insert key, sent as the `Api-Key` header
+    #[serde(default)]
+    pub api_key: String,
+    #[serde(default)]
+    pub api_key_file: Option<String>,
+    /// Attributes of every metric, e.g. `service.name`
+    #[serde(default)]
+    pub attributes: Labels,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A Redis server keeping the latest document of every target, and/or publishing them
+pub struct RedisConfig {
+    /// `redis://[:password@]host[:port][/db]`, or `rediss://` for TLS
+    pub url: String,
+    /// Key of the latest document of every target, `{target}` standing for its name
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Expiry of the keys, so that the documents of targets gone away do not linger
+    #[serde(default, with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+    /// Channel every document of a target is published on, `{target}` as in `key`
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A NATS server every family of every scrape is published to, as a JSON message of its own
+pub struct NatsConfig {
+    /// `nats://host:4222`, several servers separated by commas
+    pub url: String,
+    /// Subject of the messages, `{family}` standing for the name of the family
+    pub subject: String,
+    /// Publish to JetStream, waiting for the stream to acknowledge every message
+    #[serde(default)]
+    pub jetstream: bool,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub token_file: Option<String>,
+    /// `.creds` file of a user JWT and NKey
+    #[serde(default)]
+    pub credentials_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An S3 location archiving every document, credentials coming from the standard AWS chain
+/// (environment, profile, web identity, instance metadata...)
+pub struct S3Config {
+    /// `s3://bucket/prefix/`, keys are the prefix followed by the upload time
+    pub uri: String,
+    /// Overrides the region of the AWS config
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Custom endpoint for S3 compatible stores
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Upload gzipped `.json.gz` documents
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A Google Cloud Storage location archiving every document, authenticated with Application
+/// Default Credentials
+pub struct GcsConfig {
+    /// `gs://bucket/prefix/`, keys are the prefix followed by the upload time
+    pub uri: String,
+    /// Custom endpoint, e.g. an emulator, used without credentials
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Upload gzipped `.json.gz` documents
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A client of `serve`, identified by its bearer token, limited to some targets
+pub struct TenantConfig {
+    pub name: String,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub token_file: Option<String>,
+    /// Names of the targets of the config it may scrape
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Hosts, networks and unix sockets the urls it scrapes may be on, none by default
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// What `serve` may scrape, and for whom
+pub struct ServeConfig {
+    /// Hosts (`node-1`, `*.svc.cluster.local`), networks (`10.0.0.0/8`) and unix sockets the
+    /// urls of `?target=` may be on, any host but no socket by default
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Only scrape the targets of the config, never an arbitrary url
+    #[serde(default)]
+    pub named_targets_only: bool,
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The `--config` file
+pub struct Config {
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    /// Prometheus file_sd files listing more targets
+    #[serde(default)]
+    pub file_sd: Vec<String>,
+    /// SRV records resolved to more targets on every scrape
+    #[serde(default)]
+    pub dns_srv: Vec<String>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    #[serde(default)]
+    pub tls: Tls,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    /// Maximum number of targets scraped at once
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    /// Random delay of up to this duration before each scrape in watch and multi-target modes
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Option<Duration>,
+    /// Global limit of scrape requests per second
+    #[serde(default)]
+    pub rate_limit: Option<f64>,
+    /// Speak HTTP/2 to plain http targets without upgrading (h2c). https targets negotiate
+    /// HTTP/2 on their own.
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// Fail on redirects rather than following them, for `serve`
+    #[serde(skip)]
+    pub no_redirects: bool,
+    /// Skip and log the lines the parser cannot read rather than failing, unless `--fail-on`
+    /// makes them fail
+    #[serde(skip)]
+    pub skip_malformed: bool,
+    /// Ask targets for the protobuf exposition format, which some Java and Go exporters still
+    /// answer with. Targets answering in text are parsed as usual.
+    #[serde(default)]
+    pub protobuf: bool,
+    /// How long idle connections are kept for the next scrape (defaults to 90s)
+    #[serde(default, with = "humantime_serde")]
+    pub pool_idle_timeout: Option<Duration>,
+    #[serde(default)]
+    pub filters: Filters,
+    #[serde(default)]
+    pub relabel: Vec<RelabelConfig>,
+    /// Labels of info families joined onto the other series, after relabeling and before
+    /// filtering
+    #[serde(default)]
+    pub joins: Vec<InfoJoin>,
+    /// Series derived from others with arithmetic expressions, after joins and before
+    /// filtering
+    #[serde(default)]
+    pub derive: Vec<Derivation>,
+    /// Unit conversions of families, applied after relabeling and filtering
+    #[serde(default)]
+    pub units: Vec<UnitConversion>,
+    /// Labels redacted or hashed before the data leaves the host
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    /// POST documents here instead of printing them
+    #[serde(default)]
+    pub post: Option<PostConfig>,
+    /// Prometheus remote-write endpoint receiving every scrape
+    #[serde(default)]
+    pub remote_write: Option<PushConfig>,
+    /// Pushgateway receiving every scrape, re-rendered as exposition text
+    #[serde(default)]
+    pub pushgateway: Option<PushgatewayConfig>,
+    /// New Relic Metric API receiving every scrape
+    #[serde(default)]
+    pub newrelic: Option<NewRelicConfig>,
+    /// Redis server keeping the latest document of every target
+    #[serde(default)]
+    pub redis: Option<RedisConfig>,
+    /// NATS server every family of every scrape is published to
+    #[serde(default)]
+    pub nats: Option<NatsConfig>,
+    /// OpenTelemetry collector receiving every scrape
+    #[cfg(feature = "otlp")]
+    #[serde(default)]
+    pub otlp: Option<OtlpConfig>,
+    /// S3 location every document is uploaded to
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    /// Google Cloud Storage location every document is uploaded to
+    #[serde(default)]
+    pub gcs: Option<GcsConfig>,
+    /// Retries of a failed push, with exponential backoff
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub serve: ServeConfig,
+    /// Bounds on the payloads parsed, failing the scrape of targets going over them
+    #[serde(default)]
+    pub limits: ParseLimits,
+    #[serde(default)]
+    pub output: Output,
+}
+
+/// Where the comment of a YAML line starts: a `#` at the start of the line or after whitespace,
+/// outside quoted scalars
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' && previous.is_whitespace() => return Some(i),
+            None if (c == '"' || c == '\'') && " \t[{,:".contains(previous) => quote = Some(c),
+            None => {}
+        }
+        previous = c;
+    }
+    None
+}
+
+/// Replace `${VAR}` with the value of the environment variable `VAR`, failing when it is not
+/// set. `$${VAR}` is a literal `${VAR}`, `${1}` style references of relabel replacements are
+/// left alone, and so are comments.
+fn expand_env(raw: &str) -> Result<String, String> {
+    lazy_static! {
+        static ref VAR: Regex = Regex::new(r"\$?\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    }
+    let mut missing = None;
+    let mut expanded = String::with_capacity(raw.len());
+    for line in raw.split_inclusive('\n') {
+        let (code, comment) = line.split_at(comment_start(line).unwrap_or(line.len()));
+        let code = VAR.replace_all(code, |caps: &Captures| {
+            if caps[0].starts_with("$$") {
+                return caps[0][1..].to_string();
+            }
+            std::env::var(&caps[1]).unwrap_or_else(|_| {
+                missing.get_or_insert_with(|| caps[1].to_string());
+                String::new()
+            })
+        });
+        expanded.push_str(&code);
+        expanded.push_str(comment);
+    }
+    match missing {
+        Some(name) => Err(format!("Environment variable {} is not set", name)),
+        None => Ok(expanded),
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        debug!(path, "loading config");
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config {}: {}", path, e))?;
+        let raw = expand_env(&raw).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        let mut config: Config =
+            serde_yaml::from_str(&raw).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        config.read_secret_files()?;
+        Ok(config)
+    }
+
+    /// Read the secrets of every `_file` setting
+    fn read_secret_files(&mut self) -> Result<(), String> {
+        let auths = self
+            .targets
+            .iter_mut()
+            .filter_map(|t| t.auth.as_mut())
+            .chain(self.auth.as_mut())
+            .chain(self.post.as_mut().and_then(|p| p.auth.as_mut()))
+            .chain(self.remote_write.as_mut().and_then(|p| p.auth.as_mut()))
+            .chain(self.pushgateway.as_mut().and_then(|p| p.auth.as_mut()));
+        for auth in auths {
+            auth.read_secret_files()?;
+        }
+        if let Some(newrelic) = &mut self.newrelic {
+            if let Some(path) = newrelic.api_key_file.take() {
+                newrelic.api_key = read_secret(&path)?;
+            }
+        }
+        if let Some(nats) = &mut self.nats {
+            if let Some(path) = nats.token_file.take() {
+                nats.token = Some(read_secret(&path)?);
+            }
+        }
+        for tenant in &mut self.serve.tenants {
+            if let Some(path) = tenant.token_file.take() {
+                tenant.token = Some(read_secret(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the config lists targets, directly or through discovery
+    pub fn has_targets(&self) -> bool {
+        !(self.targets.is_empty() && self.file_sd.is_empty() && self.dns_srv.is_empty())
+    }
+}
+
+/// Parse a `Name: value` header
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    let mut parts = header.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim().to_string();
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("Invalid header {}, expected `Name: value`", header))?;
+    Ok((name, value.trim().to_string()))
+}
+
+/// Parse a `label[=algorithm]` to hash
+fn parse_hash_label(s: &str) -> Result<(String, HashAlgorithm), String> {
+    match s.split_once('=') {
+        Some((label, algorithm)) => Ok((label.to_string(), algorithm.parse()?)),
+        None => Ok((s.to_string(), HashAlgorithm::default())),
+    }
+}
+
+/// Parse a duration such as `500ms`, `5s` or `1m`; a bare number is in seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(s).map_err(|e| format!("Invalid duration {}: {}", s, e))
+}
+
+#[derive(StructOpt, Clone)]
+// Options shared by every subcommand, overriding the config file
+pub struct GlobalOpts {
+    /// YAML file with targets, auth, TLS, filters, relabel rules and output settings
+    #[structopt(long, global = true)]
+    config: Option<String>,
+    /// Bearer token sent with every scrape
+    #[structopt(long, global = true)]
+    bearer_token: Option<String>,
+    /// `user:password` sent as basic auth with every scrape
+    #[structopt(long, global = true)]
+    basic_auth: Option<String>,
+    /// OAuth2 token url, to scrape with client credentials
+    #[structopt(long, global = true, requires_all = &["oauth2-client-id", "oauth2-client-secret"])]
+    oauth2_token_url: Option<String>,
+    /// OAuth2 client id
+    #[structopt(long, global = true)]
+    oauth2_client_id: Option<String>,
+    /// OAuth2 client secret
+    #[structopt(long, global = true)]
+    oauth2_client_secret: Option<String>,
+    /// Comma separated OAuth2 scopes
+    #[structopt(long, global = true, use_delimiter = true)]
+    oauth2_scopes: Vec<String>,
+    /// Extra `Name: value` header sent with every scrape
+    #[structopt(short = "H", long = "header", global = true, number_of_values = 1)]
+    headers: Vec<String>,
+    /// PEM file of an extra CA to trust
+    #[structopt(long, global = true)]
+    ca_file: Option<String>,
+    /// Skip TLS certificate verification
+    #[structopt(long, global = true)]
+    insecure: bool,
+    /// Scrape timeout, e.g. `10s`
+    #[structopt(long, global = true, parse(try_from_str = parse_duration))]
+    timeout: Option<Duration>,
+    /// Random delay of up to this duration before each scrape in watch and multi-target modes
+    #[structopt(long, global = true, parse(try_from_str = parse_duration))]
+    jitter: Option<Duration>,
+    /// Maximum number of scrape requests per second, across all targets
+    #[structopt(long, global = true)]
+    rate_limit: Option<f64>,
+    /// Speak HTTP/2 to plain http targets without upgrading
+    #[structopt(long, global = true)]
+    http2_prior_knowledge: bool,
+    /// Ask targets for the protobuf exposition format
+    #[structopt(long, global = true)]
+    protobuf: bool,
+    /// Only keep series matching one of these selectors, e.g. `up` or `{job="api"}`
+    #[structopt(long, global = true, number_of_values = 1)]
+    include: Vec<Selector>,
+    /// Drop series matching any of these selectors
+    #[structopt(long, global = true, number_of_values = 1)]
+    exclude: Vec<Selector>,
+    /// Join the labels of an info family onto the series sharing its identifying labels, e.g.
+    /// `node_uname_info on(instance) group_left(release)`
+    #[structopt(long, global = true, number_of_values = 1)]
+    join_info: Vec<InfoJoin>,
+    /// Add a series computed from others, e.g.
+    /// `mem_used_ratio = node_memory_Active_bytes / node_memory_MemTotal_bytes`
+    #[structopt(long, global = true, number_of_values = 1)]
+    derive: Vec<Derivation>,
+    /// Convert the families named after a unit to another, e.g. `seconds:milliseconds` or
+    /// `bytes:MiB`, renaming them after it
+    #[structopt(long, global = true, number_of_values = 1)]
+    convert_units: Vec<UnitConversion>,
+    /// Replace the values of this label with `REDACTED`
+    #[structopt(long, global = true, number_of_values = 1)]
+    redact_label: Vec<String>,
+    /// Replace the values of this label with their digest, e.g. `email` or `email=sha512`
+    /// (sha256 by default)
+    #[structopt(long, global = true, number_of_values = 1, parse(try_from_str = parse_hash_label))]
+    hash_label: Vec<(String, HashAlgorithm)>,
+    /// Comma separated quantiles to estimate from the buckets of every histogram, e.g.
+    /// `0.5,0.9,0.99`, added to its `percentiles`
+    #[structopt(long, global = true, require_delimiter = true)]
+    percentiles: Vec<f64>,
+    /// Add the fraction of the observations of a histogram at most a threshold, e.g.
+    /// `http_request_duration_seconds=0.3`, as `<family>_slo_attainment`
+    #[structopt(long, global = true, number_of_values = 1)]
+    slo_threshold: Vec<SloThreshold>,
+    /// Output format: `json`, `pretty` or `vector` (one Vector metric event a line, for Vector and
+    /// Fluent Bit pipelines). Defaults to a summary on terminals, json otherwise.
+    #[structopt(long, global = true)]
+    format: Option<OutputFormat>,
+    /// Write the output to this file instead of stdout, compressed if named `*.gz` or `*.zst`
+    #[structopt(short, long, global = true)]
+    output: Option<PathBuf>,
+    /// Compress output files, split files and archives with `gzip` or `zstd`
+    #[structopt(long, global = true)]
+    compress: Option<Compression>,
+    /// Write one file per `family` in `--output-dir` instead of printing
+    #[structopt(long, global = true, requires = "output-dir")]
+    split_by: Option<SplitBy>,
+    /// Directory of the files written with `--split-by`
+    #[structopt(long, global = true, requires = "split-by")]
+    output_dir: Option<PathBuf>,
+    /// Only check that every target can be fetched (connectivity, auth and TLS), printing a
+    /// status per target instead of converting
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    /// Save the raw exposition text of every scrape in this directory, along with its
+    /// conversion, for `replay --recording`
+    #[structopt(long, global = true)]
+    record: Option<PathBuf>,
+    /// Add the per-second rate of every `*_total` counter since the previous conversion, as a
+    /// `<family>_rate` gauge
+    #[structopt(long, global = true)]
+    rates: bool,
+    /// Load the last-seen counter values from this file and save them back after every
+    /// conversion, so that `--rates` carries over restarts and one-shot runs
+    #[structopt(long, global = true)]
+    state_file: Option<PathBuf>,
+    /// Fail conversions of text with parse `error`s (lines skipped or misread), with any issue
+    /// down to a `warning`, or `never` and convert what can be
+    #[structopt(long, global = true, default_value = "never")]
+    fail_on: FailOn,
+    /// Format of files and stdin: `text`, `openmetrics`, `protobuf` or `json` (documents
+    /// converted before). Detected from the first bytes by default.
+    #[structopt(long, global = true)]
+    from: Option<InputFormat>,
+    /// Add the SHA-256 of the raw payload and of the converted data to every document, in an
+    /// `integrity` field
+    #[structopt(long, global = true)]
+    checksum: bool,
+    /// Keep a `<output>.sha256` file with the SHA-256 of the output file, for `sha256sum -c`
+    #[structopt(long, global = true, requires = "output")]
+    emit_checksum: bool,
+    /// Add how the scrape of every target went (status, duration, payload size and parse
+    /// issues) to the documents, in a `scrape` field
+    #[structopt(long, global = true)]
+    scrape_metadata: bool,
+    /// Set by `serve`, whose clients could be redirected past the hosts they may scrape
+    #[structopt(skip)]
+    no_redirects: bool,
+}
+
+impl GlobalOpts {
+    /// The same options, failing on redirects rather than following them
+    pub fn without_redirects(self) -> GlobalOpts {
+        GlobalOpts {
+            no_redirects: true,
+            ..self
+        }
+    }
+}
+
+/// The effective settings, the command line taking precedence over the config file
+pub struct Settings {
+    pub config: Config,
+    pub scraper: Scraper,
+    pub include: Vec<Selector>,
+    pub exclude: Vec<Selector>,
+    pub relabeler: Relabeler,
+    pub units: UnitConverter,
+    pub format: OutputFormat,
+    /// Where documents go, stdout when empty
+    pub sinks: Vec<Sink>,
+    /// Print documents as a colored summary rather than JSON
+    pub summary: bool,
+    /// Write documents as one file per family in this directory
+    pub split_dir: Option<PathBuf>,
+    /// Write documents to this file rather than stdout
+    pub output_file: Option<PathBuf>,
+    /// Compression of the files written
+    pub compression: Option<Compression>,
+    /// Whether the output file was written to yet: it is truncated first, then appended to
+    output_started: AtomicBool,
+    pub dry_run: bool,
+    /// Where the raw text of every scrape is saved
+    pub recorder: Option<Recorder>,
+    /// The last counter values rates are computed from, with `--rates` or `--state-file`
+    counters: Option<Mutex<CounterState>>,
+    /// Where the counter values are saved
+    state_file: Option<PathBuf>,
+    /// The parse issues that fail a conversion
+    pub fail_on: FailOn,
+    /// The format of files and stdin, detected when not given
+    from: Option<InputFormat>,
+    /// The digests of the payloads last read from each source or url, with `--checksum`
+    raw_digests: Option<Mutex<HashMap<String, String>>>,
+    /// The running digest of the output file, with `--emit-checksum`
+    output_digest: Option<Mutex<Sha256>>,
+    /// How the last scrape of each target went, by display name, with `--scrape-metadata`
+    scrapes: Option<Mutex<HashMap<String, ScrapeMetadata>>>,
+    /// What the settings were built from, to build them again on reload
+    opts: (GlobalOpts, SinkOpts),
+}
+
+impl Settings {
+    pub fn new(opts: GlobalOpts, sink_opts: SinkOpts) -> Result<Settings, Error> {
+        let given = (opts.clone(), sink_opts.clone());
+        let mut config = match &opts.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        sink_opts.apply(&mut config)?;
+        if opts.bearer_token.is_some() || opts.basic_auth.is_some() {
+            let basic = opts.basic_auth.map(|auth| {
+                let mut parts = auth.splitn(2, ':');
+                BasicAuth {
+                    username: parts.next().unwrap_or("").to_string(),
+                    password: parts.next().unwrap_or("").to_string(),
+                    password_file: None,
+                }
+            });
+            config.auth = Some(Auth {
+                basic,
+                bearer_token: opts.bearer_token,
+                ..Default::default()
+            });
+        }
+        if let Some(token_url) = opts.oauth2_token_url {
+            config.auth = Some(Auth {
+                oauth2: Some(OAuth2 {
+                    token_url,
+                    client_id: opts.oauth2_client_id.unwrap_or_default(),
+                    client_secret: opts.oauth2_client_secret.unwrap_or_default(),
+                    scopes: opts.oauth2_scopes,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        for header in opts.headers {
+            let (name, value) = parse_header(&header)?;
+            config.headers.insert(name, value);
+        }
+        if opts.ca_file.is_some() {
+            config.tls.ca_file = opts.ca_file;
+        }
+        config.tls.insecure_skip_verify |= opts.insecure;
+        config.http2_prior_knowledge |= opts.http2_prior_knowledge;
+        config.no_redirects |= opts.no_redirects;
+        config.skip_malformed = opts.fail_on == FailOn::Never;
+        config.protobuf |= opts.protobuf;
+        if opts.timeout.is_some() {
+            config.timeout = opts.timeout;
+        }
+        if opts.jitter.is_some() {
+            config.jitter = opts.jitter;
+        }
+        if opts.rate_limit.is_some() {
+            config.rate_limit = opts.rate_limit;
+        }
+        let parse_all = |selectors: &[String]| -> Result<Vec<Selector>, String> {
+            selectors.iter().map(|s| s.parse()).collect()
+        };
+        let include = if opts.include.is_empty() {
+            parse_all(&config.filters.include)?
+        } else {
+            opts.include
+        };
+        let exclude = if opts.exclude.is_empty() {
+            parse_all(&config.filters.exclude)?
+        } else {
+            opts.exclude
+        };
+        if !opts.percentiles.is_empty() {
+            config.output.percentiles = opts.percentiles;
+        }
+        if !opts.slo_threshold.is_empty() {
+            config.output.slo_thresholds = opts.slo_threshold;
+        }
+        if let Some(q) = config
+            .output
+            .percentiles
+            .iter()
+            .find(|q| !(0.0..=1.0).contains(*q))
+        {
+            return Err(format!("Invalid percentile {}, expected between 0 and 1", q).into());
+        }
+        let relabeler = Relabeler::new(&config.relabel)?;
+        if !opts.join_info.is_empty() {
+            config.joins = opts.join_info;
+        }
+        if !opts.derive.is_empty() {
+            config.derive = opts.derive;
+        }
+        if !opts.convert_units.is_empty() {
+            config.units = opts.convert_units;
+        }
+        let units = UnitConverter::new(&config.units)?;
+        // Added to those of the config, which should not be lifted by mistake
+        config.privacy.redact.extend(opts.redact_label);
+        config.privacy.hash.extend(opts.hash_label);
+        let format = opts.format.or(config.output.format);
+        // Terminals get a summary unless a format is asked for, pipes always get JSON
+        let summary = format.is_none() && opts.output.is_none() && std::io::stdout().is_terminal();
+        let output_file = opts.output;
+        let compression = opts
+            .compress
+            .or_else(|| output_file.as_deref().and_then(Compression::from_path));
+        let format = format.unwrap_or(OutputFormat::Json);
+        if opts.record.is_some() && config.protobuf {
+            return Err("--record saves text payloads, it cannot scrape protobuf".into());
+        }
+        if opts.scrape_metadata && config.protobuf {
+            return Err("--scrape-metadata reads text payloads, it cannot scrape protobuf".into());
+        }
+        let recorder = opts.record.as_deref().map(Recorder::new).transpose()?;
+        let counters = match &opts.state_file {
+            Some(path) => Some(load_state(path)?),
+            None => opts.rates.then(CounterState::new),
+        };
+        let scraper = Scraper::new(&config)?;
+        let sinks = Sink::from_config(&config)?;
+        Ok(Settings {
+            config,
+            scraper,
+            include,
+            exclude,
+            relabeler,
+            units,
+            format,
+            sinks,
+            summary,
+            split_dir: opts.split_by.and(opts.output_dir),
+            output_file,
+            compression,
+            output_started: AtomicBool::new(false),
+            dry_run: opts.dry_run,
+            recorder,
+            counters: counters.map(Mutex::new),
+            state_file: opts.state_file,
+            fail_on: opts.fail_on,
+            from: opts.from,
+            raw_digests: opts.checksum.then(Default::default),
+            output_digest: opts.emit_checksum.then(Default::default),
+            scrapes: opts.scrape_metadata.then(Default::default),
+            opts: given,
+        })
+    }
+
+    /// Build the settings again from the same options, reading the config file and its secret
+    /// files anew
+    pub fn reload(&self) -> Result<Settings, Error> {
+        let settings = Settings::new(self.opts.0.clone(), self.opts.1.clone())?;
+        // Keep the counter values seen since the state file was saved, if there is one
+        if let (Some(counters), Some(previous)) = (&settings.counters, &self.counters) {
+            let previous = previous.lock().unwrap_or_else(|e| e.into_inner());
+            *counters.lock().unwrap_or_else(|e| e.into_inner()) = previous.clone();
+        }
+        // Keep appending to the output file, and hashing what it holds
+        if let (Some(digest), Some(previous)) = (&settings.output_digest, &self.output_digest) {
+            let previous = previous.lock().unwrap_or_else(|e| e.into_inner());
+            *digest.lock().unwrap_or_else(|e| e.into_inner()) = previous.clone();
+        }
+        settings
+            .output_started
+            .store(self.output_started.load(Ordering::SeqCst), Ordering::SeqCst);
+        Ok(settings)
+    }
+}
+
+/// The counter values saved in a state file, none if it does not exist yet. A file that cannot
+/// be parsed, e.g. one cut short by a crash, is ignored with a warning.
+fn load_state(path: &Path) -> Result<CounterState, Error> {
+    let raw = match std::fs::read(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(CounterState::new()),
+        Err(err) => return Err(format!("Cannot read {}: {}", path.display(), err).into()),
+    };
+    match serde_json::from_slice(&raw) {
+        Ok(state) => Ok(state),
+        Err(err) => {
+            warn!(path = %path.display(), error = %err, "ignoring unreadable state file");
+            Ok(CounterState::new())
+        }
+    }
+}
+
+/// Whether `source` is a url to scrape rather than a file
+fn is_url(source: &str) -> bool {
+    ["http://", "https://", "unix://"]
+        .iter()
+        .any(|scheme| source.starts_with(scheme))
+}
+
+/// Tarballs hold several payloads, which only `convert` converts
+fn refuse_tarball(source: &str) -> Result<(), String> {
+    if is_tarball(Path::new(source)) {
+        return Err(format!(
+            "{} is a tarball, convert its members with `convert`",
+            source
+        ));
+    }
+    Ok(())
+}
+
+/// A file (decompressed if named `.gz` or `.zst`), or stdin when `source` is `-`, read whole
+async fn read_file(source: &str) -> Result<Vec<u8>, Error> {
+    if source == "-" {
+        let mut raw = Vec::new();
+        tokio::io::stdin().read_to_end(&mut raw).await?;
+        return Ok(raw);
+    }
+    refuse_tarball(source)?;
+    let raw = tokio::fs::read(source)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", source, e))?;
+    match Compression::from_path(Path::new(source)) {
+        Some(compression) => Ok(compression
+            .decompress(&raw)
+            .map_err(|e| format!("Cannot decompress {}: {}", source, e))?),
+        None => Ok(raw),
+    }
+}
+
+/// How much of a file is read before detecting its format
+const DETECT_BYTES: usize = 64 * 1024;
+
+/// Parse a file (decompressing `.gz` and `.zst` files), or stdin when `source` is `-`, of the
+/// format `from` or the one detected from its first bytes. Text is parsed as it is read a chunk
+/// at a time, handing the families parsed so far to `each` after every chunk, while the other
+/// formats are parsed whole.
+async fn read_families<F>(
+    source: &str,
+    mut parser: StreamParser,
+    from: Option<InputFormat>,
+    mut each: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Vec<MetricFamily>) -> Result<(), Error>,
+{
+    refuse_tarball(source)?;
+    let cannot_read = |e: std::io::Error| format!("Cannot read {}: {}", source, e);
+    let mut reader: Box<dyn AsyncRead + Unpin + Send> = if source == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(source).await.map_err(cannot_read)?)
+    };
+    let mut decoder = match Compression::from_path(Path::new(source)) {
+        Some(compression) if source != "-" => Some(StreamDecoder::new(compression)?),
+        _ => None,
+    };
+    let cannot_parse = |e: String| format!("Cannot parse {}: {}", source, e);
+    let unparsed = |e: ParseError| cannot_parse(e.to_string());
+    let mut format = from;
+    // The bytes read before detecting the format, then all of them in other formats than text
+    let mut whole = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let cannot_decompress = |e| format!("Cannot decompress {}: {}", source, e);
+    loop {
+        let read = reader.read(&mut chunk).await.map_err(cannot_read)?;
+        let bytes = match (decoder.as_mut(), read) {
+            (Some(_), 0) => decoder.take().map_or(Ok(vec![]), |d| d.finish()),
+            (Some(decoder), _) => decoder.feed(&chunk[..read]),
+            (None, _) => Ok(chunk[..read].to_vec()),
+        }
+        .map_err(cannot_decompress)?;
+        match format {
+            Some(InputFormat::Text) => parser.feed(&bytes).map_err(unparsed)?,
+            _ => whole.extend_from_slice(&bytes),
+        }
+        if format.is_none() && (whole.len() >= DETECT_BYTES || read == 0) {
+            let detected = InputFormat::detect(&whole);
+            debug!(source, format = %detected, "detected input format");
+            if detected == InputFormat::Text {
+                parser.feed(&std::mem::take(&mut whole)).map_err(unparsed)?;
+            }
+            format = Some(detected);
+        }
+        if read == 0 {
+            break;
+        }
+        let families = parser.take_families();
+        if !families.is_empty() {
+            each(families)?;
+        }
+    }
+    match format.unwrap_or(InputFormat::Text) {
+        InputFormat::Text => each(parser.finish().map_err(unparsed)?.metrics),
+        format => each(
+            format
+                .parse_with(&whole, parser)
+                .map_err(cannot_parse)?
+                .metrics,
+        ),
+    }
+}
+
+impl Settings {
+    /// Add the target labels, relabel, join, derive, add SLO attainments, filter, enrich and
+    /// anonymize freshly parsed data
+    pub fn process(&self, data: &mut PrometheusData, target_labels: &Labels) {
+        data.add_labels(target_labels);
+        self.relabeler.apply(data);
+        for join in &self.config.joins {
+            data.join_info(join);
+        }
+        for derivation in &self.config.derive {
+            data.derive(derivation);
+        }
+        for slo in &self.config.output.slo_thresholds {
+            data.add_slo_attainment(slo);
+        }
+        data.filter(&self.include, &self.exclude);
+        self.units.apply(data);
+        data.add_percentiles(&self.config.output.percentiles);
+        self.config.privacy.apply(data);
+    }
+
+    /// Log the issues of the text read from `source`, failing if `--fail-on` says one of them
+    /// should
+    fn check_issues(&self, source: &str, raw: &str) -> Result<(), Error> {
+        if self.fail_on == FailOn::Never {
+            return Ok(());
+        }
+        let issues = lint(raw);
+        for issue in &issues {
+            warn!(source, %issue, "parse issue");
+        }
+        let failing = issues
+            .iter()
+            .filter(|issue| self.fail_on.fails(issue.severity))
+            .count();
+        if failing > 0 {
+            return Err(format!("{} parse issues in {}", failing, source).into());
+        }
+        Ok(())
+    }
+
+    /// Whether conversions need the whole raw payload rather than parsing it as it arrives, to
+    /// lint or hash it
+    fn needs_raw(&self) -> bool {
+        self.fail_on != FailOn::Never || self.raw_digests.is_some() || self.scrapes.is_some()
+    }
+
+    /// Remember the digest of the payload read from `source`, with `--checksum`
+    fn hash_raw(&self, source: &str, raw: &[u8]) {
+        if let Some(digests) = &self.raw_digests {
+            let mut digests = digests.lock().unwrap_or_else(|e| e.into_inner());
+            digests.insert(source.to_string(), sha256_hex(raw));
+        }
+    }
+
+    /// The integrity metadata of `data` with `--checksum`, along with the digest of the payload
+    /// last read from `source` if there is one
+    pub fn integrity(&self, source: Option<&str>, data: &PrometheusData) -> Option<Integrity> {
+        let digests = self.raw_digests.as_ref()?;
+        let raw = source.and_then(|source| {
+            let mut digests = digests.lock().unwrap_or_else(|e| e.into_inner());
+            digests.remove(source)
+        });
+        Some(Integrity::new(raw, data))
+    }
+
+    /// Whether `--checksum` asked for integrity metadata
+    pub fn checksums(&self) -> bool {
+        self.raw_digests.is_some()
+    }
+
+    /// Whether `--rates` or `--state-file` asked for rates
+    pub fn rates(&self) -> bool {
+        self.counters.is_some()
+    }
+
+    /// Add the rates of the counters of `data`, scraped from `scope` (a source or a target)
+    /// at `scraped_at`, with `--rates`
+    pub fn add_rates(&self, scope: &str, data: &mut PrometheusData, scraped_at: SystemTime) {
+        if let Some(counters) = &self.counters {
+            let ms = scraped_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+            data.add_rates(&mut counters, scope, ms.as_millis() as i64);
+        }
+    }
+
+    /// Save the counter values to the state file if any, failures only being logged
+    pub async fn save_state(&self) {
+        let (counters, path) = match (&self.counters, &self.state_file) {
+            (Some(counters), Some(path)) => (counters, path),
+            _ => return,
+        };
+        let state = {
+            let counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+            serde_json::to_vec(&*counters)
+        };
+        // Written whole then renamed, a crash never leaves half a file
+        let partial = path.with_extension("partial");
+        let saved = match state {
+            Ok(state) => match tokio::fs::write(&partial, state).await {
+                Ok(()) => tokio::fs::rename(&partial, path).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = saved {
+            warn!(path = %path.display(), error = %err, "cannot save state file");
+        }
+    }
+
+    /// A random delay in `[0, jitter)`, to spread scrapes of many targets over time
+    pub fn jitter(&self) -> Duration {
+        match self.config.jitter {
+            Some(jitter) if !jitter.is_zero() => jitter.mul_f64(fastrand::f64()),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// How the last scrape of the target named `name` went, with `--scrape-metadata`
+    pub fn scrape_metadata(&self, name: &str) -> Option<ScrapeMetadata> {
+        let scrapes = self.scrapes.as_ref()?;
+        let mut scrapes = scrapes.lock().unwrap_or_else(|e| e.into_inner());
+        scrapes.remove(name)
+    }
+
+    /// Scrape, parse and process a target, recording the raw text and its conversion with
+    /// `--record`, and how the scrape went with `--scrape-metadata`
+    pub async fn scrape(&self, target: &Target) -> Result<PrometheusData, Error> {
+        let start = Instant::now();
+        let mut metadata = ScrapeMetadata::new(&target.url);
+        let result = self.scrape_target(target, &mut metadata).await;
+        if let Some(scrapes) = &self.scrapes {
+            metadata.finish(&result, start.elapsed());
+            let mut scrapes = scrapes.lock().unwrap_or_else(|e| e.into_inner());
+            scrapes.insert(target.display_name().to_string(), metadata);
+        }
+        result
+    }
+
+    async fn scrape_target(
+        &self,
+        target: &Target,
+        metadata: &mut ScrapeMetadata,
+    ) -> Result<PrometheusData, Error> {
+        let start = Instant::now();
+        let scraped_at = SystemTime::now();
+        let auth = target.auth.as_ref();
+        // Recording, linting, hashing and describing the payload need the whole body rather
+        // than parsing it as it arrives
+        let (mut data, raw) = if self.recorder.is_some() || self.needs_raw() {
+            let (status, raw) = self.scraper.fetch_with_status(&target.url, auth).await?;
+            if self.scrapes.is_some() {
+                metadata.read(status, &raw);
+            }
+            self.hash_raw(&target.url, raw.as_bytes());
+            self.check_issues(&target.url, &raw)?;
+            (self.scraper.parse(&target.url, &raw)?, Some(raw))
+        } else {
+            (self.scraper.scrape(&target.url, auth).await?, None)
+        };
+        let families = data.metrics.len();
+        self.process(&mut data, &target.labels);
+        if let (Some(recorder), Some(raw)) = (&self.recorder, raw) {
+            if let Err(err) = recorder.write(target, scraped_at, &raw, &data).await {
+                warn!(target = target.display_name(), error = %err, "recording failed");
+            }
+        }
+        info!(
+            target = target.display_name(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            families,
+            kept_families = data.metrics.len(),
+            "scraped"
+        );
+        Ok(data)
+    }
+
+    /// Scrape, parse and process a plain url
+    pub async fn scrape_url(&self, url: &str) -> Result<PrometheusData, Error> {
+        self.scrape(&Target::from_url(url)).await
+    }
+
+    /// Read raw metrics from an http(s) or unix socket url, a file (decompressed if named `.gz`
+    /// or `.zst`), or stdin when `source` is `-`
+    pub async fn read_source(&self, source: &str) -> Result<String, Error> {
+        if is_url(source) {
+            return self.scraper.fetch(source).await;
+        }
+        let raw = read_file(source).await?;
+        Ok(String::from_utf8(raw).map_err(|e| format!("Cannot read {}: {}", source, e))?)
+    }
+
+    /// Load and process metrics from an http(s) or unix socket url, a file, or stdin when
+    /// `source` is `-`. Everything is parsed while it streams in, never held whole.
+    pub async fn load(&self, source: &str) -> Result<PrometheusData, Error> {
+        if is_url(source) {
+            return self.scrape_url(source).await;
+        }
+        if self.needs_raw() {
+            return self.parse_input(source, &read_file(source).await?);
+        }
+        let mut data = PrometheusData { metrics: vec![] };
+        read_families(source, self.scraper.parser(source), self.from, |metrics| {
+            data.metrics.extend(metrics);
+            Ok(())
+        })
+        .await?;
+        // Processed as a whole, joins needing the info families along with the others
+        self.process(&mut data, &Labels::new());
+        Ok(data)
+    }
+
+    /// Parse and process what was read from `source`, of the format `--from` or the detected
+    /// one, checking the issues of text first
+    pub fn parse_input(&self, source: &str, raw: &[u8]) -> Result<PrometheusData, Error> {
+        self.hash_raw(source, raw);
+        let format = self.from.unwrap_or_else(|| InputFormat::detect(raw));
+        let mut data = match format {
+            InputFormat::Text | InputFormat::OpenMetrics => {
+                let mut text = String::from_utf8_lossy(raw);
+                if format == InputFormat::OpenMetrics {
+                    text = openmetrics_to_text(&text).into();
+                }
+                self.check_issues(source, &text)?;
+                self.scraper.parse(source, &text)?
+            }
+            format => format
+                .parse_with(raw, self.scraper.parser(source))
+                .map_err(|e| format!("Cannot parse {}: {}", source, e))?,
+        };
+        self.process(&mut data, &Labels::new());
+        Ok(data)
+    }
+
+    /// Load and process metrics like `load`, handing them to `each` a few families at a time
+    /// as they are parsed. Files and stdin are converted in constant memory this way, while
+    /// urls, and sources that an option needs to read whole (see `needs_raw`), come in a single
+    /// piece. Info families are only joined onto the families parsed along with them.
+    pub async fn load_families<F>(&self, source: &str, mut each: F) -> Result<(), Error>
+    where
+        F: FnMut(PrometheusData) -> Result<(), Error>,
+    {
+        if is_url(source) || self.needs_raw() {
+            return each(self.load(source).await?);
+        }
+        read_families(source, self.scraper.parser(source), self.from, |metrics| {
+            let mut data = PrometheusData { metrics };
+            self.process(&mut data, &Labels::new());
+            each(data)
+        })
+        .await
+    }
+
+    /// Print a rendered document, or hand it to the sinks
+    pub async fn emit<D: Document>(&self, document: &D, body: String) -> Result<(), Error> {
+        if self.sinks.is_empty() {
+            match &self.output_file {
+                Some(path) => self.write_output(path, &body).await?,
+                None => println!("{}", body),
+            }
+        }
+        let (data, sections) = (document.data(), document.sections());
+        for sink in &self.sinks {
+            sink.send(&body, &data, &sections).await?;
+        }
+        Ok(())
+    }
+
+    /// Write a document to the output file, each one a line (or a compressed frame) after the
+    /// previous ones, updating its sidecar with `--emit-checksum`
+    async fn write_output(&self, path: &Path, body: &str) -> Result<(), Error> {
+        let contents = maybe_compress(format!("{}\n", body).as_bytes(), self.compression)?;
+        let append = self.output_started.swap(true, Ordering::SeqCst);
+        let digest = self.output_digest.as_ref().map(|digest| {
+            let mut digest = digest.lock().unwrap_or_else(|e| e.into_inner());
+            if !append {
+                digest.reset();
+            }
+            digest.update(&contents);
+            digest.clone()
+        });
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .await
+            .map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+        file.write_all(&contents).await?;
+        if let Some(digest) = digest {
+            integrity::write_sidecar(path, &digest).await?;
+        }
+        Ok(())
+    }
+
+    /// Render a document in the configured output format and emit it
+    pub async fn output<D: Document>(&self, document: &D) -> Result<(), Error> {
+        if let Some(dir) = &self.split_dir {
+            return split::write_families(document, dir, self.compression, |family| {
+                self.render(family)
+            })
+            .await;
+        }
+        if self.summary && self.sinks.is_empty() {
+            print!("{}", summary::render(document, summary::use_color(), None));
+            return Ok(());
+        }
+        if self.format == OutputFormat::Vector {
+            let events = vector::render(document, SystemTime::now());
+            return self.emit(document, events).await;
+        }
+        self.emit(document, self.render(document)).await
+    }
+
+    /// Serialize a document in the configured output format. Reports and other values than
+    /// scrapes have no Vector events, and are JSON in the `vector` format.
+    pub fn render<T: serde::Serialize>(&self, value: &T) -> String {
+        match self.format {
+            OutputFormat::Json | OutputFormat::Vector => serde_json::to_string(value).unwrap(),
+            OutputFormat::Pretty => serde_json::to_string_pretty(value).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prom2jsonrs::Unit;
+
+    #[test]
+    fn config_parsing_works() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+targets:
+  - name: api
+    url: http://localhost:9090/metrics
+    labels: {env: prod}
+auth:
+  bearer_token: secret
+tls:
+  insecure_skip_verify: true
+interval: 10s
+filters:
+  include: ['{job="api"}']
+relabel:
+  - source_labels: [instance]
+    regex: '(.*):\d+'
+    target_label: host
+joins:
+  - {info: node_uname_info, on: [instance], labels: [release]}
+derive:
+  - 'mem_used_ratio = node_memory_Active_bytes / node_memory_MemTotal_bytes'
+units:
+  - {from: seconds, to: ms}
+  - {from: bytes, to: MiB, metrics: 'node_memory_.*'}
+output:
+  format: pretty
+  percentiles: [0.5, 0.99]
+  slo_thresholds: ['http_request_duration_seconds=0.3']
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.targets[0].display_name(), "api");
+        assert_eq!(config.interval, Some(Duration::from_secs(10)));
+        assert_eq!(config.output.format, Some(OutputFormat::Pretty));
+        assert_eq!(config.output.percentiles, vec![0.5, 0.99]);
+        assert_eq!(config.output.slo_thresholds[0].threshold, 0.3);
+        assert_eq!(config.units[1].to, Unit::Mebibytes);
+        assert_eq!(config.joins[0].labels, vec!["release"]);
+        assert_eq!(config.derive[0].name, "mem_used_ratio");
+        assert!(serde_yaml::from_str::<Config>("unknown: 1").is_err());
+        assert_eq!(parse_duration("5").unwrap(), Duration::from_secs(5));
+        assert_eq!(parse_duration("1m").unwrap(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn config_expansion_works() {
+        std::env::set_var("PROM2JSONRS_TEST_TOKEN", "s3cr3t");
+        assert_eq!(
+            expand_env("token: ${PROM2JSONRS_TEST_TOKEN}, replacement: '${1}', raw: $${HOME}")
+                .unwrap(),
+            "token: s3cr3t, replacement: '${1}', raw: ${HOME}"
+        );
+        assert!(expand_env("token: ${PROM2JSONRS_TEST_UNSET}").is_err());
+        let commented = "# token: ${PROM2JSONRS_TEST_UNSET}\n\
+                         url: 'http://host/#${PROM2JSONRS_TEST_TOKEN}' # was ${PROM2JSONRS_TEST_UNSET}\n";
+        assert_eq!(
+            expand_env(commented).unwrap(),
+            "# token: ${PROM2JSONRS_TEST_UNSET}\n\
+             url: 'http://host/#s3cr3t' # was ${PROM2JSONRS_TEST_UNSET}\n"
+        );
+
+        let path = std::env::temp_dir().join(format!("prom2jsonrs-secret-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+        let mut config: Config = serde_yaml::from_str(&format!(
+            "auth: {{bearer_token_file: {}}}\ntargets:\n  - url: http://a\n    auth: {{basic: {{username: u, password_file: {}}}}}",
+            path.display(),
+            path.display()
+        ))
+        .unwrap();
+        config.read_secret_files().unwrap();
+        assert_eq!(config.auth.unwrap().bearer_token.unwrap(), "from-file");
+        let basic = config.targets[0].auth.clone().unwrap().basic.unwrap();
+        assert_eq!(basic.password, "from-file");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The families read from `source`, as JSON
+    async fn read_all(source: &str, from: Option<InputFormat>) -> Result<String, Error> {
+        let mut metrics = Vec::new();
+        read_families(source, StreamParser::new(), from, |families| {
+            metrics.extend(families);
+            Ok(())
+        })
+        .await?;
+        Ok(serde_json::to_string(&PrometheusData { metrics })?)
+    }
+
+    #[tokio::test]
+    async fn reading_families_works() {
+        let raw = "# TYPE up gauge\nup{job=\"a\"} 1\nup{job=\"b\"} 0\n# TYPE info gauge\ninfo 1\n";
+        let path = std::env::temp_dir().join(format!("prom2jsonrs-read-{}", std::process::id()));
+        std::fs::write(&path, raw).unwrap();
+        let source = path.to_str().unwrap();
+        let expected = serde_json::to_string(&PrometheusData::from_string(raw)).unwrap();
+        assert_eq!(read_all(source, None).await.unwrap(), expected);
+        // A converted document is detected and read back the same
+        std::fs::write(&path, &expected).unwrap();
+        assert_eq!(read_all(source, None).await.unwrap(), expected);
+        let json = Some(InputFormat::Json);
+        assert_eq!(read_all(source, json).await.unwrap(), expected);
+
+        // Malformed lines fail the read, unless skipped as `--fail-on never` does
+        std::fs::write(&path, "up 1\nup{a=\"b\" 2\nx 3\n").unwrap();
+        assert!(read_all(source, None).await.is_err());
+        let config = Config {
+            skip_malformed: true,
+            ..Config::default()
+        };
+        let parser = Scraper::new(&config).unwrap().parser(source);
+        let mut names = Vec::new();
+        read_families(source, parser, None, |families| {
+            names.extend(families.into_iter().map(|f| f.metric_name));
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(names, ["up", "x"]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            read_families("/nonexistent", StreamParser::new(), None, |_| Ok(()))
+                .await
+                .is_err()
+        );
+    }
+}
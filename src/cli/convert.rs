@@ -0,0 +1,623 @@
+use super::archive::{Archive, ArchiveOpts};
+use super::compress::{is_tarball, read_tarball};
+use super::config::{parse_duration, FailOn, Settings, Target};
+use super::discovery::{dns_srv, file_sd, targets_file};
+use super::dry_run;
+use super::heatmap::Heatmap;
+use super::integrity::Integrity;
+use super::reload::{on_hangup, Reloadable};
+use super::scraper::{scrape_all, ScrapeMetadata};
+use super::sink::{serialize_time, Document, Scraped, Section};
+use super::sparkline::Sparklines;
+use super::summary;
+use super::Error;
+use prom2jsonrs::{
+    dedup_replicas, Aggregation, Anomaly, AnomalyDetector, ChangeTracker, Dedup, PrometheusData,
+    Sample, Selector, SortBy,
+};
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use tokio::time::Instant;
+use tracing::warn;
+
+#[derive(StructOpt)]
+/// Options shaping the converted document
+pub struct OutputOpts {
+    /// Sort families and series by `name` or `value` (largest first)
+    #[structopt(long)]
+    sort_by: Option<SortBy>,
+    /// Only output the N series with the largest values
+    #[structopt(long)]
+    top: Option<usize>,
+    /// Keep at most N series in every family, summing the others into an `__overflow__` series
+    #[structopt(long)]
+    max_series_per_family: Option<usize>,
+    /// Which series `--max-series-per-family` keeps: those with the largest `value`s, or the
+    /// first by `name` of their labels
+    #[structopt(long, default_value = "value")]
+    max_series_by: SortBy,
+    /// Give histogram buckets the observations of their own range rather than every one up to
+    /// their bound, as plotting tools want them
+    #[structopt(long)]
+    decumulate: bool,
+    /// Give every gauge, counter and untyped sample without a timestamp the time of the scrape,
+    /// for sinks that need a time per sample
+    #[structopt(long)]
+    sample_timestamps: bool,
+}
+
+impl OutputOpts {
+    /// Shape the data scraped at `scraped_at`
+    pub fn apply(&self, data: &mut PrometheusData, scraped_at: SystemTime) {
+        if self.sample_timestamps {
+            let ms = scraped_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            data.stamp(ms.as_millis() as i64);
+        }
+        if let Some(n) = self.max_series_per_family {
+            data.cap_series(n, self.max_series_by);
+        }
+        if let Some(n) = self.top {
+            data.top(n);
+            data.sort(SortBy::Value);
+        }
+        if let Some(sort_by) = self.sort_by {
+            data.sort(sort_by);
+        }
+        if self.decumulate {
+            data.decumulate();
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct ConvertOpts {
+    /// url or file to convert, `-` for stdin. `.gz` and `.zst` files are decompressed, and the
+    /// files of `.tar.gz`, `.tgz` and `.tar.zst` tarballs each converted into a document.
+    source: String,
+    /// Print each family as a JSON line as soon as it is parsed, converting files and stdin of
+    /// any size in constant memory
+    #[structopt(long, conflicts_with_all = &["sort-by", "top", "decumulate"])]
+    ndjson: bool,
+    /// Only print the values of the samples matching this selector, one per line, e.g.
+    /// `node_load1` or `up{job="api"}`
+    #[structopt(long, conflicts_with = "ndjson")]
+    get: Option<Selector>,
+    /// Print the series of every `--get` value before it, as `name{labels} value`
+    #[structopt(long, requires = "get")]
+    output_labels: bool,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(StructOpt)]
+pub struct ScrapeOpts {
+    /// urls to scrape, instead of the targets of the config
+    urls: Vec<String>,
+    /// File listing more targets, one `url [key=value...]` per line, `-` for stdin
+    #[structopt(long, number_of_values = 1)]
+    targets_file: Vec<String>,
+    /// Prometheus file_sd file (JSON or YAML) listing more targets, with their labels
+    #[structopt(long, number_of_values = 1)]
+    file_sd: Vec<String>,
+    /// SRV record resolved to more targets on every scrape, e.g. `_metrics._tcp.example.com`
+    /// (or `https://_metrics._tcp.example.com/path` for another scheme or path)
+    #[structopt(long, number_of_values = 1)]
+    dns_srv: Vec<String>,
+    /// Maximum number of targets scraped at once (defaults to the config, or 4)
+    #[structopt(long)]
+    concurrency: Option<usize>,
+    /// Emit a single document aggregating the series of every target, e.g. `sum`, `avg` or
+    /// `sum by(job, code)`
+    #[structopt(long)]
+    merge: Option<Aggregation>,
+    /// What `--merge` does with histograms whose series have different buckets: fail with an
+    /// `error`, or `rebucket` them onto the buckets all of them have
+    #[structopt(long, default_value = "error")]
+    bucket_mismatch: BucketMismatch,
+    /// Merge the targets into one document keeping a single copy of the series scraped from
+    /// several replicas, which only differ by this label: the newest copy with `replica`, or the
+    /// copy of a replica when it has one with `replica=a`
+    #[structopt(long)]
+    dedup: Option<Dedup>,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How histograms with different buckets are aggregated
+pub enum BucketMismatch {
+    Error,
+    Rebucket,
+}
+
+impl std::str::FromStr for BucketMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BucketMismatch, String> {
+        match s {
+            "error" => Ok(BucketMismatch::Error),
+            "rebucket" => Ok(BucketMismatch::Rebucket),
+            other => Err(format!(
+                "Unknown bucket mismatch handling {}, expected error or rebucket",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct WatchOpts {
+    /// url or file to convert, `-` for stdin
+    source: String,
+    /// Time between conversions, e.g. `5s` (defaults to the config interval, or 5s)
+    #[structopt(short, long, parse(try_from_str = parse_duration))]
+    interval: Option<Duration>,
+    /// Stop after this many conversions
+    #[structopt(long)]
+    count: Option<u64>,
+    /// Stop after this long, e.g. `5m`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+    /// Only output the series that are new or changed since the previous conversion
+    #[structopt(long)]
+    changed_only: bool,
+    /// Flag the samples more than this many standard deviations from the mean of their recent
+    /// values, in an `anomalies` list of the document
+    #[structopt(long)]
+    anomaly_z_score: Option<f64>,
+    /// Flag the samples whose value changed by more than this percentage since the previous
+    /// conversion
+    #[structopt(long)]
+    anomaly_change: Option<f64>,
+    /// How many recent values of each series the z-score is computed over
+    #[structopt(long, default_value = "10")]
+    anomaly_window: usize,
+    /// Log anomalies as warnings on stderr instead of adding them to the documents
+    #[structopt(long)]
+    anomalies_to_stderr: bool,
+    /// Print a summary of every conversion, each family with a sparkline of the total of its
+    /// last N values, instead of JSON lines
+    #[structopt(long)]
+    sparklines: Option<usize>,
+    /// Print the bucket time-slices of this histogram family as CSV instead of JSON lines: a
+    /// column per bucket bound and a row per conversion, for heatmap panels
+    #[structopt(long, conflicts_with = "sparklines")]
+    heatmap: Option<String>,
+    #[structopt(flatten)]
+    archive: ArchiveOpts,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Serialize)]
+/// A conversion of `watch`, with the anomalies flagged in it if any
+struct WatchDocument {
+    #[serde(flatten)]
+    data: PrometheusData,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    anomalies: Vec<Anomaly>,
+}
+
+impl Document for WatchDocument {
+    fn data(&self) -> Vec<&PrometheusData> {
+        vec![&self.data]
+    }
+}
+
+#[derive(Serialize)]
+/// The document of one target, when scraping several targets
+struct TargetDocument<'a> {
+    target: &'a str,
+    url: &'a str,
+    #[serde(serialize_with = "serialize_time")]
+    scraped_at: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrape: Option<ScrapeMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    data: Option<PrometheusData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrity: Option<Integrity>,
+}
+
+impl Document for TargetDocument<'_> {
+    fn data(&self) -> Vec<&PrometheusData> {
+        self.data.iter().collect()
+    }
+
+    fn scraped_at(&self) -> Option<SystemTime> {
+        Some(self.scraped_at)
+    }
+
+    fn sections(&self) -> Vec<Section<'_>> {
+        vec![Section {
+            title: Some(self.target),
+            data: self.data.as_ref(),
+            error: self.error.as_deref(),
+            scraped_at: Some(self.scraped_at),
+        }]
+    }
+}
+
+pub async fn convert(opts: ConvertOpts, settings: &Settings) -> Result<(), Error> {
+    if is_tarball(Path::new(&opts.source)) {
+        return convert_tarball(&opts, settings).await;
+    }
+    if opts.ndjson {
+        return convert_ndjson(&opts.source, &opts.output, settings).await;
+    }
+    let scraped_at = SystemTime::now();
+    let mut data = settings.load(&opts.source).await?;
+    settings.add_rates(&opts.source, &mut data, scraped_at);
+    settings.save_state().await;
+    opts.output.apply(&mut data, scraped_at);
+    if let Some(selector) = &opts.get {
+        return print_values(&data, selector, opts.output_labels);
+    }
+    let document = Scraped {
+        scraped_at,
+        scrape: settings.scrape_metadata(&opts.source),
+        integrity: settings.integrity(Some(&opts.source), &data),
+        document: data,
+    };
+    settings.output(&document).await
+}
+
+/// Convert every file of a tarball, printing one document per file as `scrape` does per
+/// target. With `--fail-on`, files that fail to convert fail the run once the others are output.
+async fn convert_tarball(opts: &ConvertOpts, settings: &Settings) -> Result<(), Error> {
+    if opts.ndjson || opts.get.is_some() {
+        return Err("--ndjson and --get convert a single payload, not a tarball".into());
+    }
+    let scraped_at = SystemTime::now();
+    let members = read_tarball(Path::new(&opts.source)).await?;
+    let mut documents = Vec::new();
+    for (name, raw) in &members {
+        let (data, error) = match settings.parse_input(name, raw) {
+            Ok(mut data) => {
+                settings.add_rates(name, &mut data, scraped_at);
+                opts.output.apply(&mut data, scraped_at);
+                (Some(data), None)
+            }
+            Err(err) => {
+                warn!(source = %opts.source, file = %name, error = %err, "conversion failed");
+                (None, Some(err.to_string()))
+            }
+        };
+        let integrity = data
+            .as_ref()
+            .and_then(|d| settings.integrity(Some(name), d));
+        documents.push(TargetDocument {
+            target: name,
+            url: &opts.source,
+            scraped_at,
+            scrape: None,
+            error,
+            data,
+            integrity,
+        });
+    }
+    settings.save_state().await;
+    settings.output(&documents).await?;
+    let failed = documents.iter().filter(|d| d.error.is_some()).count();
+    if failed > 0 && settings.fail_on != FailOn::Never {
+        return Err(format!("{} of {} files failed", failed, documents.len()).into());
+    }
+    Ok(())
+}
+
+/// Print the values of the samples matching `selector` a line each, failing when none does
+fn print_values(data: &PrometheusData, selector: &Selector, labels: bool) -> Result<(), Error> {
+    let samples: Vec<Sample> = data
+        .samples()
+        .into_iter()
+        .filter(|s| selector.matches(s))
+        .collect();
+    if samples.is_empty() {
+        return Err(format!("No samples match {}", selector).into());
+    }
+    for sample in samples {
+        if labels {
+            println!("{} {}", sample.series_id(), sample.value);
+        } else {
+            println!("{}", sample.value);
+        }
+    }
+    Ok(())
+}
+
+/// Print the families of `source` to stdout as JSON lines, a few at a time as they are parsed
+async fn convert_ndjson(
+    source: &str,
+    output: &OutputOpts,
+    settings: &Settings,
+) -> Result<(), Error> {
+    if settings.rates() {
+        return Err("--ndjson cannot add --rates, which need every family at once".into());
+    }
+    if settings.checksums() {
+        return Err("--ndjson prints families, which have no document to add --checksum to".into());
+    }
+    let mut out = std::io::BufWriter::new(std::io::stdout());
+    let scraped_at = SystemTime::now();
+    settings
+        .load_families(source, |mut data| {
+            // The options ndjson allows only touch the families one at a time
+            output.apply(&mut data, scraped_at);
+            for family in &data.metrics {
+                serde_json::to_writer(&mut out, family)?;
+                out.write_all(b"\n")?;
+            }
+            Ok(())
+        })
+        .await?;
+    out.flush()?;
+    Ok(())
+}
+
+pub async fn scrape(opts: ScrapeOpts, settings: &Settings) -> Result<(), Error> {
+    let config = &settings.config;
+    let (mut targets, file_sd, dns_srv): (Vec<Target>, &[String], &[String]) =
+        if opts.urls.is_empty()
+            && opts.targets_file.is_empty()
+            && opts.file_sd.is_empty()
+            && opts.dns_srv.is_empty()
+        {
+            (config.targets.clone(), &config.file_sd, &config.dns_srv)
+        } else {
+            let urls = opts.urls.iter().map(|url| Target::from_url(url));
+            (urls.collect(), &opts.file_sd, &opts.dns_srv)
+        };
+    for path in &opts.targets_file {
+        targets.extend(targets_file::load(path).await?);
+    }
+    for path in file_sd {
+        targets.extend(file_sd::load(path).await?);
+    }
+    for name in dns_srv {
+        targets.extend(dns_srv::resolve(name).await?);
+    }
+    if targets.is_empty() {
+        return Err("No targets to scrape, pass urls or configure targets".into());
+    }
+    let aggregation = opts.merge.as_ref();
+    scrape_targets(
+        &targets,
+        opts.concurrency,
+        aggregation.is_some() || opts.dedup.is_some(),
+        aggregation,
+        opts.dedup.as_ref(),
+        opts.bucket_mismatch,
+        &opts.output,
+        settings,
+    )
+    .await
+}
+
+/// Scrape the targets concurrently and print one document per target, or a single document
+/// with the series of every target when merging, deduplicated across replicas and aggregated
+/// if asked to (with histograms handled per `bucket_mismatch`). With `--fail-on`, failing
+/// targets fail the run once the others are output. A dry run only checks the targets.
+#[allow(clippy::too_many_arguments)]
+pub async fn scrape_targets(
+    targets: &[Target],
+    concurrency: Option<usize>,
+    merge: bool,
+    aggregation: Option<&Aggregation>,
+    dedup: Option<&Dedup>,
+    bucket_mismatch: BucketMismatch,
+    output: &OutputOpts,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let concurrency = concurrency.or(settings.config.concurrency).unwrap_or(4);
+    if settings.dry_run {
+        return dry_run::run(targets, concurrency, settings).await;
+    }
+    let scraped_at = SystemTime::now();
+    let results = scrape_all(settings, targets, concurrency).await;
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    // Failing targets only fail the run when asked to
+    let outcome = || match failed {
+        0 => Ok(()),
+        _ if settings.fail_on == FailOn::Never => Ok(()),
+        _ => Err(format!("{} of {} targets failed", failed, targets.len()).into()),
+    };
+    if merge {
+        let mut scrapes = Vec::new();
+        for (target, (scraped_at, result)) in targets.iter().zip(results) {
+            match result {
+                Ok(data) => scrapes.push((scraped_at, data)),
+                Err(err) => warn!(target = target.display_name(), error = %err, "scrape failed"),
+            }
+        }
+        let mut merged = match dedup {
+            Some(dedup) => {
+                let ms = |at: SystemTime| {
+                    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    since_epoch.as_millis() as i64
+                };
+                let scrapes = scrapes.into_iter().map(|(at, data)| (ms(at), data));
+                dedup_replicas(scrapes.collect(), dedup)
+            }
+            None => {
+                let mut merged = PrometheusData {
+                    metrics: Vec::new(),
+                };
+                for (_, data) in scrapes {
+                    merged.merge(data);
+                }
+                merged
+            }
+        };
+        if let Some(aggregation) = aggregation {
+            match bucket_mismatch {
+                BucketMismatch::Error => merged.check_histogram_buckets()?,
+                BucketMismatch::Rebucket => merged.rebucket_histograms(),
+            }
+            merged = merged.aggregate(aggregation);
+        }
+        settings.add_rates("merged", &mut merged, scraped_at);
+        settings.save_state().await;
+        output.apply(&mut merged, scraped_at);
+        let document = Scraped {
+            scraped_at,
+            scrape: None,
+            integrity: settings.integrity(None, &merged),
+            document: merged,
+        };
+        settings.output(&document).await?;
+        return outcome();
+    }
+    let mut documents = Vec::new();
+    for (target, (scraped_at, result)) in targets.iter().zip(results) {
+        let (data, error) = match result {
+            Ok(mut data) => {
+                settings.add_rates(target.display_name(), &mut data, scraped_at);
+                output.apply(&mut data, scraped_at);
+                (Some(data), None)
+            }
+            Err(err) => {
+                warn!(target = target.display_name(), error = %err, "scrape failed");
+                (None, Some(err))
+            }
+        };
+        let integrity = data
+            .as_ref()
+            .and_then(|d| settings.integrity(Some(&target.url), d));
+        documents.push(TargetDocument {
+            target: target.display_name(),
+            url: &target.url,
+            scraped_at,
+            scrape: settings.scrape_metadata(target.display_name()),
+            error,
+            data,
+            integrity,
+        });
+    }
+    settings.save_state().await;
+    settings.output(&documents).await?;
+    outcome()
+}
+
+/// Hand one conversion of `watch` to the archive and the sinks, or print it as a line
+async fn record(
+    data: &Scraped<WatchDocument>,
+    archive: Option<&Archive>,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // One compact document per line, whatever the output format
+    let body = serde_json::to_string(data)?;
+    if let Some(archive) = archive {
+        if let Err(err) = archive.write(&body).await {
+            warn!(error = %err, "archiving failed");
+        }
+    }
+    // The archive takes the place of stdout, not of the other sinks
+    if archive.is_none() || !settings.sinks.is_empty() {
+        if let Err(err) = settings.emit(data, body).await {
+            warn!(error = %err, "push failed");
+        }
+    }
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+/// Convert the source every interval, printing one document per line, until the count or
+/// duration is reached if any. `SIGHUP` reloads the config.
+pub async fn watch(opts: WatchOpts, settings: Settings) -> Result<(), Error> {
+    let interval = opts
+        .interval
+        .or(settings.config.interval)
+        .unwrap_or_else(|| Duration::from_secs(5));
+    let archive = opts.archive.archive(settings.compression)?;
+    let deadline = opts.duration.map(|d| Instant::now() + d);
+    let reloadable = Arc::new(Reloadable::new(settings));
+    let on_reload = reloadable.clone();
+    on_hangup(move || on_reload.reload())?;
+    let mut tracker = ChangeTracker::new();
+    let mut detector =
+        (opts.anomaly_z_score.is_some() || opts.anomaly_change.is_some()).then(|| {
+            AnomalyDetector::new(
+                opts.anomaly_z_score,
+                opts.anomaly_change,
+                opts.anomaly_window,
+            )
+        });
+    let mut trends = opts.sparklines.map(Sparklines::new);
+    let mut heatmap = opts.heatmap.as_deref().map(Heatmap::new);
+    for conversion in 1.. {
+        let current = reloadable.get();
+        let settings = &*current;
+        let scraped_at = SystemTime::now();
+        match settings.load(&opts.source).await {
+            Ok(mut data) => {
+                settings.add_rates(&opts.source, &mut data, scraped_at);
+                settings.save_state().await;
+                opts.output.apply(&mut data, scraped_at);
+                // Checked before --changed-only leaves out the series that did not change
+                let mut anomalies = match &mut detector {
+                    Some(detector) => detector.check(&data),
+                    None => Vec::new(),
+                };
+                if opts.anomalies_to_stderr {
+                    for anomaly in anomalies.drain(..) {
+                        warn!(
+                            series = %anomaly.series_id(),
+                            value = anomaly.value,
+                            mean = anomaly.mean,
+                            z_score = ?anomaly.z_score,
+                            change_percent = ?anomaly.change_percent,
+                            "anomaly"
+                        );
+                    }
+                }
+                let mut unchanged = false;
+                if opts.changed_only {
+                    tracker.retain_changed(&mut data);
+                    unchanged = data.metrics.is_empty() && anomalies.is_empty();
+                }
+                if !unchanged {
+                    let document = Scraped {
+                        scraped_at,
+                        scrape: settings.scrape_metadata(&opts.source),
+                        integrity: settings.integrity(Some(&opts.source), &data),
+                        document: WatchDocument { data, anomalies },
+                    };
+                    // Summaries and heatmaps take the place of stdout, not of the archive or sinks
+                    let printed = trends.is_some() || heatmap.is_some();
+                    if let Some(trends) = &mut trends {
+                        summary::record_trends(&document, trends);
+                        let color = summary::use_color();
+                        println!("{}", summary::render(&document, color, Some(trends)));
+                    }
+                    if let Some(heatmap) = &mut heatmap {
+                        for line in heatmap.lines(scraped_at, &document.document.data) {
+                            println!("{}", line);
+                        }
+                    }
+                    if !printed || archive.is_some() || !settings.sinks.is_empty() {
+                        record(&document, archive.as_ref(), settings).await?;
+                    }
+                }
+            }
+            Err(err) => warn!(source = %opts.source, error = %err, "conversion failed"),
+        }
+        if opts.count.is_some_and(|count| conversion >= count) {
+            break;
+        }
+        let next = Instant::now() + interval + settings.jitter();
+        match deadline {
+            Some(deadline) if deadline <= next => {
+                tokio::time::sleep_until(deadline).await;
+                break;
+            }
+            _ => tokio::time::sleep_until(next).await,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Delta encoding of archived documents: the series that changed since the previous document,
+//! which replay back into the full documents
+
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// The families of a document
+fn families(document: &Value) -> &[Value] {
+    document["metrics"].as_array().map_or(&[], Vec::as_slice)
+}
+
+/// The series of a family
+fn series_of(family: &Value) -> &[Value] {
+    family["data"].as_array().map_or(&[], Vec::as_slice)
+}
+
+/// The identity of a series in its family: its labels, sorted
+fn series_key(series: &Value) -> Vec<(String, String)> {
+    let mut key: Vec<(String, String)> = series["labels"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.to_string()))
+        .collect();
+    key.sort();
+    key
+}
+
+/// A family without its series
+fn metadata(family: &Value) -> Map<String, Value> {
+    let mut metadata = family.as_object().cloned().unwrap_or_default();
+    metadata.remove("data");
+    metadata
+}
+
+/// The changes from `previous` to `current`, two documents with `metrics`: under `delta`, the
+/// families that are new or whose metadata or series changed with only their new and changed
+/// series (`changed`), the labels of the series removed by family (`removed`) and the names of
+/// the families removed (`removed_families`). The other fields of `current` (e.g.
+/// `scraped_at`) are kept as they are.
+pub fn encode(previous: &Value, current: &Value) -> Value {
+    let before: HashMap<&str, &Value> = families(previous)
+        .iter()
+        .map(|f| (f["metric_name"].as_str().unwrap_or_default(), f))
+        .collect();
+    let mut changed = Vec::new();
+    let mut removed = Map::new();
+    for family in families(current) {
+        let name = family["metric_name"].as_str().unwrap_or_default();
+        let previous = match before.get(name) {
+            Some(previous) => previous,
+            None => {
+                changed.push(family.clone());
+                continue;
+            }
+        };
+        let previous_series: HashMap<Vec<(String, String)>, &Value> = series_of(previous)
+            .iter()
+            .map(|s| (series_key(s), s))
+            .collect();
+        let current_series = series_of(family);
+        let data: Vec<Value> = current_series
+            .iter()
+            .filter(|s| previous_series.get(&series_key(s)) != Some(s))
+            .cloned()
+            .collect();
+        let kept: HashSet<Vec<(String, String)>> = current_series.iter().map(series_key).collect();
+        let gone: Vec<Value> = series_of(previous)
+            .iter()
+            .filter(|s| !kept.contains(&series_key(s)))
+            .map(|s| s["labels"].clone())
+            .collect();
+        if !gone.is_empty() {
+            removed.insert(name.to_string(), Value::Array(gone));
+        }
+        if !data.is_empty() || metadata(family) != metadata(previous) {
+            let mut family = metadata(family);
+            family.insert("data".to_string(), Value::Array(data));
+            changed.push(Value::Object(family));
+        }
+    }
+    let names: HashSet<&str> = families(current)
+        .iter()
+        .filter_map(|f| f["metric_name"].as_str())
+        .collect();
+    let removed_families: Vec<&str> = before
+        .keys()
+        .filter(|name| !names.contains(*name))
+        .copied()
+        .collect();
+    let mut delta = current.as_object().cloned().unwrap_or_default();
+    delta.remove("metrics");
+    delta.insert(
+        "delta".to_string(),
+        json!({
+            "changed": changed,
+            "removed": removed,
+            "removed_families": removed_families,
+        }),
+    );
+    Value::Object(delta)
+}
+
+/// Turn `document` into the one `delta` was encoded from, see `encode`
+pub fn apply(document: &mut Value, delta: &Value) -> Result<(), String> {
+    let changes = delta
+        .get("delta")
+        .ok_or("Not a delta document, it has no `delta`")?;
+    let mut metrics: Vec<Value> = match document["metrics"].take() {
+        Value::Array(metrics) => metrics,
+        _ => Vec::new(),
+    };
+    let removed_families: HashSet<&str> = changes["removed_families"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    metrics.retain(|f| !removed_families.contains(f["metric_name"].as_str().unwrap_or_default()));
+    for family in metrics.iter_mut() {
+        let name = family["metric_name"].as_str().unwrap_or_default();
+        let gone: HashSet<Vec<(String, String)>> = match changes["removed"].get(name) {
+            Some(Value::Array(labels)) => labels
+                .iter()
+                .map(|labels| series_key(&json!({ "labels": labels })))
+                .collect(),
+            _ => continue,
+        };
+        if let Some(data) = family["data"].as_array_mut() {
+            data.retain(|s| !gone.contains(&series_key(s)));
+        }
+    }
+    for change in changes["changed"].as_array().into_iter().flatten() {
+        let name = &change["metric_name"];
+        let family = match metrics.iter_mut().find(|f| f["metric_name"] == *name) {
+            Some(family) => family,
+            None => {
+                metrics.push(change.clone());
+                continue;
+            }
+        };
+        let mut data = match family["data"].take() {
+            Value::Array(data) => data,
+            _ => Vec::new(),
+        };
+        for series in series_of(change) {
+            let key = series_key(series);
+            match data.iter_mut().find(|s| series_key(s) == key) {
+                Some(existing) => *existing = series.clone(),
+                None => data.push(series.clone()),
+            }
+        }
+        let mut updated = metadata(change);
+        updated.insert("data".to_string(), Value::Array(data));
+        *family = Value::Object(updated);
+    }
+    let mut rebuilt = delta.as_object().cloned().unwrap_or_default();
+    rebuilt.remove("delta");
+    rebuilt.insert("metrics".to_string(), Value::Array(metrics));
+    *document = Value::Object(rebuilt);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delta_encoding_works() {
+        let series = |labels: Value, value: &str| json!({"type": "Metric", "labels": labels, "value": value});
+        let first = json!({
+            "scraped_at": "2024-01-01T00:00:00.000Z",
+            "metrics": [
+                {"metric_type": "Gauge", "metric_name": "up", "help": "", "data": [
+                    series(json!({"job": "a"}), "1"),
+                    series(json!({"job": "b"}), "1"),
+                    series(json!({"job": "c"}), "1"),
+                ]},
+                {"metric_type": "Gauge", "metric_name": "temp", "help": "", "data": [series(Value::Null, "20")]},
+                {"metric_type": "Gauge", "metric_name": "gone", "help": "", "data": [series(Value::Null, "0")]},
+            ],
+        });
+        let second = json!({
+            "scraped_at": "2024-01-01T00:01:00.000Z",
+            "anomalies": [{"name": "up"}],
+            "metrics": [
+                {"metric_type": "Gauge", "metric_name": "up", "help": "", "data": [
+                    series(json!({"job": "a"}), "1"),
+                    series(json!({"job": "b"}), "0"),
+                    series(json!({"job": "d"}), "1"),
+                ]},
+                {"metric_type": "Gauge", "metric_name": "temp", "help": "Celsius", "data": [series(Value::Null, "20")]},
+                {"metric_type": "Gauge", "metric_name": "new", "help": "", "data": [series(Value::Null, "5")]},
+            ],
+        });
+        let delta = encode(&first, &second);
+        assert_eq!(delta["scraped_at"], "2024-01-01T00:01:00.000Z");
+        assert_eq!(delta["anomalies"], second["anomalies"]);
+        let changed = &delta["delta"]["changed"];
+        assert_eq!(changed.as_array().unwrap().len(), 3);
+        assert_eq!(changed[0]["data"].as_array().unwrap().len(), 2);
+        assert!(changed[1]["data"].as_array().unwrap().is_empty());
+        assert_eq!(delta["delta"]["removed"], json!({"up": [{"job": "c"}]}));
+        assert_eq!(delta["delta"]["removed_families"], json!(["gone"]));
+
+        let mut replayed = first.clone();
+        apply(&mut replayed, &delta).unwrap();
+        assert_eq!(replayed, second);
+
+        let unchanged = encode(&second, &second);
+        assert!(unchanged["delta"]["changed"].as_array().unwrap().is_empty());
+        let mut replayed = second.clone();
+        apply(&mut replayed, &unchanged).unwrap();
+        assert_eq!(replayed, second);
+        assert!(apply(&mut replayed, &first).is_err());
+    }
+}
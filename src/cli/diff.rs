@@ -0,0 +1,71 @@
+use super::config::Settings;
+use super::Error;
+use prom2jsonrs::{Diff, Sample};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct DiffOpts {
+    /// url or file of the old metrics
+    old: String,
+    /// url or file of the new metrics
+    new: String,
+    /// Print the report as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+pub async fn run(opts: DiffOpts, settings: &Settings) -> Result<(), Error> {
+    let (old, new) = tokio::try_join!(settings.load(&opts.old), settings.load(&opts.new))?;
+    let diff = prom2jsonrs::diff(&old, &new);
+    if opts.json {
+        println!("{}", settings.render(&diff));
+        return Ok(());
+    }
+    print(&diff, "");
+    if diff.is_empty() {
+        println!("no differences");
+    }
+    Ok(())
+}
+
+/// Print the differences of a diff a line each, every line starting with `indent`
+pub fn print(diff: &Diff, indent: &str) {
+    let series = |s: &Sample| format!("{} {}", s.series_id(), s.value);
+    for family in &diff.added_families {
+        println!("{}+ family {}", indent, family);
+    }
+    for family in &diff.removed_families {
+        println!("{}- family {}", indent, family);
+    }
+    for change in &diff.metadata {
+        println!(
+            "{}! {} {}: {:?} -> {:?}",
+            indent, change.family, change.field, change.old, change.new
+        );
+    }
+    for sample in &diff.added {
+        println!("{}+ {}", indent, series(sample));
+    }
+    for sample in &diff.removed {
+        println!("{}- {}", indent, series(sample));
+    }
+    for change in &diff.changed {
+        let id = Sample {
+            name: change.name.clone(),
+            labels: change.labels.clone(),
+            value: change.new.clone(),
+        }
+        .series_id();
+        match change.delta {
+            Some(delta) if change.reset => println!(
+                "{}~ {} {} -> {} (reset, {:+})",
+                indent, id, change.old, change.new, delta
+            ),
+            Some(delta) => println!(
+                "{}~ {} {} -> {} ({:+})",
+                indent, id, change.old, change.new, delta
+            ),
+            None => println!("{}~ {} {} -> {}", indent, id, change.old, change.new),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use super::super::config::Target;
+use super::super::Error;
+use hickory_resolver::TokioAsyncResolver;
+use tracing::debug;
+
+/// A SRV record name, optionally wrapped as `https://_metrics._tcp.example.com/path` to pick
+/// the scheme and metrics path of the targets
+struct SrvName<'a> {
+    scheme: &'a str,
+    name: &'a str,
+    path: &'a str,
+}
+
+impl SrvName<'_> {
+    fn parse(s: &str) -> SrvName<'_> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => (scheme, rest),
+            None => ("http", s),
+        };
+        let (name, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/metrics"),
+        };
+        SrvName { scheme, name, path }
+    }
+
+    /// The target of a resolved `host:port`
+    fn target(&self, host: &str, port: u16) -> Target {
+        let address = format!("{}:{}", host.trim_end_matches('.'), port);
+        let mut target = Target::from_url(&format!("{}://{}{}", self.scheme, address, self.path));
+        target.labels.insert("instance".to_string(), address);
+        target
+    }
+}
+
+/// Resolve the SRV records of `name` into targets, by priority then descending weight
+pub async fn resolve(name: &str) -> Result<Vec<Target>, Error> {
+    let srv = SrvName::parse(name);
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let lookup = resolver
+        .srv_lookup(srv.name)
+        .await
+        .map_err(|e| format!("Cannot resolve SRV {}: {}", srv.name, e))?;
+    let mut records: Vec<_> = lookup.iter().collect();
+    records.sort_by_key(|r| (r.priority(), std::cmp::Reverse(r.weight())));
+    let targets: Vec<Target> = records
+        .into_iter()
+        .map(|r| srv.target(&r.target().to_utf8(), r.port()))
+        .collect();
+    debug!(
+        name = srv.name,
+        targets = targets.len(),
+        "resolved SRV records"
+    );
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn srv_name_parsing_works() {
+        let srv = SrvName::parse("_metrics._tcp.example.com");
+        assert_eq!(srv.name, "_metrics._tcp.example.com");
+        let target = srv.target("node-1.example.com.", 9100);
+        assert_eq!(target.url, "http://node-1.example.com:9100/metrics");
+        assert_eq!(target.labels["instance"], "node-1.example.com:9100");
+
+        let srv = SrvName::parse("https://_exporter._tcp.example.com/stats");
+        assert_eq!(srv.name, "_exporter._tcp.example.com");
+        assert_eq!(srv.target("a.", 443).url, "https://a:443/stats");
+    }
+}
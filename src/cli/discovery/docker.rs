@@ -0,0 +1,168 @@
+use super::super::config::Settings;
+use super::super::config::Target;
+use super::super::Error;
+use super::{target, Annotations, ScrapeTargetsOpts};
+use serde::Deserialize;
+use std::collections::HashMap;
+use structopt::StructOpt;
+use tracing::{debug, info};
+
+#[derive(StructOpt)]
+pub struct DockerOpts {
+    /// Docker API socket (defaults to `DOCKER_HOST` when it is a unix socket, or
+    /// `unix:///var/run/docker.sock`)
+    #[structopt(long)]
+    docker_host: Option<String>,
+    /// Scrape the ports published on this host instead of the container addresses, for
+    /// setups where container networks are not routable (e.g. Docker Desktop)
+    #[structopt(long)]
+    host_ports: bool,
+    #[structopt(flatten)]
+    scrape: ScrapeTargetsOpts,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct Container {
+    names: Vec<String>,
+    image: String,
+    labels: HashMap<String, String>,
+    ports: Vec<Port>,
+    network_settings: NetworkSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct Port {
+    private_port: u16,
+    public_port: Option<u16>,
+    #[serde(rename = "Type")]
+    kind: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct NetworkSettings {
+    networks: HashMap<String, Network>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct Network {
+    #[serde(rename = "IPAddress")]
+    ip_address: String,
+}
+
+/// The targets of the running containers labelled `prometheus.io/scrape=true`
+fn container_targets(containers: Vec<Container>, host_ports: bool) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for container in containers {
+        let labels = Annotations(&container.labels);
+        let name = container
+            .names
+            .first()
+            .map(|n| n.trim_start_matches('/'))
+            .unwrap_or_default();
+        if !labels.scrape() {
+            continue;
+        }
+        let port = labels.port().or_else(|| {
+            container
+                .ports
+                .iter()
+                .find(|p| p.kind == "tcp")
+                .map(|p| p.private_port)
+        });
+        let port = match port {
+            Some(port) => port,
+            None => {
+                debug!(container = name, "labelled container without a port");
+                continue;
+            }
+        };
+        let address = if host_ports {
+            container
+                .ports
+                .iter()
+                .find(|p| p.private_port == port && p.kind == "tcp")
+                .and_then(|p| p.public_port)
+                .map(|public| ("localhost".to_string(), public))
+        } else {
+            let mut networks: Vec<_> = container.network_settings.networks.iter().collect();
+            networks.sort_by_key(|(name, _)| name.as_str());
+            networks
+                .into_iter()
+                .map(|(_, n)| n.ip_address.clone())
+                .find(|ip| !ip.is_empty())
+                .map(|ip| (ip, port))
+        };
+        match address {
+            Some((host, port)) => targets.push(target(
+                name.to_string(),
+                labels.url(&host, port),
+                &[("container", name), ("image", &container.image)],
+            )),
+            None => debug!(container = name, "container without a reachable address"),
+        }
+    }
+    targets
+}
+
+#[cfg(unix)]
+async fn list_containers(host: &str, settings: &Settings) -> Result<Vec<Container>, Error> {
+    let url = format!("{}:/containers/json", host);
+    let raw = super::super::unix::fetch(
+        &url,
+        &reqwest::header::HeaderMap::new(),
+        settings.config.timeout,
+    )
+    .await?;
+    Ok(serde_json::from_str(&raw).map_err(|e| format!("Invalid Docker API response: {}", e))?)
+}
+
+#[cfg(not(unix))]
+async fn list_containers(host: &str, _settings: &Settings) -> Result<Vec<Container>, Error> {
+    Err(format!("Only unix socket Docker hosts are supported, not {}", host).into())
+}
+
+pub async fn run(opts: DockerOpts, settings: &Settings) -> Result<(), Error> {
+    let host = opts
+        .docker_host
+        .clone()
+        .or_else(|| std::env::var("DOCKER_HOST").ok())
+        .filter(|host| host.starts_with("unix://"))
+        .unwrap_or_else(|| "unix:///var/run/docker.sock".to_string());
+    let targets = container_targets(list_containers(&host, settings).await?, opts.host_ports);
+    info!(targets = targets.len(), "discovered docker targets");
+    opts.scrape.run(&targets, settings).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn container_discovery_works() {
+        let containers: Vec<Container> = serde_json::from_str(
+            r#"[
+                {"Names": ["/node-exporter"], "Image": "prom/node-exporter",
+                 "Labels": {"prometheus.io/scrape": "true"},
+                 "Ports": [{"PrivatePort": 9100, "PublicPort": 19100, "Type": "tcp"}],
+                 "NetworkSettings": {"Networks": {"bridge": {"IPAddress": "172.17.0.2"}}}},
+                {"Names": ["/app"], "Image": "app:1",
+                 "Labels": {"prometheus.io/scrape": "true", "prometheus.io/port": "8080",
+                            "prometheus.io/path": "/stats"},
+                 "NetworkSettings": {"Networks": {"bridge": {"IPAddress": "172.17.0.3"}}}},
+                {"Names": ["/db"], "Image": "postgres", "Labels": {},
+                 "NetworkSettings": {"Networks": {"bridge": {"IPAddress": "172.17.0.4"}}}}
+            ]"#,
+        )
+        .unwrap();
+        let targets = container_targets(containers, false);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].url, "http://172.17.0.2:9100/metrics");
+        assert_eq!(targets[0].labels["container"], "node-exporter");
+        assert_eq!(targets[0].labels["image"], "prom/node-exporter");
+        assert_eq!(targets[1].url, "http://172.17.0.3:8080/stats");
+    }
+}
@@ -0,0 +1,108 @@
+use super::super::config::Target;
+use super::super::Error;
+use prom2jsonrs::Labels;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+/// A target group of a Prometheus `file_sd_configs` file
+struct TargetGroup {
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: Labels,
+}
+
+impl TargetGroup {
+    /// Turn each `host:port` into a target, honoring the `__scheme__`, `__metrics_path__`
+    /// and `__param_<name>` labels. Other `__` labels are dropped and `instance` defaults to
+    /// the address.
+    fn into_targets(self) -> Vec<Target> {
+        let scheme = self
+            .labels
+            .get("__scheme__")
+            .map(|s| s.as_str())
+            .unwrap_or("http");
+        let path = self
+            .labels
+            .get("__metrics_path__")
+            .map(|s| s.as_str())
+            .unwrap_or("/metrics");
+        let mut params: Vec<(&str, &str)> = self
+            .labels
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix("__param_").map(|k| (k, v.as_str())))
+            .collect();
+        params.sort();
+        let query: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let labels: Labels = self
+            .labels
+            .iter()
+            .filter(|(k, _)| !k.starts_with("__"))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        self.targets
+            .iter()
+            .map(|address| {
+                let mut url = format!("{}://{}{}", scheme, address, path);
+                if !query.is_empty() {
+                    url = format!("{}?{}", url, query.join("&"));
+                }
+                let mut target = Target::from_url(&url);
+                target.labels = labels.clone();
+                target
+                    .labels
+                    .entry("instance".to_string())
+                    .or_insert_with(|| address.clone());
+                target
+            })
+            .collect()
+    }
+}
+
+fn parse(path: &str, raw: &str) -> Result<Vec<Target>, Error> {
+    let groups: Vec<TargetGroup> = if path.ends_with(".json") {
+        serde_json::from_str(raw).map_err(|e| format!("Invalid file_sd {}: {}", path, e))?
+    } else {
+        serde_yaml::from_str(raw).map_err(|e| format!("Invalid file_sd {}: {}", path, e))?
+    };
+    Ok(groups
+        .into_iter()
+        .flat_map(TargetGroup::into_targets)
+        .collect())
+}
+
+/// Read the targets of a file_sd file, JSON when named `*.json` and YAML otherwise
+pub async fn load(path: &str) -> Result<Vec<Target>, Error> {
+    let raw = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Cannot read file_sd {}: {}", path, e))?;
+    parse(path, &raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_sd_parsing_works() {
+        let targets = parse(
+            "targets.json",
+            r#"[
+                {"targets": ["a:9100", "b:9100"], "labels": {"job": "node"}},
+                {"targets": ["c:443"], "labels": {"__scheme__": "https", "__metrics_path__": "/probe",
+                                                  "__param_module": "http_2xx", "instance": "c"}}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].url, "http://a:9100/metrics");
+        assert_eq!(targets[0].labels["job"], "node");
+        assert_eq!(targets[1].labels["instance"], "b:9100");
+        assert_eq!(targets[2].url, "https://c:443/probe?module=http_2xx");
+        assert_eq!(targets[2].labels.len(), 1);
+        assert_eq!(targets[2].labels["instance"], "c");
+
+        let targets = parse("targets.yaml", "- targets: ['a:9100']\n").unwrap();
+        assert_eq!(targets[0].url, "http://a:9100/metrics");
+        assert!(parse("targets.yaml", "targets: a").is_err());
+    }
+}
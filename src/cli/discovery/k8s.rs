@@ -0,0 +1,383 @@
+use super::super::config::{Settings, Target};
+use super::super::Error;
+use super::{target, Annotations, ScrapeTargetsOpts};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing::{debug, info};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What kind of object is turned into targets
+pub enum Role {
+    Pod,
+    Service,
+    Endpoints,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pod" | "pods" => Ok(Role::Pod),
+            "service" | "services" => Ok(Role::Service),
+            "endpoints" => Ok(Role::Endpoints),
+            other => Err(format!(
+                "Unknown role {}, expected pod, service or endpoints",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct K8sOpts {
+    /// `pod`, `service` or `endpoints`. Objects (the service, for endpoints) need the
+    /// `prometheus.io/scrape: "true"` annotation; `prometheus.io/port`, `/path` and `/scheme`
+    /// are honored.
+    #[structopt(long, default_value = "pod")]
+    role: Role,
+    /// Only discover in this namespace, instead of all namespaces
+    #[structopt(short, long)]
+    namespace: Option<String>,
+    /// Kubernetes label selector, e.g. `app=api,tier!=db`
+    #[structopt(short = "l", long)]
+    selector: Option<String>,
+    /// API server url, e.g. `http://localhost:8001` for `kubectl proxy` (defaults to the
+    /// in-cluster API server and service account)
+    #[structopt(long)]
+    api_server: Option<String>,
+    /// File holding the bearer token for the API server
+    #[structopt(long)]
+    token_file: Option<String>,
+    #[structopt(flatten)]
+    scrape: ScrapeTargetsOpts,
+}
+
+#[derive(Debug, Deserialize)]
+struct List<T> {
+    #[serde(default = "Vec::new")]
+    items: Vec<T>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ObjectMeta {
+    name: String,
+    namespace: String,
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Pod {
+    metadata: ObjectMeta,
+    spec: PodSpec,
+    status: PodStatus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PodSpec {
+    containers: Vec<Container>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Container {
+    ports: Vec<ContainerPort>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ContainerPort {
+    container_port: u16,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PodStatus {
+    phase: String,
+    #[serde(rename = "podIP")]
+    pod_ip: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Service {
+    metadata: ObjectMeta,
+    spec: ServiceSpec,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ServiceSpec {
+    ports: Vec<ServicePort>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ServicePort {
+    port: u16,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Endpoints {
+    metadata: ObjectMeta,
+    subsets: Vec<Subset>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Subset {
+    addresses: Vec<Address>,
+    ports: Vec<ServicePort>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Address {
+    ip: String,
+    target_ref: Option<ObjectRef>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ObjectRef {
+    kind: String,
+    name: String,
+}
+
+fn pod_targets(pods: Vec<Pod>) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for pod in pods {
+        let meta = &pod.metadata;
+        let annotations = Annotations(&meta.annotations);
+        if !annotations.scrape() || pod.status.phase != "Running" {
+            continue;
+        }
+        let ip = match &pod.status.pod_ip {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let port = annotations.port().or_else(|| {
+            pod.spec
+                .containers
+                .iter()
+                .flat_map(|c| c.ports.iter())
+                .map(|p| p.container_port)
+                .next()
+        });
+        match port {
+            Some(port) => targets.push(target(
+                format!("{}/{}", meta.namespace, meta.name),
+                annotations.url(ip, port),
+                &[("namespace", &meta.namespace), ("pod", &meta.name)],
+            )),
+            None => debug!(pod = %meta.name, "annotated pod without a port"),
+        }
+    }
+    targets
+}
+
+fn service_targets(services: Vec<Service>) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for service in services {
+        let meta = &service.metadata;
+        let annotations = Annotations(&meta.annotations);
+        if !annotations.scrape() {
+            continue;
+        }
+        let port = annotations
+            .port()
+            .or_else(|| service.spec.ports.first().map(|p| p.port));
+        if let Some(port) = port {
+            let host = format!("{}.{}.svc", meta.name, meta.namespace);
+            targets.push(target(
+                format!("{}/{}", meta.namespace, meta.name),
+                annotations.url(&host, port),
+                &[("namespace", &meta.namespace), ("service", &meta.name)],
+            ));
+        }
+    }
+    targets
+}
+
+fn endpoints_targets(services: Vec<Service>, endpoints: Vec<Endpoints>) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for endpoints in endpoints {
+        let meta = &endpoints.metadata;
+        let service = services
+            .iter()
+            .find(|s| s.metadata.name == meta.name && s.metadata.namespace == meta.namespace);
+        let annotations = match service {
+            Some(service) => Annotations(&service.metadata.annotations),
+            None => continue,
+        };
+        if !annotations.scrape() {
+            continue;
+        }
+        for subset in &endpoints.subsets {
+            let ports: Vec<u16> = match annotations.port() {
+                Some(port) => vec![port],
+                None => subset.ports.iter().map(|p| p.port).collect(),
+            };
+            for address in &subset.addresses {
+                let pod = address
+                    .target_ref
+                    .as_ref()
+                    .filter(|r| r.kind == "Pod")
+                    .map(|r| r.name.as_str());
+                for port in &ports {
+                    let mut labels = vec![("namespace", meta.namespace.as_str())];
+                    labels.push(("service", &meta.name));
+                    if let Some(pod) = pod {
+                        labels.push(("pod", pod));
+                    }
+                    targets.push(target(
+                        format!("{}/{}/{}", meta.namespace, pod.unwrap_or(&address.ip), port),
+                        annotations.url(&address.ip, *port),
+                        &labels,
+                    ));
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// A client of the Kubernetes API
+struct Api {
+    client: Client,
+    server: String,
+    token: Option<String>,
+}
+
+impl Api {
+    fn new(opts: &K8sOpts, settings: &Settings) -> Result<Api, Error> {
+        let in_cluster = std::env::var("KUBERNETES_SERVICE_HOST")
+            .ok()
+            .zip(std::env::var("KUBERNETES_SERVICE_PORT").ok())
+            .map(|(host, port)| format!("https://{}:{}", host, port));
+        let server = opts.api_server.clone().or(in_cluster).ok_or(
+            "Not running in a cluster, pass --api-server (e.g. http://localhost:8001 for kubectl proxy)",
+        )?;
+        let token_file = opts.token_file.clone().or_else(|| {
+            let path = format!("{}/token", SERVICE_ACCOUNT);
+            (opts.api_server.is_none() && std::path::Path::new(&path).exists()).then_some(path)
+        });
+        let token = match token_file {
+            Some(path) => Some(
+                std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Cannot read token {}: {}", path, e))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let mut builder = Client::builder();
+        if let Some(timeout) = settings.config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let ca_file = format!("{}/ca.crt", SERVICE_ACCOUNT);
+        if opts.api_server.is_none() && std::path::Path::new(&ca_file).exists() {
+            let pem = std::fs::read(&ca_file)?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(Api {
+            client: builder.build()?,
+            server: server.trim_end_matches('/').to_string(),
+            token,
+        })
+    }
+
+    async fn list<T: DeserializeOwned>(&self, kind: &str, opts: &K8sOpts) -> Result<Vec<T>, Error> {
+        let path = match &opts.namespace {
+            Some(namespace) => format!("/api/v1/namespaces/{}/{}", namespace, kind),
+            None => format!("/api/v1/{}", kind),
+        };
+        let mut request = self.client.get(format!("{}{}", self.server, path));
+        if let Some(selector) = &opts.selector {
+            request = request.query(&[("labelSelector", selector)]);
+        }
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let list: List<T> = request.send().await?.error_for_status()?.json().await?;
+        Ok(list.items)
+    }
+}
+
+/// List the annotated objects of the role as scrape targets
+async fn discover(opts: &K8sOpts, settings: &Settings) -> Result<Vec<Target>, Error> {
+    let api = Api::new(opts, settings)?;
+    let targets = match opts.role {
+        Role::Pod => pod_targets(api.list("pods", opts).await?),
+        Role::Service => service_targets(api.list("services", opts).await?),
+        Role::Endpoints => endpoints_targets(
+            api.list("services", opts).await?,
+            api.list("endpoints", opts).await?,
+        ),
+    };
+    info!(role = ?opts.role, targets = targets.len(), "discovered kubernetes targets");
+    Ok(targets)
+}
+
+pub async fn run(opts: K8sOpts, settings: &Settings) -> Result<(), Error> {
+    let targets = discover(&opts, settings).await?;
+    opts.scrape.run(&targets, settings).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pod_discovery_works() {
+        let pods: List<Pod> = serde_json::from_str(
+            r#"{"items": [
+                {"metadata": {"name": "api-1", "namespace": "prod",
+                              "annotations": {"prometheus.io/scrape": "true", "prometheus.io/path": "/stats"}},
+                 "spec": {"containers": [{"ports": [{"containerPort": 8080}]}]},
+                 "status": {"phase": "Running", "podIP": "10.0.0.1"}},
+                {"metadata": {"name": "api-2", "namespace": "prod",
+                              "annotations": {"prometheus.io/scrape": "true", "prometheus.io/port": "9100"}},
+                 "status": {"phase": "Running", "podIP": "10.0.0.2"}},
+                {"metadata": {"name": "pending", "namespace": "prod",
+                              "annotations": {"prometheus.io/scrape": "true", "prometheus.io/port": "9100"}},
+                 "status": {"phase": "Pending"}},
+                {"metadata": {"name": "db", "namespace": "prod"},
+                 "status": {"phase": "Running", "podIP": "10.0.0.3"}}
+            ]}"#,
+        )
+        .unwrap();
+        let targets = pod_targets(pods.items);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].url, "http://10.0.0.1:8080/stats");
+        assert_eq!(targets[0].display_name(), "prod/api-1");
+        assert_eq!(targets[0].labels["pod"], "api-1");
+        assert_eq!(targets[1].url, "http://10.0.0.2:9100/metrics");
+
+        let services: List<Service> = serde_json::from_str(
+            r#"{"items": [{"metadata": {"name": "api", "namespace": "prod",
+                "annotations": {"prometheus.io/scrape": "true", "prometheus.io/port": "9100"}}}]}"#,
+        )
+        .unwrap();
+        let endpoints: List<Endpoints> = serde_json::from_str(
+            r#"{"items": [{"metadata": {"name": "api", "namespace": "prod"},
+                "subsets": [{"addresses": [{"ip": "10.0.0.1", "targetRef": {"kind": "Pod", "name": "api-1"}}],
+                             "ports": [{"port": 8080}]}]}]}"#,
+        )
+        .unwrap();
+        let targets = endpoints_targets(services.items, endpoints.items);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].url, "http://10.0.0.1:9100/metrics");
+        assert_eq!(targets[0].labels["service"], "api");
+    }
+}
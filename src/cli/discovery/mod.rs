@@ -0,0 +1,98 @@
+//! Discovery of scrape targets from outside sources
+
+use super::config::{Settings, Target};
+use super::convert::{scrape_targets, BucketMismatch, OutputOpts};
+use super::Error;
+use prom2jsonrs::{Dedup, Labels};
+use std::collections::HashMap;
+use structopt::StructOpt;
+
+pub mod dns_srv;
+pub mod docker;
+pub mod file_sd;
+pub mod k8s;
+pub mod targets_file;
+
+#[derive(StructOpt)]
+/// Options of the discovery subcommands, once targets are found
+pub struct ScrapeTargetsOpts {
+    /// Emit a single document with the series of every target, instead of one per target
+    #[structopt(long)]
+    merge: bool,
+    /// Keep a single copy of the series of replicas when merging, see `scrape --dedup`
+    #[structopt(long, requires = "merge")]
+    dedup: Option<Dedup>,
+    /// Only print the discovered targets
+    #[structopt(long)]
+    list: bool,
+    /// Maximum number of targets scraped at once (defaults to the config, or 4)
+    #[structopt(long)]
+    concurrency: Option<usize>,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+impl ScrapeTargetsOpts {
+    /// List or scrape the discovered targets
+    pub async fn run(&self, targets: &[Target], settings: &Settings) -> Result<(), Error> {
+        if self.list {
+            for target in targets {
+                println!("{} {}", target.display_name(), target.url);
+            }
+            return Ok(());
+        }
+        scrape_targets(
+            targets,
+            self.concurrency,
+            self.merge,
+            None,
+            self.dedup.as_ref(),
+            BucketMismatch::Error,
+            &self.output,
+            settings,
+        )
+        .await
+    }
+}
+
+/// The `prometheus.io/*` annotations (or container labels) of an object
+pub struct Annotations<'a>(pub &'a HashMap<String, String>);
+
+impl Annotations<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(&format!("prometheus.io/{}", key))
+            .map(|v| v.as_str())
+    }
+
+    pub fn scrape(&self) -> bool {
+        self.get("scrape") == Some("true")
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.get("port").and_then(|p| p.parse().ok())
+    }
+
+    pub fn url(&self, host: &str, port: u16) -> String {
+        let path = self.get("path").unwrap_or("/metrics");
+        let path = path.strip_prefix('/').unwrap_or(path);
+        format!(
+            "{}://{}:{}/{}",
+            self.get("scheme").unwrap_or("http"),
+            host,
+            port,
+            path
+        )
+    }
+}
+
+/// A named target whose series get `labels`
+pub fn target(name: String, url: String, labels: &[(&str, &str)]) -> Target {
+    let mut target = Target::from_url(&url);
+    target.name = Some(name);
+    target.labels = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Labels>();
+    target
+}
@@ -0,0 +1,68 @@
+use super::super::config::Target;
+use super::super::Error;
+use tokio::io::AsyncReadExt;
+
+/// Parse a list of targets, one `url [key=value...]` per line. Blank lines and `#` comments
+/// are skipped.
+fn parse(path: &str, raw: &str) -> Result<Vec<Target>, Error> {
+    let mut targets = Vec::new();
+    for (number, line) in raw.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut fields = line.split_whitespace();
+        let url = match fields.next() {
+            Some(url) => url,
+            None => continue,
+        };
+        let mut target = Target::from_url(url);
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or_else(|| {
+                format!(
+                    "Invalid label {} at {}:{}, expected key=value",
+                    field,
+                    path,
+                    number + 1
+                )
+            })?;
+            target.labels.insert(key.to_string(), value.to_string());
+        }
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+/// Read the targets listed in a file, or on stdin when `path` is `-`
+pub async fn load(path: &str) -> Result<Vec<Target>, Error> {
+    let mut raw = String::new();
+    if path == "-" {
+        tokio::io::stdin().read_to_string(&mut raw).await?;
+    } else {
+        raw = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Cannot read targets file {}: {}", path, e))?;
+    }
+    parse(path, &raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn targets_file_parsing_works() {
+        let targets = parse(
+            "urls.txt",
+            "# inventory dump
+http://a:9100/metrics env=prod dc=eu
+
+https://b:9100/metrics   # no labels
+",
+        )
+        .unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].url, "http://a:9100/metrics");
+        assert_eq!(targets[0].labels["dc"], "eu");
+        assert!(targets[1].labels.is_empty());
+        let err = parse("urls.txt", "http://a/metrics env").unwrap_err();
+        assert!(err.to_string().contains("urls.txt:1"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Checking that every target can be reached, without converting anything
+
+use super::config::{Settings, Target};
+use super::Error;
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use std::time::Instant;
+
+#[derive(Serialize)]
+/// The outcome of fetching one target
+struct TargetStatus<'a> {
+    target: &'a str,
+    url: &'a str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<usize>,
+}
+
+/// An error with its chain of causes, where TLS and connection details usually hide
+pub fn describe(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut description = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        let cause_description = cause.to_string();
+        if !description.contains(&cause_description) {
+            description = format!("{}: {}", description, cause_description);
+        }
+        source = cause.source();
+    }
+    description
+}
+
+/// Fetch every target with its auth and TLS settings, print a status per target and exit 1
+/// if any failed
+pub async fn run(targets: &[Target], concurrency: usize, settings: &Settings) -> Result<(), Error> {
+    let statuses: Vec<TargetStatus> = stream::iter(targets)
+        .map(|target| async move {
+            let start = Instant::now();
+            let result = settings
+                .scraper
+                .fetch_with_auth(&target.url, target.auth.as_ref())
+                .await;
+            let elapsed_ms = start.elapsed().as_millis() as u64;
+            TargetStatus {
+                target: target.display_name(),
+                url: &target.url,
+                ok: result.is_ok(),
+                error: result.as_ref().err().map(|e| describe(e.as_ref())),
+                elapsed_ms,
+                bytes: result.ok().map(|body| body.len()),
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+    println!("{}", settings.render(&statuses));
+    if statuses.iter().any(|s| !s.ok) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct Layer(&'static str, Option<Box<Layer>>);
+
+    impl fmt::Display for Layer {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl std::error::Error for Layer {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.1.as_deref().map(|e| e as _)
+        }
+    }
+
+    #[test]
+    fn error_description_works() {
+        let err = Layer(
+            "error sending request",
+            Some(Box::new(Layer(
+                "client error (Connect)",
+                Some(Box::new(Layer("certificate has expired", None))),
+            ))),
+        );
+        assert_eq!(
+            describe(&err),
+            "error sending request: client error (Connect): certificate has expired"
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use super::config::Settings;
+use super::convert::OutputOpts;
+use super::sink::Scraped;
+use super::Error;
+use prom2jsonrs::Selector;
+use reqwest::Url;
+use std::time::SystemTime;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct FederateOpts {
+    /// Prometheus server url, `/federate` is appended unless the path already ends with it
+    url: String,
+    /// Series selector sent as a `match[]` parameter, e.g. `up` or `{job="api"}`. Repeatable,
+    /// at least one is required by Prometheus.
+    #[structopt(long = "match", number_of_values = 1, required = true)]
+    matches: Vec<String>,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+/// The `/federate` url of the server, with a `match[]` parameter per selector
+fn federate_url(base: &str, matches: &[String]) -> Result<Url, Error> {
+    for selector in matches {
+        selector
+            .parse::<Selector>()
+            .map_err(|e| format!("Invalid --match {}: {}", selector, e))?;
+    }
+    let mut url = Url::parse(base).map_err(|e| format!("Invalid url {}: {}", base, e))?;
+    if !url.path().ends_with("/federate") {
+        let path = format!("{}/federate", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+    }
+    url.query_pairs_mut()
+        .extend_pairs(matches.iter().map(|m| ("match[]", m)));
+    Ok(url)
+}
+
+pub async fn run(opts: FederateOpts, settings: &Settings) -> Result<(), Error> {
+    let url = federate_url(&opts.url, &opts.matches)?;
+    let scraped_at = SystemTime::now();
+    let mut data = settings.scrape_url(url.as_str()).await?;
+    opts.output.apply(&mut data, scraped_at);
+    let document = Scraped {
+        scraped_at,
+        scrape: settings.scrape_metadata(url.as_str()),
+        integrity: settings.integrity(Some(url.as_str()), &data),
+        document: data,
+    };
+    settings.output(&document).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn federate_url_works() {
+        let matches = vec!["up".to_string(), "{job=\"api\"}".to_string()];
+        assert_eq!(
+            federate_url("http://prometheus:9090", &matches)
+                .unwrap()
+                .as_str(),
+            "http://prometheus:9090/federate?match%5B%5D=up&match%5B%5D=%7Bjob%3D%22api%22%7D"
+        );
+        assert_eq!(
+            federate_url("http://prometheus:9090/federate", &matches[..1])
+                .unwrap()
+                .as_str(),
+            "http://prometheus:9090/federate?match%5B%5D=up"
+        );
+        assert!(federate_url("http://prometheus:9090", &["{job=".to_string()]).is_err());
+    }
+}
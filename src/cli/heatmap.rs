@@ -0,0 +1,238 @@
+//! Histogram bucket time-slices as CSV, bucket bounds as columns and scrapes as rows, the layout
+//! Grafana's heatmap panel and Plotly take as is
+
+use super::archive;
+use super::config::parse_duration;
+use super::Error;
+use prom2jsonrs::{counter_increase, is_counter_reset, parse_float, MetricType, PrometheusData};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct HeatmapOpts {
+    /// Histogram family to slice, e.g. `http_request_duration_seconds`
+    family: String,
+    /// Directory of the documents recorded by `watch --archive-dir`
+    #[structopt(long)]
+    archive_dir: PathBuf,
+    /// Only use the documents archived in this last period, e.g. `6h`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    since: Option<Duration>,
+}
+
+/// A bucket of one series of a histogram
+struct Bucket {
+    /// The id of the series, without the `le` label
+    series: String,
+    le: String,
+    bound: f64,
+    count: f64,
+}
+
+/// The buckets of every series of a histogram family, none when the scrape does not have it
+fn buckets(data: &PrometheusData, family: &str) -> Option<Vec<Bucket>> {
+    let family = data
+        .metrics
+        .iter()
+        .find(|f| f.metric_name == family && f.metric_type == MetricType::Histogram)?;
+    let mut buckets = Vec::new();
+    for mut sample in family.samples() {
+        let le = match sample.labels.remove("le") {
+            Some(le) => le,
+            None => continue,
+        };
+        if let (Some(bound), Some(count)) = (parse_float(&le), sample.float_value()) {
+            buckets.push(Bucket {
+                series: sample.series_id(),
+                le,
+                bound,
+                count,
+            });
+        }
+    }
+    Some(buckets)
+}
+
+/// Turns the scrapes of a histogram into rows of the observations each bucket got (in its own
+/// range) since the previous scrape, added up across the series of the family
+pub struct Heatmap {
+    family: String,
+    /// The `le` labels of the columns, those of the first scrape holding the family
+    columns: Option<Vec<String>>,
+    /// The counts of the previous scrape, by series and `le`
+    previous: Option<HashMap<(String, String), f64>>,
+    header_written: bool,
+}
+
+impl Heatmap {
+    pub fn new(family: &str) -> Heatmap {
+        Heatmap {
+            family: family.to_string(),
+            columns: None,
+            previous: None,
+            header_written: false,
+        }
+    }
+
+    /// The lines a scrape adds to the CSV: its row, after the header for the first one
+    pub fn lines(&mut self, time: SystemTime, data: &PrometheusData) -> Vec<String> {
+        let row = self.row(time, data);
+        let mut lines = Vec::new();
+        if !self.header_written {
+            if let Some(header) = self.header() {
+                lines.push(header);
+                self.header_written = true;
+            }
+        }
+        lines.extend(row);
+        lines
+    }
+
+    /// The header line, once the columns are known
+    pub fn header(&self) -> Option<String> {
+        let columns = self.columns.as_ref()?;
+        Some(format!("time,{}", columns.join(",")))
+    }
+
+    /// The row of a scrape at `time`, none for the first one or when the family is missing.
+    /// Increases are those of Prometheus' `sum by (le) (increase(...))`: a series whose counts
+    /// went down was reset, and got every observation it has since. Buckets the first scrape did
+    /// not have are left out, and those no series had before are empty.
+    pub fn row(&mut self, time: SystemTime, data: &PrometheusData) -> Option<String> {
+        let mut current = buckets(data, &self.family)?;
+        current.sort_by(|a, b| a.bound.partial_cmp(&b.bound).unwrap_or(Ordering::Equal));
+        let columns = self.columns.get_or_insert_with(|| {
+            let mut columns: Vec<String> = Vec::new();
+            for bucket in &current {
+                if !columns.contains(&bucket.le) {
+                    columns.push(bucket.le.clone());
+                }
+            }
+            columns
+        });
+        let counts = current
+            .iter()
+            .map(|b| ((b.series.clone(), b.le.clone()), b.count))
+            .collect();
+        let previous = self.previous.replace(counts)?;
+        let before = |b: &Bucket| previous.get(&(b.series.clone(), b.le.clone())).copied();
+        let reset: HashSet<&str> = current
+            .iter()
+            .filter(|b| before(b).is_some_and(|before| is_counter_reset(before, b.count)))
+            .map(|b| b.series.as_str())
+            .collect();
+        // The cumulative increase of every bucket, summed across series
+        let mut increases: HashMap<&str, f64> = HashMap::new();
+        for bucket in &current {
+            let before = match before(bucket) {
+                _ if reset.contains(bucket.series.as_str()) => 0.0,
+                Some(before) => before,
+                None => continue,
+            };
+            *increases.entry(&bucket.le).or_default() += counter_increase(before, bucket.count);
+        }
+        let mut cells = Vec::with_capacity(columns.len());
+        let mut below = 0.0;
+        for le in columns.iter() {
+            match increases.get(le.as_str()) {
+                Some(&increase) => {
+                    cells.push(format_count((increase - below).max(0.0)));
+                    below = increase;
+                }
+                None => cells.push(String::new()),
+            }
+        }
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Some(format!("{},{}", millis, cells.join(",")))
+    }
+}
+
+fn format_count(count: f64) -> String {
+    if count.fract() == 0.0 {
+        format!("{:.0}", count)
+    } else {
+        count.to_string()
+    }
+}
+
+/// Print the heatmap of a histogram from the documents of an archive
+pub async fn run(opts: HeatmapOpts) -> Result<(), Error> {
+    let end = SystemTime::now();
+    let start = match opts.since {
+        Some(since) => end - since,
+        None => UNIX_EPOCH,
+    };
+    let mut heatmap = Heatmap::new(&opts.family);
+    for (time, data) in archive::read(&opts.archive_dir, start, end).await? {
+        for line in heatmap.lines(time, &data) {
+            println!("{}", line);
+        }
+    }
+    if heatmap.header().is_none() {
+        return Err(format!(
+            "No histogram {} in {}",
+            opts.family,
+            opts.archive_dir.display()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heatmap_works() {
+        let scrape = |a: [u32; 3], b: [u32; 3]| {
+            PrometheusData::from_string(&format!(
+                "# TYPE latency histogram
+latency_bucket{{pod=\"a\",le=\"0.1\"}} {}
+latency_bucket{{pod=\"a\",le=\"1\"}} {}
+latency_bucket{{pod=\"a\",le=\"+Inf\"}} {}
+latency_count{{pod=\"a\"}} {}
+latency_bucket{{pod=\"b\",le=\"0.1\"}} {}
+latency_bucket{{pod=\"b\",le=\"1\"}} {}
+latency_bucket{{pod=\"b\",le=\"+Inf\"}} {}
+latency_count{{pod=\"b\"}} {}",
+                a[0], a[1], a[2], a[2], b[0], b[1], b[2], b[2]
+            ))
+        };
+        let at = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let mut heatmap = Heatmap::new("latency");
+        assert_eq!(heatmap.header(), None);
+        assert_eq!(heatmap.row(at(0), &scrape([1, 2, 2], [0, 1, 1])), None);
+        assert_eq!(heatmap.header().unwrap(), "time,0.1,1,+Inf");
+        assert_eq!(
+            heatmap.row(at(15), &scrape([3, 5, 6], [0, 2, 2])).unwrap(),
+            "15000,2,2,1"
+        );
+        // A restarted pod, whose observations since are all new
+        assert_eq!(
+            heatmap.row(at(30), &scrape([0, 1, 1], [1, 3, 3])).unwrap(),
+            "30000,1,1,0"
+        );
+        assert_eq!(
+            heatmap.row(at(45), &scrape([1, 2, 2], [1, 3, 3])).unwrap(),
+            "45000,1,0,0"
+        );
+        assert_eq!(heatmap.row(at(60), &PrometheusData::from_string("")), None);
+
+        let mut heatmap = Heatmap::new("latency");
+        assert_eq!(
+            heatmap.lines(at(0), &scrape([1, 2, 2], [0, 1, 1])),
+            vec!["time,0.1,1,+Inf"]
+        );
+        assert_eq!(
+            heatmap.lines(at(15), &scrape([1, 2, 3], [0, 1, 1])),
+            vec!["15000,0,0,1"]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Integrity metadata: the digests of the raw payload and of the converted data that
+//! `--checksum` adds to documents, and the `sha256sum` sidecar of the output file that
+//! `--emit-checksum` keeps
+
+use super::Error;
+use prom2jsonrs::PrometheusData;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+/// The digests of a document, as hex SHA-256
+pub struct Integrity {
+    /// Of the payload the data was parsed from, decompressed. Left out when the data comes from
+    /// several payloads, as when merging targets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_sha256: Option<String>,
+    /// Of the canonical text of the data as output, see `PrometheusData::canonical_text`
+    pub output_sha256: String,
+}
+
+impl Integrity {
+    pub fn new(raw_sha256: Option<String>, data: &PrometheusData) -> Integrity {
+        Integrity {
+            raw_sha256,
+            output_sha256: data.content_sha256(),
+        }
+    }
+}
+
+/// `<file>.sha256` next to the file at `path`
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+/// Write the sidecar of the file at `path`, whose contents hashed into `digest`, as the line
+/// `sha256sum` prints for it so that `sha256sum -c` checks it from the same directory
+pub async fn write_sidecar(path: &Path, digest: &Sha256) -> Result<(), Error> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let line = format!("{:x}  {}\n", digest.clone().finalize(), name);
+    let sidecar = sidecar_path(path);
+    tokio::fs::write(&sidecar, line)
+        .await
+        .map_err(|e| format!("Cannot write {}: {}", sidecar.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn sidecars_work() {
+        let path = std::env::temp_dir().join(format!("prom2jsonrs-{}.json", std::process::id()));
+        assert_eq!(
+            sidecar_path(&path).file_name().unwrap().to_string_lossy(),
+            format!("prom2jsonrs-{}.json.sha256", std::process::id())
+        );
+        let mut digest = Sha256::new();
+        digest.update(b"{\"metrics\":[]}\n");
+        write_sidecar(&path, &digest).await.unwrap();
+        let sidecar = std::fs::read_to_string(sidecar_path(&path)).unwrap();
+        assert_eq!(
+            sidecar,
+            format!(
+                "{}  prom2jsonrs-{}.json\n",
+                prom2jsonrs::sha256_hex(b"{\"metrics\":[]}\n"),
+                std::process::id()
+            )
+        );
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+
+        let data = PrometheusData::from_string("# TYPE up gauge\nup 1\n");
+        let integrity = serde_json::to_value(Integrity::new(None, &data)).unwrap();
+        assert_eq!(integrity["output_sha256"], data.content_sha256());
+        assert!(integrity.get("raw_sha256").is_none());
+    }
+}
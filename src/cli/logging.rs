@@ -0,0 +1,71 @@
+use std::io::IsTerminal;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format {}", other)),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+// Logging options, shared by every subcommand
+pub struct LogOpts {
+    /// Log more to stderr: `-v` for scrape timings, `-vv` for debug and `-vvv` for trace
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    /// Log format: `text` or `json`
+    #[structopt(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+}
+
+/// The filter directive for the number of `-v`, only this crate gets chatty
+fn directive(verbose: u8) -> &'static str {
+    match verbose {
+        0 => "warn",
+        1 => "warn,prom2jsonrs=info",
+        2 => "warn,prom2jsonrs=debug",
+        _ => "warn,prom2jsonrs=trace",
+    }
+}
+
+/// Install the stderr logger. `RUST_LOG` takes precedence over `-v`.
+pub fn init(opts: &LogOpts) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(directive(opts.verbose)));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal() && super::summary::use_color());
+    match opts.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_opts_parsing_works() {
+        let opts = LogOpts::from_iter(&["test", "-vv", "--log-format", "json"]);
+        assert_eq!(opts.verbose, 2);
+        assert_eq!(opts.log_format, LogFormat::Json);
+        assert_eq!(directive(opts.verbose), "warn,prom2jsonrs=debug");
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use structopt::clap::App;
+
+/// Escape text for roff: backslashes, and control characters at the start of a line
+fn escape(line: &str) -> String {
+    let line = line.replace('\\', "\\e");
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
+
+fn long_help(mut app: App) -> String {
+    let mut help = Vec::new();
+    app.write_long_help(&mut help).unwrap();
+    String::from_utf8_lossy(&help).into_owned()
+}
+
+fn preformatted(page: &mut String, text: &str) {
+    page.push_str(".nf\n");
+    for line in text.trim_end().lines() {
+        page.push_str(&escape(line));
+        page.push('\n');
+    }
+    page.push_str(".fi\n");
+}
+
+/// Render a man page out of the help of the command and of each of its subcommands
+pub fn render(app: App) -> String {
+    let name = app.get_name().to_string();
+    let about = app.p.meta.about.unwrap_or("").to_string();
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"\n",
+        name.to_uppercase(),
+        name,
+        env!("CARGO_PKG_VERSION")
+    );
+    page.push_str(&format!(".SH NAME\n{} \\- {}\n", name, escape(&about)));
+    page.push_str(".SH DESCRIPTION\n");
+    preformatted(&mut page, &long_help(app.clone()));
+    // clap 2 only exposes the subcommands through its (frozen) parser internals
+    let subcommands: Vec<App> = app
+        .p
+        .subcommands
+        .iter()
+        .filter(|s| s.get_name() != "help")
+        .cloned()
+        .collect();
+    if !subcommands.is_empty() {
+        page.push_str(".SH SUBCOMMANDS\n");
+    }
+    for subcommand in subcommands {
+        let sub_name = subcommand.get_name().to_string();
+        page.push_str(&format!(".SS {}\n", sub_name));
+        let subcommand = subcommand.bin_name(format!("{} {}", name, sub_name));
+        preformatted(&mut page, &long_help(subcommand));
+    }
+    page
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use structopt::clap::SubCommand;
+
+    #[test]
+    fn render_works() {
+        let app = App::new("tool")
+            .about("Does things")
+            .subcommand(SubCommand::with_name("run").about(".starts with a dot"));
+        let page = render(app);
+        assert!(page.starts_with(".TH TOOL 1"));
+        assert!(page.contains(".SH NAME\ntool \\- Does things\n"));
+        assert!(page.contains(".SS run\n.nf\n"));
+        assert!(page.contains("\n\\&.starts with a dot\n"));
+    }
+}
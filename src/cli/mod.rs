@@ -0,0 +1,168 @@
+use config::{GlobalOpts, Settings};
+use std::ffi::OsString;
+use std::sync::Arc;
+use structopt::clap::ErrorKind;
+use structopt::StructOpt;
+
+mod archive;
+mod bench;
+mod check;
+mod compare;
+mod compress;
+mod config;
+mod convert;
+mod delta;
+mod diff;
+mod discovery;
+mod dry_run;
+mod federate;
+mod heatmap;
+mod integrity;
+mod logging;
+mod manpage;
+mod oauth2;
+mod query;
+mod record;
+mod reload;
+mod replay;
+mod scraper;
+mod serve;
+mod sink;
+mod sparkline;
+mod split;
+mod stats;
+mod summary;
+mod tui;
+#[cfg(unix)]
+mod unix;
+mod validate;
+mod vector;
+
+/// Errors of the command line, which can cross task boundaries
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(StructOpt)]
+/// Parse prometheus metrics as json
+///
+/// `prom2jsonrs <url>` is a shortcut for `prom2jsonrs convert <url>`, and without any
+/// subcommand the targets of the config are scraped.
+struct Cli {
+    #[structopt(flatten)]
+    global: GlobalOpts,
+    #[structopt(flatten)]
+    log: logging::LogOpts,
+    #[structopt(flatten)]
+    sink: sink::SinkOpts,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Convert a url, file or stdin to json
+    Convert(convert::ConvertOpts),
+    /// Scrape several urls, or the targets of the config, into one json document per target
+    Scrape(convert::ScrapeOpts),
+    /// Convert a url or file every interval, printing one json document per line
+    Watch(convert::WatchOpts),
+    /// Scrape the `/federate` endpoint of a Prometheus server for the `--match` selectors
+    Federate(federate::FederateOpts),
+    /// Run a PromQL instant or range query and convert the result to the scrape json schema
+    Query(query::QueryOpts),
+    /// Discover annotated pods, services or endpoints through the Kubernetes API and scrape them
+    K8s(discovery::k8s::K8sOpts),
+    /// Discover containers labelled `prometheus.io/scrape=true` on the local Docker host and scrape them
+    Docker(discovery::docker::DockerOpts),
+    /// Live, filterable and sortable view of the scraped metrics
+    Tui(tui::TuiOpts),
+    /// Check a metric against thresholds, exiting Nagios style (0 ok, 1 warning, 2 critical, 3 unknown)
+    Check(check::CheckOpts),
+    /// Compare two scrapes: added and removed series, value deltas and metadata changes
+    Diff(diff::DiffOpts),
+    /// Compare a conversion with an expected JSON document, exiting 1 on drift, for contract
+    /// tests of exporters
+    Compare(compare::CompareOpts),
+    /// Strictly check exposition text, printing issues and exiting nonzero on errors
+    Validate(validate::ValidateOpts),
+    /// Print the bucket time-slices of an archived histogram as CSV, for heatmap panels
+    Heatmap(heatmap::HeatmapOpts),
+    /// Print the documents of an archive as `watch` did, rebuilding those stored as deltas, or
+    /// convert the payloads saved by `--record` again
+    Replay(replay::ReplayOpts),
+    /// Print family, series and label cardinality figures of a scrape
+    Stats(stats::StatsOpts),
+    /// Parse a payload repeatedly, printing throughput, peak memory and allocation figures
+    Bench(bench::BenchOpts),
+    /// Serve JSON conversions over HTTP: `/convert?target=<url>` and `/targets/<name>`
+    Serve(serve::ServeOpts),
+    /// Print a shell completion script
+    Completions {
+        /// bash, zsh, fish, powershell or elvish
+        #[structopt(possible_values = &structopt::clap::Shell::variants())]
+        shell: structopt::clap::Shell,
+    },
+    /// Print the man page, in roff format
+    Man,
+}
+
+/// Parse the command line, treating arguments without a subcommand as `convert` arguments
+fn parse_args() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    match Cli::from_iter_safe(&args) {
+        Ok(cli) => cli,
+        Err(err)
+            if err.kind == ErrorKind::UnknownArgument
+                || err.kind == ErrorKind::UnrecognizedSubcommand =>
+        {
+            let mut convert_args = args.clone();
+            convert_args.insert(1.min(args.len()), "convert".into());
+            Cli::from_iter_safe(&convert_args).unwrap_or_else(|_| err.exit())
+        }
+        Err(err) => err.exit(),
+    }
+}
+
+pub async fn run() -> Result<(), Error> {
+    let cli = parse_args();
+    logging::init(&cli.log);
+    let global = match cli.cmd {
+        Some(Command::Serve(_)) => cli.global.without_redirects(),
+        _ => cli.global,
+    };
+    let settings = Settings::new(global, cli.sink)?;
+    match cli.cmd {
+        Some(Command::Convert(opts)) => convert::convert(opts, &settings).await,
+        Some(Command::Scrape(opts)) => convert::scrape(opts, &settings).await,
+        Some(Command::Watch(opts)) => convert::watch(opts, settings).await,
+        Some(Command::Federate(opts)) => federate::run(opts, &settings).await,
+        Some(Command::Query(opts)) => query::run(opts, &settings).await,
+        Some(Command::K8s(opts)) => discovery::k8s::run(opts, &settings).await,
+        Some(Command::Docker(opts)) => discovery::docker::run(opts, &settings).await,
+        Some(Command::Tui(opts)) => tui::run(opts, Arc::new(settings)).await,
+        Some(Command::Serve(opts)) => serve::run(opts, settings).await,
+        Some(Command::Check(opts)) => check::run(opts, &settings).await,
+        Some(Command::Diff(opts)) => diff::run(opts, &settings).await,
+        Some(Command::Compare(opts)) => compare::run(opts, &settings).await,
+        Some(Command::Validate(opts)) => validate::run(opts, &settings).await,
+        Some(Command::Heatmap(opts)) => heatmap::run(opts).await,
+        Some(Command::Replay(opts)) => replay::run(opts, &settings).await,
+        Some(Command::Stats(opts)) => stats::run(opts, &settings).await,
+        Some(Command::Bench(opts)) => bench::run(opts, &settings).await,
+        Some(Command::Completions { shell }) => {
+            Cli::clap().gen_completions_to("prom2jsonrs", shell, &mut std::io::stdout());
+            Ok(())
+        }
+        Some(Command::Man) => {
+            print!("{}", manpage::render(Cli::clap()));
+            Ok(())
+        }
+        None if settings.config.has_targets() => {
+            convert::scrape(convert::ScrapeOpts::from_iter(&["scrape"]), &settings).await
+        }
+        None => {
+            Cli::clap().print_help()?;
+            println!();
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! OAuth2 client credentials grant, with tokens cached until shortly before they expire
+
+use super::config::OAuth2;
+use super::Error;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Tokens are renewed this long before they expire, so a scrape never carries a stale one
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Hands out the access token of one client, fetching a new one when needed
+pub struct TokenSource {
+    config: OAuth2,
+    /// The current token, and when to renew it (never when the server gave no lifetime)
+    token: Mutex<Option<(String, Option<Instant>)>>,
+}
+
+impl TokenSource {
+    pub fn new(config: &OAuth2) -> TokenSource {
+        TokenSource {
+            config: config.clone(),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// A valid access token, from the cache or freshly requested
+    pub async fn token(&self, client: &Client) -> Result<String, Error> {
+        let mut token = self.token.lock().await;
+        match &*token {
+            Some((access_token, renew)) if renew.is_none_or(|at| Instant::now() < at) => {
+                Ok(access_token.clone())
+            }
+            _ => {
+                let (access_token, lifetime) = self.request(client).await?;
+                let renew = lifetime.map(|l| Instant::now() + l.saturating_sub(EXPIRY_MARGIN));
+                *token = Some((access_token.clone(), renew));
+                Ok(access_token)
+            }
+        }
+    }
+
+    /// Forget the cached token, e.g. after the target rejected it
+    pub async fn invalidate(&self) {
+        *self.token.lock().await = None;
+    }
+
+    async fn request(&self, client: &Client) -> Result<(String, Option<Duration>), Error> {
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if !self.config.scopes.is_empty() {
+            form.push(("scope", self.config.scopes.join(" ")));
+        }
+        for (key, value) in &self.config.endpoint_params {
+            form.push((key.as_str(), value.clone()));
+        }
+        debug!(token_url = %self.config.token_url, "requesting oauth2 token");
+        let response = client
+            .post(&self.config.token_url)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "OAuth2 token request to {} returned {}: {}",
+                self.config.token_url,
+                status,
+                message.trim()
+            )
+            .into());
+        }
+        let token: TokenResponse = response.json().await?;
+        Ok((
+            token.access_token,
+            token.expires_in.map(Duration::from_secs),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn token_caching_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_url = format!("http://{}/token", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for n in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 2048];
+                let len = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..len]).to_string());
+                let body = format!("{{\"access_token\":\"t{}\",\"expires_in\":3600}}", n);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        let source = TokenSource::new(&OAuth2 {
+            token_url,
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: vec!["read".to_string(), "metrics".to_string()],
+            ..Default::default()
+        });
+        let client = Client::new();
+        assert_eq!(source.token(&client).await.unwrap(), "t0");
+        assert_eq!(source.token(&client).await.unwrap(), "t0");
+        source.invalidate().await;
+        assert_eq!(source.token(&client).await.unwrap(), "t1");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("authorization: Basic aWQ6c2VjcmV0"));
+        assert!(requests[0].ends_with("grant_type=client_credentials&scope=read+metrics"));
+    }
+}
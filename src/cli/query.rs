@@ -0,0 +1,96 @@
+use super::config::Settings;
+use super::convert::OutputOpts;
+use super::Error;
+use prom2jsonrs::PrometheusData;
+use reqwest::Url;
+use std::time::SystemTime;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct QueryOpts {
+    /// Prometheus server url, e.g. `http://prometheus:9090`
+    server: String,
+    /// PromQL expression
+    expr: String,
+    /// Evaluation time of an instant query, RFC 3339 or unix seconds (defaults to now)
+    #[structopt(long, conflicts_with = "start")]
+    time: Option<String>,
+    /// Start of a range query, RFC 3339 or unix seconds
+    #[structopt(long, requires_all = &["end", "step"])]
+    start: Option<String>,
+    /// End of a range query
+    #[structopt(long, requires = "start")]
+    end: Option<String>,
+    /// Resolution of a range query, e.g. `15s`
+    #[structopt(long, requires = "start")]
+    step: Option<String>,
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+/// The `/api/v1/query` url, or `/api/v1/query_range` when a start is given
+fn query_url(opts: &QueryOpts) -> Result<Url, Error> {
+    let mut url =
+        Url::parse(&opts.server).map_err(|e| format!("Invalid url {}: {}", opts.server, e))?;
+    let endpoint = if opts.start.is_some() {
+        "query_range"
+    } else {
+        "query"
+    };
+    let path = format!("{}/api/v1/{}", url.path().trim_end_matches('/'), endpoint);
+    url.set_path(&path);
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("query", &opts.expr);
+        let params = [
+            ("time", &opts.time),
+            ("start", &opts.start),
+            ("end", &opts.end),
+            ("step", &opts.step),
+        ];
+        for (name, value) in params {
+            if let Some(value) = value {
+                query.append_pair(name, value);
+            }
+        }
+    }
+    Ok(url)
+}
+
+pub async fn run(opts: QueryOpts, settings: &Settings) -> Result<(), Error> {
+    let url = query_url(&opts)?;
+    let raw = settings.scraper.fetch_api(url.as_str()).await?;
+    let mut data = PrometheusData::from_query_response(&raw, &opts.expr)?;
+    settings.process(&mut data, &Default::default());
+    opts.output.apply(&mut data, SystemTime::now());
+    settings.output(&data).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_url_works() {
+        let opts = QueryOpts::from_iter(&["query", "http://prom:9090/", "sum(up) by (job)"]);
+        assert_eq!(
+            query_url(&opts).unwrap().as_str(),
+            "http://prom:9090/api/v1/query?query=sum%28up%29+by+%28job%29"
+        );
+        let opts = QueryOpts::from_iter(&[
+            "query",
+            "http://prom:9090/prometheus",
+            "up",
+            "--start",
+            "1700000000",
+            "--end",
+            "1700003600",
+            "--step",
+            "60s",
+        ]);
+        assert_eq!(
+            query_url(&opts).unwrap().as_str(),
+            "http://prom:9090/prometheus/api/v1/query_range?query=up&start=1700000000&end=1700003600&step=60s"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Recordings of scrapes: the raw exposition text of every scrape of `--record` next to its
+//! conversion, which `replay --recording` converts again to catch parser regressions
+
+use super::archive::format_time;
+use super::config::Target;
+use super::sink::serialize_time;
+use super::Error;
+use prom2jsonrs::{Labels, PrometheusData};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+#[derive(Serialize, Deserialize)]
+/// What a scrape was converted into, and what it needs to be converted again: the target
+/// labels are added to every series
+pub struct Recording {
+    pub target: String,
+    pub url: String,
+    #[serde(default)]
+    pub labels: Labels,
+    #[serde(
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    pub scraped_at: SystemTime,
+    #[serde(flatten)]
+    pub data: PrometheusData,
+}
+
+fn deserialize_time<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SystemTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339(&time).map_err(serde::de::Error::custom)
+}
+
+/// A directory of `<time>-<target>.prom` files with the raw text of the scrapes, each with a
+/// `<time>-<target>.json` `Recording` of its conversion
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+/// A target name fit for a file name
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl Recorder {
+    pub fn new(dir: &Path) -> Result<Recorder, Error> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Save the raw text a target was scraped into at `scraped_at` and its conversion
+    pub async fn write(
+        &self,
+        target: &Target,
+        scraped_at: SystemTime,
+        raw: &str,
+        data: &PrometheusData,
+    ) -> Result<(), Error> {
+        let stem = format!(
+            "{}-{}",
+            format_time(scraped_at).replace(':', "-"),
+            slug(target.display_name())
+        );
+        let recording = Recording {
+            target: target.display_name().to_string(),
+            url: target.url.clone(),
+            labels: target.labels.clone(),
+            scraped_at,
+            data: data.clone(),
+        };
+        let path = self.dir.join(format!("{}.json", stem));
+        tokio::fs::write(&path, serde_json::to_string(&recording)?).await?;
+        // Written last, a payload always has its conversion
+        tokio::fs::write(self.dir.join(format!("{}.prom", stem)), raw).await?;
+        debug!(path = %path.display(), "recorded");
+        Ok(())
+    }
+}
+
+/// The payloads recorded in `dir`, oldest first
+pub async fn payloads(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut payloads = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", dir.display(), e))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "prom") {
+            payloads.push(path);
+        }
+    }
+    // The names start with the time of the scrape
+    payloads.sort();
+    Ok(payloads)
+}
+
+/// The recorded conversion of a payload
+pub async fn read(payload: &Path) -> Result<Recording, Error> {
+    let path = payload.with_extension("json");
+    let raw = tokio::fs::read(&path)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn recording_works() {
+        let dir = std::env::temp_dir().join(format!("prom2jsonrs-record-{}", std::process::id()));
+        let recorder = Recorder::new(&dir).unwrap();
+        let target = Target {
+            name: None,
+            url: "http://node:9100/metrics".to_string(),
+            labels: vec![("job".to_string(), "node".to_string())]
+                .into_iter()
+                .collect(),
+            auth: None,
+        };
+        let raw = "# TYPE up gauge\nup 1\n";
+        let data = PrometheusData::from_string(raw);
+        for s in [2, 1] {
+            let at = UNIX_EPOCH + Duration::from_secs(s);
+            recorder.write(&target, at, raw, &data).await.unwrap();
+        }
+        let payloads = payloads(&dir).await.unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(
+            payloads[0].file_name().unwrap(),
+            "1970-01-01T00-00-01.000Z-http___node_9100_metrics.prom"
+        );
+        assert_eq!(tokio::fs::read_to_string(&payloads[0]).await.unwrap(), raw);
+        let recording = read(&payloads[0]).await.unwrap();
+        assert_eq!(recording.url, target.url);
+        assert_eq!(recording.labels, target.labels);
+        assert_eq!(recording.scraped_at, UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(recording.data.metrics[0].metric_name, "up");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! Reloading the settings without restarting, on `SIGHUP` or on request
+
+use super::config::Settings;
+use super::Error;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// Settings that can be swapped for freshly loaded ones while in use
+pub struct Reloadable {
+    current: RwLock<Arc<Settings>>,
+}
+
+impl Reloadable {
+    pub fn new(settings: Settings) -> Reloadable {
+        Reloadable {
+            current: RwLock::new(Arc::new(settings)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read the config file and its secret files again. The current settings stay when that
+    /// fails.
+    pub fn reload(&self) -> Result<(), Error> {
+        let settings = self.get().reload()?;
+        info!(
+            targets = settings.config.targets.len(),
+            "reloaded the config"
+        );
+        *self.current.write().unwrap() = Arc::new(settings);
+        Ok(())
+    }
+}
+
+/// Call `reload` on every `SIGHUP`, for as long as the process runs
+pub fn on_hangup<F>(reload: F) -> Result<(), Error>
+where
+    F: Fn() -> Result<(), Error> + Send + 'static,
+{
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(err) = reload() {
+                    warn!(error = %err, "reload failed");
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    drop(reload);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::GlobalOpts;
+    use super::super::sink::SinkOpts;
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn reload_works() {
+        let path =
+            std::env::temp_dir().join(format!("prom2jsonrs-reload-{}.yaml", std::process::id()));
+        std::fs::write(&path, "targets:\n  - url: http://a/metrics\n").unwrap();
+        let settings = Settings::new(
+            GlobalOpts::from_iter(&["test", "--config", path.to_str().unwrap()]),
+            SinkOpts::from_iter(&["test"]),
+        )
+        .unwrap();
+        let reloadable = Reloadable::new(settings);
+        let before = reloadable.get();
+
+        std::fs::write(
+            &path,
+            "targets:\n  - url: http://a/metrics\n  - url: http://b/metrics\nfilters:\n  include: [up]\n",
+        )
+        .unwrap();
+        reloadable.reload().unwrap();
+        let after = reloadable.get();
+        assert_eq!(after.config.targets.len(), 2);
+        assert_eq!(after.include.len(), 1);
+        // Holders of the previous settings keep them
+        assert_eq!(before.config.targets.len(), 1);
+
+        std::fs::write(&path, "targets: {").unwrap();
+        assert!(reloadable.reload().is_err());
+        assert_eq!(reloadable.get().config.targets.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+//! Full documents rebuilt from an archive, snapshots and deltas alike, as `watch` printed them,
+//! or the payloads of a recording converted again
+
+use super::archive;
+use super::config::{parse_duration, Settings};
+use super::diff;
+use super::record::{self, Recording};
+use super::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct ReplayOpts {
+    /// Directory of the documents recorded by `watch --archive-dir`
+    #[structopt(long, required_unless = "recording", conflicts_with = "recording")]
+    archive_dir: Option<PathBuf>,
+    /// Directory of the payloads saved by `--record`, to convert again with the current parser
+    /// and settings
+    #[structopt(long)]
+    recording: Option<PathBuf>,
+    /// Compare the conversions of the recorded payloads with the recorded ones instead of
+    /// printing them, listing the differences and failing on any
+    #[structopt(long, requires = "recording")]
+    check: bool,
+    /// Only replay the documents archived in this last period, e.g. `6h`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    since: Option<Duration>,
+}
+
+/// Print the documents of an archive or the conversions of a recording, one per line and
+/// oldest first
+pub async fn run(opts: ReplayOpts, settings: &Settings) -> Result<(), Error> {
+    let end = SystemTime::now();
+    let start = match opts.since {
+        Some(since) => end - since,
+        None => UNIX_EPOCH,
+    };
+    let dir = match (&opts.recording, &opts.archive_dir) {
+        (Some(dir), _) => return replay_recording(dir, start, opts.check, settings).await,
+        (None, Some(dir)) => dir,
+        (None, None) => return Err("Pass --archive-dir or --recording".into()),
+    };
+    for (_, document) in archive::replay(dir, start, end).await? {
+        println!("{}", serde_json::to_string(&document)?);
+    }
+    Ok(())
+}
+
+/// Convert the payloads of a recording scraped from `start` on again, printing the new
+/// conversions, or with `check` their differences with the recorded ones
+async fn replay_recording(
+    dir: &Path,
+    start: SystemTime,
+    check: bool,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let (mut replayed, mut different) = (0, 0);
+    for payload in record::payloads(dir).await? {
+        let recorded = record::read(&payload).await?;
+        if recorded.scraped_at < start {
+            continue;
+        }
+        let raw = tokio::fs::read_to_string(&payload)
+            .await
+            .map_err(|e| format!("Cannot read {}: {}", payload.display(), e))?;
+        let mut data = settings.scraper.parse(&recorded.url, &raw)?;
+        settings.process(&mut data, &recorded.labels);
+        replayed += 1;
+        if !check {
+            let recording = Recording { data, ..recorded };
+            println!("{}", serde_json::to_string(&recording)?);
+            continue;
+        }
+        let diff = prom2jsonrs::diff(&recorded.data, &data);
+        if diff.is_empty() {
+            continue;
+        }
+        different += 1;
+        println!("{}", payload.display());
+        diff::print(&diff, "  ");
+    }
+    if different > 0 {
+        return Err(format!("{} of {} payloads convert differently", different, replayed).into());
+    }
+    if check {
+        println!("{} payloads convert as recorded", replayed);
+    }
+    Ok(())
+}
@@ -0,0 +1,491 @@
+use super::config::{Auth, Config, OAuth2, Settings, Target};
+use super::oauth2::TokenSource;
+use super::Error;
+use futures_util::{stream, StreamExt};
+use prom2jsonrs::PROTOBUF_CONTENT_TYPE;
+use prom2jsonrs::{lint, FetchError, ParseLimits, PrometheusData, StreamParser};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT};
+use reqwest::redirect::Policy;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+/// How the scrape of a target went, as Prometheus tells with its `up` and
+/// `scrape_duration_seconds` series
+pub struct ScrapeMetadata {
+    pub url: String,
+    /// Whether the target was scraped and parsed
+    pub up: bool,
+    /// Of the response, none for unix sockets and requests that got no response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub duration_seconds: f64,
+    /// Size of the payload, none when the scrape failed before reading it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_bytes: Option<usize>,
+    /// Parse issues of the payload, errors included, as `validate` lists them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warnings: Option<usize>,
+}
+
+impl ScrapeMetadata {
+    pub fn new(url: &str) -> ScrapeMetadata {
+        ScrapeMetadata {
+            url: url.to_string(),
+            ..ScrapeMetadata::default()
+        }
+    }
+
+    /// Fill in the response of the scrape
+    pub fn read(&mut self, status: Option<u16>, body: &str) {
+        self.status = status;
+        self.body_bytes = Some(body.len());
+        self.warnings = Some(lint(body).len());
+    }
+
+    /// Fill in the outcome of the scrape, which took `duration`
+    pub fn finish<T>(&mut self, result: &Result<T, Error>, duration: Duration) {
+        self.up = result.is_ok();
+        self.duration_seconds = duration.as_secs_f64();
+        if let Err(err) = result {
+            let status = err
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|e| e.status());
+            self.status = self.status.or(status.map(|s| s.as_u16()));
+        }
+    }
+}
+
+#[derive(Debug)]
+/// A payload that was fetched but could not be parsed, told apart from failed requests
+pub struct CannotParse {
+    pub url: String,
+    pub error: String,
+}
+
+impl CannotParse {
+    fn error(url: &str, error: impl fmt::Display) -> Error {
+        Box::new(CannotParse {
+            url: url.to_string(),
+            error: error.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for CannotParse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cannot parse {}: {}", self.url, self.error)
+    }
+}
+
+impl std::error::Error for CannotParse {}
+
+/// Spaces requests evenly so that at most `rate` of them start per second
+struct RateLimiter {
+    period: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Result<RateLimiter, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(format!("Invalid rate limit {}", rate));
+        }
+        Ok(RateLimiter {
+            period: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Wait for the next request slot
+    async fn wait(&self) {
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(now);
+            *next = slot + self.period;
+            slot
+        };
+        if slot > now {
+            trace!(wait_ms = (slot - now).as_millis() as u64, "rate limited");
+        }
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+type TokenSources = Vec<(OAuth2, Arc<TokenSource>)>;
+
+#[derive(Clone)]
+/// An http client configured with the headers, TLS settings and auth of the config
+pub struct Scraper {
+    client: Client,
+    auth: Option<Auth>,
+    limiter: Option<Arc<RateLimiter>>,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+    limits: ParseLimits,
+    skip_malformed: bool,
+    /// The token source of every OAuth2 client in use, shared by the clones of the scraper
+    tokens: Arc<Mutex<TokenSources>>,
+}
+
+/// Add the basic or bearer auth of `auth` to a request
+pub fn authenticate(request: RequestBuilder, auth: Option<&Auth>) -> RequestBuilder {
+    match auth {
+        Some(Auth {
+            basic: Some(basic), ..
+        }) => request.basic_auth(&basic.username, Some(&basic.password)),
+        Some(Auth {
+            bearer_token: Some(token),
+            ..
+        }) => request.bearer_auth(token),
+        _ => request,
+    }
+}
+
+impl Scraper {
+    pub fn new(config: &Config) -> Result<Scraper, Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if config.protobuf && !headers.contains_key(ACCEPT) {
+            let accept = format!(
+                "{};q=0.7,text/plain;version=0.0.4;q=0.3",
+                PROTOBUF_CONTENT_TYPE
+            );
+            headers.insert(ACCEPT, HeaderValue::from_str(&accept)?);
+        }
+        // One pooled client serves every scrape, so watch and multi-target modes reuse
+        // connections (multiplexed over HTTP/2 where the target speaks it)
+        let mut builder = Client::builder()
+            .default_headers(headers.clone())
+            .pool_idle_timeout(config.pool_idle_timeout.unwrap_or(Duration::from_secs(90)))
+            .tcp_keepalive(Duration::from_secs(60))
+            .http2_adaptive_window(true);
+        if config.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if config.no_redirects {
+            builder = builder.redirect(Policy::custom(|attempt| {
+                attempt.error("redirects are not followed")
+            }));
+        }
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(ca_file) = &config.tls.ca_file {
+            let pem = std::fs::read(ca_file)
+                .map_err(|e| format!("Cannot read CA file {}: {}", ca_file, e))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        if config.tls.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let limiter = match config.rate_limit {
+            Some(rate) => Some(Arc::new(RateLimiter::new(rate)?)),
+            None => None,
+        };
+        Ok(Scraper {
+            client: builder.build()?,
+            auth: config.auth.clone(),
+            limiter,
+            headers,
+            timeout: config.timeout,
+            limits: config.limits,
+            skip_malformed: config.skip_malformed,
+            tokens: Arc::default(),
+        })
+    }
+
+    fn token_source(&self, oauth2: &OAuth2) -> Arc<TokenSource> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some((_, source)) = tokens.iter().find(|(config, _)| config == oauth2) {
+            return source.clone();
+        }
+        let source = Arc::new(TokenSource::new(oauth2));
+        tokens.push((oauth2.clone(), source.clone()));
+        source
+    }
+
+    /// Add `auth` to a request, fetching an OAuth2 access token if needed
+    async fn authorize(
+        &self,
+        request: RequestBuilder,
+        auth: Option<&Auth>,
+    ) -> Result<RequestBuilder, Error> {
+        match auth.and_then(|auth| auth.oauth2.as_ref()) {
+            Some(oauth2) => {
+                let token = self.token_source(oauth2).token(&self.client).await?;
+                Ok(request.bearer_auth(token))
+            }
+            None => Ok(authenticate(request, auth)),
+        }
+    }
+
+    /// Send the request for an http(s) `url`, using `auth` over the configured one. A
+    /// rejected OAuth2 token is renewed and the request sent again.
+    async fn send(&self, url: &str, auth: Option<&Auth>) -> Result<Response, Error> {
+        if let Some(limiter) = &self.limiter {
+            limiter.wait().await;
+        }
+        let auth = auth.or(self.auth.as_ref());
+        let request = self.authorize(self.client.get(url), auth).await?;
+        let response = request.send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            if let Some(oauth2) = auth.and_then(|auth| auth.oauth2.as_ref()) {
+                debug!(url, "token rejected, renewing it");
+                self.token_source(oauth2).invalidate().await;
+                let request = self.authorize(self.client.get(url), auth).await?;
+                return Ok(request.send().await?.error_for_status()?);
+            }
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    /// Fetch the body at an http(s) `url` whatever the response status, for APIs that
+    /// describe their errors in the body
+    pub async fn fetch_api(&self, url: &str) -> Result<String, Error> {
+        if let Some(limiter) = &self.limiter {
+            limiter.wait().await;
+        }
+        let request = self
+            .authorize(self.client.get(url), self.auth.as_ref())
+            .await?;
+        Ok(request.send().await?.text().await?)
+    }
+
+    /// Fetch the raw metrics exposed at an http(s) or `unix://` `url`, using `auth` over the
+    /// configured one
+    pub async fn fetch_with_auth(&self, url: &str, auth: Option<&Auth>) -> Result<String, Error> {
+        Ok(self.fetch_with_status(url, auth).await?.1)
+    }
+
+    /// Fetch like `fetch_with_auth`, along with the status of the response over http(s)
+    pub async fn fetch_with_status(
+        &self,
+        url: &str,
+        auth: Option<&Auth>,
+    ) -> Result<(Option<u16>, String), Error> {
+        if url.starts_with("unix://") {
+            return Ok((None, self.fetch_unix(url, auth).await?));
+        }
+        let response = self.send(url, auth).await?;
+        let status = response.status().as_u16();
+        Ok((Some(status), response.text().await?))
+    }
+
+    /// Fetch the raw metrics exposed at `url`
+    pub async fn fetch(&self, url: &str) -> Result<String, Error> {
+        self.fetch_with_auth(url, None).await
+    }
+
+    /// Fetch and parse the metrics exposed at `url`, feeding the body to the parser as it
+    /// arrives instead of buffering it whole
+    pub async fn scrape(&self, url: &str, auth: Option<&Auth>) -> Result<PrometheusData, Error> {
+        if url.starts_with("unix://") {
+            let body = self.fetch_unix(url, auth).await?;
+            return self.parse(url, &body);
+        }
+        let response = self.send(url, auth).await?;
+        debug!(url, status = %response.status(), version = ?response.version(), "response");
+        match PrometheusData::from_response_with(response, self.parser(url)).await {
+            Ok(data) => Ok(data),
+            Err(FetchError::Http(e)) => Err(e.into()),
+            Err(e) => Err(CannotParse::error(url, e)),
+        }
+    }
+
+    /// A parser of what was read from `source` within the configured limits, skipping (and
+    /// logging) the lines it cannot read unless `--fail-on` makes them fail
+    pub fn parser(&self, source: &str) -> StreamParser {
+        let parser = StreamParser::with_limits(self.limits);
+        if !self.skip_malformed {
+            return parser;
+        }
+        let source = source.to_string();
+        parser.skipping_malformed(move |error| {
+            warn!(source = source.as_str(), error, "skipped a line")
+        })
+    }
+
+    /// Parse a body fetched from `url` within the configured limits
+    pub fn parse(&self, url: &str, body: &str) -> Result<PrometheusData, Error> {
+        let unparsed = |e| CannotParse::error(url, e);
+        let mut parser = self.parser(url);
+        parser.feed(body.as_bytes()).map_err(unparsed)?;
+        parser.finish().map_err(unparsed)
+    }
+
+    #[cfg(unix)]
+    async fn fetch_unix(&self, url: &str, auth: Option<&Auth>) -> Result<String, Error> {
+        if let Some(limiter) = &self.limiter {
+            limiter.wait().await;
+        }
+        // Let reqwest render the auth header, then send it along with the configured headers
+        let request = self
+            .authorize(
+                self.client.get("http://localhost/"),
+                auth.or(self.auth.as_ref()),
+            )
+            .await?
+            .build()?;
+        let mut headers = self.headers.clone();
+        headers.extend(request.headers().clone());
+        super::unix::fetch(url, &headers, self.timeout).await
+    }
+
+    #[cfg(not(unix))]
+    async fn fetch_unix(&self, url: &str, _auth: Option<&Auth>) -> Result<String, Error> {
+        Err(format!(
+            "Unix socket targets are not supported on this platform: {}",
+            url
+        )
+        .into())
+    }
+}
+
+/// Scrape every target with at most `concurrency` requests in flight, returning the results
+/// in the order of `targets`
+pub async fn scrape_all(
+    settings: &Settings,
+    targets: &[Target],
+    concurrency: usize,
+) -> Vec<(SystemTime, Result<PrometheusData, String>)> {
+    stream::iter(targets)
+        .map(|target| async move {
+            let jitter = settings.jitter();
+            if !jitter.is_zero() {
+                debug!(
+                    target = target.display_name(),
+                    jitter_ms = jitter.as_millis() as u64,
+                    "jitter"
+                );
+            }
+            tokio::time::sleep(jitter).await;
+            let scraped_at = SystemTime::now();
+            let result = settings.scrape(target).await.map_err(|e| e.to_string());
+            (scraped_at, result)
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scrape_metadata_works() {
+        let mut metadata = ScrapeMetadata::new("http://localhost:9100/metrics");
+        metadata.read(Some(200), "# TYPE up gauge\nup 1\nup 2\n");
+        metadata.finish(&Ok(()), Duration::from_millis(250));
+        assert_eq!(metadata.duration_seconds, 0.25);
+        assert!(metadata.up);
+        assert_eq!(metadata.body_bytes, Some(26));
+        assert_eq!(metadata.warnings, Some(2));
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(json["status"], 200);
+
+        let mut failed = ScrapeMetadata::new("unix:///run/exporter.sock");
+        failed.finish(&Err::<(), Error>("refused".into()), Duration::ZERO);
+        let json = serde_json::to_value(&failed).unwrap();
+        assert_eq!(json["up"], false);
+        assert!(json.get("status").is_none() && json.get("body_bytes").is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_works() {
+        let limiter = RateLimiter::new(100.0).unwrap();
+        let start = Instant::now();
+        for _ in 0..6 {
+            limiter.wait().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(RateLimiter::new(0.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn redirects_work() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        // `/` redirects to `/metrics`
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let read = socket.read(&mut request).await.unwrap();
+                let response = if request[..read].starts_with(b"GET /metrics ") {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nup 1\n"
+                } else {
+                    "HTTP/1.1 302 Found\r\nLocation: /metrics\r\nContent-Length: 0\r\n\r\n"
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let url = format!("http://{}/", address);
+        let scraper = Scraper::new(&Config::default()).unwrap();
+        assert_eq!(scraper.fetch(&url).await.unwrap(), "up 1\n");
+        let config = Config {
+            no_redirects: true,
+            ..Config::default()
+        };
+        let scraper = Scraper::new(&config).unwrap();
+        assert!(scraper.fetch(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scrape_all_works() {
+        use super::super::config::GlobalOpts;
+        use super::super::sink::SinkOpts;
+        use structopt::StructOpt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        // `/bad` answers a malformed line
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let read = socket.read(&mut request).await.unwrap();
+                let body = if request[..read].starts_with(b"GET /bad ") {
+                    "up 1\nup{a=\"b\" 2\n"
+                } else {
+                    "up 1\n"
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let targets = [
+            Target::from_url(&format!("http://{}/bad", address)),
+            Target::from_url(&format!("http://{}/metrics", address)),
+        ];
+        let mut settings = Settings::new(
+            GlobalOpts::from_iter(&["test"]),
+            SinkOpts::from_iter(&["test"]),
+        )
+        .unwrap();
+        // Skipping the malformed line by default
+        let results = scrape_all(&settings, &targets, 2).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        // Failing on it otherwise, that target alone
+        settings.scraper = Scraper::new(&Config::default()).unwrap();
+        let results = scrape_all(&settings, &targets, 2).await;
+        let error = results[0].1.as_ref().err().unwrap();
+        assert!(error.starts_with("Cannot parse http://"), "{}", error);
+        assert_eq!(results[1].1.as_ref().unwrap().samples().len(), 1);
+    }
+}
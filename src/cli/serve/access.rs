@@ -0,0 +1,229 @@
+//! Who may call `serve`, and how often
+
+use super::super::Error;
+use super::tenancy::Tenant;
+use super::{error, Server};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Forget about the clients idle for long enough past this many
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The `Authorization` headers accepted, from bearer tokens and basic auth credentials
+pub struct Credentials {
+    accepted: Vec<String>,
+    basic: bool,
+}
+
+/// The non-empty lines of a file
+fn lines(path: &Path) -> Result<Vec<String>, Error> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    Ok(raw
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Whether both are equal, in a time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Credentials {
+    /// From a file of bearer tokens and one of `user:password` lines, one per line
+    pub fn load(tokens: Option<&Path>, basic: Option<&Path>) -> Result<Option<Credentials>, Error> {
+        if tokens.is_none() && basic.is_none() {
+            return Ok(None);
+        }
+        let mut accepted = Vec::new();
+        if let Some(path) = tokens {
+            accepted.extend(lines(path)?.iter().map(|t| format!("Bearer {}", t)));
+        }
+        if let Some(path) = basic {
+            for line in lines(path)? {
+                if !line.contains(':') {
+                    return Err(
+                        format!("Expected user:password lines in {}", path.display()).into(),
+                    );
+                }
+                accepted.push(format!("Basic {}", STANDARD.encode(line)));
+            }
+        }
+        Ok(Some(Credentials {
+            accepted,
+            basic: basic.is_some(),
+        }))
+    }
+
+    fn accepts(&self, authorization: Option<&HeaderValue>) -> bool {
+        let authorization = match authorization {
+            Some(authorization) => authorization.as_bytes(),
+            None => return false,
+        };
+        // Check them all, not to tell which one was close
+        self.accepted.iter().fold(false, |ok, a| {
+            constant_time_eq(a.as_bytes(), authorization) | ok
+        })
+    }
+}
+
+/// Lets each client address make `rate` requests per second on average, in bursts of up to
+/// `burst`
+pub struct ClientLimiter {
+    period: Duration,
+    burst: u32,
+    /// When each client will have its whole burst back
+    clients: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl ClientLimiter {
+    pub fn new(rate: f64, burst: Option<u32>) -> Result<ClientLimiter, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(format!("Invalid client rate limit {}", rate));
+        }
+        Ok(ClientLimiter {
+            period: Duration::from_secs_f64(1.0 / rate),
+            burst: burst.unwrap_or(rate.ceil() as u32).max(1),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Count a request from `client`, or tell how long until it may make one
+    fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > MAX_TRACKED_CLIENTS {
+            clients.retain(|_, full| *full > now);
+        }
+        let full = clients.get(&client).map_or(now, |full| (*full).max(now));
+        let next = full + self.period;
+        // Past a whole burst ahead of now
+        let allowed_from = next.checked_sub(self.period * self.burst).unwrap_or(now);
+        if allowed_from > now {
+            return Err(allowed_from - now);
+        }
+        clients.insert(client, next);
+        Ok(())
+    }
+}
+
+/// Why a request is turned away
+pub enum Refusal {
+    /// The client is over its rate, for this long
+    TooManyRequests(Duration),
+    Unauthorized,
+}
+
+/// Let a request from `client` through unless it is over its rate, failed attempts included, or
+/// lacks accepted credentials, telling which tenant it comes from
+pub fn admit(
+    server: &Server,
+    client: Option<IpAddr>,
+    authorization: Option<&HeaderValue>,
+) -> Result<Option<Arc<Tenant>>, Refusal> {
+    if let (Some(limiter), Some(client)) = (&server.limiter, client) {
+        limiter.check(client).map_err(Refusal::TooManyRequests)?;
+    }
+    // Tenants identify with their token, other clients need the credentials of the files
+    let tenant = server.tenancy.tenant(authorization);
+    let authorized = tenant.is_some()
+        || match &server.credentials {
+            Some(credentials) => credentials.accepts(authorization),
+            None => !server.tenancy.has_tenants(),
+        };
+    if !authorized {
+        return Err(Refusal::Unauthorized);
+    }
+    Ok(tenant)
+}
+
+/// Answer `429` to clients over their rate and `401` to requests without accepted credentials.
+/// The tenant a request comes from is added to its extensions.
+pub async fn guard(
+    State(server): State<Arc<Server>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let authorization = request.headers().get(header::AUTHORIZATION);
+    match admit(&server, client, authorization) {
+        Ok(tenant) => {
+            if let Some(tenant) = tenant {
+                request.extensions_mut().insert(tenant);
+            }
+            next.run(request).await
+        }
+        Err(Refusal::TooManyRequests(wait)) => {
+            let mut response = error(StatusCode::TOO_MANY_REQUESTS, "Too many requests");
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+            );
+            response
+        }
+        Err(Refusal::Unauthorized) => {
+            let mut response = error(StatusCode::UNAUTHORIZED, "Unauthorized");
+            let challenge = match &server.credentials {
+                Some(credentials) if credentials.basic => "Basic realm=\"prom2jsonrs\"",
+                _ => "Bearer",
+            };
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(challenge),
+            );
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn access_works() {
+        let dir = std::env::temp_dir().join(format!("prom2jsonrs-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tokens"), "s3cret\n\nother\n").unwrap();
+        std::fs::write(dir.join("users"), "alice:wonderland\n").unwrap();
+        let credentials = Credentials::load(Some(&dir.join("tokens")), Some(&dir.join("users")))
+            .unwrap()
+            .unwrap();
+        assert!(credentials.accepts(Some(&HeaderValue::from_static("Bearer other"))));
+        assert!(credentials.accepts(Some(&HeaderValue::from_static(
+            "Basic YWxpY2U6d29uZGVybGFuZA=="
+        ))));
+        assert!(!credentials.accepts(Some(&HeaderValue::from_static("Bearer s3cre"))));
+        assert!(!credentials.accepts(None));
+        std::fs::write(dir.join("users"), "alice\n").unwrap();
+        assert!(Credentials::load(None, Some(&dir.join("users"))).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let limiter = ClientLimiter::new(2.0, Some(3)).unwrap();
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.check(client).is_ok());
+        }
+        assert_eq!(limiter.check(client), Err(Duration::from_millis(500)));
+        assert!(limiter.check("10.0.0.2".parse().unwrap()).is_ok());
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check(client).is_ok());
+        assert!(limiter.check(client).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! `/aggregate`: every target of a job of the config, merged into one document
+
+use super::super::config::Target;
+use super::tenancy::Tenant;
+use super::{error, json, scrape_data, tenant, Server, TenantExtension};
+use axum::extract::{Query, State};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::Response;
+use futures_util::{stream, StreamExt};
+use prom2jsonrs::PrometheusData;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct AggregateParams {
+    job: Option<String>,
+}
+
+/// The `host:port` of a url, as Prometheus labels its targets
+fn instance(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.host_str().is_some() => match parsed.port_or_known_default() {
+            Some(port) => format!("{}:{}", parsed.host_str().unwrap(), port),
+            None => parsed.host_str().unwrap().to_string(),
+        },
+        _ => url.to_string(),
+    }
+}
+
+/// The targets of the config labeled with `job` the tenant may scrape, with an `instance` label
+/// telling them apart
+fn job_targets(server: &Server, tenant: Option<&Tenant>, job: &str) -> Vec<Target> {
+    server
+        .settings()
+        .config
+        .targets
+        .iter()
+        .filter(|t| t.labels.get("job").map(String::as_str) == Some(job))
+        .filter(|t| {
+            t.name
+                .as_deref()
+                .is_some_and(|name| server.tenancy.may_scrape_named(tenant, name))
+                || tenant.is_none()
+        })
+        .map(|target| {
+            let mut target = target.clone();
+            if !target.labels.contains_key("instance") {
+                let instance = instance(&target.url);
+                target.labels.insert("instance".to_string(), instance);
+            }
+            target
+        })
+        .collect()
+}
+
+/// Scrape every target of the job concurrently into a single document. Failed targets are left
+/// out and counted in an `X-Failed-Targets` header, unless all of them failed.
+pub async fn aggregate(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    Query(params): Query<AggregateParams>,
+) -> Response {
+    let job = match params.job {
+        Some(job) => job,
+        None => return error(StatusCode::BAD_REQUEST, "Missing job parameter"),
+    };
+    let targets = job_targets(&server, tenant(&extension), &job);
+    if targets.is_empty() {
+        return error(
+            StatusCode::NOT_FOUND,
+            &format!("No target of job {} in the config", job),
+        );
+    }
+    let concurrency = server.settings().config.concurrency.unwrap_or(4).max(1);
+    let results: Vec<Result<PrometheusData, String>> = stream::iter(targets.clone())
+        .map(|target| {
+            let server = server.clone();
+            async move { scrape_data(&server, &target).await }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    let mut merged = PrometheusData { metrics: vec![] };
+    let mut errors = Vec::new();
+    for (target, result) in targets.iter().zip(results) {
+        match result {
+            Ok(data) => merged.merge(data),
+            Err(err) => errors.push(format!("{}: {}", target.display_name(), err)),
+        }
+    }
+    if errors.len() == targets.len() {
+        return error(StatusCode::BAD_GATEWAY, &errors.join("; "));
+    }
+    let mut response = json(StatusCode::OK, server.settings().render(&merged));
+    if !errors.is_empty() {
+        response
+            .headers_mut()
+            .insert("x-failed-targets", HeaderValue::from(errors.len()));
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instance_works() {
+        assert_eq!(instance("http://node-1:9100/metrics"), "node-1:9100");
+        assert_eq!(
+            instance("https://api.example.com/metrics"),
+            "api.example.com:443"
+        );
+        assert_eq!(
+            instance("unix:///run/app.sock:/metrics"),
+            "unix:///run/app.sock:/metrics"
+        );
+    }
+}
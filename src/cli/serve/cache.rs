@@ -0,0 +1,156 @@
+//! Per-target caching of converted documents, with stale-while-revalidate
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How a request was answered
+pub enum CacheStatus {
+    /// From a fresh entry
+    Hit,
+    /// From an entry past its TTL, while it is refreshed in the background
+    Stale,
+    /// By converting the target
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Miss => "miss",
+        }
+    }
+}
+
+struct Entry {
+    body: String,
+    fetched: Instant,
+}
+
+#[derive(Default)]
+/// The cache of one target. The entry lock is held while converting, so concurrent misses
+/// wait for a single conversion instead of each scraping the target.
+struct Slot {
+    entry: tokio::sync::Mutex<Option<Entry>>,
+    refreshing: AtomicBool,
+}
+
+pub struct Cache {
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    slots: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, stale_while_revalidate: Duration) -> Cache {
+        Cache {
+            ttl,
+            stale_while_revalidate,
+            slots: Mutex::default(),
+        }
+    }
+
+    /// Forget every conversion, e.g. once the settings they were made with changed
+    pub fn clear(&self) {
+        self.slots.lock().unwrap().clear();
+    }
+
+    fn slot(&self, key: &str) -> Arc<Slot> {
+        let mut slots = self.slots.lock().unwrap();
+        slots.entry(key.to_string()).or_default().clone()
+    }
+
+    /// The document of `key`: cached while younger than the TTL, then served stale for up to
+    /// `stale_while_revalidate` while `fetch` runs in the background, and fetched on the spot
+    /// past that. Failures are not cached.
+    pub async fn get<F, Fut>(&self, key: &str, fetch: F) -> (Result<String, String>, CacheStatus)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        let slot = self.slot(key);
+        let mut entry = slot.entry.lock().await;
+        if let Some(cached) = &*entry {
+            let age = cached.fetched.elapsed();
+            if age < self.ttl {
+                return (Ok(cached.body.clone()), CacheStatus::Hit);
+            }
+            if age < self.ttl + self.stale_while_revalidate {
+                if !slot.refreshing.swap(true, Ordering::SeqCst) {
+                    debug!(key, "revalidating");
+                    let refresh = fetch();
+                    let slot = slot.clone();
+                    let key = key.to_string();
+                    tokio::spawn(async move {
+                        let fetched = Instant::now();
+                        match refresh.await {
+                            Ok(body) => *slot.entry.lock().await = Some(Entry { body, fetched }),
+                            Err(err) => warn!(key, error = %err, "revalidation failed"),
+                        }
+                        slot.refreshing.store(false, Ordering::SeqCst);
+                    });
+                }
+                return (Ok(cached.body.clone()), CacheStatus::Stale);
+            }
+        }
+        let fetched = Instant::now();
+        let result = fetch().await;
+        if let Ok(body) = &result {
+            *entry = Some(Entry {
+                body: body.clone(),
+                fetched,
+            });
+        }
+        (result, CacheStatus::Miss)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_works() {
+        let cache = Cache::new(Duration::from_secs(10), Duration::from_secs(20));
+        let fetches = Arc::new(AtomicU32::new(0));
+        let fetch = || {
+            let fetches = fetches.clone();
+            async move { Ok(format!("v{}", fetches.fetch_add(1, Ordering::SeqCst))) }
+        };
+        assert_eq!(
+            cache.get("a", fetch).await,
+            (Ok("v0".to_string()), CacheStatus::Miss)
+        );
+        assert_eq!(
+            cache.get("a", fetch).await,
+            (Ok("v0".to_string()), CacheStatus::Hit)
+        );
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(
+            cache.get("a", fetch).await,
+            (Ok("v0".to_string()), CacheStatus::Stale)
+        );
+        // Let the background refresh run
+        tokio::task::yield_now().await;
+        assert_eq!(
+            cache.get("a", fetch).await,
+            (Ok("v1".to_string()), CacheStatus::Hit)
+        );
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(
+            cache.get("a", fetch).await,
+            (Ok("v2".to_string()), CacheStatus::Miss)
+        );
+        let failing = || async { Err::<String, _>("down".to_string()) };
+        assert_eq!(cache.get("b", failing).await.1, CacheStatus::Miss);
+        assert_eq!(cache.get("b", fetch).await.1, CacheStatus::Miss);
+    }
+}
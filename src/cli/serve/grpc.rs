@@ -0,0 +1,345 @@
+//! The gRPC API of `serve`, generated from `proto/prom2jsonrs.proto`
+// tonic answers calls and interceptors with its `Status`, large as it is
+#![allow(clippy::result_large_err)]
+
+use super::super::config::Target;
+use super::super::Error;
+use super::access::{admit, Refusal};
+use super::stream::MIN_INTERVAL;
+use super::tenancy::Tenant;
+use super::{listen_address, scrape_data, Server};
+use axum::http::HeaderValue;
+use futures_util::stream::{self, Stream};
+use prom2jsonrs::{Histogram, Metric, MetricLike, MetricType, PrometheusData, Summary};
+use proto::converter_server::{Converter, ConverterServer};
+use proto::series::Kind;
+use proto::{ConvertRequest, WatchRequest};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+mod proto {
+    tonic::include_proto!("prom2jsonrs.v1");
+}
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+/// The kinds of series, as tagged when serialized
+enum Series {
+    Metric(Metric),
+    Summary(Summary),
+    Histogram(Histogram),
+}
+
+impl From<&Box<dyn MetricLike>> for proto::Series {
+    fn from(series: &Box<dyn MetricLike>) -> proto::Series {
+        let series = serde_json::to_value(series)
+            .and_then(serde_json::from_value)
+            .expect("Series serialize with their type");
+        let kind = match series {
+            Series::Metric(m) => Kind::Metric(proto::Metric {
+                labels: m.labels.unwrap_or_default(),
+                value: m.value,
+                timestamp: m.timestamp,
+            }),
+            Series::Summary(s) => Kind::Summary(proto::Summary {
+                labels: s.labels.unwrap_or_default(),
+                quantiles: s.quantiles,
+                count: s.count,
+                sum: s.sum,
+            }),
+            Series::Histogram(h) => Kind::Histogram(proto::Histogram {
+                labels: h.labels.unwrap_or_default(),
+                buckets: h.buckets,
+                count: h.count,
+                sum: h.sum,
+            }),
+        };
+        proto::Series { kind: Some(kind) }
+    }
+}
+
+impl From<&PrometheusData> for proto::PrometheusData {
+    fn from(data: &PrometheusData) -> proto::PrometheusData {
+        let metrics = data
+            .metrics
+            .iter()
+            .map(|family| proto::MetricFamily {
+                metric_type: match family.metric_type {
+                    MetricType::Gauge => proto::MetricType::Gauge,
+                    MetricType::Histogram => proto::MetricType::Histogram,
+                    MetricType::Summary => proto::MetricType::Summary,
+                    MetricType::Untyped => proto::MetricType::Untyped,
+                } as i32,
+                metric_name: family.metric_name.clone(),
+                help: family.help.clone(),
+                data: family.data.iter().map(proto::Series::from).collect(),
+            })
+            .collect();
+        proto::PrometheusData { metrics }
+    }
+}
+
+struct ConverterService {
+    server: Arc<Server>,
+}
+
+impl ConverterService {
+    /// A target of the config by name, or else a url, as the tenant of the call, if any, may
+    /// scrape them
+    fn target<T>(&self, request: &Request<T>, target: &str) -> Result<Target, Status> {
+        if target.is_empty() {
+            return Err(Status::invalid_argument("Missing target"));
+        }
+        let tenant = request.extensions().get::<Arc<Tenant>>().map(Arc::as_ref);
+        // Other tenants' targets are taken for urls, as unknown names are
+        let named = self
+            .server
+            .named_target(target)
+            .filter(|_| self.server.tenancy.may_scrape_named(tenant, target));
+        match named {
+            Some(target) => Ok(target),
+            None => self
+                .server
+                .url_target(tenant, target)
+                .map_err(Status::permission_denied),
+        }
+    }
+}
+
+/// Refuse calls as the HTTP routes do, over the client's rate or without accepted credentials,
+/// adding the tenant a call comes from to its extensions
+fn intercept(server: &Server, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let client = request.remote_addr().map(|address| address.ip());
+    let authorization = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok());
+    match admit(server, client, authorization.as_ref()) {
+        Ok(tenant) => {
+            if let Some(tenant) = tenant {
+                request.extensions_mut().insert(tenant);
+            }
+            Ok(request)
+        }
+        Err(Refusal::TooManyRequests(_)) => Err(Status::resource_exhausted("Too many requests")),
+        Err(Refusal::Unauthorized) => Err(Status::unauthenticated("Unauthorized")),
+    }
+}
+
+/// The period of a Watch call, refusing those that would have the target hammered
+fn watch_interval(interval_ms: u64) -> Result<Duration, String> {
+    match Duration::from_millis(interval_ms) {
+        period if period.is_zero() => Ok(DEFAULT_INTERVAL),
+        period if period < MIN_INTERVAL => {
+            Err(format!("The interval must be at least {:?}", MIN_INTERVAL))
+        }
+        period => Ok(period),
+    }
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::PrometheusData, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Converter for ConverterService {
+    async fn convert(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<proto::PrometheusData>, Status> {
+        let target = self.target(&request, &request.get_ref().target)?;
+        let data = scrape_data(&self.server, &target)
+            .await
+            .map_err(Status::unavailable)?;
+        Ok(Response::new((&data).into()))
+    }
+
+    type WatchStream = WatchStream;
+
+    /// A failed scrape ends the call with `UNAVAILABLE`
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<WatchStream>, Status> {
+        let target = self.target(&request, &request.get_ref().target)?;
+        let request = request.into_inner();
+        let period = watch_interval(request.interval_ms).map_err(Status::invalid_argument)?;
+        let changed_only = request.changed_only;
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let state = (self.server.clone(), target, ticker, None::<PrometheusData>);
+        let updates = stream::unfold(
+            state,
+            move |(server, target, mut ticker, mut previous)| async move {
+                let update = loop {
+                    ticker.tick().await;
+                    match scrape_data(&server, &target).await {
+                        Ok(mut data) => {
+                            if changed_only {
+                                let current = data.clone();
+                                let unchanged = match &previous {
+                                    Some(previous) => {
+                                        data.retain_changed(previous);
+                                        data.metrics.is_empty()
+                                    }
+                                    None => false,
+                                };
+                                previous = Some(current);
+                                if unchanged {
+                                    continue;
+                                }
+                            }
+                            break Ok((&data).into());
+                        }
+                        Err(err) => break Err(Status::unavailable(err)),
+                    }
+                };
+                Some((update, (server, target, ticker, previous)))
+            },
+        );
+        Ok(Response::new(Box::pin(updates)))
+    }
+}
+
+pub async fn serve(listen: &str, server: Arc<Server>) -> Result<(), Error> {
+    let address = listen_address(listen);
+    let address = address
+        .parse()
+        .map_err(|e| format!("Invalid gRPC address {}: {}", address, e))?;
+    info!(%address, "serving gRPC");
+    let service = ConverterService {
+        server: server.clone(),
+    };
+    tonic::transport::Server::builder()
+        .add_service(ConverterServer::with_interceptor(service, move |request| {
+            intercept(&server, request)
+        }))
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::config::{GlobalOpts, ServeConfig, Settings, TenantConfig};
+    use super::super::super::reload::Reloadable;
+    use super::super::super::sink::SinkOpts;
+    use super::super::metrics::Metrics;
+    use super::super::tenancy::Tenancy;
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn proto_conversion_works() {
+        let data = PrometheusData::from_string(
+            "# HELP up Target up.
+# TYPE up gauge
+up{job=\"api\"} 1
+# HELP rpc_duration_seconds RPC latency.
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le=\"1\"} 3
+rpc_duration_seconds_bucket{le=\"+Inf\"} 5
+rpc_duration_seconds_sum 8
+rpc_duration_seconds_count 5
+",
+        );
+        let converted = proto::PrometheusData::from(&data);
+        let up = &converted.metrics[0];
+        assert_eq!(up.metric_type, proto::MetricType::Gauge as i32);
+        match &up.data[0].kind {
+            Some(Kind::Metric(m)) => {
+                assert_eq!(m.labels["job"], "api");
+                assert_eq!(m.value, "1");
+                assert_eq!(m.timestamp, None);
+            }
+            other => panic!("Unexpected series {:?}", other),
+        }
+        let latency = &converted.metrics[1];
+        assert_eq!(latency.metric_type, proto::MetricType::Histogram as i32);
+        match &latency.data[0].kind {
+            Some(Kind::Histogram(h)) => {
+                assert_eq!(h.buckets["+Inf"], "5");
+                assert_eq!(h.count, "5");
+                assert_eq!(h.sum, "8");
+            }
+            other => panic!("Unexpected series {:?}", other),
+        }
+    }
+
+    #[test]
+    fn access_works() {
+        let mut settings = Settings::new(
+            GlobalOpts::from_iter(&["test"]),
+            SinkOpts::from_iter(&["test"]),
+        )
+        .unwrap();
+        for name in ["api", "db"] {
+            settings.config.targets.push(Target {
+                name: Some(name.to_string()),
+                ..Target::from_url(&format!("http://{}:8080/metrics", name))
+            });
+        }
+        let config = ServeConfig {
+            allow: vec!["10.0.0.0/8".to_string()],
+            named_targets_only: false,
+            tenants: vec![TenantConfig {
+                name: "team-a".to_string(),
+                token: Some("a".to_string()),
+                targets: vec!["api".to_string()],
+                ..TenantConfig::default()
+            }],
+        };
+        let server = Arc::new(Server {
+            settings: Reloadable::new(settings),
+            cache: None,
+            metrics: Metrics::default(),
+            ready_requires_targets: false,
+            archive_dir: None,
+            history: None,
+            credentials: None,
+            limiter: None,
+            tenancy: Tenancy::new(&config, &[], false).unwrap(),
+        });
+        let service = ConverterService {
+            server: server.clone(),
+        };
+
+        // Only tenants are let in, with their token
+        let status = intercept(&server, Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let mut call = Request::new(());
+        call.metadata_mut()
+            .insert("authorization", "Bearer a".parse().unwrap());
+        let call = intercept(&server, call).unwrap();
+        let target = |name: &str| service.target(&call, name).map(|t| t.url);
+        assert_eq!(target("api").unwrap(), "http://api:8080/metrics");
+        assert_eq!(
+            target("db").unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            target("http://10.0.0.1/metrics").unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(target("").unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        // Without a tenant, urls must be on the allowed hosts
+        let call = Request::new(());
+        let target = |name: &str| service.target(&call, name).map(|t| t.url);
+        assert_eq!(target("db").unwrap(), "http://db:8080/metrics");
+        assert!(target("http://10.0.0.1/metrics").is_ok());
+        assert_eq!(
+            target("http://169.254.169.254/").unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn watch_interval_works() {
+        assert_eq!(watch_interval(0).unwrap(), DEFAULT_INTERVAL);
+        assert_eq!(watch_interval(5000).unwrap(), Duration::from_secs(5));
+        assert!(watch_interval(1).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! `/history`: the last scrapes of a target kept in memory with `--history`, or the points and
+//! rates of some of its series
+
+use super::super::sink::Scraped;
+use super::{error, json, tenant, Server, TenantExtension};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use prom2jsonrs::Selector;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    /// The name of a target of the config, or a url
+    target: Option<String>,
+    /// A selector, e.g. `http_requests_total{code="500"}`, to answer with the points of the
+    /// series it matches rather than with the documents
+    series: Option<String>,
+}
+
+/// Answer with the recorded scrapes of a target as documents, oldest first, or with the points
+/// of the series matched by `series`, counters with their per-second rate
+pub async fn history(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    Query(params): Query<HistoryParams>,
+) -> Response {
+    let history = match &server.history {
+        Some(history) => history,
+        None => return error(StatusCode::NOT_FOUND, "No history is kept, see --history"),
+    };
+    let target = match params.target {
+        Some(target) => target,
+        None => return error(StatusCode::BAD_REQUEST, "Missing target parameter"),
+    };
+    let tenant = tenant(&extension);
+    // As for the conversions, other tenants' targets are not even acknowledged
+    let target = match server
+        .named_target(&target)
+        .filter(|_| server.tenancy.may_scrape_named(tenant, &target))
+    {
+        Some(named) => named,
+        None => match server.url_target(tenant, &target) {
+            Ok(url) => url,
+            Err(err) => return error(StatusCode::FORBIDDEN, &err),
+        },
+    };
+    let selector = match params.series.as_deref().map(str::parse::<Selector>) {
+        Some(Ok(selector)) => Some(selector),
+        Some(Err(err)) => return error(StatusCode::BAD_REQUEST, &err),
+        None => None,
+    };
+    let history = history.lock().unwrap_or_else(|e| e.into_inner());
+    let name = target.display_name();
+    if history.history(name).next().is_none() {
+        return error(
+            StatusCode::NOT_FOUND,
+            &format!("No scrapes of {} in the history", name),
+        );
+    }
+    let body = match selector {
+        Some(selector) => serde_json::to_string(&history.series(name, &selector)),
+        None => {
+            let documents: Vec<Scraped<_>> = history
+                .history(name)
+                .map(|(scraped_at, document)| Scraped {
+                    scraped_at,
+                    document,
+                    scrape: None,
+                    integrity: None,
+                })
+                .collect();
+            serde_json::to_string(&documents)
+        }
+    };
+    match body {
+        Ok(body) => json(StatusCode::OK, body),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
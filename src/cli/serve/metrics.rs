@@ -0,0 +1,193 @@
+//! The server's own metrics, built with the crate's data model and rendered as exposition text
+
+use super::cache::CacheStatus;
+use prom2jsonrs::{
+    Histogram, Labels, Metric, MetricFamily, MetricLike, MetricType, PrometheusData,
+};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the scrape duration buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ScrapeResult {
+    Success,
+    /// The target could not be fetched
+    Error,
+    /// The target answered exposition text the parser rejected
+    ParseError,
+}
+
+impl ScrapeResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            ScrapeResult::Success => "success",
+            ScrapeResult::Error => "error",
+            ScrapeResult::ParseError => "parse_error",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    /// By handler and status code
+    requests: BTreeMap<(String, u16), u64>,
+    scrapes: BTreeMap<ScrapeResult, u64>,
+    /// Observations at or below each bucket bound (not cumulative yet), then the sum and count
+    duration_buckets: [u64; BUCKETS.len()],
+    duration_sum: f64,
+    duration_count: u64,
+    cache: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Option<Labels> {
+    Some(
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    )
+}
+
+fn counter(name: &str, help: &str, series: Vec<(Option<Labels>, u64)>) -> MetricFamily {
+    MetricFamily {
+        metric_type: MetricType::Gauge,
+        metric_name: name.to_string(),
+        help: help.to_string(),
+        data: series
+            .into_iter()
+            .map(|(labels, value)| {
+                Box::new(Metric {
+                    labels,
+                    value: value.to_string(),
+                    timestamp: None,
+                }) as Box<dyn MetricLike>
+            })
+            .collect(),
+    }
+}
+
+impl Metrics {
+    pub fn request(&self, handler: &str, status: u16) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts
+            .requests
+            .entry((handler.to_string(), status))
+            .or_default() += 1;
+    }
+
+    pub fn scrape(&self, result: ScrapeResult, duration: Duration) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.scrapes.entry(result).or_default() += 1;
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            counts.duration_buckets[i] += 1;
+        }
+        counts.duration_sum += seconds;
+        counts.duration_count += 1;
+    }
+
+    pub fn cache(&self, status: CacheStatus) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .cache
+            .entry(status.as_str())
+            .or_default() += 1;
+    }
+
+    /// The current values, as parsed data
+    pub fn data(&self) -> PrometheusData {
+        let counts = self.counts.lock().unwrap();
+        let mut buckets = Labels::new();
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(counts.duration_buckets) {
+            cumulative += count;
+            buckets.insert(bound.to_string(), cumulative.to_string());
+        }
+        buckets.insert("+Inf".to_string(), counts.duration_count.to_string());
+        let duration = MetricFamily {
+            metric_type: MetricType::Histogram,
+            metric_name: "prom2jsonrs_scrape_duration_seconds".to_string(),
+            help: "Duration of the scrapes of targets, parsing included.".to_string(),
+            data: vec![Box::new(Histogram {
+                labels: None,
+                buckets,
+                count: counts.duration_count.to_string(),
+                sum: counts.duration_sum.to_string(),
+                percentiles: None,
+            })],
+        };
+        PrometheusData {
+            metrics: vec![
+                counter(
+                    "prom2jsonrs_http_requests_total",
+                    "HTTP requests answered, by handler and status code.",
+                    counts
+                        .requests
+                        .iter()
+                        .map(|((handler, code), n)| {
+                            let code = code.to_string();
+                            (labels(&[("handler", handler), ("code", &code)]), *n)
+                        })
+                        .collect(),
+                ),
+                counter(
+                    "prom2jsonrs_scrapes_total",
+                    "Scrapes of targets, by result.",
+                    counts
+                        .scrapes
+                        .iter()
+                        .map(|(result, n)| (labels(&[("result", result.as_str())]), *n))
+                        .collect(),
+                ),
+                duration,
+                counter(
+                    "prom2jsonrs_cache_requests_total",
+                    "Conversions answered through the cache, by hit, stale or miss.",
+                    counts
+                        .cache
+                        .iter()
+                        .map(|(status, n)| (labels(&[("status", status)]), *n))
+                        .collect(),
+                ),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics_exposition_works() {
+        let metrics = Metrics::default();
+        metrics.request("/convert", 200);
+        metrics.request("/convert", 200);
+        metrics.scrape(ScrapeResult::Success, Duration::from_millis(20));
+        metrics.scrape(ScrapeResult::ParseError, Duration::from_secs(30));
+        metrics.cache(CacheStatus::Hit);
+        let text = metrics.data().to_exposition();
+        assert!(
+            text.contains("prom2jsonrs_http_requests_total{code=\"200\",handler=\"/convert\"} 2\n")
+        );
+        assert!(text.contains("prom2jsonrs_scrapes_total{result=\"parse_error\"} 1\n"));
+        assert!(text.contains("prom2jsonrs_scrape_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("prom2jsonrs_scrape_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("prom2jsonrs_cache_requests_total{status=\"hit\"} 1\n"));
+        // What the server exposes parses back
+        let parsed = PrometheusData::from_string(&text);
+        assert_eq!(parsed.samples(), metrics.data().samples());
+    }
+}
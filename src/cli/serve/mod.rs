@@ -0,0 +1,601 @@
+//! `serve`: an HTTP service converting scrapes to JSON on demand
+
+use super::config::{parse_duration, Settings, Target};
+use super::dry_run::describe;
+use super::reload::{on_hangup, Reloadable};
+use super::scraper::CannotParse;
+use super::Error;
+use access::{ClientLimiter, Credentials};
+use axum::extract::{Extension, MatchedPath, Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use cache::{Cache, CacheStatus};
+use futures_util::future::join_all;
+use metrics::{Metrics, ScrapeResult};
+use prom2jsonrs::{PrometheusData, ScrapeHistory};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use structopt::StructOpt;
+use tenancy::{Tenancy, Tenant};
+use tracing::{info, warn};
+
+mod access;
+mod aggregate;
+mod cache;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod history;
+mod metrics;
+mod remote_read;
+mod stream;
+mod tenancy;
+mod ws;
+
+#[derive(StructOpt)]
+pub struct ServeOpts {
+    /// Address to listen on, e.g. `:8080` or `127.0.0.1:8080`
+    #[structopt(long, default_value = ":8080")]
+    listen: String,
+    /// Answer from a cached conversion of the target for this long, e.g. `10s`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    cache_ttl: Option<Duration>,
+    /// Past the TTL, keep answering from the cache for this long while the target is scraped
+    /// again in the background
+    #[structopt(long, parse(try_from_str = parse_duration), requires = "cache-ttl")]
+    stale_while_revalidate: Option<Duration>,
+    /// Report ready on `/readyz` only while every target of the config can be fetched
+    #[structopt(long)]
+    ready_requires_targets: bool,
+    /// Answer Prometheus remote-read queries at `/api/v1/read` from the documents archived in
+    /// this directory by `watch --archive-dir`
+    #[structopt(long)]
+    archive_dir: Option<PathBuf>,
+    /// Keep the last N scrapes of every target in memory, served at `/history?target=...`
+    #[structopt(long)]
+    history: Option<usize>,
+    /// Only answer requests bearing one of the tokens of this file, one per line, as
+    /// `Authorization: Bearer <token>`
+    #[structopt(long)]
+    auth_token_file: Option<PathBuf>,
+    /// Only answer requests with the basic auth credentials of one of the `user:password` lines
+    /// of this file
+    #[structopt(long)]
+    auth_basic_file: Option<PathBuf>,
+    /// Maximum number of requests per second of each client address
+    #[structopt(long)]
+    client_rate_limit: Option<f64>,
+    /// Number of requests a client may make at once before being limited, the rate by default
+    #[structopt(long, requires = "client-rate-limit")]
+    client_burst: Option<u32>,
+    /// Host (`node-1`, `*.svc.cluster.local`), network (`10.0.0.0/8`) or unix socket
+    /// (`unix:///run/app.sock`) the urls of `?target=` may be on, in addition to the
+    /// `serve.allow` of the config. Any host but no socket by default.
+    #[structopt(long, number_of_values = 1)]
+    allow_target: Vec<String>,
+    /// Only scrape the targets of the config, never an arbitrary url
+    #[structopt(long)]
+    named_targets_only: bool,
+    /// Also serve the gRPC API on this address, e.g. `:9090`
+    #[cfg(feature = "grpc")]
+    #[structopt(long)]
+    grpc_listen: Option<String>,
+}
+
+/// The state shared by the handlers
+struct Server {
+    settings: Reloadable,
+    cache: Option<Cache>,
+    metrics: Metrics,
+    ready_requires_targets: bool,
+    archive_dir: Option<PathBuf>,
+    /// The last scrapes of every target, by display name, with `--history`
+    history: Option<Mutex<ScrapeHistory>>,
+    credentials: Option<Credentials>,
+    limiter: Option<ClientLimiter>,
+    tenancy: Tenancy,
+}
+
+/// The tenant a request comes from, added by the access guard
+type TenantExtension = Option<Extension<Arc<Tenant>>>;
+
+fn tenant(extension: &TenantExtension) -> Option<&Tenant> {
+    extension.as_ref().map(|Extension(tenant)| tenant.as_ref())
+}
+
+impl Server {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.get()
+    }
+
+    /// Reload the settings, dropping the conversions cached with the previous ones
+    fn reload(&self) -> Result<(), Error> {
+        self.settings.reload()?;
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    fn named_target(&self, name: &str) -> Option<Target> {
+        self.settings()
+            .config
+            .targets
+            .iter()
+            .find(|t| t.name.as_deref() == Some(name))
+            .cloned()
+    }
+
+    /// The target of a url, unless the tenant, if any, may not scrape it
+    fn url_target(&self, tenant: Option<&Tenant>, url: &str) -> Result<Target, String> {
+        self.tenancy
+            .check_url(&self.settings().config.targets, tenant, url)?;
+        Ok(Target::from_url(url))
+    }
+}
+
+#[derive(Deserialize)]
+struct ConvertParams {
+    target: Option<String>,
+}
+
+/// `:8080` listens on every interface
+fn listen_address(listen: &str) -> String {
+    match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    }
+}
+
+fn json(status: StatusCode, body: String) -> Response {
+    (status, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    json(status, serde_json::json!({ "error": message }).to_string())
+}
+
+/// Scrape, parse and process a target, counting the outcome and recording it in the history
+async fn scrape_data(server: &Server, target: &Target) -> Result<PrometheusData, String> {
+    let start = Instant::now();
+    let (result, data) = match server.settings().scrape(target).await {
+        Ok(data) => (ScrapeResult::Success, Ok(data)),
+        Err(err) if err.is::<CannotParse>() => {
+            warn!(target = target.display_name(), error = %err, "cannot parse the scrape");
+            (ScrapeResult::ParseError, Err(err.to_string()))
+        }
+        Err(err) => {
+            warn!(target = target.display_name(), error = %err, "scrape failed");
+            (ScrapeResult::Error, Err(err.to_string()))
+        }
+    };
+    server.metrics.scrape(result, start.elapsed());
+    if let (Some(history), Ok(data)) = (&server.history, &data) {
+        let mut history = history.lock().unwrap_or_else(|e| e.into_inner());
+        history.record(target.display_name(), SystemTime::now(), data.clone());
+    }
+    data
+}
+
+/// Scrape a target into its rendered JSON document
+async fn scrape(server: Arc<Server>, target: Target) -> Result<String, String> {
+    let data = scrape_data(&server, &target).await?;
+    Ok(server.settings().render(&data))
+}
+
+/// Answer with the JSON document of a target, from the cache if enabled, or a 502 describing
+/// the failure
+async fn convert_target(server: &Arc<Server>, target: &Target) -> Response {
+    let fetch = || scrape(server.clone(), target.clone());
+    let (result, status) = match &server.cache {
+        // Named targets may share a url with other labels or auth
+        Some(cache) => {
+            let key = format!("{} {}", target.display_name(), target.url);
+            cache.get(&key, fetch).await
+        }
+        None => (fetch().await, CacheStatus::Miss),
+    };
+    let mut response = match result {
+        Ok(body) => json(StatusCode::OK, body),
+        Err(err) => error(StatusCode::BAD_GATEWAY, &err),
+    };
+    if server.cache.is_some() {
+        server.metrics.cache(status);
+        response
+            .headers_mut()
+            .insert("x-cache", header::HeaderValue::from_static(status.as_str()));
+    }
+    response
+}
+
+async fn convert(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    Query(params): Query<ConvertParams>,
+) -> Response {
+    let url = match params.target {
+        Some(url) => url,
+        None => return error(StatusCode::BAD_REQUEST, "Missing target parameter"),
+    };
+    match server.url_target(tenant(&extension), &url) {
+        Ok(target) => convert_target(&server, &target).await,
+        Err(err) => error(StatusCode::FORBIDDEN, &err),
+    }
+}
+
+async fn named_target(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    Path(name): Path<String>,
+) -> Response {
+    // Other tenants' targets are not even acknowledged
+    let target = server
+        .named_target(&name)
+        .filter(|_| server.tenancy.may_scrape_named(tenant(&extension), &name));
+    match target {
+        Some(target) => convert_target(&server, &target).await,
+        None => error(
+            StatusCode::NOT_FOUND,
+            &format!("No target named {} in the config", name),
+        ),
+    }
+}
+
+/// The server's own metrics, in Prometheus text exposition format
+async fn self_metrics(State(server): State<Arc<Server>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        server.metrics.data().to_exposition(),
+    )
+        .into_response()
+}
+
+/// Count the requests by route
+async fn count_requests(
+    State(server): State<Arc<Server>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let handler = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("other", |path| path.as_str())
+        .to_string();
+    let response = next.run(request).await;
+    server.metrics.request(&handler, response.status().as_u16());
+    response
+}
+
+/// Liveness: the process answers
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize)]
+struct TargetReadiness<'a> {
+    target: &'a str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Readiness: always once listening, or only while every target of the config can be fetched
+/// with `--ready-requires-targets`, answering `503` with the failing ones otherwise
+async fn readyz(State(server): State<Arc<Server>>) -> Response {
+    if !server.ready_requires_targets {
+        return "ok".into_response();
+    }
+    let settings = &*server.settings();
+    let targets: Vec<TargetReadiness> =
+        join_all(settings.config.targets.iter().map(|target| async move {
+            let result = settings
+                .scraper
+                .fetch_with_auth(&target.url, target.auth.as_ref())
+                .await;
+            TargetReadiness {
+                target: target.display_name(),
+                ok: result.is_ok(),
+                error: result.err().map(|e| describe(e.as_ref())),
+            }
+        }))
+        .await;
+    let ready = targets.iter().all(|t| t.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json(
+        status,
+        serde_json::json!({ "ready": ready, "targets": targets }).to_string(),
+    )
+}
+
+/// Reload the config, answering `500` with the error when it cannot be, the previous one staying
+async fn reload(State(server): State<Arc<Server>>, extension: TenantExtension) -> Response {
+    if extension.is_some() {
+        return error(StatusCode::FORBIDDEN, "Tenants may not reload the config");
+    }
+    match server.reload() {
+        Ok(()) => "ok".into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn router(server: Arc<Server>) -> Router {
+    Router::new()
+        .route("/convert", get(convert))
+        .route("/targets/{name}", get(named_target))
+        .route("/aggregate", get(aggregate::aggregate))
+        .route("/stream", get(stream::stream))
+        .route("/ws", get(ws::ws))
+        .route("/history", get(history::history))
+        .route("/api/v1/read", post(remote_read::read))
+        .route("/metrics", get(self_metrics))
+        .route("/-/reload", post(reload).put(reload))
+        .route_layer(middleware::from_fn_with_state(
+            server.clone(),
+            access::guard,
+        ))
+        // Probes need neither credentials nor a rate
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route_layer(middleware::from_fn_with_state(
+            server.clone(),
+            count_requests,
+        ))
+        .with_state(server)
+}
+
+/// Serve until the process is stopped. `SIGHUP` and `/-/reload` reload the config.
+pub async fn run(opts: ServeOpts, settings: Settings) -> Result<(), Error> {
+    let cache = opts.cache_ttl.map(|ttl| {
+        Cache::new(
+            ttl,
+            opts.stale_while_revalidate
+                .unwrap_or(Duration::from_secs(0)),
+        )
+    });
+    let credentials = Credentials::load(
+        opts.auth_token_file.as_deref(),
+        opts.auth_basic_file.as_deref(),
+    )?;
+    let limiter = opts
+        .client_rate_limit
+        .map(|rate| ClientLimiter::new(rate, opts.client_burst))
+        .transpose()?;
+    let tenancy = Tenancy::new(
+        &settings.config.serve,
+        &opts.allow_target,
+        opts.named_targets_only,
+    )?;
+    let server = Arc::new(Server {
+        settings: Reloadable::new(settings),
+        cache,
+        metrics: Metrics::default(),
+        ready_requires_targets: opts.ready_requires_targets,
+        archive_dir: opts.archive_dir,
+        history: opts.history.map(|n| Mutex::new(ScrapeHistory::new(n))),
+        credentials,
+        limiter,
+        tenancy,
+    });
+    let reloading = server.clone();
+    on_hangup(move || reloading.reload())?;
+    let address = listen_address(&opts.listen);
+    let listener = tokio::net::TcpListener::bind(&address)
+        .await
+        .map_err(|e| format!("Cannot listen on {}: {}", address, e))?;
+    info!(address = %listener.local_addr()?, "serving");
+    let app = router(server.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    let http = async { Ok::<_, Error>(axum::serve(listener, app).await?) };
+    #[cfg(feature = "grpc")]
+    if let Some(listen) = &opts.grpc_listen {
+        tokio::try_join!(http, grpc::serve(listen, server))?;
+        return Ok(());
+    }
+    http.await
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{Config, GlobalOpts};
+    use super::super::scraper::Scraper;
+    use super::super::sink::SinkOpts;
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_works() {
+        assert_eq!(listen_address(":8080"), "0.0.0.0:8080");
+        assert_eq!(listen_address("127.0.0.1:9"), "127.0.0.1:9");
+
+        let mut settings = Settings::new(
+            GlobalOpts::from_iter(&["test"]),
+            SinkOpts::from_iter(&["test"]),
+        )
+        .unwrap();
+        settings
+            .config
+            .targets
+            .push(Target::from_url("http://127.0.0.1:1/metrics"));
+        // Failing on malformed lines rather than skipping them, until the reload below
+        settings.scraper = Scraper::new(&Config::default()).unwrap();
+        let exporter = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let exporter_address = exporter.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut socket, _)) = exporter.accept().await {
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await.unwrap();
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nup{a=\"b\" 2";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(Server {
+            settings: Reloadable::new(settings),
+            cache: None,
+            metrics: Metrics::default(),
+            ready_requires_targets: true,
+            archive_dir: None,
+            history: Some(Mutex::new(ScrapeHistory::new(2))),
+            credentials: None,
+            limiter: Some(ClientLimiter::new(1.0, Some(30)).unwrap()),
+            tenancy: Tenancy::new(&Default::default(), &["127.0.0.0/8".to_string()], false)
+                .unwrap(),
+        });
+        let app = router(server.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("http://{}{}", address, path)).send();
+
+        let response = client
+            .get(format!("http://{}/healthz", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let response = client
+            .get(format!("http://{}/readyz", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .starts_with("{\"ready\":false,\"targets\":[{\"error\":"));
+        let response = client
+            .get(format!("http://{}/convert", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        let response = client
+            .get(format!(
+                "http://{}/convert?target=http://169.254.169.254/",
+                address
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+        let response = get(&format!(
+            "/convert?target=http://{}/metrics",
+            exporter_address
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 502);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("Invalid format up{a=\\\"b\\\" 2"), "{}", body);
+        let response = client
+            .post(format!("http://{}/-/reload", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let response = client
+            .get(format!("http://{}/targets/missing", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        let response = client
+            .get(format!(
+                "http://{}/convert?target=http://127.0.0.1:1/metrics",
+                address
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 502);
+        assert!(response.text().await.unwrap().starts_with("{\"error\":"));
+        for interval in ["0s", "1ms"] {
+            let response = client
+                .get(format!(
+                    "http://{}/stream?interval={}&target=http://127.0.0.1/",
+                    address, interval
+                ))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 400);
+        }
+        let mut response = client
+            .get(format!(
+                "http://{}/stream?target=http://127.0.0.1:1/metrics",
+                address
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let event = response.chunk().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&event).starts_with("event: error\ndata: {\"error\":"));
+        let metrics = client
+            .get(format!("http://{}/metrics", address))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("prom2jsonrs_scrapes_total{result=\"error\"} 2\n"));
+        assert!(metrics.contains("prom2jsonrs_scrapes_total{result=\"parse_error\"} 1\n"));
+        assert!(metrics.contains(
+            "prom2jsonrs_http_requests_total{code=\"404\",handler=\"/targets/{name}\"} 1\n"
+        ));
+
+        let response = get("/history").await.unwrap();
+        assert_eq!(response.status(), 400);
+        let response = get("/history?target=http://127.0.0.1:1/metrics")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        for (requests, time) in [(10, 1_000), (40, 11_000), (70, 21_000)] {
+            let data = PrometheusData::from_string(&format!(
+                "# TYPE requests_total counter\nrequests_total {}\n# TYPE up gauge\nup 1",
+                requests
+            ));
+            let time = SystemTime::UNIX_EPOCH + Duration::from_millis(time);
+            let mut history = server.history.as_ref().unwrap().lock().unwrap();
+            history.record("http://127.0.0.1:1/metrics", time, data);
+        }
+        let documents: serde_json::Value = get("/history?target=http://127.0.0.1:1/metrics")
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(documents.as_array().unwrap().len(), 2);
+        assert_eq!(documents[0]["scraped_at"], "1970-01-01T00:00:11.000Z");
+        let series: serde_json::Value =
+            get("/history?target=http://127.0.0.1:1/metrics&series=requests_total")
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        assert_eq!(
+            series,
+            serde_json::json!([{
+                "name": "requests_total",
+                "labels": {},
+                "points": [[11000, 40.0], [21000, 70.0]],
+                "rate": 3.0,
+            }])
+        );
+        let response = get("/history?target=http://127.0.0.1:1/metrics&series={")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -0,0 +1,282 @@
+//! `/api/v1/read`: the Prometheus remote-read protocol, over the documents archived by `watch`
+
+use super::super::archive;
+use super::{error, Server, TenantExtension};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use prom2jsonrs::{LabelMatcher, MatchOp, Selector};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, PartialEq, Message)]
+struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    queries: Vec<Query>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Query {
+    #[prost(int64, tag = "1")]
+    start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    matchers: Vec<Matcher>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Matcher {
+    /// `EQ`, `NEQ`, `RE` or `NRE`
+    #[prost(int32, tag = "1")]
+    kind: i32,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    results: Vec<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+fn selector(matchers: &[Matcher]) -> Result<Selector, String> {
+    let matchers = matchers
+        .iter()
+        .map(|m| {
+            let op = match m.kind {
+                0 => MatchOp::Equal,
+                1 => MatchOp::NotEqual,
+                2 => MatchOp::RegexMatch,
+                3 => MatchOp::RegexNoMatch,
+                other => return Err(format!("Unknown matcher type {}", other)),
+            };
+            LabelMatcher::new(&m.name, op, &m.value)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Selector {
+        metric: None,
+        matchers,
+    })
+}
+
+fn time(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+/// The numeric samples of the archived documents matching the query, a document's samples being
+/// stamped with the time it was written
+async fn query(dir: &Path, query: &Query, selector: &Selector) -> Result<QueryResult, String> {
+    let documents = archive::read(
+        dir,
+        time(query.start_timestamp_ms),
+        time(query.end_timestamp_ms),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut series: BTreeMap<String, TimeSeries> = BTreeMap::new();
+    for (written, data) in &documents {
+        let timestamp = written
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        for sample in data.samples() {
+            let value = match sample.float_value() {
+                Some(value) if selector.matches(&sample) => value,
+                _ => continue,
+            };
+            series
+                .entry(sample.series_id())
+                .or_insert_with(|| {
+                    let mut labels: Vec<Label> = sample
+                        .labels
+                        .iter()
+                        .map(|(name, value)| Label {
+                            name: name.clone(),
+                            value: value.clone(),
+                        })
+                        .collect();
+                    labels.push(Label {
+                        name: "__name__".to_string(),
+                        value: sample.name.clone(),
+                    });
+                    // Prometheus expects them sorted by name
+                    labels.sort_by(|a, b| a.name.cmp(&b.name));
+                    TimeSeries {
+                        labels,
+                        samples: Vec::new(),
+                    }
+                })
+                .samples
+                .push(Sample { value, timestamp });
+        }
+    }
+    Ok(QueryResult {
+        timeseries: series.into_values().collect(),
+    })
+}
+
+async fn respond(dir: &Path, request: &ReadRequest) -> Result<ReadResponse, Response> {
+    let mut results = Vec::new();
+    for q in &request.queries {
+        let selector = selector(&q.matchers).map_err(|err| error(StatusCode::BAD_REQUEST, &err))?;
+        let result = query(dir, q, &selector)
+            .await
+            .map_err(|err| error(StatusCode::INTERNAL_SERVER_ERROR, &err))?;
+        results.push(result);
+    }
+    Ok(ReadResponse { results })
+}
+
+/// Answer a snappy-compressed protobuf `ReadRequest` with the samples of the archive, in the
+/// `SAMPLES` response type
+pub async fn read(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    body: Bytes,
+) -> Response {
+    // The archive holds any target
+    if extension.is_some() {
+        return error(StatusCode::FORBIDDEN, "Tenants may not read the archive");
+    }
+    let dir = match &server.archive_dir {
+        Some(dir) => dir,
+        None => return error(StatusCode::NOT_FOUND, "Remote read needs --archive-dir"),
+    };
+    let request = snap::raw::Decoder::new()
+        .decompress_vec(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|raw| ReadRequest::decode(&raw[..]).map_err(|e| e.to_string()));
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid remote-read request: {}", err),
+            )
+        }
+    };
+    let response = match respond(dir, &request).await {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+    match snap::raw::Encoder::new().compress_vec(&response.encode_to_vec()) {
+        Ok(body) => (
+            [
+                (header::CONTENT_TYPE, "application/x-protobuf"),
+                (header::CONTENT_ENCODING, "snappy"),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn remote_read_works() {
+        let dir =
+            std::env::temp_dir().join(format!("prom2jsonrs-remote-read-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, value) in [
+            ("2023-11-14T22-13-20.000Z.json", 1),
+            ("2023-11-14T22-13-30.000Z.json", 0),
+            ("2023-11-14T22-13-40.000Z.json", 1),
+        ] {
+            let data = prom2jsonrs::PrometheusData::from_string(&format!(
+                "# HELP up Target up.\n# TYPE up gauge\nup{{job=\"api\"}} {}\nup{{job=\"db\"}} 1\n",
+                value
+            ));
+            std::fs::write(dir.join(name), serde_json::to_string(&data).unwrap()).unwrap();
+        }
+        let request = ReadRequest {
+            queries: vec![Query {
+                start_timestamp_ms: 1_700_000_000_000,
+                end_timestamp_ms: 1_700_000_010_000,
+                matchers: vec![
+                    Matcher {
+                        kind: 0,
+                        name: "__name__".to_string(),
+                        value: "up".to_string(),
+                    },
+                    Matcher {
+                        kind: 2,
+                        name: "job".to_string(),
+                        value: "a.*".to_string(),
+                    },
+                ],
+            }],
+        };
+        let response = respond(&dir, &request).await.ok().unwrap();
+        let series = &response.results[0].timeseries;
+        assert_eq!(series.len(), 1);
+        assert_eq!(
+            series[0].labels,
+            vec![
+                Label {
+                    name: "__name__".to_string(),
+                    value: "up".to_string()
+                },
+                Label {
+                    name: "job".to_string(),
+                    value: "api".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            series[0].samples,
+            vec![
+                Sample {
+                    value: 1.0,
+                    timestamp: 1_700_000_000_000
+                },
+                Sample {
+                    value: 0.0,
+                    timestamp: 1_700_000_010_000
+                },
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
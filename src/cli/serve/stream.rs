@@ -0,0 +1,99 @@
+//! `/stream`: Server-Sent Events carrying the conversions of a target, every interval
+
+use super::super::config::parse_duration;
+use super::{error, scrape_data, tenant, Server, TenantExtension};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream;
+use prom2jsonrs::PrometheusData;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// The shortest interval clients may ask for, so that they cannot have targets hammered
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    target: Option<String>,
+    interval: Option<String>,
+    /// After the first document, send only the differences with the previous scrape
+    #[serde(default)]
+    diff: bool,
+}
+
+/// Scrape the target every interval, sending a `data` event with each document, a `diff` event
+/// with the differences when asked to (skipping scrapes that changed nothing), or an `error`
+/// event when a scrape fails
+pub async fn stream(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    Query(params): Query<StreamParams>,
+) -> Response {
+    let url = match params.target {
+        Some(url) => url,
+        None => return error(StatusCode::BAD_REQUEST, "Missing target parameter"),
+    };
+    let target = match server.url_target(tenant(&extension), &url) {
+        Ok(target) => target,
+        Err(err) => return error(StatusCode::FORBIDDEN, &err),
+    };
+    let period = match params.interval.as_deref().map(parse_duration) {
+        None => DEFAULT_INTERVAL,
+        Some(Ok(period)) if period >= MIN_INTERVAL => period,
+        Some(Ok(_)) => {
+            let message = format!("The interval must be at least {:?}", MIN_INTERVAL);
+            return error(StatusCode::BAD_REQUEST, &message);
+        }
+        Some(Err(err)) => return error(StatusCode::BAD_REQUEST, &err),
+    };
+    let diff = params.diff;
+    let mut ticker = interval(period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let state = (server, target, ticker, None::<PrometheusData>);
+    let events = stream::unfold(
+        state,
+        move |(server, target, mut ticker, mut previous)| async move {
+            let event = loop {
+                ticker.tick().await;
+                match scrape_data(&server, &target).await {
+                    Ok(data) => {
+                        let event = match previous.as_ref().filter(|_| diff) {
+                            Some(previous) => {
+                                let changes = prom2jsonrs::diff(previous, &data);
+                                if changes.is_empty() {
+                                    continue;
+                                }
+                                Event::default()
+                                    .event("diff")
+                                    .data(server.settings().render(&changes))
+                            }
+                            None => Event::default()
+                                .event("data")
+                                .data(server.settings().render(&data)),
+                        };
+                        previous = Some(data);
+                        break event;
+                    }
+                    Err(err) => {
+                        break Event::default()
+                            .event("error")
+                            .data(serde_json::json!({ "error": err }).to_string())
+                    }
+                }
+            };
+            Some((
+                Ok::<_, Infallible>(event),
+                (server, target, ticker, previous),
+            ))
+        },
+    );
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,320 @@
+//! Which targets `serve` may scrape, and for which tenant
+
+use super::super::config::{ServeConfig, Target};
+use super::access::constant_time_eq;
+use axum::http::HeaderValue;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A host name, `*.` domain or network urls may be on, or a unix socket they may be served on
+enum HostPattern {
+    Host(String),
+    /// `.example.com` for `*.example.com`
+    Suffix(String),
+    Network(IpAddr, u8),
+    /// `unix:///path/to.sock`, for any path on the socket
+    Socket(String),
+}
+
+fn is_socket(url: &str) -> bool {
+    url.starts_with("unix://")
+}
+
+fn width(address: IpAddr) -> u8 {
+    if address.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// The first `prefix` bits of an address
+fn network_bits(address: IpAddr, prefix: u8) -> u128 {
+    let bits = match address {
+        IpAddr::V4(v4) => u128::from(u32::from(v4)),
+        IpAddr::V6(v6) => u128::from(v6),
+    };
+    match width(address) - prefix {
+        128 => 0,
+        shift => bits >> shift,
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HostPattern, String> {
+        if is_socket(s) {
+            return Ok(HostPattern::Socket(s.to_string()));
+        }
+        if let Some((address, prefix)) = s.split_once('/') {
+            let invalid = || format!("Invalid network {}", s);
+            let address: IpAddr = address.parse().map_err(|_| invalid())?;
+            let prefix = prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= width(address))
+                .ok_or_else(invalid)?;
+            return Ok(HostPattern::Network(address, prefix));
+        }
+        if let Ok(address) = s.parse::<IpAddr>() {
+            return Ok(HostPattern::Network(address, width(address)));
+        }
+        match s.strip_prefix("*.") {
+            Some(domain) => Ok(HostPattern::Suffix(format!(".{}", domain.to_lowercase()))),
+            None => Ok(HostPattern::Host(s.to_lowercase())),
+        }
+    }
+}
+
+impl HostPattern {
+    /// Whether the host of a url matches. Names are matched as written, never resolved: only
+    /// name patterns match them.
+    fn matches(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        match self {
+            HostPattern::Host(name) => host == *name,
+            HostPattern::Suffix(suffix) => host.ends_with(suffix.as_str()),
+            HostPattern::Network(network, prefix) => {
+                match host.trim_start_matches('[').trim_end_matches(']').parse() {
+                    Ok(address) => {
+                        width(address) == width(*network)
+                            && network_bits(address, *prefix) == network_bits(*network, *prefix)
+                    }
+                    Err(_) => false,
+                }
+            }
+            HostPattern::Socket(_) => false,
+        }
+    }
+
+    /// Whether a `unix://` url is on the socket of the pattern
+    fn matches_socket(&self, url: &str) -> bool {
+        match self {
+            HostPattern::Socket(socket) => url
+                .strip_prefix(socket.as_str())
+                .is_some_and(|path| path.is_empty() || path.starts_with(":/")),
+            _ => false,
+        }
+    }
+}
+
+fn patterns(raw: &[String]) -> Result<Vec<HostPattern>, String> {
+    raw.iter().map(|p| p.parse()).collect()
+}
+
+fn allowed(patterns: &[HostPattern], url: &str) -> bool {
+    if is_socket(url) {
+        return patterns.iter().any(|p| p.matches_socket(url));
+    }
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    match host {
+        Some(host) => patterns.iter().any(|p| p.matches(&host)),
+        None => false,
+    }
+}
+
+/// A client of `serve` limited to some targets
+pub struct Tenant {
+    pub name: String,
+    authorization: String,
+    targets: Vec<String>,
+    allow: Vec<HostPattern>,
+}
+
+/// The targets clients may scrape: the targets of the config and, unless disabled, urls on the
+/// allowed hosts (any by default, but no unix socket). Tenants may only scrape their own targets and urls on their
+/// own allowed hosts.
+pub struct Tenancy {
+    allow: Vec<HostPattern>,
+    named_targets_only: bool,
+    tenants: Vec<Arc<Tenant>>,
+}
+
+impl Tenancy {
+    /// From the `serve` section of the config, plus the hosts allowed on the command line
+    pub fn new(
+        config: &ServeConfig,
+        allow: &[String],
+        named_targets_only: bool,
+    ) -> Result<Tenancy, String> {
+        let tenants = config
+            .tenants
+            .iter()
+            .map(|tenant| {
+                let token = tenant
+                    .token
+                    .as_ref()
+                    .ok_or_else(|| format!("Tenant {} has no token", tenant.name))?;
+                Ok(Arc::new(Tenant {
+                    name: tenant.name.clone(),
+                    authorization: format!("Bearer {}", token),
+                    targets: tenant.targets.clone(),
+                    allow: patterns(&tenant.allow)?,
+                }))
+            })
+            .collect::<Result<_, String>>()?;
+        let mut patterns_allowed = patterns(&config.allow)?;
+        patterns_allowed.extend(patterns(allow)?);
+        Ok(Tenancy {
+            allow: patterns_allowed,
+            named_targets_only: named_targets_only || config.named_targets_only,
+            tenants,
+        })
+    }
+
+    pub fn has_tenants(&self) -> bool {
+        !self.tenants.is_empty()
+    }
+
+    /// The tenant whose token a request bears
+    pub fn tenant(&self, authorization: Option<&HeaderValue>) -> Option<Arc<Tenant>> {
+        let authorization = authorization?.as_bytes();
+        self.tenants
+            .iter()
+            .find(|t| constant_time_eq(t.authorization.as_bytes(), authorization))
+            .cloned()
+    }
+
+    /// Whether a target of the config may be scraped by the tenant, if any
+    pub fn may_scrape_named(&self, tenant: Option<&Tenant>, name: &str) -> bool {
+        tenant.is_none_or(|tenant| tenant.targets.iter().any(|t| t == name))
+    }
+
+    /// Check that a url may be scraped by the tenant, if any
+    pub fn check_url(
+        &self,
+        targets: &[Target],
+        tenant: Option<&Tenant>,
+        url: &str,
+    ) -> Result<(), String> {
+        match tenant {
+            None if targets.iter().any(|t| t.url == url) => Ok(()),
+            None if self.named_targets_only => {
+                Err("Only the targets of the config may be scraped".to_string())
+            }
+            None if allowed(&self.allow, url) => Ok(()),
+            // Local sockets such as Docker's must be allowed explicitly
+            None if self.allow.is_empty() && !is_socket(url) => Ok(()),
+            None => Err(format!("{} is not on an allowed host", url)),
+            Some(tenant) => {
+                let own = targets.iter().any(|t| {
+                    t.url == url
+                        && t.name
+                            .as_deref()
+                            .is_some_and(|name| self.may_scrape_named(Some(tenant), name))
+                });
+                if own || (!self.named_targets_only && allowed(&tenant.allow, url)) {
+                    Ok(())
+                } else {
+                    Err(format!("Tenant {} may not scrape {}", tenant.name, url))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::config::TenantConfig;
+    use super::*;
+
+    #[test]
+    fn tenancy_works() {
+        let pattern = |s: &str| s.parse::<HostPattern>().unwrap();
+        assert!(pattern("10.0.0.0/8").matches("10.1.2.3"));
+        assert!(!pattern("10.0.0.0/8").matches("11.1.2.3"));
+        assert!(pattern("0.0.0.0/0").matches("192.168.0.1"));
+        assert!(!pattern("0.0.0.0/0").matches("::1"));
+        assert!(pattern("fd00::/8").matches("[fd12::1]"));
+        assert!(pattern("*.svc.cluster.local").matches("api.ns.svc.cluster.local"));
+        assert!(!pattern("*.svc.cluster.local").matches("svc.cluster.local"));
+        assert!(pattern("Node-1").matches("node-1"));
+        assert!(!pattern("127.0.0.1").matches("localhost"));
+        assert!("10.0.0.0/33".parse::<HostPattern>().is_err());
+        let socket = pattern("unix:///run/app.sock");
+        assert!(socket.matches_socket("unix:///run/app.sock:/metrics"));
+        assert!(!socket.matches_socket("unix:///run/app.sock2"));
+        assert!(!socket.matches("localhost"));
+
+        let targets = vec![
+            Target {
+                name: Some("api".to_string()),
+                ..Target::from_url("http://api:8080/metrics")
+            },
+            Target {
+                name: Some("db".to_string()),
+                ..Target::from_url("http://db:9187/metrics")
+            },
+        ];
+        let config = ServeConfig {
+            allow: vec!["10.0.0.0/8".to_string()],
+            named_targets_only: false,
+            tenants: vec![TenantConfig {
+                name: "team-a".to_string(),
+                token: Some("a".to_string()),
+                targets: vec!["api".to_string()],
+                allow: vec!["*.team-a.svc".to_string()],
+                ..TenantConfig::default()
+            }],
+        };
+        let tenancy = Tenancy::new(&config, &["node-1".to_string()], false).unwrap();
+        assert!(tenancy
+            .check_url(&targets, None, "http://db:9187/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, None, "http://10.0.0.1/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, None, "http://node-1:9100/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, None, "http://169.254.169.254/")
+            .is_err());
+
+        let tenant = tenancy.tenant(Some(&HeaderValue::from_static("Bearer a")));
+        let tenant = tenant.as_deref();
+        assert_eq!(tenant.map(|t| t.name.as_str()), Some("team-a"));
+        assert!(tenancy
+            .tenant(Some(&HeaderValue::from_static("Bearer b")))
+            .is_none());
+        assert!(tenancy.may_scrape_named(tenant, "api"));
+        assert!(!tenancy.may_scrape_named(tenant, "db"));
+        assert!(tenancy
+            .check_url(&targets, tenant, "http://api:8080/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, tenant, "http://db:9187/metrics")
+            .is_err());
+        assert!(tenancy
+            .check_url(&targets, tenant, "http://x.team-a.svc/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, tenant, "http://10.0.0.1/metrics")
+            .is_err());
+
+        // Any host when none is allowed, but no socket unless allowed
+        let open = Tenancy::new(&ServeConfig::default(), &[], false).unwrap();
+        assert!(open.check_url(&[], None, "http://169.254.169.254/").is_ok());
+        let docker = "unix:///var/run/docker.sock:/containers/json";
+        assert!(open.check_url(&[], None, docker).is_err());
+        let allow = ["unix:///var/run/docker.sock".to_string()];
+        let sockets = Tenancy::new(&ServeConfig::default(), &allow, false).unwrap();
+        assert!(sockets.check_url(&[], None, docker).is_ok());
+        assert!(sockets
+            .check_url(&[], None, "http://10.0.0.1/metrics")
+            .is_err());
+
+        let tenancy = Tenancy::new(&config, &[], true).unwrap();
+        assert!(tenancy
+            .check_url(&targets, None, "http://db:9187/metrics")
+            .is_ok());
+        assert!(tenancy
+            .check_url(&targets, None, "http://10.0.0.1/metrics")
+            .is_err());
+    }
+}
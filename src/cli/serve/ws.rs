@@ -0,0 +1,171 @@
+//! `/ws`: WebSocket subscriptions to the conversions of a target
+
+use super::super::config::{parse_duration, Target};
+use super::stream::MIN_INTERVAL;
+use super::tenancy::Tenant;
+use super::{scrape_data, Server, TenantExtension};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Extension, State};
+use axum::response::Response;
+use prom2jsonrs::{PrometheusData, Selector};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+/// What a client sends to (re)subscribe, e.g.
+/// `{"target": "http://node:9100/metrics", "interval": "5s", "include": ["node_load1"]}`
+struct SubscribeMessage {
+    target: String,
+    interval: Option<String>,
+    #[serde(default)]
+    changed_only: bool,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+struct Subscription {
+    target: Target,
+    period: Duration,
+    /// After the first document, only send the series that changed
+    changed_only: bool,
+    include: Vec<Selector>,
+    exclude: Vec<Selector>,
+}
+
+fn selectors(raw: &[String]) -> Result<Vec<Selector>, String> {
+    raw.iter().map(|s| s.parse()).collect()
+}
+
+impl Subscription {
+    fn parse(message: &str) -> Result<Subscription, String> {
+        let message: SubscribeMessage = serde_json::from_str(message)
+            .map_err(|e| format!("Invalid subscribe message: {}", e))?;
+        let period = match message.interval {
+            Some(interval) => parse_duration(&interval)?,
+            None => DEFAULT_INTERVAL,
+        };
+        if period < MIN_INTERVAL {
+            return Err(format!("The interval must be at least {:?}", MIN_INTERVAL));
+        }
+        Ok(Subscription {
+            target: Target::from_url(&message.target),
+            period,
+            changed_only: message.changed_only,
+            include: selectors(&message.include)?,
+            exclude: selectors(&message.exclude)?,
+        })
+    }
+}
+
+fn error_message(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+pub async fn ws(
+    State(server): State<Arc<Server>>,
+    extension: TenantExtension,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let tenant = extension.map(|Extension(tenant)| tenant);
+    upgrade.on_upgrade(move |socket| session(socket, server, tenant))
+}
+
+/// Wait for a subscribe message, then send the documents of its target every interval until
+/// the client goes away. A new subscribe message replaces the current subscription; invalid or
+/// forbidden ones and failed scrapes are answered with an `{"error"}` message.
+async fn session(mut socket: WebSocket, server: Arc<Server>, tenant: Option<Arc<Tenant>>) {
+    let subscribe = |text: &str| {
+        let subscription = Subscription::parse(text)?;
+        let settings = server.settings();
+        server.tenancy.check_url(
+            &settings.config.targets,
+            tenant.as_deref(),
+            &subscription.target.url,
+        )?;
+        Ok::<_, String>(subscription)
+    };
+    let mut subscription: Option<Subscription> = None;
+    let mut previous: Option<PrometheusData> = None;
+    let mut ticker = interval(DEFAULT_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => match subscribe(&text) {
+                    Ok(subscribed) => {
+                        ticker = interval(subscribed.period);
+                        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                        subscription = Some(subscribed);
+                        previous = None;
+                        continue;
+                    }
+                    Err(err) => error_message(&err),
+                },
+                // Pings are answered by axum itself
+                Some(Ok(_)) => continue,
+                _ => return,
+            },
+            _ = ticker.tick(), if subscription.is_some() => {
+                let subscription = subscription.as_ref().unwrap();
+                match scrape_data(&server, &subscription.target).await {
+                    Ok(mut data) => {
+                        data.filter(&subscription.include, &subscription.exclude);
+                        if subscription.changed_only {
+                            let current = data.clone();
+                            let unchanged = match &previous {
+                                Some(previous) => {
+                                    data.retain_changed(previous);
+                                    data.metrics.is_empty()
+                                }
+                                None => false,
+                            };
+                            previous = Some(current);
+                            if unchanged {
+                                continue;
+                            }
+                        }
+                        server.settings().render(&data)
+                    }
+                    Err(err) => error_message(&err),
+                }
+            }
+        };
+        if socket.send(Message::Text(message.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscription_parsing_works() {
+        let subscription = Subscription::parse(
+            r#"{"target": "http://node:9100/metrics", "interval": "5s", "changed_only": true,
+                "include": ["node_load1", "{job=\"node\"}"]}"#,
+        )
+        .unwrap();
+        assert_eq!(subscription.target.url, "http://node:9100/metrics");
+        assert_eq!(subscription.period, Duration::from_secs(5));
+        assert!(subscription.changed_only);
+        assert_eq!(subscription.include.len(), 2);
+        assert!(subscription.exclude.is_empty());
+
+        let subscription = Subscription::parse(r#"{"target": "http://a/metrics"}"#).unwrap();
+        assert_eq!(subscription.period, DEFAULT_INTERVAL);
+        assert!(Subscription::parse(r#"{"target": "x", "interval": "0s"}"#).is_err());
+        assert!(Subscription::parse(r#"{"target": "x", "interval": "1ms"}"#).is_err());
+        assert!(Subscription::parse(r#"{"target": "x", "include": ["{job"]}"#).is_err());
+        assert!(Subscription::parse("{}")
+            .err()
+            .unwrap()
+            .starts_with("Invalid subscribe message"));
+    }
+}
@@ -0,0 +1,146 @@
+use super::super::compress::Compression;
+use super::super::config::{Config, GcsConfig};
+use super::super::Error;
+use super::{object_key, split_object_uri, with_retries, Failure};
+use gcp_auth::TokenProvider;
+use reqwest::{Client, StatusCode, Url};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Uploads every document to Google Cloud Storage under a timestamped key
+pub struct GcsSink {
+    client: Client,
+    provider: OnceCell<Arc<dyn TokenProvider>>,
+    config: GcsConfig,
+    bucket: String,
+    prefix: String,
+    extension: &'static str,
+    retries: u32,
+}
+
+/// The JSON API media upload url of `name` in `bucket`
+fn upload_url(endpoint: &str, bucket: &str, name: &str) -> Result<Url, Error> {
+    let mut url =
+        Url::parse(endpoint).map_err(|e| format!("Invalid endpoint {}: {}", endpoint, e))?;
+    url.path_segments_mut()
+        .map_err(|_| format!("Invalid endpoint {}", endpoint))?
+        .pop_if_empty()
+        .extend(["upload", "storage", "v1", "b", bucket, "o"]);
+    url.query_pairs_mut()
+        .append_pair("uploadType", "media")
+        .append_pair("name", name);
+    Ok(url)
+}
+
+impl GcsSink {
+    pub fn new(gcs: &GcsConfig, config: &Config) -> Result<GcsSink, Error> {
+        let (bucket, prefix) = split_object_uri(&gcs.uri, "gs")?;
+        let mut builder =
+            Client::builder().user_agent(concat!("prom2jsonrs/", env!("CARGO_PKG_VERSION")));
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(GcsSink {
+            client: builder.build()?,
+            provider: OnceCell::new(),
+            config: gcs.clone(),
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            extension: if gcs.gzip { "json.gz" } else { "json" },
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    /// A bearer token from Application Default Credentials, none for a custom endpoint
+    /// (emulators do not check them)
+    async fn token(&self) -> Result<Option<String>, Failure> {
+        if self.config.endpoint.is_some() {
+            return Ok(None);
+        }
+        let provider = self
+            .provider
+            .get_or_try_init(gcp_auth::provider)
+            .await
+            .map_err(|e| Failure::Permanent(format!("No GCP credentials: {}", e).into()))?;
+        let token = provider
+            .token(&[SCOPE])
+            .await
+            .map_err(|e| Failure::Transient(format!("Cannot get GCP token: {}", e).into()))?;
+        Ok(Some(token.as_str().to_string()))
+    }
+
+    async fn attempt(&self, url: &Url, body: &[u8]) -> Result<(), Failure> {
+        let content_type = if self.config.gzip {
+            "application/gzip"
+        } else {
+            "application/json"
+        };
+        let mut request = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", content_type)
+            .body(body.to_vec());
+        if let Some(token) = self.token().await? {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        let err = format!(
+            "upload to gs://{} returned {}: {}",
+            self.bucket,
+            status,
+            message.trim()
+        )
+        .into();
+        if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+        {
+            Err(Failure::Transient(err))
+        } else {
+            Err(Failure::Permanent(err))
+        }
+    }
+
+    /// Upload the document under a key named after the current time
+    pub async fn send(&self, body: &str) -> Result<(), Error> {
+        let name = object_key(&self.prefix, SystemTime::now(), self.extension);
+        let endpoint = self
+            .config
+            .endpoint
+            .as_deref()
+            .unwrap_or("https://storage.googleapis.com");
+        let url = upload_url(endpoint, &self.bucket, &name)?;
+        let body = if self.config.gzip {
+            Compression::Gzip.compress(body.as_bytes())?
+        } else {
+            body.as_bytes().to_vec()
+        };
+        with_retries("gcs", self.retries, || self.attempt(&url, &body)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upload_url_works() {
+        assert_eq!(
+            upload_url("https://storage.googleapis.com", "archive", "scrapes/a b.json")
+                .unwrap()
+                .as_str(),
+            "https://storage.googleapis.com/upload/storage/v1/b/archive/o?uploadType=media&name=scrapes%2Fa+b.json"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use super::super::config::{Auth, Config, PostConfig};
+use super::super::scraper::authenticate;
+use super::super::Error;
+use super::{with_retries, Failure};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+
+/// POSTs every document to a webhook
+pub struct HttpSink {
+    client: Client,
+    url: String,
+    auth: Option<Auth>,
+    retries: u32,
+}
+
+impl HttpSink {
+    pub fn new(post: &PostConfig, config: &Config) -> Result<HttpSink, Error> {
+        if post.url.is_empty() {
+            return Err("post needs a url".into());
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(post.content_type.as_deref().unwrap_or("application/json"))?,
+        );
+        for (name, value) in &post.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let mut builder = Client::builder().default_headers(headers);
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(HttpSink {
+            client: builder.build()?,
+            url: post.url.clone(),
+            auth: post.auth.clone(),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, body: &str) -> Result<(), Failure> {
+        let request = self.client.post(&self.url).body(body.to_string());
+        let response = authenticate(request, self.auth.as_ref())
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let err = format!("POST {} returned {}", self.url, status).into();
+        if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+        {
+            Err(Failure::Transient(err))
+        } else {
+            Err(Failure::Permanent(err))
+        }
+    }
+
+    pub async fn send(&self, body: &str) -> Result<(), Error> {
+        with_retries(&self.url, self.retries, || self.attempt(body)).await
+    }
+}
@@ -0,0 +1,499 @@
+//! Destinations of the converted documents, other than stdout
+
+use super::config::{
+    parse_duration, parse_header, Config, GcsConfig, NatsConfig, NewRelicConfig, PostConfig,
+    PushConfig, PushgatewayConfig, RedisConfig, S3Config,
+};
+#[cfg(feature = "otlp")]
+use super::config::{OtlpConfig, OtlpProtocol};
+use super::integrity::Integrity;
+use super::scraper::ScrapeMetadata;
+use super::Error;
+use prom2jsonrs::PrometheusData;
+use serde::{Serialize, Serializer};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+use tracing::warn;
+
+pub mod gcs;
+pub mod http;
+pub mod nats;
+pub mod newrelic;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod pushgateway;
+pub mod redis;
+pub mod remote_write;
+pub mod s3;
+
+#[derive(StructOpt, Clone)]
+// Sink options, shared by every subcommand and overriding the config file
+pub struct SinkOpts {
+    /// POST the converted document to this url instead of printing it
+    #[structopt(long, global = true)]
+    post_to: Option<String>,
+    /// Extra `Name: value` header sent with every POST
+    #[structopt(long = "post-header", global = true, number_of_values = 1)]
+    post_headers: Vec<String>,
+    /// Bearer token sent with every POST
+    #[structopt(long, global = true)]
+    post_bearer_token: Option<String>,
+    /// Content type of the POSTed document (defaults to `application/json`)
+    #[structopt(long, global = true)]
+    post_content_type: Option<String>,
+    /// Push every scrape to this Prometheus remote-write endpoint (e.g. Mimir, Thanos receive
+    /// or VictoriaMetrics) instead of printing it
+    #[structopt(long, global = true)]
+    remote_write_url: Option<String>,
+    /// Push every scrape, after filtering and relabeling, to this Pushgateway
+    #[structopt(long, global = true)]
+    pushgateway_url: Option<String>,
+    /// Job of the Pushgateway grouping key (defaults to `prom2jsonrs`)
+    #[structopt(long, global = true)]
+    pushgateway_job: Option<String>,
+    /// Instance of the Pushgateway grouping key
+    #[structopt(long, global = true)]
+    pushgateway_instance: Option<String>,
+    /// Send every scrape to the New Relic Metric API with this license or insert key
+    #[structopt(long, global = true)]
+    newrelic_api_key: Option<String>,
+    /// New Relic Metric API endpoint (defaults to the US one)
+    #[structopt(long, global = true)]
+    newrelic_url: Option<String>,
+    /// Keep the latest document of every target in Redis, e.g. `redis://localhost:6379`
+    #[structopt(long, global = true)]
+    redis_url: Option<String>,
+    /// Key of the documents, `{target}` standing for the name of the target (defaults to
+    /// `prom2jsonrs:{target}`, unless only publishing with `--redis-channel`)
+    #[structopt(long, global = true)]
+    redis_key: Option<String>,
+    /// Expiry of the keys, e.g. `5m`
+    #[structopt(long, global = true, parse(try_from_str = parse_duration))]
+    redis_ttl: Option<Duration>,
+    /// Publish the documents on this channel, `{target}` as in `--redis-key`
+    #[structopt(long, global = true)]
+    redis_channel: Option<String>,
+    /// Publish every family of every scrape to this NATS server, e.g. `nats://localhost:4222`
+    #[structopt(long, global = true, requires = "nats-subject")]
+    nats_url: Option<String>,
+    /// Subject of the messages, `{family}` standing for the name of the family
+    #[structopt(long, global = true)]
+    nats_subject: Option<String>,
+    /// Publish to JetStream, waiting for the stream to acknowledge every message
+    #[structopt(long, global = true)]
+    nats_jetstream: bool,
+    /// Export every scrape as OTLP metrics to this OpenTelemetry collector
+    #[cfg(feature = "otlp")]
+    #[structopt(long, global = true)]
+    otlp_url: Option<String>,
+    /// `http` (protobuf POSTed to the url, the default) or `grpc`
+    #[cfg(feature = "otlp")]
+    #[structopt(long, global = true)]
+    otlp_protocol: Option<OtlpProtocol>,
+    /// Upload every document to `s3://bucket/prefix/`, under a key named after the upload time
+    #[structopt(long, global = true)]
+    s3_uri: Option<String>,
+    /// Gzip the documents uploaded to S3
+    #[structopt(long, global = true)]
+    s3_gzip: bool,
+    /// Upload every document to `gs://bucket/prefix/`, under a key named after the upload time
+    #[structopt(long, global = true)]
+    gcs_uri: Option<String>,
+    /// Gzip the documents uploaded to GCS
+    #[structopt(long, global = true)]
+    gcs_gzip: bool,
+    /// Number of retries of a failed push, with exponential backoff (defaults to 3)
+    #[structopt(long, global = true)]
+    retries: Option<u32>,
+}
+
+impl SinkOpts {
+    /// Fold the command line into the config
+    pub fn apply(self, config: &mut Config) -> Result<(), Error> {
+        if let Some(url) = self.post_to {
+            config.post.get_or_insert_with(PostConfig::default).url = url;
+        }
+        if let Some(post) = &mut config.post {
+            for header in &self.post_headers {
+                let (name, value) = parse_header(header)?;
+                post.headers.insert(name, value);
+            }
+            if let Some(token) = self.post_bearer_token {
+                post.auth.get_or_insert_with(Default::default).bearer_token = Some(token);
+            }
+            if self.post_content_type.is_some() {
+                post.content_type = self.post_content_type;
+            }
+        }
+        if let Some(url) = self.remote_write_url {
+            config
+                .remote_write
+                .get_or_insert_with(PushConfig::default)
+                .url = url;
+        }
+        if let Some(url) = self.pushgateway_url {
+            config
+                .pushgateway
+                .get_or_insert_with(PushgatewayConfig::default)
+                .url = url;
+        }
+        if let Some(pushgateway) = &mut config.pushgateway {
+            if self.pushgateway_job.is_some() {
+                pushgateway.job = self.pushgateway_job;
+            }
+            if self.pushgateway_instance.is_some() {
+                pushgateway.instance = self.pushgateway_instance;
+            }
+        }
+        if let Some(key) = self.newrelic_api_key {
+            config
+                .newrelic
+                .get_or_insert_with(NewRelicConfig::default)
+                .api_key = key;
+        }
+        if let (Some(newrelic), Some(url)) = (&mut config.newrelic, self.newrelic_url) {
+            newrelic.url = Some(url);
+        }
+        if let Some(url) = self.redis_url {
+            config.redis.get_or_insert_with(RedisConfig::default).url = url;
+        }
+        if let Some(redis) = &mut config.redis {
+            if self.redis_key.is_some() {
+                redis.key = self.redis_key;
+            }
+            if self.redis_ttl.is_some() {
+                redis.ttl = self.redis_ttl;
+            }
+            if self.redis_channel.is_some() {
+                redis.channel = self.redis_channel;
+            }
+            if redis.key.is_none() && (redis.channel.is_none() || redis.ttl.is_some()) {
+                redis.key = Some("prom2jsonrs:{target}".to_string());
+            }
+        }
+        if let Some(url) = self.nats_url {
+            config.nats.get_or_insert_with(NatsConfig::default).url = url;
+        }
+        if let Some(nats) = &mut config.nats {
+            if let Some(subject) = self.nats_subject {
+                nats.subject = subject;
+            }
+            nats.jetstream |= self.nats_jetstream;
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(url) = self.otlp_url {
+            config.otlp.get_or_insert_with(OtlpConfig::default).url = url;
+        }
+        #[cfg(feature = "otlp")]
+        if let (Some(otlp), Some(protocol)) = (&mut config.otlp, self.otlp_protocol) {
+            otlp.protocol = protocol;
+        }
+        if let Some(uri) = self.s3_uri {
+            config.s3.get_or_insert_with(S3Config::default).uri = uri;
+        }
+        if let Some(s3) = &mut config.s3 {
+            s3.gzip |= self.s3_gzip;
+        }
+        if let Some(uri) = self.gcs_uri {
+            config.gcs.get_or_insert_with(GcsConfig::default).uri = uri;
+        }
+        if let Some(gcs) = &mut config.gcs {
+            gcs.gzip |= self.gcs_gzip;
+        }
+        if self.retries.is_some() {
+            config.retries = self.retries;
+        }
+        Ok(())
+    }
+}
+
+/// A part of a document, e.g. the scrape of one target
+pub struct Section<'a> {
+    pub title: Option<&'a str>,
+    pub data: Option<&'a PrometheusData>,
+    pub error: Option<&'a str>,
+    pub scraped_at: Option<SystemTime>,
+}
+
+/// A document the sinks can deliver: rendered as a whole, or as the parsed data it holds
+pub trait Document: Serialize {
+    fn data(&self) -> Vec<&PrometheusData>;
+
+    /// When the data was scraped (or read), if the document knows
+    fn scraped_at(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// The parts of the document, for human readable summaries and row outputs
+    fn sections(&self) -> Vec<Section<'_>> {
+        self.data()
+            .into_iter()
+            .map(|data| Section {
+                title: None,
+                data: Some(data),
+                error: None,
+                scraped_at: self.scraped_at(),
+            })
+            .collect()
+    }
+}
+
+/// A time as RFC 3339 with milliseconds, e.g. `2024-01-01T00:00:00.123Z`
+pub fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+#[derive(Serialize)]
+/// A document with the time its data was scraped (or read) at, in a `scraped_at` field
+pub struct Scraped<D> {
+    #[serde(serialize_with = "serialize_time")]
+    pub scraped_at: SystemTime,
+    /// With `--scrape-metadata`, for documents of a single target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<ScrapeMetadata>,
+    #[serde(flatten)]
+    pub document: D,
+    /// With `--checksum`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<Integrity>,
+}
+
+impl<D: Document> Document for Scraped<D> {
+    fn data(&self) -> Vec<&PrometheusData> {
+        self.document.data()
+    }
+
+    fn scraped_at(&self) -> Option<SystemTime> {
+        Some(self.scraped_at)
+    }
+
+    fn sections(&self) -> Vec<Section<'_>> {
+        let mut sections = self.document.sections();
+        for section in &mut sections {
+            section.scraped_at.get_or_insert(self.scraped_at);
+        }
+        sections
+    }
+}
+
+impl Document for PrometheusData {
+    fn data(&self) -> Vec<&PrometheusData> {
+        vec![self]
+    }
+}
+
+impl<D: Document> Document for Vec<D> {
+    fn data(&self) -> Vec<&PrometheusData> {
+        self.iter().flat_map(|d| d.data()).collect()
+    }
+
+    fn sections(&self) -> Vec<Section<'_>> {
+        self.iter().flat_map(|d| d.sections()).collect()
+    }
+}
+
+/// Where converted documents go
+pub enum Sink {
+    Http(http::HttpSink),
+    RemoteWrite(remote_write::RemoteWriteSink),
+    Pushgateway(pushgateway::PushgatewaySink),
+    NewRelic(newrelic::NewRelicSink),
+    Redis(redis::RedisSink),
+    Nats(nats::NatsSink),
+    #[cfg(feature = "otlp")]
+    Otlp(otlp::OtlpSink),
+    S3(s3::S3Sink),
+    Gcs(gcs::GcsSink),
+}
+
+impl Sink {
+    /// The sinks of the config, none meaning stdout
+    pub fn from_config(config: &Config) -> Result<Vec<Sink>, Error> {
+        let mut sinks = Vec::new();
+        if let Some(post) = &config.post {
+            sinks.push(Sink::Http(http::HttpSink::new(post, config)?));
+        }
+        if let Some(remote_write) = &config.remote_write {
+            sinks.push(Sink::RemoteWrite(remote_write::RemoteWriteSink::new(
+                remote_write,
+                config,
+            )?));
+        }
+        if let Some(pushgateway) = &config.pushgateway {
+            sinks.push(Sink::Pushgateway(pushgateway::PushgatewaySink::new(
+                pushgateway,
+                config,
+            )?));
+        }
+        if let Some(newrelic) = &config.newrelic {
+            sinks.push(Sink::NewRelic(newrelic::NewRelicSink::new(
+                newrelic, config,
+            )?));
+        }
+        if let Some(redis) = &config.redis {
+            sinks.push(Sink::Redis(redis::RedisSink::new(redis, config)?));
+        }
+        if let Some(nats) = &config.nats {
+            sinks.push(Sink::Nats(nats::NatsSink::new(nats, config)?));
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = &config.otlp {
+            sinks.push(Sink::Otlp(otlp::OtlpSink::new(otlp, config)?));
+        }
+        if let Some(s3) = &config.s3 {
+            sinks.push(Sink::S3(s3::S3Sink::new(s3, config)?));
+        }
+        if let Some(gcs) = &config.gcs {
+            sinks.push(Sink::Gcs(gcs::GcsSink::new(gcs, config)?));
+        }
+        Ok(sinks)
+    }
+
+    /// Deliver a document, given both rendered and as the data and parts it holds
+    pub async fn send(
+        &self,
+        body: &str,
+        data: &[&PrometheusData],
+        sections: &[Section<'_>],
+    ) -> Result<(), Error> {
+        match self {
+            Sink::Http(sink) => sink.send(body).await,
+            Sink::RemoteWrite(sink) => sink.send(data).await,
+            Sink::Pushgateway(sink) => sink.send(data).await,
+            Sink::NewRelic(sink) => sink.send(data).await,
+            Sink::Redis(sink) => sink.send(body, sections).await,
+            Sink::Nats(sink) => sink.send(sections).await,
+            #[cfg(feature = "otlp")]
+            Sink::Otlp(sink) => sink.send(data).await,
+            Sink::S3(sink) => sink.send(body).await,
+            Sink::Gcs(sink) => sink.send(body).await,
+        }
+    }
+}
+
+/// The outcome of a failed delivery attempt
+pub enum Failure {
+    /// Worth trying again, e.g. a connection error or a 5xx response
+    Transient(Error),
+    /// Trying again will not help, e.g. a 4xx response
+    Permanent(Error),
+}
+
+/// Run `attempt` until it succeeds, at most `retries` more times with exponential backoff
+/// starting at 500ms
+pub async fn with_retries<F, Fut>(what: &str, retries: u32, mut attempt: F) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Failure>>,
+{
+    let mut backoff = Duration::from_millis(500);
+    for retry in 0.. {
+        match attempt().await {
+            Ok(()) => return Ok(()),
+            Err(Failure::Permanent(err)) => return Err(err),
+            Err(Failure::Transient(err)) if retry >= retries => return Err(err),
+            Err(Failure::Transient(err)) => {
+                warn!(sink = what, error = %err, retry = retry + 1, "push failed, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+    unreachable!()
+}
+
+/// Split a `<scheme>://bucket/prefix/` object store uri into the bucket and the key prefix
+pub fn split_object_uri<'a>(uri: &'a str, scheme: &str) -> Result<(&'a str, &'a str), String> {
+    let rest = uri
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .ok_or_else(|| format!("Not a {}:// uri: {}", scheme, uri))?;
+    let (bucket, prefix) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if bucket.is_empty() {
+        return Err(format!("Missing bucket in {}", uri));
+    }
+    Ok((bucket, prefix.trim_start_matches('/')))
+}
+
+/// The object key of a document uploaded at `time`, e.g. `prefix/2024-01-01T00:00:00.123Z.json`
+pub fn object_key(prefix: &str, time: SystemTime, extension: &str) -> String {
+    format!(
+        "{}{}.{}",
+        prefix,
+        humantime::format_rfc3339_millis(time),
+        extension
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_work() {
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("test", 3, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(Failure::Transient("down".into())),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("test", 3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Failure::Permanent("bad request".into()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let result = with_retries("test", 2, || async {
+            Err(Failure::Transient("down".into()))
+        })
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn object_keys_work() {
+        assert_eq!(
+            split_object_uri("s3://archive/scrapes/api/", "s3").unwrap(),
+            ("archive", "scrapes/api/")
+        );
+        assert_eq!(
+            split_object_uri("gs://archive", "gs").unwrap(),
+            ("archive", "")
+        );
+        assert!(split_object_uri("s3:///scrapes", "s3").is_err());
+        assert!(split_object_uri("https://archive", "s3").is_err());
+        assert!(split_object_uri("s3://archive", "gs").is_err());
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            object_key("scrapes/", time, "json.gz"),
+            "scrapes/2023-11-14T22:13:20.123Z.json.gz"
+        );
+    }
+    #[test]
+    fn scraped_documents_work() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let document = Scraped {
+            scraped_at: time,
+            scrape: None,
+            document: PrometheusData::from_string("# TYPE up gauge\nup 1"),
+            integrity: None,
+        };
+        let json = serde_json::to_value(&document).unwrap();
+        assert_eq!(json["scraped_at"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(json["metrics"][0]["metric_name"], "up");
+        let documents = vec![document];
+        assert_eq!(documents.sections()[0].scraped_at, Some(time));
+    }
+}
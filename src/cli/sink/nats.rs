@@ -0,0 +1,172 @@
+use super::super::config::{Config, NatsConfig};
+use super::super::Error;
+use super::{with_retries, Failure, Section};
+use async_nats::{Client, ConnectOptions, HeaderMap};
+use std::time::UNIX_EPOCH;
+use tokio::sync::Mutex;
+
+/// Publishes every family of every scrape to NATS, as one NDJSON line per message
+pub struct NatsSink {
+    connection: Mutex<Option<Client>>,
+    config: NatsConfig,
+    retries: u32,
+}
+
+/// A message to publish
+struct Message {
+    subject: String,
+    headers: HeaderMap,
+    payload: String,
+}
+
+/// The messages of a delivery: one per family of every part, its JSON on a line of its own,
+/// with the name of the target in a `Target` header. JetStream deduplicates the retries of a
+/// delivery by their `Nats-Msg-Id`, made of the target, family and scrape time.
+fn messages(subject: &str, sections: &[Section<'_>]) -> Result<Vec<Message>, Error> {
+    let mut messages = Vec::new();
+    for section in sections {
+        let data = match section.data {
+            Some(data) => data,
+            None => continue,
+        };
+        let time = section
+            .scraped_at
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis());
+        for family in &data.metrics {
+            let mut headers = HeaderMap::new();
+            if let Some(title) = section.title {
+                headers.insert("Target", title);
+            }
+            let id = format!(
+                "{}/{}/{}",
+                section.title.unwrap_or_default(),
+                family.metric_name,
+                time
+            );
+            headers.insert("Nats-Msg-Id", id.as_str());
+            messages.push(Message {
+                subject: subject.replace("{family}", &family.metric_name),
+                headers,
+                payload: serde_json::to_string(family)? + "\n",
+            });
+        }
+    }
+    Ok(messages)
+}
+
+impl NatsSink {
+    pub fn new(nats: &NatsConfig, config: &Config) -> Result<NatsSink, Error> {
+        if nats.url.is_empty() || nats.subject.is_empty() {
+            return Err("nats needs a url and a subject".into());
+        }
+        Ok(NatsSink {
+            connection: Mutex::new(None),
+            config: nats.clone(),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    /// The client, connecting on first use. It reconnects by itself afterwards.
+    async fn client(&self) -> Result<Client, Error> {
+        let mut connection = self.connection.lock().await;
+        if let Some(client) = connection.as_ref() {
+            return Ok(client.clone());
+        }
+        let mut options = ConnectOptions::new().name("prom2jsonrs");
+        if let Some(token) = &self.config.token {
+            options = options.token(token.clone());
+        }
+        if let Some(path) = &self.config.credentials_file {
+            options = options
+                .credentials_file(path)
+                .await
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        }
+        let servers: Vec<&str> = self.config.url.split(',').map(str::trim).collect();
+        let client = options.connect(servers.as_slice()).await?;
+        Ok(connection.insert(client).clone())
+    }
+
+    async fn attempt(&self, messages: &[Message]) -> Result<(), Failure> {
+        let failed =
+            |e: Error| Failure::Transient(format!("nats {} failed: {}", self.config.url, e).into());
+        let client = self.client().await.map_err(failed)?;
+        if self.config.jetstream {
+            let jetstream = async_nats::jetstream::new(client);
+            let mut acks = Vec::new();
+            for m in messages {
+                let ack = jetstream
+                    .publish_with_headers(
+                        m.subject.clone(),
+                        m.headers.clone(),
+                        m.payload.clone().into(),
+                    )
+                    .await
+                    .map_err(|e| failed(e.into()))?;
+                acks.push(ack);
+            }
+            for ack in acks {
+                ack.await.map_err(|e| failed(e.into()))?;
+            }
+        } else {
+            for m in messages {
+                client
+                    .publish_with_headers(
+                        m.subject.clone(),
+                        m.headers.clone(),
+                        m.payload.clone().into(),
+                    )
+                    .await
+                    .map_err(|e| failed(e.into()))?;
+            }
+            client.flush().await.map_err(|e| failed(e.into()))?;
+        }
+        Ok(())
+    }
+
+    /// Publish the families of every scrape of a delivery
+    pub async fn send(&self, sections: &[Section<'_>]) -> Result<(), Error> {
+        let messages = messages(&self.config.subject, sections)?;
+        if messages.is_empty() {
+            return Ok(());
+        }
+        with_retries(&self.config.url, self.retries, || self.attempt(&messages)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prom2jsonrs::PrometheusData;
+    use std::time::Duration;
+
+    #[test]
+    fn messages_work() {
+        let data = PrometheusData::from_string("# TYPE up gauge\nup 1\n# TYPE load gauge\nload 2");
+        let section = Section {
+            title: Some("api"),
+            data: Some(&data),
+            error: None,
+            scraped_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        };
+        let failed = Section {
+            title: Some("db"),
+            data: None,
+            error: Some("down"),
+            scraped_at: None,
+        };
+        let messages = messages("metrics.{family}", &[section, failed]).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].subject, "metrics.up");
+        assert_eq!(messages[1].subject, "metrics.load");
+        assert!(messages[0].payload.ends_with("}\n"));
+        let family: serde_json::Value = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(family["metric_name"], "up");
+        assert_eq!(messages[0].headers.get("Target").unwrap().as_str(), "api");
+        assert_eq!(
+            messages[1].headers.get("Nats-Msg-Id").unwrap().as_str(),
+            "api/load/1700000000123"
+        );
+    }
+}
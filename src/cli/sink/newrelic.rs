@@ -0,0 +1,96 @@
+use super::super::config::{Config, NewRelicConfig};
+use super::super::Error;
+use super::{with_retries, Failure};
+use prom2jsonrs::{newrelic_payload, CounterState, Labels, PrometheusData};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_URL: &str = "https://metric-api.newrelic.com/metric/v1";
+
+/// Sends every scrape to the New Relic Metric API, counters and histograms as the increase since
+/// the previous scrape
+pub struct NewRelicSink {
+    client: Client,
+    url: String,
+    attributes: Labels,
+    state: Mutex<CounterState>,
+    retries: u32,
+}
+
+impl NewRelicSink {
+    pub fn new(newrelic: &NewRelicConfig, config: &Config) -> Result<NewRelicSink, Error> {
+        if newrelic.api_key.is_empty() {
+            return Err("newrelic needs an api_key".into());
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+        let mut key = HeaderValue::from_str(&newrelic.api_key)?;
+        key.set_sensitive(true);
+        headers.insert("Api-Key", key);
+        for (name, value) in &newrelic.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .user_agent(concat!("prom2jsonrs/", env!("CARGO_PKG_VERSION")));
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(NewRelicSink {
+            client: builder.build()?,
+            url: newrelic.url.as_deref().unwrap_or(DEFAULT_URL).to_string(),
+            attributes: newrelic.attributes.clone(),
+            state: Mutex::new(CounterState::new()),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, body: &str) -> Result<(), Failure> {
+        let response = self
+            .client
+            .post(&self.url)
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        let err = format!(
+            "newrelic push to {} returned {}: {}",
+            self.url,
+            status,
+            message.trim()
+        )
+        .into();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(err))
+        } else {
+            Err(Failure::Permanent(err))
+        }
+    }
+
+    /// Send the metrics of every scrape of the document, stamped with the current time
+    pub async fn send(&self, data: &[&PrometheusData]) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let payload = {
+            let mut state = self.state.lock().unwrap();
+            newrelic_payload(data, &mut state, "newrelic", now, &self.attributes)
+        };
+        if payload[0]["metrics"]
+            .as_array()
+            .is_some_and(|m| m.is_empty())
+        {
+            return Ok(());
+        }
+        let body = payload.to_string();
+        with_retries(&self.url, self.retries, || self.attempt(&body)).await
+    }
+}
@@ -0,0 +1,214 @@
+use super::super::config::{Config, OtlpConfig, OtlpProtocol};
+use super::super::Error;
+use super::{with_retries, Failure};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use prom2jsonrs::{otlp_metrics_request, ExportMetricsServiceRequest, PrometheusData};
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::metadata::{MetadataKey, MetadataMap};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+
+/// How requests reach the collector
+enum Transport {
+    Http(Client),
+    Grpc(Box<GrpcClient>),
+}
+
+struct GrpcClient {
+    client: MetricsServiceClient<Channel>,
+    metadata: MetadataMap,
+}
+
+/// Exports every scrape to an OpenTelemetry collector as OTLP metrics
+pub struct OtlpSink {
+    transport: Transport,
+    url: String,
+    resource: HashMap<String, String>,
+    retries: u32,
+}
+
+impl OtlpSink {
+    pub fn new(otlp: &OtlpConfig, config: &Config) -> Result<OtlpSink, Error> {
+        if otlp.url.is_empty() {
+            return Err("otlp needs a url".into());
+        }
+        let transport = match otlp.protocol {
+            OtlpProtocol::Http => {
+                let mut headers = HeaderMap::new();
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-protobuf"),
+                );
+                for (name, value) in &otlp.headers {
+                    headers.insert(
+                        HeaderName::from_bytes(name.as_bytes())?,
+                        HeaderValue::from_str(value)?,
+                    );
+                }
+                let mut builder = Client::builder()
+                    .default_headers(headers)
+                    .user_agent(concat!("prom2jsonrs/", env!("CARGO_PKG_VERSION")));
+                if let Some(timeout) = config.timeout {
+                    builder = builder.timeout(timeout);
+                }
+                Transport::Http(builder.build()?)
+            }
+            OtlpProtocol::Grpc => {
+                let mut endpoint = Endpoint::from_shared(otlp.url.clone())
+                    .map_err(|e| format!("Invalid otlp url {}: {}", otlp.url, e))?;
+                if let Some(timeout) = config.timeout {
+                    endpoint = endpoint.timeout(timeout);
+                }
+                let mut metadata = MetadataMap::new();
+                for (name, value) in &otlp.headers {
+                    metadata.insert(
+                        MetadataKey::from_bytes(name.to_lowercase().as_bytes())?,
+                        value.parse()?,
+                    );
+                }
+                // Connects on the first export, reconnecting as needed
+                Transport::Grpc(Box::new(GrpcClient {
+                    client: MetricsServiceClient::new(endpoint.connect_lazy()),
+                    metadata,
+                }))
+            }
+        };
+        let mut resource = otlp.resource.clone();
+        resource
+            .entry("service.name".to_string())
+            .or_insert_with(|| "prom2jsonrs".to_string());
+        Ok(OtlpSink {
+            transport,
+            url: otlp.url.clone(),
+            resource,
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, request: &ExportMetricsServiceRequest) -> Result<(), Failure> {
+        match &self.transport {
+            Transport::Http(client) => {
+                let response = client
+                    .post(&self.url)
+                    .body(request.encode_to_vec())
+                    .send()
+                    .await
+                    .map_err(|e| Failure::Transient(e.into()))?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(());
+                }
+                let err = format!("OTLP export to {} returned {}", self.url, status).into();
+                // The statuses OTLP/HTTP clients retry on
+                if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                    Err(Failure::Transient(err))
+                } else {
+                    Err(Failure::Permanent(err))
+                }
+            }
+            Transport::Grpc(grpc) => {
+                let mut grpc_request = tonic::Request::new(request.clone());
+                *grpc_request.metadata_mut() = grpc.metadata.clone();
+                match grpc.client.clone().export(grpc_request).await {
+                    Ok(_) => Ok(()),
+                    Err(status) => {
+                        let err = format!("OTLP export to {} failed: {}", self.url, status).into();
+                        // The codes OTLP/gRPC clients retry on
+                        match status.code() {
+                            Code::Cancelled
+                            | Code::DeadlineExceeded
+                            | Code::ResourceExhausted
+                            | Code::Aborted
+                            | Code::OutOfRange
+                            | Code::Unavailable
+                            | Code::DataLoss => Err(Failure::Transient(err)),
+                            _ => Err(Failure::Permanent(err)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Export the series of every scrape, observed at the current time
+    pub async fn send(&self, data: &[&PrometheusData]) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        for data in data {
+            let request = otlp_metrics_request(data, &self.resource, now);
+            with_retries(&self.url, self.retries, || self.attempt(&request)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+        MetricsService, MetricsServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceResponse;
+    use std::sync::{Arc, Mutex};
+    use tonic::transport::server::TcpIncoming;
+    use tonic::{Request, Response, Status};
+
+    /// The tenant header and metric names of an export
+    type Export = (String, Vec<String>);
+
+    #[derive(Clone, Default)]
+    /// A collector keeping what it receives
+    struct Collector {
+        received: Arc<Mutex<Vec<Export>>>,
+    }
+
+    #[tonic::async_trait]
+    impl MetricsService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportMetricsServiceRequest>,
+        ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+            let tenant = request
+                .metadata()
+                .get("x-tenant")
+                .map(|v| v.to_str().unwrap());
+            let tenant = tenant.unwrap_or_default().to_string();
+            let names = request.into_inner().resource_metrics[0].scope_metrics[0]
+                .metrics
+                .iter()
+                .map(|m| m.name.clone())
+                .collect();
+            self.received.lock().unwrap().push((tenant, names));
+            Ok(Response::new(ExportMetricsServiceResponse::default()))
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_export_works() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Collector::default();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let server = tonic::transport::Server::builder()
+            .add_service(MetricsServiceServer::new(collector.clone()))
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+
+        let otlp = OtlpConfig {
+            url,
+            protocol: OtlpProtocol::Grpc,
+            headers: [("X-Tenant".to_string(), "a".to_string())].into(),
+            resource: HashMap::new(),
+        };
+        let sink = OtlpSink::new(&otlp, &Config::default()).unwrap();
+        let data = PrometheusData::from_string("# TYPE up gauge\nup 1");
+        sink.send(&[&data]).await.unwrap();
+        assert_eq!(
+            *collector.received.lock().unwrap(),
+            vec![("a".to_string(), vec!["up".to_string()])]
+        );
+    }
+}
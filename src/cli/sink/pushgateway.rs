@@ -0,0 +1,120 @@
+use super::super::config::{Auth, Config, PushgatewayConfig};
+use super::super::scraper::authenticate;
+use super::super::Error;
+use super::{with_retries, Failure};
+use prom2jsonrs::{render_exposition, PrometheusData};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode, Url};
+
+/// Pushes every scrape to a Pushgateway, re-rendered as exposition text
+pub struct PushgatewaySink {
+    client: Client,
+    url: Url,
+    auth: Option<Auth>,
+    retries: u32,
+}
+
+/// The `/metrics/job/<job>[/instance/<instance>]` url of a grouping key
+fn group_url(base: &str, job: &str, instance: Option<&str>) -> Result<Url, Error> {
+    let mut url =
+        Url::parse(base).map_err(|e| format!("Invalid pushgateway url {}: {}", base, e))?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| format!("Invalid pushgateway url {}", base))?;
+        segments.pop_if_empty().extend(["metrics", "job", job]);
+        if let Some(instance) = instance {
+            segments.extend(["instance", instance]);
+        }
+    }
+    Ok(url)
+}
+
+impl PushgatewaySink {
+    pub fn new(push: &PushgatewayConfig, config: &Config) -> Result<PushgatewaySink, Error> {
+        if push.url.is_empty() {
+            return Err("pushgateway needs a url".into());
+        }
+        let job = push.job.as_deref().unwrap_or("prom2jsonrs");
+        if job.is_empty() {
+            return Err("pushgateway job cannot be empty".into());
+        }
+        let url = group_url(&push.url, job, push.instance.as_deref())?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Content-Type",
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        for (name, value) in &push.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .user_agent(concat!("prom2jsonrs/", env!("CARGO_PKG_VERSION")));
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(PushgatewaySink {
+            client: builder.build()?,
+            url,
+            auth: push.auth.clone(),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, body: &str) -> Result<(), Failure> {
+        let request = self.client.put(self.url.clone()).body(body.to_string());
+        let response = authenticate(request, self.auth.as_ref())
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        let err = format!(
+            "push to {} returned {}: {}",
+            self.url,
+            status,
+            message.trim()
+        )
+        .into();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(err))
+        } else {
+            Err(Failure::Permanent(err))
+        }
+    }
+
+    /// Replace the metrics of the grouping key with those of every scrape of the document
+    pub async fn send(&self, data: &[&PrometheusData]) -> Result<(), Error> {
+        let body = render_exposition(data.iter().flat_map(|d| &d.metrics));
+        with_retries(self.url.as_str(), self.retries, || self.attempt(&body)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_url_works() {
+        assert_eq!(
+            group_url("http://pgw:9091", "batch", None)
+                .unwrap()
+                .as_str(),
+            "http://pgw:9091/metrics/job/batch"
+        );
+        assert_eq!(
+            group_url("http://pgw:9091/prefix/", "a b", Some("host:9100"))
+                .unwrap()
+                .as_str(),
+            "http://pgw:9091/prefix/metrics/job/a%20b/instance/host:9100"
+        );
+        assert!(group_url("not a url", "batch", None).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use super::super::config::{Config, RedisConfig};
+use super::super::Error;
+use super::{serialize_time, with_retries, Failure, Section};
+use redis::aio::MultiplexedConnection;
+use redis::{Client, RedisError};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+/// Keeps the latest document of every target in a Redis key, and/or publishes them on a channel
+pub struct RedisSink {
+    client: Client,
+    connection: Mutex<Option<MultiplexedConnection>>,
+    config: RedisConfig,
+    retries: u32,
+}
+
+/// The documents of a delivery by target: the whole body of a document of a single part (under
+/// `default` when it has no title), or every part of the others as a JSON document of its own
+fn target_documents(body: &str, sections: &[Section<'_>]) -> Result<Vec<(String, String)>, Error> {
+    if let [section] = sections {
+        let target = section.title.unwrap_or("default");
+        return Ok(vec![(target.to_string(), body.to_string())]);
+    }
+    let mut documents = Vec::new();
+    for (i, section) in sections.iter().enumerate() {
+        let mut document = match section.data {
+            Some(data) => serde_json::to_value(data)?,
+            None => json!({}),
+        };
+        let target = match section.title {
+            Some(title) => title.to_string(),
+            None => i.to_string(),
+        };
+        document["target"] = target.clone().into();
+        if let Some(time) = section.scraped_at {
+            document["scraped_at"] = serialize_time(&time, serde_json::value::Serializer)?;
+        }
+        if let Some(error) = section.error {
+            document["error"] = error.into();
+        }
+        documents.push((target, document.to_string()));
+    }
+    Ok(documents)
+}
+
+/// Whether trying again on a new connection may help
+fn is_transient(err: &RedisError) -> bool {
+    err.is_io_error() || err.is_connection_dropped() || err.is_timeout()
+}
+
+impl RedisSink {
+    pub fn new(redis: &RedisConfig, config: &Config) -> Result<RedisSink, Error> {
+        if redis.key.is_none() && redis.channel.is_none() {
+            return Err("redis needs a key, a channel or both".into());
+        }
+        Ok(RedisSink {
+            client: Client::open(redis.url.as_str())?,
+            connection: Mutex::new(None),
+            config: redis.clone(),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, documents: &[(String, String)]) -> Result<(), Failure> {
+        let mut connection = self.connection.lock().await;
+        let mut pipe = redis::pipe();
+        for (target, document) in documents {
+            if let Some(key) = &self.config.key {
+                let key = key.replace("{target}", target);
+                match self.config.ttl {
+                    Some(ttl) => pipe.pset_ex(key, document, ttl.as_millis() as u64),
+                    None => pipe.set(key, document),
+                };
+            }
+            if let Some(channel) = &self.config.channel {
+                pipe.publish(channel.replace("{target}", target), document);
+            }
+        }
+        let result = async {
+            let mut open = match connection.as_ref() {
+                Some(open) => open.clone(),
+                None => self.client.get_multiplexed_async_connection().await?,
+            };
+            pipe.query_async::<()>(&mut open).await?;
+            Ok::<_, RedisError>(open)
+        }
+        .await;
+        match result {
+            Ok(open) => {
+                *connection = Some(open);
+                Ok(())
+            }
+            Err(err) => {
+                let transient = is_transient(&err);
+                let err = format!("redis {} failed: {}", self.config.url, err).into();
+                if transient {
+                    // Reconnect on the next attempt
+                    *connection = None;
+                    Err(Failure::Transient(err))
+                } else {
+                    Err(Failure::Permanent(err))
+                }
+            }
+        }
+    }
+
+    /// Store and publish the document of every target of a delivery
+    pub async fn send(&self, body: &str, sections: &[Section<'_>]) -> Result<(), Error> {
+        let documents = target_documents(body, sections)?;
+        if documents.is_empty() {
+            return Ok(());
+        }
+        with_retries("redis", self.retries, || self.attempt(&documents)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prom2jsonrs::PrometheusData;
+    use serde_json::Value;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn target_documents_work() {
+        let data = PrometheusData::from_string("# TYPE up gauge\nup 1");
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let section = |title, error: Option<&'static str>| Section {
+            title,
+            data: if error.is_none() { Some(&data) } else { None },
+            error,
+            scraped_at: Some(time),
+        };
+        assert_eq!(
+            target_documents("{}", &[section(None, None)]).unwrap(),
+            vec![("default".to_string(), "{}".to_string())]
+        );
+        let documents = target_documents(
+            "[]",
+            &[
+                section(Some("api"), None),
+                section(Some("db"), Some("down")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(documents[0].0, "api");
+        let api: Value = serde_json::from_str(&documents[0].1).unwrap();
+        assert_eq!(api["target"], "api");
+        assert_eq!(api["scraped_at"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(api["metrics"][0]["metric_name"], "up");
+        let db: Value = serde_json::from_str(&documents[1].1).unwrap();
+        assert_eq!(
+            db,
+            json!({"target": "db", "scraped_at": "2023-11-14T22:13:20.123Z", "error": "down"})
+        );
+    }
+}
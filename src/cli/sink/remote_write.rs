@@ -0,0 +1,84 @@
+use super::super::config::{Config, PushConfig};
+use super::super::scraper::authenticate;
+use super::super::Error;
+use super::{with_retries, Failure};
+use prom2jsonrs::{remote_write_body, PrometheusData};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Pushes every scrape to a Prometheus remote-write endpoint
+pub struct RemoteWriteSink {
+    client: Client,
+    config: PushConfig,
+    retries: u32,
+}
+
+impl RemoteWriteSink {
+    pub fn new(push: &PushConfig, config: &Config) -> Result<RemoteWriteSink, Error> {
+        if push.url.is_empty() {
+            return Err("remote_write needs a url".into());
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("Content-Type", "application/x-protobuf"),
+            ("Content-Encoding", "snappy"),
+            ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        for (name, value) in &push.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .user_agent(concat!("prom2jsonrs/", env!("CARGO_PKG_VERSION")));
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(RemoteWriteSink {
+            client: builder.build()?,
+            config: push.clone(),
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    async fn attempt(&self, body: &[u8]) -> Result<(), Failure> {
+        let request = self.client.post(&self.config.url).body(body.to_vec());
+        let response = authenticate(request, self.config.auth.as_ref())
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        let err = format!(
+            "remote write to {} returned {}: {}",
+            self.config.url,
+            status,
+            message.trim()
+        )
+        .into();
+        // Remote-write receivers answer 5xx and 429 for what is worth resending
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(err))
+        } else {
+            Err(Failure::Permanent(err))
+        }
+    }
+
+    /// Push the samples of every scrape, stamped with the current time
+    pub async fn send(&self, data: &[&PrometheusData]) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        for data in data {
+            let body = remote_write_body(data, now);
+            with_retries(&self.config.url, self.retries, || self.attempt(&body)).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use super::super::compress::Compression;
+use super::super::config::{Config, S3Config};
+use super::super::Error;
+use super::{object_key, split_object_uri, with_retries, Failure};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+
+/// Uploads every document to S3 under a timestamped key
+pub struct S3Sink {
+    client: OnceCell<Client>,
+    config: S3Config,
+    bucket: String,
+    prefix: String,
+    extension: &'static str,
+    retries: u32,
+}
+
+impl S3Sink {
+    pub fn new(s3: &S3Config, config: &Config) -> Result<S3Sink, Error> {
+        let (bucket, prefix) = split_object_uri(&s3.uri, "s3")?;
+        let extension = if s3.gzip { "json.gz" } else { "json" };
+        Ok(S3Sink {
+            client: OnceCell::new(),
+            config: s3.clone(),
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            extension,
+            retries: config.retries.unwrap_or(3),
+        })
+    }
+
+    /// The client, resolving credentials and region the standard AWS way on first use
+    async fn client(&self) -> &Client {
+        self.client
+            .get_or_init(|| async {
+                let mut loader = aws_config::from_env();
+                if let Some(region) = &self.config.region {
+                    loader = loader.region(aws_config::Region::new(region.clone()));
+                }
+                let shared = loader.load().await;
+                let mut builder = aws_sdk_s3::config::Builder::from(&shared);
+                if let Some(endpoint) = &self.config.endpoint {
+                    // S3 compatible stores (MinIO, R2, ...) generally want path style requests
+                    builder = builder.endpoint_url(endpoint).force_path_style(true);
+                }
+                Client::from_conf(builder.build())
+            })
+            .await
+    }
+
+    async fn attempt(&self, key: &str, body: &[u8]) -> Result<(), Failure> {
+        let content_type = if self.config.gzip {
+            "application/gzip"
+        } else {
+            "application/json"
+        };
+        let request = self
+            .client()
+            .await
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .body(ByteStream::from(body.to_vec()));
+        request.send().await.map_err(|e| {
+            let err = format!(
+                "upload to s3://{}/{} failed: {}",
+                self.bucket,
+                key,
+                aws_sdk_s3::error::DisplayErrorContext(&e)
+            );
+            // Throttling, server and connection errors are worth retrying, not bad credentials
+            // or a missing bucket
+            match e.raw_response().map(|r| r.status().as_u16()) {
+                Some(status) if status < 500 && status != 429 => Failure::Permanent(err.into()),
+                _ => Failure::Transient(err.into()),
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Upload the document under a key named after the current time
+    pub async fn send(&self, body: &str) -> Result<(), Error> {
+        let key = object_key(&self.prefix, SystemTime::now(), self.extension);
+        let body = if self.config.gzip {
+            Compression::Gzip.compress(body.as_bytes())?
+        } else {
+            body.as_bytes().to_vec()
+        };
+        with_retries("s3", self.retries, || self.attempt(&key, &body)).await
+    }
+}
@@ -0,0 +1,86 @@
+//! Unicode sparklines of the recent values of series, for the views refreshed every interval
+
+use std::collections::{HashMap, VecDeque};
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The values as bars scaled between their minimum and maximum, a space for those that are
+/// not numbers
+pub fn sparkline(values: &[f64]) -> String {
+    let finite = values.iter().copied().filter(|v| v.is_finite());
+    let (min, max) = finite.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+        (min.min(v), max.max(v))
+    });
+    values
+        .iter()
+        .map(|&v| {
+            if !v.is_finite() {
+                ' '
+            } else if max > min {
+                let level = ((v - min) / (max - min) * (BARS.len() - 1) as f64).round();
+                BARS[level as usize]
+            } else {
+                BARS[0]
+            }
+        })
+        .collect()
+}
+
+/// The last values of every series, by id
+pub struct Sparklines {
+    length: usize,
+    series: HashMap<String, VecDeque<f64>>,
+}
+
+impl Sparklines {
+    /// Keep the last `length` values of every series
+    pub fn new(length: usize) -> Sparklines {
+        Sparklines {
+            length: length.max(1),
+            series: HashMap::new(),
+        }
+    }
+
+    /// Add the values of a refresh, forgetting the series it does not have anymore
+    pub fn record<I: IntoIterator<Item = (String, f64)>>(&mut self, values: I) {
+        let mut previous = std::mem::take(&mut self.series);
+        for (id, value) in values {
+            let mut history = previous.remove(&id).unwrap_or_default();
+            history.push_back(value);
+            if history.len() > self.length {
+                history.pop_front();
+            }
+            self.series.insert(id, history);
+        }
+    }
+
+    /// The sparkline of a series, empty when it is unknown
+    pub fn line(&self, id: &str) -> String {
+        match self.series.get(id) {
+            Some(history) => sparkline(&history.iter().copied().collect::<Vec<f64>>()),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sparklines_work() {
+        assert_eq!(sparkline(&[1.0, 2.0, 3.0, 4.0, 8.0]), "▁▂▃▄█");
+        assert_eq!(sparkline(&[5.0, 5.0]), "▁▁");
+        assert_eq!(sparkline(&[0.0, f64::NAN, 7.0]), "▁ █");
+        assert_eq!(sparkline(&[]), "");
+
+        let mut sparklines = Sparklines::new(3);
+        for value in 1..=4 {
+            sparklines.record(vec![("a".to_string(), value as f64)]);
+        }
+        assert_eq!(sparklines.line("a"), "▁▅█");
+        sparklines.record(vec![("b".to_string(), 1.0)]);
+        assert_eq!(sparklines.line("a"), "");
+        assert_eq!(sparklines.line("b"), "▁");
+    }
+}
@@ -0,0 +1,104 @@
+//! Writing documents as one file per metric family
+
+use super::compress::{maybe_compress, with_extension, Compression};
+use super::sink::Document;
+use super::Error;
+use prom2jsonrs::{MetricFamily, PrometheusData};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitBy {
+    Family,
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SplitBy, String> {
+        match s {
+            "family" => Ok(SplitBy::Family),
+            other => Err(format!("Unknown split {}, expected family", other)),
+        }
+    }
+}
+
+/// A file name for a family: metric names are safe but for `:`, which some filesystems reject
+fn file_name(family: &str) -> String {
+    let name: String = family
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.json", name)
+}
+
+/// The families of every part of a document, those of the same name merged together
+fn families<D: Document>(document: &D) -> Vec<MetricFamily> {
+    let mut merged = PrometheusData {
+        metrics: Vec::new(),
+    };
+    for data in document.data() {
+        merged.merge(data.clone());
+    }
+    merged.metrics
+}
+
+/// Write each family of the document to `<dir>/<family>.json`, rendered with `render` and
+/// compressed if asked to
+pub async fn write_families<D, F>(
+    document: &D,
+    dir: &Path,
+    compression: Option<Compression>,
+    render: F,
+) -> Result<(), Error>
+where
+    D: Document,
+    F: Fn(&MetricFamily) -> String,
+{
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    for family in families(document) {
+        let path = dir.join(with_extension(file_name(&family.metric_name), compression));
+        let contents = maybe_compress(render(&family).as_bytes(), compression)?;
+        tokio::fs::write(&path, contents)
+            .await
+            .map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn family_split_works() {
+        assert_eq!(
+            file_name("job:http_requests:rate5m"),
+            "job_http_requests_rate5m.json"
+        );
+        assert!("target".parse::<SplitBy>().is_err());
+
+        let a = PrometheusData::from_string("# HELP up Up.\n# TYPE up gauge\nup{i=\"a\"} 1\n");
+        let b = PrometheusData::from_string(
+            "# HELP up Up.\n# TYPE up gauge\nup{i=\"b\"} 0\n# HELP x X.\n# TYPE x gauge\nx 2\n",
+        );
+        let dir = std::env::temp_dir().join(format!("prom2jsonrs-split-{}", std::process::id()));
+        write_families(&vec![a, b], &dir, None, |f| {
+            serde_json::to_string(f).unwrap()
+        })
+        .await
+        .unwrap();
+        let up = std::fs::read_to_string(dir.join("up.json")).unwrap();
+        assert!(up.contains("\"i\":\"a\"") && up.contains("\"i\":\"b\""));
+        assert!(dir.join("x.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
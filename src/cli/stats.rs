@@ -0,0 +1,46 @@
+use super::config::Settings;
+use super::Error;
+use prom2jsonrs::{Labels, PrometheusData};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct StatsOpts {
+    /// url or file to summarize
+    source: String,
+    /// Number of families and labels to list
+    #[structopt(long, default_value = "10")]
+    top: usize,
+    /// Print the full figures as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+pub async fn run(opts: StatsOpts, settings: &Settings) -> Result<(), Error> {
+    let raw = settings.read_source(&opts.source).await?;
+    let mut data = PrometheusData::from_string(&raw);
+    settings.process(&mut data, &Labels::new());
+    let stats = prom2jsonrs::stats(&data);
+    if opts.json {
+        let mut value = serde_json::to_value(&stats)?;
+        value["payload_bytes"] = raw.len().into();
+        println!("{}", settings.render(&value));
+        return Ok(());
+    }
+    println!("payload:  {} bytes", raw.len());
+    println!("families: {}", stats.families);
+    println!("series:   {}", stats.series);
+    println!();
+    println!("{:>8} {:>10}  FAMILY", "SERIES", "BYTES");
+    for family in stats.per_family.iter().take(opts.top) {
+        println!(
+            "{:>8} {:>10}  {} ({:?})",
+            family.series, family.bytes, family.name, family.metric_type
+        );
+    }
+    println!();
+    println!("{:>8} {:>10}  LABEL", "VALUES", "SERIES");
+    for label in stats.labels.iter().take(opts.top) {
+        println!("{:>8} {:>10}  {}", label.values, label.series, label.name);
+    }
+    Ok(())
+}
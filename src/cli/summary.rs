@@ -0,0 +1,233 @@
+//! Concise, colored rendering of documents for terminals
+
+use super::sink::{Document, Section};
+use super::sparkline::Sparklines;
+use prom2jsonrs::{MetricFamily, MetricType};
+use std::fmt::Write;
+
+/// ANSI styles, or nothing when colors are off
+struct Palette {
+    color: bool,
+}
+
+impl Palette {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn title(&self, text: &str) -> String {
+        self.paint("1;32", text)
+    }
+
+    fn name(&self, text: &str) -> String {
+        self.paint("1;36", text)
+    }
+
+    fn dim(&self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    fn value(&self, text: &str) -> String {
+        self.paint("33", text)
+    }
+
+    fn error(&self, text: &str) -> String {
+        self.paint("1;31", text)
+    }
+}
+
+/// Whether to color the output: https://no-color.org asks for none when `NO_COLOR` is set
+/// to anything but an empty string
+pub fn use_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// A short spelling of a value: integers in full, others with at most 4 decimals or in
+/// scientific notation when very large or small
+fn number(value: f64) -> String {
+    if value.is_nan() || value.is_infinite() {
+        return value.to_string();
+    }
+    let magnitude = value.abs();
+    if value.fract() == 0.0 && magnitude < 1e15 {
+        format!("{:.0}", value)
+    } else if !(1e-3..1e6).contains(&magnitude) {
+        format!("{:.3e}", value)
+    } else {
+        let fixed = format!("{:.4}", value);
+        fixed
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::Gauge => "gauge",
+        MetricType::Histogram => "histogram",
+        MetricType::Summary => "summary",
+        MetricType::Untyped => "untyped",
+    }
+}
+
+/// The value of a single series, or the range of the values of several. Summaries and
+/// histograms are described by their observation counts.
+fn values(family: &MetricFamily) -> String {
+    let values: Vec<f64> = family
+        .data
+        .iter()
+        .filter_map(|m| m.rank_value())
+        .filter(|v| !v.is_nan())
+        .collect();
+    let (min, max) = match values.iter().copied().fold(None, |acc, v| match acc {
+        None => Some((v, v)),
+        Some((min, max)) => Some((f64::min(min, v), f64::max(max, v))),
+    }) {
+        Some(range) => range,
+        None => return String::new(),
+    };
+    let prefix = match family.metric_type {
+        MetricType::Histogram | MetricType::Summary => "count ",
+        _ => "",
+    };
+    if min == max {
+        format!("{}{}", prefix, number(min))
+    } else {
+        format!("{}{} … {}", prefix, number(min), number(max))
+    }
+}
+
+/// The total of the values of a family, whose trend the summary shows
+fn total(family: &MetricFamily) -> f64 {
+    family
+        .data
+        .iter()
+        .filter_map(|m| m.rank_value())
+        .filter(|v| !v.is_nan())
+        .sum()
+}
+
+/// The id of a family in the trends, the same family of different sections kept apart
+fn trend_id(section: &Section, family: &MetricFamily) -> String {
+    format!("{}/{}", section.title.unwrap_or(""), family.metric_name)
+}
+
+/// Add the totals of every family of a document to the trends
+pub fn record_trends<D: Document>(document: &D, trends: &mut Sparklines) {
+    let mut totals = Vec::new();
+    for section in document.sections() {
+        for family in section.data.iter().flat_map(|data| &data.metrics) {
+            totals.push((trend_id(&section, family), total(family)));
+        }
+    }
+    trends.record(totals);
+}
+
+fn render_section(
+    out: &mut String,
+    section: &Section,
+    palette: &Palette,
+    trends: Option<&Sparklines>,
+) {
+    if let Some(title) = section.title {
+        let _ = writeln!(out, "{}", palette.title(title));
+    }
+    if let Some(error) = section.error {
+        let _ = writeln!(out, "  {}", palette.error(error));
+    }
+    let data = match section.data {
+        Some(data) => data,
+        None => return,
+    };
+    let width = data
+        .metrics
+        .iter()
+        .map(|f| f.metric_name.len())
+        .max()
+        .unwrap_or(0);
+    for family in &data.metrics {
+        let series = family.data.len();
+        let _ = write!(
+            out,
+            "  {}{} {} {} {}",
+            palette.name(&family.metric_name),
+            " ".repeat(width - family.metric_name.len()),
+            palette.dim(&format!("{:<9}", type_name(family.metric_type))),
+            palette.dim(&format!("{:>5} series", series)),
+            palette.value(&values(family)),
+        );
+        if let Some(trends) = trends {
+            let _ = write!(out, " {}", trends.line(&trend_id(section, family)));
+        }
+        out.push('\n');
+    }
+    let series: usize = data.metrics.iter().map(|f| f.data.len()).sum();
+    let _ = writeln!(
+        out,
+        "  {}",
+        palette.dim(&format!(
+            "{} families, {} series",
+            data.metrics.len(),
+            series
+        ))
+    );
+}
+
+/// Summarize a document: per section, one line per family with its type, series count and
+/// values, followed by the sparkline of its total when given trends
+pub fn render<D: Document>(document: &D, color: bool, trends: Option<&Sparklines>) -> String {
+    let palette = Palette { color };
+    let mut out = String::new();
+    for section in document.sections() {
+        render_section(&mut out, &section, &palette, trends);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prom2jsonrs::PrometheusData;
+
+    #[test]
+    fn summary_works() {
+        let data = PrometheusData::from_string(
+            "# HELP up Up.
+# TYPE up gauge
+up{job=\"a\"} 1
+up{job=\"b\"} 0
+# HELP rpc_duration_seconds RPC latency.
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile=\"0.5\"} 0.25
+rpc_duration_seconds_sum 1.5
+rpc_duration_seconds_count 12
+",
+        );
+        let summary = render(&data, false, None);
+        assert_eq!(
+            summary,
+            "  up                   gauge         2 series 0 … 1
+  rpc_duration_seconds summary       1 series count 12
+  2 families, 3 series
+"
+        );
+        assert!(render(&data, true, None).contains("\x1b[1;36mup\x1b[0m"));
+
+        let mut trends = Sparklines::new(10);
+        record_trends(&data, &mut trends);
+        let mut busier = data.clone();
+        busier.merge(PrometheusData::from_string(
+            "# TYPE up gauge\nup{job=\"c\"} 1",
+        ));
+        record_trends(&busier, &mut trends);
+        let summary = render(&busier, false, Some(&trends));
+        assert!(summary.contains("0 … 1 ▁█\n"));
+        assert_eq!(number(0.123456), "0.1235");
+        assert_eq!(number(123456789.5), "1.235e8");
+    }
+}
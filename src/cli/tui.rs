@@ -0,0 +1,490 @@
+use super::config::{parse_duration, Settings};
+use super::sparkline::Sparklines;
+use super::Error;
+use prom2jsonrs::{LabelMatcher, MatchOp, MetricType, PrometheusData, Sample, Selector};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Cell, Clear, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct TuiOpts {
+    /// url to query for prom metrics
+    url: String,
+    /// Time between refreshes, e.g. `5s` (defaults to the config interval, or 5s)
+    #[structopt(short, long, parse(try_from_str = parse_duration))]
+    interval: Option<Duration>,
+    /// How many refreshes the sparkline of each series goes back
+    #[structopt(long, default_value = "20")]
+    history: usize,
+}
+
+/// One series of the view, together with the family it belongs to
+struct SeriesRow {
+    metric_type: MetricType,
+    help: String,
+    sample: Sample,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Value,
+}
+
+enum Mode {
+    Browse,
+    Search,
+    Detail(TableState),
+}
+
+struct App {
+    url: String,
+    rows: Vec<SeriesRow>,
+    visible: Vec<usize>,
+    filter: String,
+    filter_error: Option<String>,
+    sort: SortKey,
+    descending: bool,
+    table: TableState,
+    mode: Mode,
+    error: Option<String>,
+    refreshed: Option<Instant>,
+    trends: Sparklines,
+}
+
+fn rows_from(data: PrometheusData) -> Vec<SeriesRow> {
+    let mut rows = Vec::new();
+    for family in &data.metrics {
+        for sample in family.samples() {
+            rows.push(SeriesRow {
+                metric_type: family.metric_type,
+                help: family.help.clone(),
+                sample,
+            })
+        }
+    }
+    rows
+}
+
+fn format_labels(sample: &Sample) -> String {
+    let mut labels: Vec<String> = sample
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v))
+        .collect();
+    labels.sort();
+    labels.join(",")
+}
+
+/// What a filter matches: whitespace separated substrings of the metric name, then the label
+/// matchers of a selector, e.g. `http {code="200",path=~"/api .*"}`
+struct Filter {
+    terms: Vec<String>,
+    selector: Selector,
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Filter, String> {
+        let (terms, labels) = match filter.find('{') {
+            Some(idx) => (&filter[..idx], filter[idx..].parse()?),
+            None => (filter, Selector::default()),
+        };
+        Ok(Filter {
+            terms: terms.split_whitespace().map(str::to_string).collect(),
+            selector: labels,
+        })
+    }
+
+    fn matches(&self, sample: &Sample) -> bool {
+        self.terms
+            .iter()
+            .all(|term| sample.name.contains(term.as_str()))
+            && self.selector.matches(sample)
+    }
+}
+
+impl App {
+    fn new(url: String, history: usize) -> App {
+        App {
+            url,
+            rows: Vec::new(),
+            visible: Vec::new(),
+            filter: String::new(),
+            filter_error: None,
+            sort: SortKey::Name,
+            descending: false,
+            table: TableState::default(),
+            mode: Mode::Browse,
+            error: None,
+            refreshed: None,
+            trends: Sparklines::new(history),
+        }
+    }
+
+    fn update_view(&mut self) {
+        let rows = &self.rows;
+        // A filter being typed may not parse yet, it then matches nothing
+        let filter = Filter::parse(&self.filter);
+        let mut visible: Vec<usize> = match &filter {
+            Ok(filter) => (0..rows.len())
+                .filter(|&i| filter.matches(&rows[i].sample))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        self.filter_error = filter.err();
+        match self.sort {
+            SortKey::Name => visible.sort_by(|&a, &b| {
+                (&rows[a].sample.name, format_labels(&rows[a].sample))
+                    .cmp(&(&rows[b].sample.name, format_labels(&rows[b].sample)))
+            }),
+            SortKey::Value => visible.sort_by(|&a, &b| {
+                let a = rows[a].sample.float_value().unwrap_or(f64::NAN);
+                let b = rows[b].sample.float_value().unwrap_or(f64::NAN);
+                a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+        if self.descending {
+            visible.reverse();
+        }
+        self.visible = visible;
+        let selected = match self.table.selected() {
+            _ if self.visible.is_empty() => None,
+            Some(i) => Some(i.min(self.visible.len() - 1)),
+            None => Some(0),
+        };
+        self.table.select(selected);
+    }
+
+    fn selected_row(&self) -> Option<&SeriesRow> {
+        self.table
+            .selected()
+            .and_then(|i| self.visible.get(i))
+            .map(|&i| &self.rows[i])
+    }
+
+    /// Handle a key press, returning false once the user asked to quit
+    fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        match &mut self.mode {
+            Mode::Search => match code {
+                KeyCode::Enter | KeyCode::Esc => self.mode = Mode::Browse,
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                    self.update_view();
+                }
+                KeyCode::Char(c) => {
+                    self.filter.push(c);
+                    self.update_view();
+                }
+                _ => {}
+            },
+            Mode::Detail(labels) => match code {
+                KeyCode::Esc | KeyCode::Backspace => self.mode = Mode::Browse,
+                KeyCode::Char('q') => return false,
+                KeyCode::Down | KeyCode::Char('j') => labels.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => labels.select_previous(),
+                KeyCode::Enter => {
+                    // Drill down: only show the series sharing the selected label
+                    let selected = labels.selected();
+                    let term = self.selected_row().and_then(|row| {
+                        let mut pairs: Vec<(&String, &String)> = row.sample.labels.iter().collect();
+                        pairs.sort();
+                        selected
+                            .and_then(|i| pairs.get(i))
+                            .and_then(|(k, v)| LabelMatcher::new(k, MatchOp::Equal, v).ok())
+                            .map(|matcher| {
+                                Selector {
+                                    metric: None,
+                                    matchers: vec![matcher],
+                                }
+                                .to_string()
+                            })
+                    });
+                    if let Some(term) = term {
+                        self.filter = term;
+                        self.table.select(Some(0));
+                        self.update_view();
+                    }
+                    self.mode = Mode::Browse;
+                }
+                _ => {}
+            },
+            Mode::Browse => match code {
+                KeyCode::Char('q') => return false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.update_view();
+                }
+                KeyCode::Char('/') => self.mode = Mode::Search,
+                KeyCode::Char('s') => {
+                    self.sort = match self.sort {
+                        SortKey::Name => SortKey::Value,
+                        SortKey::Value => SortKey::Name,
+                    };
+                    self.update_view();
+                }
+                KeyCode::Char('r') => {
+                    self.descending = !self.descending;
+                    self.update_view();
+                }
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                KeyCode::PageDown => self.table.scroll_down_by(20),
+                KeyCode::PageUp => self.table.scroll_up_by(20),
+                KeyCode::Home | KeyCode::Char('g') => self.table.select_first(),
+                KeyCode::End | KeyCode::Char('G') => self.table.select_last(),
+                KeyCode::Enter if self.selected_row().is_some() => {
+                    let mut labels = TableState::default();
+                    labels.select(Some(0));
+                    self.mode = Mode::Detail(labels);
+                }
+                _ => {}
+            },
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let age = self
+            .refreshed
+            .map(|t| format!("{}s ago", t.elapsed().as_secs()))
+            .unwrap_or_else(|| "never".to_string());
+        let sort = match (self.sort, self.descending) {
+            (SortKey::Name, false) => "name ↑",
+            (SortKey::Name, true) => "name ↓",
+            (SortKey::Value, false) => "value ↑",
+            (SortKey::Value, true) => "value ↓",
+        };
+        frame.render_widget(
+            Paragraph::new(format!(
+                "{}  {}/{} series  sort: {}  refreshed: {}",
+                self.url,
+                self.visible.len(),
+                self.rows.len(),
+                sort,
+                age
+            ))
+            .style(Style::default().add_modifier(Modifier::REVERSED)),
+            header,
+        );
+
+        let rows = self.visible.iter().map(|&i| {
+            let sample = &self.rows[i].sample;
+            Row::new(vec![
+                Cell::from(sample.name.clone()),
+                Cell::from(format_labels(sample)),
+                Cell::from(sample.value.clone()),
+                Cell::from(self.trends.line(&sample.series_id())),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Percentage(35),
+                Constraint::Percentage(35),
+                Constraint::Percentage(12),
+                Constraint::Percentage(18),
+            ],
+        )
+        .header(
+            Row::new(vec!["NAME", "LABELS", "VALUE", "TREND"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, body, &mut self.table);
+
+        let status = match (&self.mode, &self.error) {
+            (Mode::Search, _) => match &self.filter_error {
+                Some(err) => format!("/{}  ({})", self.filter, err),
+                None => format!("/{}", self.filter),
+            },
+            (_, Some(err)) => err.clone(),
+            _ if !self.filter.is_empty() => format!("filter: {}  (esc clears)", self.filter),
+            _ => "q quit  / search  s sort  r reverse  enter details".to_string(),
+        };
+        frame.render_widget(Paragraph::new(status), footer);
+
+        let selected = self
+            .table
+            .selected()
+            .and_then(|i| self.visible.get(i))
+            .copied();
+        if let (Mode::Detail(labels), Some(selected)) = (&mut self.mode, selected) {
+            let row = &self.rows[selected];
+            let area = centered(frame.area(), 70, 60);
+            frame.render_widget(Clear, area);
+            let [info, label_area] =
+                Layout::vertical([Constraint::Length(4), Constraint::Min(1)]).areas(area);
+            frame.render_widget(
+                Paragraph::new(vec![
+                    Line::from(format!("{} ({:?})", row.sample.name, row.metric_type)),
+                    Line::from(row.help.clone()),
+                    Line::from(format!(
+                        "value: {}  {}",
+                        row.sample.value,
+                        self.trends.line(&row.sample.series_id())
+                    )),
+                ])
+                .block(Block::default().borders(Borders::TOP | Borders::LEFT | Borders::RIGHT)),
+                info,
+            );
+            let mut pairs: Vec<(&String, &String)> = row.sample.labels.iter().collect();
+            pairs.sort();
+            let label_rows = pairs
+                .into_iter()
+                .map(|(k, v)| Row::new(vec![k.clone(), v.clone()]));
+            let label_table = Table::new(
+                label_rows,
+                [Constraint::Percentage(35), Constraint::Percentage(65)],
+            )
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("labels (enter filters on label)"),
+            )
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            frame.render_stateful_widget(label_table, label_area, labels);
+        }
+    }
+}
+
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let [_, vertical, _] = Layout::vertical([
+        Constraint::Percentage((100 - percent_y) / 2),
+        Constraint::Percentage(percent_y),
+        Constraint::Percentage((100 - percent_y) / 2),
+    ])
+    .areas(area);
+    let [_, area, _] = Layout::horizontal([
+        Constraint::Percentage((100 - percent_x) / 2),
+        Constraint::Percentage(percent_x),
+        Constraint::Percentage((100 - percent_x) / 2),
+    ])
+    .areas(vertical);
+    area
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    updates: mpsc::Receiver<Result<PrometheusData, String>>,
+) -> std::io::Result<()> {
+    loop {
+        while let Ok(update) = updates.try_recv() {
+            match update {
+                Ok(data) => {
+                    let values = data.samples().into_iter().map(|s| {
+                        let value = s.float_value().unwrap_or(f64::NAN);
+                        (s.series_id(), value)
+                    });
+                    app.trends.record(values);
+                    app.rows = rows_from(data);
+                    app.error = None;
+                    app.refreshed = Some(Instant::now());
+                    app.update_view();
+                }
+                Err(err) => app.error = Some(err),
+            }
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+        if event::poll(Duration::from_millis(250))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !app.on_key(key.code, key.modifiers) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Run the interactive view until the user quits
+pub async fn run(opts: TuiOpts, settings: Arc<Settings>) -> Result<(), Error> {
+    let (tx, rx) = mpsc::channel();
+    let url = opts.url.clone();
+    let interval = opts
+        .interval
+        .or(settings.config.interval)
+        .unwrap_or_else(|| Duration::from_secs(5))
+        .max(Duration::from_millis(100));
+    let stopped = tx.clone();
+    let scraper = tokio::spawn(async move {
+        loop {
+            let update = settings
+                .scrape_url(&url)
+                .await
+                .map_err(|e| format!("scrape failed: {}", e));
+            if tx.send(update).is_err() {
+                return;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+    // The scraper only returns once the view is gone, so anything else ends up in the status line
+    tokio::spawn(async move {
+        if let Err(err) = scraper.await {
+            let reason = match err.try_into_panic() {
+                Ok(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "panicked".to_string()),
+                Err(err) => err.to_string(),
+            };
+            let _ = stopped.send(Err(format!("scraper stopped: {}", reason)));
+        }
+    });
+
+    // The terminal event loop blocks, keep it off the scraping workers
+    let result = tokio::task::block_in_place(|| {
+        let mut terminal = ratatui::init();
+        let result = event_loop(&mut terminal, App::new(opts.url, opts.history), rx);
+        ratatui::restore();
+        result
+    });
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_matching_works() {
+        let sample = Sample {
+            name: "http_requests_total".to_string(),
+            labels: vec![
+                ("code".to_string(), "200".to_string()),
+                ("path".to_string(), "/a b".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            value: "3".to_string(),
+        };
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(&sample);
+        assert!(matches(""));
+        assert!(matches("requests"));
+        assert!(matches("http total {code=\"200\"}"));
+        assert!(matches("{path=\"/a b\"}"));
+        assert!(matches("{code=~\"2..\",path!=\"/\"}"));
+        assert!(!matches("{code=\"500\"}"));
+        assert!(!matches("{path=\"/a\"}"));
+        assert!(!matches("bytes"));
+        assert!(Filter::parse("{code=\"20").is_err());
+    }
+}
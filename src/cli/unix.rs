@@ -0,0 +1,161 @@
+use super::Error;
+use reqwest::header::HeaderMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Split a `unix:///path/to.sock:/metrics` url into the socket path and the http path, which
+/// defaults to `/metrics`
+pub fn split_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("unix://")
+        .ok_or_else(|| format!("Not a unix socket url: {}", url))?;
+    let (socket, path) = match rest.rfind(":/") {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, "/metrics"),
+    };
+    if socket.is_empty() {
+        return Err(format!("Missing socket path in {}", url));
+    }
+    Ok((socket, path))
+}
+
+/// Issue a plain HTTP/1.1 GET over the unix socket of `url` and return the response body
+pub async fn fetch(
+    url: &str,
+    headers: &HeaderMap,
+    timeout: Option<Duration>,
+) -> Result<String, Error> {
+    let exchange = exchange(url, headers);
+    let response = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| format!("Timed out scraping {}", url))??,
+        None => exchange.await?,
+    };
+    parse_response(url, &response)
+}
+
+async fn exchange(url: &str, headers: &HeaderMap) -> Result<Vec<u8>, Error> {
+    let (socket, path) = split_url(url)?;
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("Cannot connect to {}: {}", socket, e))?;
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+        path
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value.to_str()?));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+fn parse_response(url: &str, response: &[u8]) -> Result<String, Error> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format!("Malformed http response from {}", url))?;
+    let head = std::str::from_utf8(&response[..split])?;
+    let body = &response[split + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    let code = status.split(' ').nth(1).unwrap_or_default();
+    if !code.starts_with('2') {
+        return Err(format!("HTTP status {} for url ({})", status, url).into());
+    }
+    let chunked = lines.any(|line| {
+        let mut header = line.splitn(2, ':');
+        header
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("transfer-encoding")
+            && header
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+                .contains("chunked")
+    });
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+    Ok(String::from_utf8(body)?)
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    loop {
+        let end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or("Truncated chunked body")?;
+        let size = std::str::from_utf8(&body[..end]).map_err(|e| e.to_string())?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| format!("Invalid chunk size {}", size))?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size {
+            return Err("Truncated chunked body".to_string());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    #[tokio::test]
+    async fn unix_fetch_works() {
+        assert_eq!(
+            split_url("unix:///var/run/exporter.sock:/metrics").unwrap(),
+            ("/var/run/exporter.sock", "/metrics")
+        );
+        assert_eq!(
+            split_url("unix:///tmp/a.sock").unwrap(),
+            ("/tmp/a.sock", "/metrics")
+        );
+        assert!(split_url("unix://:/metrics").is_err());
+
+        let dir = std::env::temp_dir().join(format!("prom2jsonrs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("exporter.sock");
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let n = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nup 1\r\n1\r\n\n\r\n0\r\n\r\n")
+                .unwrap();
+            request
+        });
+        let url = format!("unix://{}:/custom", socket.display());
+        let body = fetch(&url, &HeaderMap::new(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(body, "up 1\n");
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /custom HTTP/1.1\r\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(parse_response("u", b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use super::config::Settings;
+use super::Error;
+use prom2jsonrs::{Issue, PrometheusData};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct ValidateOpts {
+    /// url or file to validate
+    source: String,
+}
+
+pub async fn run(opts: ValidateOpts, settings: &Settings) -> Result<(), Error> {
+    let raw = settings.read_source(&opts.source).await?;
+    let mut issues = prom2jsonrs::lint(&raw);
+    // The strict parse reports the lint errors again, and whatever else it cannot read
+    if let Err(strict) = PrometheusData::from_string_strict(&raw) {
+        let missed: Vec<Issue> = strict.into_iter().filter(|i| !issues.contains(i)).collect();
+        issues.extend(missed);
+    }
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    let errors = issues
+        .iter()
+        .filter(|i| i.severity == prom2jsonrs::Severity::Error)
+        .count();
+    println!("{} errors, {} warnings", errors, issues.len() - errors);
+    if errors > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Documents as the metric events Vector's `prometheus_scrape` source produces, one JSON object a
+//! line, for Vector and Fluent Bit pipelines
+
+use super::sink::Document;
+use prom2jsonrs::{parse_float, Labels, MetricFamily, MetricLike, MetricType};
+use serde_json::{json, Map, Value};
+use std::time::SystemTime;
+
+/// An event of one series, its value under `kind` (`gauge`, `counter`, ...)
+fn event(name: &str, labels: Option<&Labels>, timestamp: &str, kind: &str, value: Value) -> Value {
+    let mut event = Map::new();
+    event.insert("name".to_string(), json!(name));
+    event.insert(
+        "tags".to_string(),
+        json!(labels.cloned().unwrap_or_default()),
+    );
+    event.insert("timestamp".to_string(), json!(timestamp));
+    event.insert("kind".to_string(), json!("absolute"));
+    event.insert(kind.to_string(), value);
+    Value::Object(event)
+}
+
+/// A count, which the exposition format spells as a float
+fn count(value: Option<f64>) -> u64 {
+    value.filter(|v| *v >= 0.0).unwrap_or(0.0) as u64
+}
+
+/// The value of a gauge, counter or untyped series, none when it is not a finite number (which
+/// JSON cannot carry)
+fn number(m: &dyn MetricLike, name: &str) -> Option<Value> {
+    let value = m.samples(name).first()?.float_value()?;
+    value.is_finite().then(|| json!({ "value": value }))
+}
+
+/// Buckets with the observations of their own range rather than every one up to their bound, as
+/// Vector keeps them, the `+Inf` one left to the count
+fn histogram(m: &dyn MetricLike, name: &str) -> Value {
+    let mut buckets = Vec::new();
+    let (mut cumulative, mut total, mut sum) = (0, 0, 0.0);
+    for sample in m.samples(name) {
+        if let Some(le) = sample.labels.get("le") {
+            match parse_float(le) {
+                Some(bound) if bound.is_finite() => {
+                    let bucket = count(sample.float_value());
+                    buckets.push(json!({
+                        "upper_limit": bound,
+                        "count": bucket.saturating_sub(cumulative),
+                    }));
+                    cumulative = bucket;
+                }
+                _ => {}
+            }
+        } else if sample.name.ends_with("_sum") {
+            sum = sample.float_value().unwrap_or_default();
+        } else if sample.name.ends_with("_count") {
+            total = count(sample.float_value());
+        }
+    }
+    json!({ "buckets": buckets, "count": total, "sum": sum })
+}
+
+fn summary(m: &dyn MetricLike, name: &str) -> Value {
+    let mut quantiles = Vec::new();
+    let (mut total, mut sum) = (0, 0.0);
+    for sample in m.samples(name) {
+        if let Some(quantile) = sample.labels.get("quantile") {
+            if let (Some(quantile), Some(value)) = (parse_float(quantile), sample.float_value()) {
+                quantiles.push(json!({ "quantile": quantile, "value": value }));
+            }
+        } else if sample.name.ends_with("_sum") {
+            sum = sample.float_value().unwrap_or_default();
+        } else if sample.name.ends_with("_count") {
+            total = count(sample.float_value());
+        }
+    }
+    json!({ "quantiles": quantiles, "count": total, "sum": sum })
+}
+
+/// The events of every series of a family. Counters parse as gauges, so gauge families named
+/// `*_total` are taken for counters.
+fn family_events(family: &MetricFamily, timestamp: &str, events: &mut Vec<Value>) {
+    let name = &family.metric_name;
+    for m in family.data.iter().map(|m| m.as_ref()) {
+        let (kind, value) = match family.metric_type {
+            MetricType::Gauge if name.ends_with("_total") => ("counter", number(m, name)),
+            MetricType::Gauge | MetricType::Untyped => ("gauge", number(m, name)),
+            MetricType::Histogram => ("aggregated_histogram", Some(histogram(m, name))),
+            MetricType::Summary => ("aggregated_summary", Some(summary(m, name))),
+        };
+        if let Some(value) = value {
+            events.push(event(name, m.labels(), timestamp, kind, value));
+        }
+    }
+}
+
+/// Every series of a document as an event observed when it was scraped, or at `time` when that
+/// is not known, one a line
+pub fn render<D: Document>(document: &D, time: SystemTime) -> String {
+    let mut events = Vec::new();
+    for section in document.sections() {
+        let data = match section.data {
+            Some(data) => data,
+            None => continue,
+        };
+        let scraped_at = section.scraped_at.unwrap_or(time);
+        let timestamp = humantime::format_rfc3339_millis(scraped_at).to_string();
+        for family in &data.metrics {
+            family_events(family, &timestamp, &mut events);
+        }
+    }
+    let lines: Vec<String> = events.iter().map(Value::to_string).collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prom2jsonrs::PrometheusData;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn vector_events_work() {
+        let data = PrometheusData::from_string(
+            "# TYPE http_requests_total counter
+http_requests_total{code=\"200\"} 1027
+# TYPE up gauge
+up 1
+# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 2
+latency_bucket{le=\"1\"} 5
+latency_bucket{le=\"+Inf\"} 6
+latency_sum 3.5
+latency_count 6",
+        );
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let rendered = render(&data, time);
+        let events: Vec<Value> = rendered
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events[0],
+            json!({
+                "name": "http_requests_total",
+                "tags": {"code": "200"},
+                "timestamp": "2020-09-13T12:26:40.000Z",
+                "kind": "absolute",
+                "counter": {"value": 1027.0},
+            })
+        );
+        assert_eq!(events[1]["gauge"], json!({"value": 1.0}));
+        assert_eq!(
+            events[2]["aggregated_histogram"],
+            json!({
+                "buckets": [{"upper_limit": 0.1, "count": 2}, {"upper_limit": 1.0, "count": 3}],
+                "count": 6,
+                "sum": 3.5,
+            })
+        );
+    }
+}
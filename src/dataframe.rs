@@ -0,0 +1,60 @@
+//! Scrapes as polars DataFrames, for Rust analysis pipelines
+
+use crate::{PrometheusData, Sample};
+use polars::prelude::*;
+use std::collections::BTreeSet;
+
+impl PrometheusData {
+    /// Every sample as a row: the metric name in `__name__`, then a column per label name in
+    /// order, null where a sample does not have the label, then the value as a float in
+    /// `__value__`, null when it is not a number
+    pub fn to_dataframe(&self) -> PolarsResult<DataFrame> {
+        let samples = self.samples();
+        let label_names: BTreeSet<&str> = samples
+            .iter()
+            .flat_map(|s| s.labels.keys().map(String::as_str))
+            .collect();
+        let mut columns = Vec::with_capacity(label_names.len() + 2);
+        let names: Vec<&str> = samples.iter().map(|s| s.name.as_str()).collect();
+        columns.push(Column::new("__name__".into(), names));
+        for label in label_names {
+            let values: Vec<Option<&str>> = samples
+                .iter()
+                .map(|s| s.labels.get(label).map(String::as_str))
+                .collect();
+            columns.push(Column::new(label.into(), values));
+        }
+        let values: Vec<Option<f64>> = samples.iter().map(Sample::float_value).collect();
+        columns.push(Column::new("__value__".into(), values));
+        DataFrame::new(columns)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dataframe_conversion_works() {
+        let data = PrometheusData::from_string(
+            "# TYPE up gauge
+up{job=\"api\"} 1
+up{job=\"node\",zone=\"a\"} 0
+# TYPE latency histogram
+latency_bucket{le=\"+Inf\"} 3
+latency_sum 0.5
+latency_count 3",
+        );
+        let df = data.to_dataframe().unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            vec!["__name__", "job", "le", "zone", "__value__"]
+        );
+        assert_eq!(df.height(), 5);
+        let zone = df.column("zone").unwrap().str().unwrap();
+        assert_eq!(zone.get(0), None);
+        assert_eq!(zone.get(1), Some("a"));
+        let values = df.column("__value__").unwrap().f64().unwrap();
+        assert_eq!(values.get(2), Some(3.0));
+    }
+}
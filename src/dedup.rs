@@ -0,0 +1,167 @@
+//! Deduplication of the series scraped from the replicas of a highly available exporter pair,
+//! so that merging and aggregating their scrapes does not count each series twice
+
+use crate::{sorted_labels, MetricFamily, PrometheusData};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which copy of a series scraped from several replicas is kept
+pub enum DedupPolicy {
+    /// The one with the latest timestamp, the time of its scrape for series without one
+    Newest,
+    /// The one of this replica when there is one, the newest otherwise
+    Prefer(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Series differing only by the replica label `label` are copies of a single series
+pub struct Dedup {
+    pub label: String,
+    pub policy: DedupPolicy,
+}
+
+impl FromStr for Dedup {
+    type Err = String;
+
+    /// `label` to keep the newest copies, or `label=replica` to prefer those of a replica
+    fn from_str(s: &str) -> Result<Dedup, String> {
+        let (label, policy) = match s.split_once('=') {
+            Some((label, replica)) => (label, DedupPolicy::Prefer(replica.to_string())),
+            None => (s, DedupPolicy::Newest),
+        };
+        if label.is_empty() || matches!(&policy, DedupPolicy::Prefer(r) if r.is_empty()) {
+            return Err(format!(
+                "Invalid dedup {}, expected `label` or `label=replica`",
+                s
+            ));
+        }
+        Ok(Dedup {
+            label: label.to_string(),
+            policy,
+        })
+    }
+}
+
+/// A family, by its index, and the labels of one of its series
+type SeriesKey = (usize, Vec<(String, String)>);
+
+/// Merge the scrapes of replicas, each with the time it was scraped at in milliseconds, keeping
+/// one copy of every series as `dedup` says and dropping the replica label. Of copies equally
+/// good, the one scraped first in `scrapes` is kept.
+pub fn dedup_replicas(scrapes: Vec<(i64, PrometheusData)>, dedup: &Dedup) -> PrometheusData {
+    let mut merged = PrometheusData { metrics: vec![] };
+    let mut families: HashMap<String, usize> = HashMap::new();
+    // Where the copy kept of each series is, and how good it is: preferred, then newest
+    let mut kept: HashMap<SeriesKey, (usize, (bool, i64))> = HashMap::new();
+    for (scraped_at, data) in scrapes {
+        for family in data.metrics {
+            let f = *families
+                .entry(family.metric_name.clone())
+                .or_insert_with(|| {
+                    merged.metrics.push(MetricFamily {
+                        metric_type: family.metric_type,
+                        metric_name: family.metric_name.clone(),
+                        help: family.help.clone(),
+                        data: Vec::new(),
+                    });
+                    merged.metrics.len() - 1
+                });
+            for mut series in family.data {
+                let labels = series.labels_mut();
+                let replica = labels.as_mut().and_then(|l| l.remove(&dedup.label));
+                if labels.as_ref().is_some_and(|l| l.is_empty()) {
+                    *labels = None;
+                }
+                let time = series
+                    .timestamp_mut()
+                    .and_then(|t| *t)
+                    .unwrap_or(scraped_at);
+                let preferred = match &dedup.policy {
+                    DedupPolicy::Prefer(wanted) => replica.as_ref() == Some(wanted),
+                    DedupPolicy::Newest => false,
+                };
+                let rank = (preferred, time);
+                let series_data = &mut merged.metrics[f].data;
+                match kept.entry((f, sorted_labels(series.labels()))) {
+                    Entry::Occupied(mut entry) => {
+                        let (i, best) = entry.get_mut();
+                        if rank > *best {
+                            series_data[*i] = series;
+                            *best = rank;
+                        }
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert((series_data.len(), rank));
+                        series_data.push(series);
+                    }
+                }
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scrape(replica: &str, up: u32, timestamp: &str) -> PrometheusData {
+        let mut data = PrometheusData::from_string(&format!(
+            "# TYPE up gauge\nup{{job=\"api\"}} {} {}\n# TYPE load gauge\nload 1\n",
+            up, timestamp
+        ));
+        let labels = vec![("replica".to_string(), replica.to_string())];
+        data.add_labels(&labels.into_iter().collect());
+        data
+    }
+
+    fn values(data: &PrometheusData) -> Vec<String> {
+        data.samples()
+            .iter()
+            .map(|s| format!("{} {}", s.series_id(), s.value))
+            .collect()
+    }
+
+    #[test]
+    fn dedup_works() {
+        let newest: Dedup = "replica".parse().unwrap();
+        assert_eq!(newest.policy, DedupPolicy::Newest);
+        // The sample timestamps win over the times of the scrapes
+        let merged = dedup_replicas(
+            vec![
+                (2000, scrape("a", 1, "1000")),
+                (1000, scrape("b", 0, "1500")),
+            ],
+            &newest,
+        );
+        assert_eq!(values(&merged), vec!["up{job=\"api\"} 0", "load 1"]);
+        // Without timestamps, the latest scrape wins, the first on a tie
+        let merged = dedup_replicas(
+            vec![(1000, scrape("a", 1, "")), (2000, scrape("b", 0, ""))],
+            &newest,
+        );
+        assert_eq!(values(&merged), vec!["up{job=\"api\"} 0", "load 1"]);
+        let merged = dedup_replicas(
+            vec![(1000, scrape("a", 1, "")), (1000, scrape("b", 0, ""))],
+            &newest,
+        );
+        assert_eq!(values(&merged), vec!["up{job=\"api\"} 1", "load 1"]);
+
+        let prefer_a: Dedup = "replica=a".parse().unwrap();
+        let merged = dedup_replicas(
+            vec![(2000, scrape("b", 0, "")), (1000, scrape("a", 1, ""))],
+            &prefer_a,
+        );
+        assert_eq!(values(&merged), vec!["up{job=\"api\"} 1", "load 1"]);
+        // Other replicas are only used when the preferred one lacks the series
+        let merged = dedup_replicas(
+            vec![(1000, scrape("b", 0, "")), (2000, scrape("c", 1, ""))],
+            &prefer_a,
+        );
+        assert_eq!(values(&merged), vec!["up{job=\"api\"} 1", "load 1"]);
+        assert!("replica=".parse::<Dedup>().is_err());
+        assert!("".parse::<Dedup>().is_err());
+    }
+}
@@ -0,0 +1,372 @@
+//! Series derived from others with arithmetic expressions at conversion time, e.g.
+//! `mem_used_ratio = node_memory_Active_bytes / node_memory_MemTotal_bytes`
+
+use crate::Selector;
+use crate::{format_float, Labels, Metric, MetricFamily, MetricLike, MetricType, PrometheusData};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Op {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div => a / b,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Series(Selector),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Number(f64),
+    Series(Selector),
+    Op(Op),
+    Open,
+    Close,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let op = match c {
+            '+' => Some(Token::Op(Op::Add)),
+            '-' => Some(Token::Op(Op::Sub)),
+            '*' => Some(Token::Op(Op::Mul)),
+            '/' => Some(Token::Op(Op::Div)),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            _ => None,
+        };
+        if let Some(op) = op {
+            tokens.push(op);
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || (matches!(chars[i], '+' | '-') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let value = number
+                .parse()
+                .map_err(|_| format!("Invalid number {}", number))?;
+            tokens.push(Token::Number(value));
+        } else if is_name_char(c) || c == '{' {
+            let start = i;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            // The label matchers, whose quoted values may hold anything
+            if chars.get(i) == Some(&'{') {
+                let mut quoted = false;
+                while i < chars.len() {
+                    match chars[i] {
+                        '\\' if quoted => i += 1,
+                        '"' => quoted = !quoted,
+                        '}' if !quoted => break,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err("Missing closing brace".to_string());
+                }
+                i += 1;
+            }
+            let selector: String = chars[start..i].iter().collect();
+            tokens.push(Token::Series(selector.parse()?));
+        } else {
+            return Err(format!("Unexpected character {}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent over the tokens, `*` and `/` binding tighter than `+` and `-`
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(Token::Op(op @ (Op::Add | Op::Sub))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(Token::Op(op @ (Op::Mul | Op::Div))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op(Op::Sub)) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Op(Op::Add)) => self.unary(),
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Series(selector)) => Ok(Expr::Series(selector)),
+            Some(Token::Open) => {
+                let expr = self.sum()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("Missing closing parenthesis".to_string()),
+                }
+            }
+            _ => Err("Expected a number, a series or a parenthesis".to_string()),
+        }
+    }
+}
+
+/// The value of an expression: a number, or series by their labels
+enum Operand {
+    Scalar(f64),
+    Vector(Vec<(Labels, f64)>),
+}
+
+/// The identity of a series in vector matching: its sorted labels
+fn label_key(labels: &Labels) -> Vec<(&String, &String)> {
+    let mut key: Vec<_> = labels.iter().collect();
+    key.sort();
+    key
+}
+
+impl Expr {
+    fn eval(&self, data: &PrometheusData) -> Operand {
+        match self {
+            Expr::Number(value) => Operand::Scalar(*value),
+            Expr::Series(selector) => Operand::Vector(
+                data.samples()
+                    .into_iter()
+                    .filter(|s| selector.matches(s))
+                    .filter_map(|s| Some((s.float_value()?, s.labels)))
+                    .map(|(value, labels)| (labels, value))
+                    .collect(),
+            ),
+            Expr::Neg(expr) => match expr.eval(data) {
+                Operand::Scalar(value) => Operand::Scalar(-value),
+                Operand::Vector(series) => {
+                    Operand::Vector(series.into_iter().map(|(l, v)| (l, -v)).collect())
+                }
+            },
+            Expr::Binary(left, op, right) => match (left.eval(data), right.eval(data)) {
+                (Operand::Scalar(a), Operand::Scalar(b)) => Operand::Scalar(op.apply(a, b)),
+                (Operand::Vector(series), Operand::Scalar(b)) => Operand::Vector(
+                    series
+                        .into_iter()
+                        .map(|(l, a)| (l, op.apply(a, b)))
+                        .collect(),
+                ),
+                (Operand::Scalar(a), Operand::Vector(series)) => Operand::Vector(
+                    series
+                        .into_iter()
+                        .map(|(l, b)| (l, op.apply(a, b)))
+                        .collect(),
+                ),
+                (Operand::Vector(left), Operand::Vector(right)) => {
+                    let right: HashMap<_, f64> =
+                        right.iter().map(|(l, v)| (label_key(l), *v)).collect();
+                    let matched: Vec<(Labels, f64)> = left
+                        .iter()
+                        .filter_map(|(l, a)| {
+                            let b = right.get(&label_key(l))?;
+                            Some((l.clone(), op.apply(*a, *b)))
+                        })
+                        .collect();
+                    Operand::Vector(matched)
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(try_from = "String"))]
+/// A series derived from others: `name = expression`, the expression combining numbers and
+/// series selectors with `+`, `-`, `*`, `/` and parentheses
+pub struct Derivation {
+    pub name: String,
+    expression: String,
+    expr: Expr,
+}
+
+impl FromStr for Derivation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Derivation, String> {
+        let (name, expression) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid derivation {}, expected `name = expression`", s))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            return Err(format!("Invalid derived series name {}", name));
+        }
+        let invalid = |e: String| format!("Invalid expression {}: {}", expression.trim(), e);
+        let mut parser = Parser {
+            tokens: tokenize(expression).map_err(invalid)?,
+            position: 0,
+        };
+        let expr = parser.sum().map_err(invalid)?;
+        if parser.peek().is_some() {
+            return Err(invalid("Unexpected trailing tokens".to_string()));
+        }
+        Ok(Derivation {
+            name: name.to_string(),
+            expression: expression.trim().to_string(),
+            expr,
+        })
+    }
+}
+
+impl TryFrom<String> for Derivation {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Derivation, String> {
+        s.parse()
+    }
+}
+
+impl PrometheusData {
+    /// Add the series of a derivation as a gauge family, replacing any family of that name.
+    /// Operations between series match those with the same labels, as PromQL's one-to-one
+    /// matching does; operations with a number apply to every series. Series whose value is
+    /// not a number are left out, and nothing is added when no series match.
+    pub fn derive(&mut self, derivation: &Derivation) {
+        let series = match derivation.expr.eval(self) {
+            Operand::Scalar(value) => vec![(Labels::new(), value)],
+            Operand::Vector(series) => series,
+        };
+        if series.is_empty() {
+            return;
+        }
+        self.metrics.retain(|f| f.metric_name != derivation.name);
+        self.metrics.push(MetricFamily {
+            metric_type: MetricType::Gauge,
+            metric_name: derivation.name.clone(),
+            help: format!("Derived: {}", derivation.expression),
+            data: series
+                .into_iter()
+                .map(|(labels, value)| {
+                    Box::new(Metric {
+                        labels: Some(labels).filter(|l| !l.is_empty()),
+                        value: format_float(value),
+                        timestamp: None,
+                    }) as Box<dyn MetricLike>
+                })
+                .collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn derivation_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE node_memory_Active_bytes gauge
+node_memory_Active_bytes{instance=\"a\"} 1024
+node_memory_Active_bytes{instance=\"b\"} 512
+# TYPE node_memory_MemTotal_bytes gauge
+node_memory_MemTotal_bytes{instance=\"a\"} 4096
+node_memory_MemTotal_bytes{instance=\"c\"} 4096
+# TYPE rpc summary
+rpc{job=\"api\",quantile=\"0.5\"} 0.2
+rpc_sum{job=\"api\"} 3
+rpc_count{job=\"api\"} 12",
+        );
+        let derivation: Derivation =
+            "mem_used_ratio = node_memory_Active_bytes / node_memory_MemTotal_bytes"
+                .parse()
+                .unwrap();
+        data.derive(&derivation);
+        let family = data.metrics.last().unwrap();
+        assert_eq!(family.metric_name, "mem_used_ratio");
+        let samples: Vec<String> = family
+            .samples()
+            .iter()
+            .map(|s| format!("{} {}", s.series_id(), s.value))
+            .collect();
+        assert_eq!(samples, vec!["mem_used_ratio{instance=\"a\"} 0.25"]);
+
+        data.derive(
+            &"rpc_mean_ms = rpc_sum{job=\"api\"} / rpc_count * 1e3"
+                .parse()
+                .unwrap(),
+        );
+        data.derive(
+            &"free_pct = -(node_memory_Active_bytes{instance=~\"a|b\"} - 1024) / 512 * 100 + 1"
+                .parse()
+                .unwrap(),
+        );
+        let values: Vec<String> = data.metrics[data.metrics.len() - 2..]
+            .iter()
+            .flat_map(|f| f.samples())
+            .map(|s| s.value)
+            .collect();
+        assert_eq!(values, vec!["250", "1", "101"]);
+
+        data.derive(&"answer = (1 + 2) * 14".parse().unwrap());
+        assert_eq!(data.metrics.last().unwrap().samples()[0].value, "42");
+
+        assert!("x = a +".parse::<Derivation>().is_err());
+        assert!("x = (a".parse::<Derivation>().is_err());
+        assert!("x = a{job=\"1\"".parse::<Derivation>().is_err());
+        assert!("a / b".parse::<Derivation>().is_err());
+        assert!("x y = a".parse::<Derivation>().is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{Labels, MetricType, PrometheusData, Sample, Value};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// A series present on both sides whose value differs
+pub struct ValueChange {
+    pub name: String,
+    pub labels: Labels,
+    pub old: Value,
+    pub new: Value,
+    /// `new - old`, when both values are numbers. For a counter that was reset, its increase
+    /// since the reset: the new value.
+    pub delta: Option<f64>,
+    /// Whether the series is a counter whose value went down, i.e. that restarted from zero
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "std::ops::Not::not"))]
+    pub reset: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// A family whose `# HELP` or `# TYPE` differs between both sides
+pub struct MetadataChange {
+    pub family: String,
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// Structured differences between two parsed scrapes
+pub struct Diff {
+    pub added_families: Vec<String>,
+    pub removed_families: Vec<String>,
+    pub added: Vec<Sample>,
+    pub removed: Vec<Sample>,
+    pub changed: Vec<ValueChange>,
+    pub metadata: Vec<MetadataChange>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added_families.is_empty()
+            && self.removed_families.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.metadata.is_empty()
+    }
+}
+
+/// Whether the samples of a family count up: those of families named `*_total`, and the
+/// buckets, counts and sums of histograms and summaries
+pub(crate) fn is_counter(metric_type: MetricType, family: &str, sample: &str) -> bool {
+    match metric_type {
+        MetricType::Gauge | MetricType::Untyped => family.ends_with("_total"),
+        // Quantiles are the only samples named after the family
+        MetricType::Histogram | MetricType::Summary => sample != family,
+    }
+}
+
+/// Whether a counter went down from `previous` to `current`, i.e. restarted from zero
+pub fn is_counter_reset(previous: f64, current: f64) -> bool {
+    current < previous
+}
+
+/// The increase of a counter from `previous` to `current`, resets handled the way Prometheus'
+/// `rate` and `increase` do: a counter that went down restarted from zero, so it increased by
+/// its whole current value rather than by a large negative delta
+pub fn counter_increase(previous: f64, current: f64) -> f64 {
+    if is_counter_reset(previous, current) {
+        current
+    } else {
+        current - previous
+    }
+}
+
+/// The ids of the counter samples of a scrape
+fn counter_ids(data: &PrometheusData) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    for family in &data.metrics {
+        for sample in family.samples() {
+            if is_counter(family.metric_type, &family.metric_name, &sample.name) {
+                ids.insert(sample.series_id());
+            }
+        }
+    }
+    ids
+}
+
+fn samples_by_id(data: &PrometheusData) -> BTreeMap<String, Sample> {
+    data.samples()
+        .into_iter()
+        .map(|s| (s.series_id(), s))
+        .collect()
+}
+
+/// Compare two scrapes, reporting what changed going from `old` to `new`
+pub fn diff(old: &PrometheusData, new: &PrometheusData) -> Diff {
+    let mut result = Diff::default();
+
+    let old_families: HashMap<&str, _> = old
+        .metrics
+        .iter()
+        .map(|f| (f.metric_name.as_str(), f))
+        .collect();
+    let new_families: HashMap<&str, _> = new
+        .metrics
+        .iter()
+        .map(|f| (f.metric_name.as_str(), f))
+        .collect();
+    for family in &new.metrics {
+        match old_families.get(family.metric_name.as_str()) {
+            None => result.added_families.push(family.metric_name.clone()),
+            Some(previous) => {
+                if previous.metric_type != family.metric_type {
+                    result.metadata.push(MetadataChange {
+                        family: family.metric_name.clone(),
+                        field: "type".to_string(),
+                        old: format!("{:?}", previous.metric_type),
+                        new: format!("{:?}", family.metric_type),
+                    })
+                }
+                if previous.help != family.help {
+                    result.metadata.push(MetadataChange {
+                        family: family.metric_name.clone(),
+                        field: "help".to_string(),
+                        old: previous.help.clone(),
+                        new: family.help.clone(),
+                    })
+                }
+            }
+        }
+    }
+    for family in &old.metrics {
+        if !new_families.contains_key(family.metric_name.as_str()) {
+            result.removed_families.push(family.metric_name.clone());
+        }
+    }
+
+    let counters = counter_ids(new);
+    let mut old_samples = samples_by_id(old);
+    for (id, sample) in samples_by_id(new) {
+        match old_samples.remove(&id) {
+            None => result.added.push(sample),
+            Some(previous) if previous.value != sample.value => {
+                let counter = counters.contains(&id);
+                let (delta, reset) = match (previous.float_value(), sample.float_value()) {
+                    (Some(a), Some(b)) if counter && is_counter_reset(a, b) => {
+                        (Some(counter_increase(a, b)), true)
+                    }
+                    (Some(a), Some(b)) if !(a - b).is_nan() => (Some(b - a), false),
+                    _ => (None, false),
+                };
+                result.changed.push(ValueChange {
+                    name: sample.name,
+                    labels: sample.labels,
+                    old: previous.value,
+                    new: sample.value,
+                    delta,
+                    reset,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    result.removed = old_samples.into_values().collect();
+    result
+}
+
+#[derive(Debug, Clone, Default)]
+/// What `compare` leaves out of the comparison
+pub struct CompareOptions {
+    /// Only compare the families and series, not their values
+    pub ignore_values: bool,
+    /// Labels whose values change from one run to the next, e.g. `pod`
+    pub ignore_labels: Vec<String>,
+}
+
+/// Compare a conversion with the document it is expected to match, e.g. for contract tests of
+/// exporters: what `actual` adds, removes and changes from `expected`, leaving out the values
+/// and the labels `options` asks to. Series only told apart by ignored labels count once.
+pub fn compare(
+    expected: &PrometheusData,
+    actual: &PrometheusData,
+    options: &CompareOptions,
+) -> Diff {
+    let without_ignored_labels = |data: &PrometheusData| {
+        let mut data = data.clone();
+        for family in data.metrics.iter_mut() {
+            for series in family.data.iter_mut() {
+                if let Some(labels) = series.labels_mut() {
+                    labels.retain(|label, _| !options.ignore_labels.contains(label));
+                }
+            }
+        }
+        data
+    };
+    let mut result = diff(
+        &without_ignored_labels(expected),
+        &without_ignored_labels(actual),
+    );
+    if options.ignore_values {
+        result.changed.clear();
+    }
+    result
+}
+
+impl PrometheusData {
+    /// Only keep the series with a sample that is new or whose value changed since `previous`
+    pub fn retain_changed(&mut self, previous: &PrometheusData) {
+        let diff = diff(previous, self);
+        let changed: HashSet<String> = diff
+            .added
+            .iter()
+            .map(Sample::series_id)
+            .chain(diff.changed.into_iter().map(|c| {
+                Sample {
+                    name: c.name,
+                    labels: c.labels,
+                    value: c.new,
+                }
+                .series_id()
+            }))
+            .collect();
+        self.retain(|name, m| {
+            m.samples(name)
+                .iter()
+                .any(|s| changed.contains(&s.series_id()))
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+/// The values of the previous scrape by series id, kept across scrapes so each one is only
+/// read once to tell which of its series changed, rather than diffed against a copy of the
+/// previous scrape
+pub struct ChangeTracker {
+    previous: Option<HashMap<String, Value>>,
+}
+
+impl ChangeTracker {
+    pub fn new() -> ChangeTracker {
+        ChangeTracker::default()
+    }
+
+    /// Only keep the series of `data` with a sample that is new or whose value changed since
+    /// the previous call, remembering all of them for the next one. The first scrape is kept
+    /// whole.
+    pub fn retain_changed(&mut self, data: &mut PrometheusData) {
+        let previous = self.previous.take();
+        let mut current = HashMap::with_capacity(previous.as_ref().map_or(0, HashMap::len));
+        data.retain(|name, m| {
+            let mut changed = previous.is_none();
+            for sample in m.samples(name) {
+                let id = sample.series_id();
+                changed |= previous
+                    .as_ref()
+                    .is_some_and(|p| p.get(&id) != Some(&sample.value));
+                current.insert(id, sample.value);
+            }
+            changed
+        });
+        self.previous = Some(current);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diff_works() {
+        let old = PrometheusData::from_string(
+            "# HELP a A.
+# TYPE a gauge
+a{x=\"1\"} 1
+a{x=\"2\"} 2
+# HELP b B.
+# TYPE b counter
+b 5",
+        );
+        let new = PrometheusData::from_string(
+            "# HELP a The A.
+# TYPE a gauge
+a{x=\"1\"} 1
+a{x=\"3\"} 3
+# HELP c C.
+# TYPE c gauge
+c 5",
+        );
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added_families, vec!["c"]);
+        assert_eq!(diff.removed_families, vec!["b"]);
+        let added: Vec<String> = diff.added.iter().map(|s| s.series_id()).collect();
+        assert_eq!(added, vec!["a{x=\"3\"}", "c"]);
+        let removed: Vec<String> = diff.removed.iter().map(|s| s.series_id()).collect();
+        assert_eq!(removed, vec!["a{x=\"2\"}", "b"]);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.metadata[0].field, "help");
+
+        let changed = PrometheusData::from_string(
+            "# HELP a The A.
+# TYPE a gauge
+a{x=\"1\"} 4
+a{x=\"3\"} 3
+# HELP c C.
+# TYPE c gauge
+c 5",
+        );
+        let diff = super::diff(&new, &changed);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].delta, Some(3.0));
+        assert!(super::diff(&changed, &changed).is_empty());
+
+        let mut only_changed = changed.clone();
+        only_changed.retain_changed(&new);
+        let kept: Vec<String> = only_changed
+            .samples()
+            .iter()
+            .map(|s| s.series_id())
+            .collect();
+        assert_eq!(kept, vec!["a{x=\"1\"}"]);
+        only_changed.retain_changed(&changed);
+        assert!(only_changed.metrics.is_empty());
+
+        let restarted = PrometheusData::from_string(
+            "# TYPE requests_total counter
+requests_total 3
+# TYPE temperature gauge
+temperature 3",
+        );
+        let before = PrometheusData::from_string(
+            "# TYPE requests_total counter
+requests_total 100
+# TYPE temperature gauge
+temperature 100",
+        );
+        let diff = super::diff(&before, &restarted);
+        assert_eq!(diff.changed[0].name, "requests_total");
+        assert_eq!(
+            (diff.changed[0].delta, diff.changed[0].reset),
+            (Some(3.0), true)
+        );
+        assert_eq!(
+            (diff.changed[1].delta, diff.changed[1].reset),
+            (Some(-97.0), false)
+        );
+        assert_eq!(counter_increase(5.0, 8.0), 3.0);
+
+        let expected = PrometheusData::from_string(
+            "# TYPE up gauge
+up{job=\"node\",pod=\"node-1\"} 1",
+        );
+        let actual = PrometheusData::from_string(
+            "# TYPE up gauge
+up{job=\"node\",pod=\"node-2\"} 0",
+        );
+        let mut options = CompareOptions::default();
+        assert_eq!(compare(&expected, &actual, &options).added.len(), 1);
+        options.ignore_labels.push("pod".to_string());
+        assert_eq!(compare(&expected, &actual, &options).changed[0].new, "0");
+        options.ignore_values = true;
+        assert!(compare(&expected, &actual, &options).is_empty());
+        assert_eq!(
+            compare(&expected, &new, &options).removed_families,
+            vec!["up"]
+        );
+
+        let mut tracker = ChangeTracker::new();
+        for (data, kept) in [(&new, 3), (&changed, 1), (&changed, 0), (&new, 1)].iter() {
+            let mut data = (*data).clone();
+            tracker.retain_changed(&mut data);
+            assert_eq!(data.samples().len(), *kept);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{MetricFamily, MetricType, PrometheusData, Sample};
+use std::fmt::Write;
+
+pub(crate) fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+pub(crate) fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+impl Sample {
+    /// The exposition text line of the sample, labels sorted by name
+    pub fn to_line(&self) -> String {
+        if self.labels.is_empty() {
+            return format!("{} {}", self.name, self.value);
+        }
+        let mut labels: Vec<(&String, &String)> = self.labels.iter().collect();
+        labels.sort();
+        let labels: Vec<String> = labels
+            .into_iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+            .collect();
+        format!("{}{{{}}} {}", self.name, labels.join(","), self.value)
+    }
+}
+
+pub(crate) fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::Gauge => "gauge",
+        MetricType::Histogram => "histogram",
+        MetricType::Summary => "summary",
+        MetricType::Untyped => "untyped",
+    }
+}
+
+/// Render families back to Prometheus text exposition format. Families sharing a name (e.g.
+/// from several scrapes) are written once, with the series of all of them. Counters come out
+/// as gauges, as they are parsed as such.
+pub fn render_exposition<'a, I>(families: I) -> String
+where
+    I: IntoIterator<Item = &'a MetricFamily>,
+{
+    let mut grouped: Vec<(&MetricFamily, Vec<Sample>)> = Vec::new();
+    for family in families {
+        match grouped
+            .iter_mut()
+            .find(|(f, _)| f.metric_name == family.metric_name)
+        {
+            Some((_, samples)) => samples.extend(family.samples()),
+            None => grouped.push((family, family.samples())),
+        }
+    }
+    let mut text = String::new();
+    for (family, samples) in grouped {
+        if !family.help.is_empty() {
+            let _ = writeln!(
+                text,
+                "# HELP {} {}",
+                family.metric_name,
+                escape_help(&family.help)
+            );
+        }
+        let _ = writeln!(
+            text,
+            "# TYPE {} {}",
+            family.metric_name,
+            type_name(family.metric_type)
+        );
+        for sample in samples {
+            text.push_str(&sample.to_line());
+            text.push('\n');
+        }
+    }
+    text
+}
+
+impl PrometheusData {
+    /// Render back to Prometheus text exposition format
+    pub fn to_exposition(&self) -> String {
+        render_exposition(&self.metrics)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exposition_rendering_works() {
+        let raw = "# HELP rpc_duration_seconds RPC latency.
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{service=\"a\",quantile=\"0.5\"} 1
+rpc_duration_seconds{service=\"a\",quantile=\"0.9\"} 2
+rpc_duration_seconds_sum{service=\"a\"} 10
+rpc_duration_seconds_count{service=\"a\"} 5
+# HELP up Target up.
+# TYPE up gauge
+up{job=\"api\"} 1
+";
+        let data = PrometheusData::from_string(raw);
+        let text = data.to_exposition();
+        // Labels come out sorted
+        assert_eq!(
+            text,
+            raw.replace(
+                "{service=\"a\",quantile=\"0.5\"}",
+                "{quantile=\"0.5\",service=\"a\"}"
+            )
+            .replace(
+                "{service=\"a\",quantile=\"0.9\"}",
+                "{quantile=\"0.9\",service=\"a\"}"
+            )
+        );
+        let reparsed = PrometheusData::from_string(&text);
+        assert_eq!(reparsed.samples(), data.samples());
+
+        let other = PrometheusData::from_string(
+            "# HELP up Target up.\n# TYPE up gauge\nup{job=\"db\"} 0\n",
+        );
+        let text = render_exposition(data.metrics.iter().chain(&other.metrics));
+        assert_eq!(text.matches("# TYPE up gauge").count(), 1);
+        assert!(text.ends_with("up{job=\"api\"} 1\nup{job=\"db\"} 0\n"));
+
+        let sample = Sample::new("up".to_string(), &None, "1").with_label("path", "C:\\a \"b\"\n");
+        assert_eq!(sample.to_line(), "up{path=\"C:\\\\a \\\"b\\\"\\n\"} 1");
+    }
+}
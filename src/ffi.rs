@@ -0,0 +1,121 @@
+//! The parser for C and C++, declared in `include/prom2json.h` which cbindgen generates from
+//! this module
+
+use crate::PrometheusData;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::{panic, ptr};
+
+#[repr(C)]
+/// What `prom2json_parse` returns: either `json` or `error` is set, the other one is null. Both
+/// belong to the caller, who hands the result back to `prom2json_result_free`.
+pub struct Prom2JsonResult {
+    /// The parsed text, as the command line tool prints it
+    pub json: *mut c_char,
+    /// Why the text could not be parsed
+    pub error: *mut c_char,
+}
+
+impl Prom2JsonResult {
+    fn new(parsed: Result<String, String>) -> Prom2JsonResult {
+        // Neither JSON nor the messages below hold a nul byte
+        let owned = |s: String| CString::new(s).map_or(ptr::null_mut(), CString::into_raw);
+        match parsed {
+            Ok(json) => Prom2JsonResult {
+                json: owned(json),
+                error: ptr::null_mut(),
+            },
+            Err(error) => Prom2JsonResult {
+                json: ptr::null_mut(),
+                error: owned(error),
+            },
+        }
+    }
+}
+
+fn parse(text: &[u8]) -> Result<String, String> {
+    let text = std::str::from_utf8(text).map_err(|e| format!("Text is not UTF-8: {}", e))?;
+    let data = PrometheusData::try_from_string(text)?;
+    serde_json::to_string(&data).map_err(|e| e.to_string())
+}
+
+/// Parse the `len` bytes of exposition text at `text` into JSON.
+///
+/// # Safety
+///
+/// `text` must point to `len` readable bytes, or be null with `len` 0.
+#[no_mangle]
+pub unsafe extern "C" fn prom2json_parse(text: *const c_char, len: usize) -> Prom2JsonResult {
+    let text = if !text.is_null() {
+        std::slice::from_raw_parts(text as *const u8, len)
+    } else if len == 0 {
+        &[]
+    } else {
+        return Prom2JsonResult::new(Err("Text is null".to_string()));
+    };
+    // `parse` turns malformed text into an error itself, this is only a backstop for bugs: a
+    // panic must not unwind into C
+    let parsed = panic::catch_unwind(|| parse(text)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| panic.downcast_ref::<&str>().copied());
+        Err(message.unwrap_or("Cannot parse the text").to_string())
+    });
+    Prom2JsonResult::new(parsed)
+}
+
+/// Free the strings of a result of `prom2json_parse`.
+///
+/// # Safety
+///
+/// `result` must come from `prom2json_parse` and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn prom2json_result_free(result: Prom2JsonResult) {
+    for s in [result.json, result.error].iter() {
+        if !s.is_null() {
+            drop(CString::from_raw(*s));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn parsing_through_ffi_works() {
+        let text = "# TYPE up gauge\nup{job=\"a\"} 1\n";
+        unsafe {
+            let result = prom2json_parse(text.as_ptr() as *const c_char, text.len());
+            assert!(result.error.is_null());
+            let json = CStr::from_ptr(result.json).to_str().unwrap();
+            let data: PrometheusData = serde_json::from_str(json).unwrap();
+            assert_eq!(data.samples().len(), 1);
+            prom2json_result_free(result);
+
+            let invalid = [b'u', b'p', 0xff];
+            let result = prom2json_parse(invalid.as_ptr() as *const c_char, invalid.len());
+            assert!(result.json.is_null());
+            let error = CStr::from_ptr(result.error).to_str().unwrap();
+            assert!(error.starts_with("Text is not UTF-8"));
+            prom2json_result_free(result);
+
+            let malformed = "# TYPE up gauge\nup{job=\"a\" 1\n";
+            let result = prom2json_parse(malformed.as_ptr() as *const c_char, malformed.len());
+            assert!(result.json.is_null());
+            let error = CStr::from_ptr(result.error).to_str().unwrap();
+            assert_eq!(error, "Invalid format up{job=\"a\" 1");
+            prom2json_result_free(result);
+
+            for malformed in &["# TYPE up gaug\nup 1\n", "# TYPE up\n", "up{job=\"a\"}\n"] {
+                assert!(parse(malformed.as_bytes()).is_err(), "{}", malformed);
+            }
+
+            let result = prom2json_parse(ptr::null(), 0);
+            assert!(!result.json.is_null());
+            prom2json_result_free(result);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! The last scrapes of every target kept in memory, for rates and small time-series queries
+//! without external storage
+
+use crate::diff::{counter_increase, is_counter};
+use crate::{Labels, PrometheusData, Selector};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// The recent values of a series
+pub struct SeriesHistory {
+    pub name: String,
+    pub labels: Labels,
+    /// The time in milliseconds since the epoch and the value of every scrape with the series
+    pub points: Vec<(i64, f64)>,
+    /// The per-second increase over the points of a counter, a reset counting as an increase
+    /// by the whole new value as in Prometheus. None for other series and with a single point.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub rate: Option<f64>,
+}
+
+/// A ring buffer of the last scrapes of every target, the oldest dropped first
+pub struct ScrapeHistory {
+    capacity: usize,
+    targets: HashMap<String, VecDeque<(SystemTime, PrometheusData)>>,
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+impl ScrapeHistory {
+    /// Keep the last `capacity` scrapes of every target
+    pub fn new(capacity: usize) -> ScrapeHistory {
+        ScrapeHistory {
+            capacity: capacity.max(1),
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Add the data a target was scraped into at `time`, dropping its oldest scrape when full
+    pub fn record(&mut self, target: &str, time: SystemTime, data: PrometheusData) {
+        let scrapes = self.targets.entry(target.to_string()).or_default();
+        if scrapes.len() == self.capacity {
+            scrapes.pop_front();
+        }
+        scrapes.push_back((time, data));
+    }
+
+    /// The targets with scrapes
+    pub fn targets(&self) -> impl Iterator<Item = &str> {
+        self.targets.keys().map(String::as_str)
+    }
+
+    /// The scrapes of a target, oldest first, with the time of each
+    pub fn history(&self, target: &str) -> impl Iterator<Item = (SystemTime, &PrometheusData)> {
+        self.targets
+            .get(target)
+            .into_iter()
+            .flatten()
+            .map(|(time, data)| (*time, data))
+    }
+
+    /// The points of the series of a target matched by `selector`, in the order they first
+    /// appear, with the rate of the counters. Values that are not numbers are left out.
+    pub fn series(&self, target: &str, selector: &Selector) -> Vec<SeriesHistory> {
+        let mut series: Vec<(SeriesHistory, bool)> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for (time, data) in self.history(target) {
+            for family in &data.metrics {
+                for sample in family.samples() {
+                    let value = match sample.float_value() {
+                        Some(value) if selector.matches(&sample) => value,
+                        _ => continue,
+                    };
+                    let counter = is_counter(family.metric_type, &family.metric_name, &sample.name);
+                    let i = *index.entry(sample.series_id()).or_insert_with(|| {
+                        let history = SeriesHistory {
+                            name: sample.name,
+                            labels: sample.labels,
+                            points: Vec::new(),
+                            rate: None,
+                        };
+                        series.push((history, counter));
+                        series.len() - 1
+                    });
+                    series[i].0.points.push((millis(time), value));
+                }
+            }
+        }
+        series
+            .into_iter()
+            .map(|(mut history, counter)| {
+                if counter {
+                    history.rate = rate(&history.points);
+                }
+                history
+            })
+            .collect()
+    }
+}
+
+/// The per-second increase over counter points, none without two points apart in time
+fn rate(points: &[(i64, f64)]) -> Option<f64> {
+    let (first, last) = (points.first()?.0, points.last()?.0);
+    if last <= first {
+        return None;
+    }
+    let increase: f64 = points
+        .windows(2)
+        .map(|w| counter_increase(w[0].1, w[1].1))
+        .sum();
+    Some(increase / ((last - first) as f64 / 1000.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn scrape_history_works() {
+        let mut history = ScrapeHistory::new(3);
+        let scrape = |requests: u32, load: f64| {
+            PrometheusData::from_string(&format!(
+                "# TYPE requests_total counter
+requests_total{{code=\"200\"}} {}
+# TYPE load gauge
+load {}",
+                requests, load
+            ))
+        };
+        let at = |s| UNIX_EPOCH + Duration::from_secs(s);
+        for (i, &(requests, load)) in [(0, 1.0), (100, 2.0), (160, 0.5), (20, 1.5)]
+            .iter()
+            .enumerate()
+        {
+            history.record("node", at(10 * i as u64), scrape(requests, load));
+        }
+        history.record("other", at(0), scrape(1, 1.0));
+
+        let times: Vec<SystemTime> = history.history("node").map(|(time, _)| time).collect();
+        assert_eq!(times, vec![at(10), at(20), at(30)]);
+        assert_eq!(history.history("missing").count(), 0);
+        let mut targets: Vec<&str> = history.targets().collect();
+        targets.sort();
+        assert_eq!(targets, vec!["node", "other"]);
+
+        let series = history.series("node", &"requests_total".parse().unwrap());
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].labels["code"], "200");
+        assert_eq!(
+            series[0].points,
+            vec![(10_000, 100.0), (20_000, 160.0), (30_000, 20.0)]
+        );
+        // 60, then 20 after the reset, in 20 seconds
+        assert_eq!(series[0].rate, Some(4.0));
+
+        let series = history.series("node", &"{__name__=~\"load|none\"}".parse().unwrap());
+        assert_eq!(series[0].points.len(), 3);
+        assert_eq!(series[0].rate, None);
+    }
+}
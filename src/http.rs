@@ -0,0 +1,135 @@
+//! Fetching and parsing targets over http(s) with reqwest
+
+use crate::{LimitExceeded, ParseError, ParseLimits, PrometheusData, StreamParser};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Response;
+use std::fmt;
+
+#[derive(Debug)]
+/// Why a target could not be fetched or parsed
+pub enum FetchError {
+    Http(reqwest::Error),
+    Limit(LimitExceeded),
+    /// A line that cannot be read
+    Malformed(String),
+    Protobuf(crate::DecodeError),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Http(e) => e.fmt(f),
+            FetchError::Limit(e) => e.fmt(f),
+            FetchError::Malformed(e) => f.write_str(e),
+            FetchError::Protobuf(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> FetchError {
+        FetchError::Http(e)
+    }
+}
+
+impl From<LimitExceeded> for FetchError {
+    fn from(e: LimitExceeded) -> FetchError {
+        FetchError::Limit(e)
+    }
+}
+
+impl From<ParseError> for FetchError {
+    fn from(e: ParseError) -> FetchError {
+        match e {
+            ParseError::Limit(e) => FetchError::Limit(e),
+            ParseError::Malformed(e) => FetchError::Malformed(e),
+        }
+    }
+}
+
+/// Whether a response is in the protobuf exposition format rather than text
+fn is_protobuf(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/vnd.google.protobuf"))
+}
+
+impl PrometheusData {
+    /// Fetch and parse the metrics exposed at an http(s) `url`
+    pub async fn fetch(url: &str) -> Result<PrometheusData, FetchError> {
+        let response = reqwest::get(url).await?.error_for_status()?;
+        PrometheusData::from_response(response, ParseLimits::default()).await
+    }
+
+    /// Parse a response within `limits`, text as it arrives rather than buffered whole. A
+    /// response in the protobuf exposition format is decoded once whole.
+    pub async fn from_response(
+        response: Response,
+        limits: ParseLimits,
+    ) -> Result<PrometheusData, FetchError> {
+        PrometheusData::from_response_with(response, StreamParser::with_limits(limits)).await
+    }
+
+    /// Parse a response like `from_response`, text with `parser` and within its limits
+    pub async fn from_response_with(
+        mut response: Response,
+        mut parser: StreamParser,
+    ) -> Result<PrometheusData, FetchError> {
+        let limits = parser.limits;
+        if is_protobuf(&response) {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                bytes.extend_from_slice(&chunk);
+                let limit = limits.max_payload_bytes;
+                crate::limits::check(bytes.len(), limit, LimitExceeded::PayloadBytes)?;
+            }
+            return PrometheusData::from_protobuf(&bytes).map_err(FetchError::Protobuf);
+        }
+        while let Some(chunk) = response.chunk().await? {
+            parser.feed(&chunk)?;
+        }
+        Ok(parser.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Answer one request on a local port with `content_type` and `body`
+    fn serve_once(content_type: &'static str, body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn fetching_works() {
+        let url = serve_once("text/plain", b"# TYPE up gauge\nup{job=\"a\"} 1\n");
+        let data = PrometheusData::fetch(&url).await.unwrap();
+        assert_eq!(data.samples().len(), 1);
+
+        // A protobuf payload cut short
+        let url = serve_once(crate::PROTOBUF_CONTENT_TYPE, b"\x02\x0a");
+        let result = PrometheusData::fetch(&url).await;
+        assert!(matches!(result, Err(FetchError::Protobuf(_))));
+    }
+}
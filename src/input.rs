@@ -0,0 +1,377 @@
+//! The formats metrics are read in, told apart from their first bytes, and OpenMetrics text
+//! rewritten into the Prometheus text format the parser reads
+
+use crate::limits::{self, LimitExceeded, ParseError};
+use crate::{ParseLimits, PrometheusData, StreamParser};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A format metrics can be read in
+pub enum InputFormat {
+    /// The Prometheus text exposition format
+    Text,
+    OpenMetrics,
+    /// Length-delimited `MetricFamily` messages, as scraped with `--protobuf`
+    Protobuf,
+    /// Documents converted before, e.g. by `convert --format json`
+    Json,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputFormat, String> {
+        match s {
+            "text" | "prometheus" => Ok(InputFormat::Text),
+            "openmetrics" => Ok(InputFormat::OpenMetrics),
+            "protobuf" => Ok(InputFormat::Protobuf),
+            "json" => Ok(InputFormat::Json),
+            other => Err(format!(
+                "Unknown input format {}, expected text, openmetrics, protobuf or json",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            InputFormat::Text => "text",
+            InputFormat::OpenMetrics => "openmetrics",
+            InputFormat::Protobuf => "protobuf",
+            InputFormat::Json => "json",
+        })
+    }
+}
+
+/// The types only OpenMetrics has
+const OPENMETRICS_TYPES: [&str; 4] = ["info", "stateset", "gaugehistogram", "unknown"];
+
+impl InputFormat {
+    /// Guess the format of a payload from its first bytes: protobuf has control characters no
+    /// text has, JSON starts with a document or a list of them, and OpenMetrics is told from
+    /// Prometheus text by its `# EOF` (when `head` goes that far), `# UNIT` lines or types
+    pub fn detect(head: &[u8]) -> InputFormat {
+        let binary = head
+            .iter()
+            .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r'));
+        // A cut in the middle of a character is still text
+        let invalid = matches!(std::str::from_utf8(head), Err(e) if e.error_len().is_some());
+        if binary || invalid {
+            return InputFormat::Protobuf;
+        }
+        match head.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') | Some(b'[') => return InputFormat::Json,
+            _ => {}
+        }
+        let text = String::from_utf8_lossy(head);
+        let openmetrics = text.lines().any(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("#"), Some("EOF")) | (Some("#"), Some("UNIT")) => true,
+                (Some("#"), Some("TYPE")) => {
+                    words.nth(1).is_some_and(|t| OPENMETRICS_TYPES.contains(&t))
+                }
+                _ => false,
+            }
+        });
+        if openmetrics {
+            InputFormat::OpenMetrics
+        } else {
+            InputFormat::Text
+        }
+    }
+
+    /// Parse a whole payload of this format, failing when it goes over `limits`
+    pub fn parse(self, bytes: &[u8], limits: ParseLimits) -> Result<PrometheusData, String> {
+        self.parse_with(bytes, StreamParser::with_limits(limits))
+    }
+
+    /// Parse a whole payload like `parse`, text with `parser` and within its limits
+    pub fn parse_with(self, bytes: &[u8], parser: StreamParser) -> Result<PrometheusData, String> {
+        let limits = parser.limits;
+        let parse_text = |text: &str| -> Result<PrometheusData, ParseError> {
+            let mut parser = parser;
+            parser.feed(text.as_bytes())?;
+            parser.finish()
+        };
+        let payload = || {
+            limits::check(
+                bytes.len(),
+                limits.max_payload_bytes,
+                LimitExceeded::PayloadBytes,
+            )
+        };
+        match self {
+            InputFormat::Text => parse_text(&String::from_utf8_lossy(bytes)),
+            InputFormat::OpenMetrics => {
+                parse_text(&openmetrics_to_text(&String::from_utf8_lossy(bytes)))
+            }
+            InputFormat::Protobuf => {
+                payload().map_err(|e| e.to_string())?;
+                return PrometheusData::from_protobuf(bytes).map_err(|e| e.to_string());
+            }
+            InputFormat::Json => {
+                payload().map_err(|e| e.to_string())?;
+                return from_json(bytes);
+            }
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// The families of JSON documents, a stream of them or lists of them (as `scrape` prints),
+/// merged into one. Documents without metrics, as those of failed targets, are skipped.
+#[cfg(feature = "serde")]
+fn from_json(bytes: &[u8]) -> Result<PrometheusData, String> {
+    use serde_json::Value;
+
+    let mut data = PrometheusData { metrics: vec![] };
+    for value in serde_json::Deserializer::from_slice(bytes).into_iter::<Value>() {
+        let documents = match value.map_err(|e| format!("Invalid JSON: {}", e))? {
+            Value::Array(documents) => documents,
+            document => vec![document],
+        };
+        for document in documents {
+            if document.get("metrics").is_none() {
+                continue;
+            }
+            let document: PrometheusData =
+                serde_json::from_value(document).map_err(|e| format!("Invalid document: {}", e))?;
+            data.merge(document);
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(not(feature = "serde"))]
+fn from_json(_: &[u8]) -> Result<PrometheusData, String> {
+    Err("Reading JSON needs the serde feature".to_string())
+}
+
+/// The name and type a family of OpenMetrics type `kind` takes in the Prometheus text format:
+/// counters and info families are named after their samples, and the types Prometheus text
+/// lacks fall back on the closest one
+fn text_family(name: &str, kind: &str) -> (String, &'static str) {
+    match kind {
+        "counter" if name.ends_with("_total") => (name.to_string(), "counter"),
+        "counter" => (format!("{}_total", name), "counter"),
+        "info" => (format!("{}_info", name), "gauge"),
+        "stateset" => (name.to_string(), "gauge"),
+        "gaugehistogram" | "histogram" => (name.to_string(), "histogram"),
+        "summary" => (name.to_string(), "summary"),
+        "gauge" => (name.to_string(), "gauge"),
+        _ => (name.to_string(), "untyped"),
+    }
+}
+
+/// Where the series of a sample line ends, past its labels if any
+fn series_end(line: &str) -> usize {
+    let bytes = line.as_bytes();
+    let name_end = bytes
+        .iter()
+        .position(|&b| b == b'{' || b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    if bytes.get(name_end) != Some(&b'{') {
+        return name_end;
+    }
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, &b) in bytes.iter().enumerate().skip(name_end + 1) {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            b'}' if !quoted => return i + 1,
+            _ => {}
+        }
+    }
+    bytes.len()
+}
+
+/// Rewrite OpenMetrics text into the Prometheus text format: counters and info families are
+/// renamed after their `_total` and `_info` samples, gauge histograms read as histograms,
+/// timestamps go from seconds to milliseconds, and `# EOF`, `# UNIT` lines, `_created`
+/// samples and exemplars are left out
+pub fn openmetrics_to_text(s: &str) -> String {
+    // Metadata may come in any order, so types are all known before rewriting
+    let mut types = HashMap::new();
+    for line in s.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("TYPE"), Some(name), Some(kind)) =
+            (words.next(), words.next(), words.next(), words.next())
+        {
+            types.insert(name, kind);
+        }
+    }
+    // The suffix a sample carries after its family name, and the type of the family
+    let family_of = |sample: &str| -> Option<(&str, &str)> {
+        let suffixes = [
+            "", "_total", "_created", "_info", "_bucket", "_count", "_sum", "_gcount", "_gsum",
+        ];
+        suffixes.iter().find_map(|suffix| {
+            let name = sample.strip_suffix(suffix)?;
+            types.get(name).map(|&kind| (*suffix, kind))
+        })
+    };
+
+    let mut out = String::with_capacity(s.len());
+    for line in s.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix("# ") {
+            // The help text is kept as it is, spacing included
+            let mut words = comment.splitn(3, ' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some("EOF"), ..) | (Some("UNIT"), ..) => {}
+                (Some(keyword @ "TYPE"), Some(name), text)
+                | (Some(keyword @ "HELP"), Some(name), text) => {
+                    let kind = types.get(name).copied().unwrap_or("untyped");
+                    let (family, text_kind) = text_family(name, kind);
+                    let rest = match keyword {
+                        "TYPE" => text_kind,
+                        _ => text.unwrap_or(""),
+                    };
+                    out.push_str(&format!("# {} {} {}\n", keyword, family, rest));
+                }
+                _ => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            continue;
+        }
+        let end = series_end(line);
+        let (mut series, rest) = (line[..end].to_string(), &line[end..]);
+        let name_end = series.find('{').unwrap_or(series.len());
+        match family_of(&series[..name_end]) {
+            Some(("_created", "counter" | "histogram" | "summary" | "gaugehistogram")) => continue,
+            Some((suffix @ "_gcount", "gaugehistogram"))
+            | Some((suffix @ "_gsum", "gaugehistogram")) => {
+                let renamed = if suffix == "_gcount" {
+                    "_count"
+                } else {
+                    "_sum"
+                };
+                series.replace_range(name_end - suffix.len()..name_end, renamed);
+            }
+            _ => {}
+        }
+        // Exemplars follow a `#`, which cannot be in a value or a timestamp
+        let mut values = rest.split('#').next().unwrap_or("").split_whitespace();
+        out.push_str(&series);
+        if let Some(value) = values.next() {
+            out.push(' ');
+            out.push_str(value);
+        }
+        if let Some(timestamp) = values.next() {
+            match timestamp.parse::<f64>() {
+                Ok(seconds) => out.push_str(&format!(" {}", (seconds * 1000.0).round() as i64)),
+                Err(_) => {
+                    out.push(' ');
+                    out.push_str(timestamp);
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OPENMETRICS: &str = "# HELP requests Requests  served.
+# TYPE requests counter
+# UNIT requests requests
+requests_total{path=\"/a # b\"} 3 1700000000.5 # {trace_id=\"abc\"} 1
+requests_created{path=\"/a # b\"} 1600000000
+# TYPE build info
+build_info{version=\"1.2\"} 1
+# TYPE queue gaugehistogram
+queue_bucket{le=\"+Inf\"} 4
+queue_gcount 4
+queue_gsum 12
+# TYPE door stateset
+door{door=\"open\"} 1
+# EOF
+";
+
+    #[test]
+    fn openmetrics_works() {
+        assert_eq!(
+            openmetrics_to_text(OPENMETRICS),
+            "# HELP requests_total Requests  served.
+# TYPE requests_total counter
+requests_total{path=\"/a # b\"} 3 1700000000500
+# TYPE build_info gauge
+build_info{version=\"1.2\"} 1
+# TYPE queue histogram
+queue_bucket{le=\"+Inf\"} 4
+queue_count 4
+queue_sum 12
+# TYPE door gauge
+door{door=\"open\"} 1
+"
+        );
+        let data = InputFormat::OpenMetrics
+            .parse(OPENMETRICS.as_bytes(), ParseLimits::default())
+            .unwrap();
+        let names: Vec<&str> = data.metrics.iter().map(|f| &*f.metric_name).collect();
+        assert_eq!(names, vec!["requests_total", "build_info", "queue", "door"]);
+    }
+
+    #[test]
+    fn detection_works() {
+        let text = "# HELP up Up.\n# TYPE up gauge\nup 1\n";
+        assert_eq!(InputFormat::detect(text.as_bytes()), InputFormat::Text);
+        assert_eq!(
+            InputFormat::detect(OPENMETRICS.as_bytes()),
+            InputFormat::OpenMetrics
+        );
+        assert_eq!(
+            InputFormat::detect(b"# TYPE up gauge\nup 1\n# EOF\n"),
+            InputFormat::OpenMetrics
+        );
+        assert_eq!(
+            InputFormat::detect(b"\n  {\"metrics\": []}"),
+            InputFormat::Json
+        );
+        assert_eq!(InputFormat::detect(b"[]"), InputFormat::Json);
+        // A length prefix, then the tag of the family name
+        assert_eq!(
+            InputFormat::detect(b"\x0c\x0a\x02up\x18\x01"),
+            InputFormat::Protobuf
+        );
+        // Cut in the middle of a character
+        assert_eq!(
+            InputFormat::detect("up 1 # é".as_bytes()[..9].as_ref()),
+            InputFormat::Text
+        );
+        assert!("yaml".parse::<InputFormat>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_works() {
+        let data = PrometheusData::from_string("# TYPE up gauge\nup{job=\"a\"} 1\n");
+        let document = serde_json::to_string(&data).unwrap();
+        let targets = format!(
+            "[{}, {{\"target\": \"b\", \"error\": \"refused\"}}]\n{}",
+            document, document
+        );
+        let parsed = InputFormat::Json
+            .parse(targets.as_bytes(), ParseLimits::default())
+            .unwrap();
+        assert_eq!(parsed.metrics.len(), 1);
+        assert_eq!(parsed.metrics[0].data.len(), 2);
+        assert_eq!(parsed.metrics[0].samples()[0], data.samples()[0]);
+        assert!(InputFormat::Json
+            .parse(b"{\"metrics\": 1}", ParseLimits::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,80 @@
+//! Digests of payloads and of converted data, so that archived copies can be checked for
+//! tampering or truncation
+
+use crate::exposition::{escape_help, type_name};
+use crate::privacy::hex;
+use crate::{MetricFamily, PrometheusData};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The SHA-256 of `bytes`, as hex
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+impl PrometheusData {
+    /// The data in a form that does not depend on the order of its families, series and labels:
+    /// the exposition text of the families sorted by name (those sharing a name written once),
+    /// each with its sample lines sorted. Sample timestamps are left out, as in
+    /// `render_exposition`.
+    pub fn canonical_text(&self) -> String {
+        let mut families: BTreeMap<&str, Vec<&MetricFamily>> = BTreeMap::new();
+        for family in &self.metrics {
+            families
+                .entry(&family.metric_name)
+                .or_default()
+                .push(family);
+        }
+        let mut text = String::new();
+        for (name, group) in families {
+            let _ = writeln!(text, "# HELP {} {}", name, escape_help(&group[0].help));
+            let _ = writeln!(text, "# TYPE {} {}", name, type_name(group[0].metric_type));
+            let mut lines: Vec<String> = group
+                .iter()
+                .flat_map(|f| f.samples())
+                .map(|s| s.to_line())
+                .collect();
+            lines.sort();
+            for line in lines {
+                text.push_str(&line);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// The SHA-256 of the canonical text of the data, as hex
+    pub fn content_sha256(&self) -> String {
+        sha256_hex(self.canonical_text().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digests_work() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let data = PrometheusData::from_string(
+            "# TYPE up gauge\nup{job=\"b\",env=\"prod\"} 1\nup{job=\"a\"} 0\n# TYPE load gauge\nload 2\n",
+        );
+        assert_eq!(
+            data.canonical_text(),
+            "# HELP load \n# TYPE load gauge\nload 2\n# HELP up \n# TYPE up gauge\nup{env=\"prod\",job=\"b\"} 1\nup{job=\"a\"} 0\n"
+        );
+        // The same series in another order hash the same, a changed value does not
+        let reordered = PrometheusData::from_string(
+            "# TYPE load gauge\nload 2\n# TYPE up gauge\nup{job=\"a\"} 0\nup{env=\"prod\",job=\"b\"} 1\n",
+        );
+        assert_eq!(reordered.content_sha256(), data.content_sha256());
+        let changed = PrometheusData::from_string(
+            "# TYPE load gauge\nload 3\n# TYPE up gauge\nup{job=\"a\"} 0\nup{env=\"prod\",job=\"b\"} 1\n",
+        );
+        assert_ne!(changed.content_sha256(), data.content_sha256());
+    }
+}
@@ -0,0 +1,188 @@
+//! Joins of the labels of `*_info` families onto the other series, as PromQL's
+//! `* on(instance) group_left(release) node_uname_info` does, at conversion time
+
+use crate::{Labels, PrometheusData};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(deny_unknown_fields))]
+/// Copy labels of the series of an info family onto the series sharing its identifying labels
+pub struct InfoJoin {
+    /// The info family, e.g. `node_uname_info`
+    pub info: String,
+    /// The labels a series and an info series must share, e.g. `instance`
+    pub on: Vec<String>,
+    /// The labels copied from the info series, all of them but the `on` ones by default
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub labels: Vec<String>,
+}
+
+/// The labels of a list in parentheses, e.g. `(a, b)`, and what follows
+fn label_list(s: &str) -> Option<(Vec<String>, &str)> {
+    let s = s.trim_start().strip_prefix('(')?;
+    let end = s.find(')')?;
+    let labels = s[..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect();
+    Some((labels, &s[end + 1..]))
+}
+
+impl FromStr for InfoJoin {
+    type Err = String;
+
+    /// `<info family> on(label, ...)`, optionally followed by `group_left(label, ...)`
+    fn from_str(s: &str) -> Result<InfoJoin, String> {
+        let invalid = || {
+            format!(
+                "Invalid join {}, expected `info_family on(label, ...) [group_left(label, ...)]`",
+                s
+            )
+        };
+        let s = s.trim();
+        let (info, rest) = s.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let rest = rest.trim_start().strip_prefix("on").ok_or_else(invalid)?;
+        let (on, rest) = label_list(rest).ok_or_else(invalid)?;
+        let rest = rest.trim();
+        let labels = if rest.is_empty() {
+            Vec::new()
+        } else {
+            let rest = rest.strip_prefix("group_left").ok_or_else(invalid)?;
+            match label_list(rest) {
+                Some((labels, rest)) if rest.trim().is_empty() => labels,
+                _ => return Err(invalid()),
+            }
+        };
+        if on.is_empty() {
+            return Err(invalid());
+        }
+        Ok(InfoJoin {
+            info: info.to_string(),
+            on,
+            labels,
+        })
+    }
+}
+
+impl InfoJoin {
+    /// The values of the `on` labels of a series, missing labels being empty as in PromQL
+    fn key(&self, labels: Option<&Labels>) -> Vec<String> {
+        self.on
+            .iter()
+            .map(|l| labels.and_then(|ls| ls.get(l)).cloned().unwrap_or_default())
+            .collect()
+    }
+
+    /// The labels copied from an info series
+    fn copied(&self, labels: &Labels) -> Labels {
+        labels
+            .iter()
+            .filter(|(k, _)| {
+                if self.labels.is_empty() {
+                    !self.on.contains(k)
+                } else {
+                    self.labels.contains(k)
+                }
+            })
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+impl PrometheusData {
+    /// Add the labels of the series of the info family of `join` to every other series with the
+    /// same values of its `on` labels, replacing labels of the same name as PromQL's
+    /// `group_left` does. When several info series share these values, the first one is joined.
+    pub fn join_info(&mut self, join: &InfoJoin) {
+        let mut info: HashMap<Vec<String>, Labels> = HashMap::new();
+        for family in self.metrics.iter().filter(|f| f.metric_name == join.info) {
+            for series in &family.data {
+                let labels = match series.labels() {
+                    Some(labels) => labels,
+                    None => continue,
+                };
+                info.entry(join.key(Some(labels)))
+                    .or_insert_with(|| join.copied(labels));
+            }
+        }
+        if info.is_empty() {
+            return;
+        }
+        for family in self
+            .metrics
+            .iter_mut()
+            .filter(|f| f.metric_name != join.info)
+        {
+            for series in family.data.iter_mut() {
+                let copied = match info.get(&join.key(series.labels())) {
+                    Some(copied) => copied,
+                    None => continue,
+                };
+                let labels = series.labels_mut().get_or_insert_with(Labels::new);
+                for (key, value) in copied {
+                    labels.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn info_join_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE node_uname_info gauge
+node_uname_info{instance=\"a\",release=\"6.1\",machine=\"x86_64\"} 1
+node_uname_info{instance=\"b\",release=\"5.15\",machine=\"arm64\"} 1
+# TYPE node_load1 gauge
+node_load1{instance=\"a\"} 0.5
+node_load1{instance=\"b\"} 2
+node_load1{instance=\"c\"} 1",
+        );
+        data.join_info(
+            &"node_uname_info on(instance) group_left(release)"
+                .parse()
+                .unwrap(),
+        );
+        let samples: Vec<String> = data.metrics[1]
+            .samples()
+            .iter()
+            .map(|s| s.series_id())
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                "node_load1{instance=\"a\",release=\"6.1\"}",
+                "node_load1{instance=\"b\",release=\"5.15\"}",
+                "node_load1{instance=\"c\"}",
+            ]
+        );
+
+        data.join_info(&"node_uname_info on (instance)".parse().unwrap());
+        let labels = data.metrics[1].data[1].labels().unwrap();
+        assert_eq!(labels["machine"], "arm64");
+        assert_eq!(data.metrics[0].data[0].labels().unwrap().len(), 3);
+
+        assert_eq!(
+            "build_info on(job, instance) group_left(version, revision)".parse(),
+            Ok(InfoJoin {
+                info: "build_info".to_string(),
+                on: vec!["job".to_string(), "instance".to_string()],
+                labels: vec!["version".to_string(), "revision".to_string()],
+            })
+        );
+        assert!("build_info".parse::<InfoJoin>().is_err());
+        assert!("build_info on()".parse::<InfoJoin>().is_err());
+        assert!("build_info on(job) group_right(x)"
+            .parse::<InfoJoin>()
+            .is_err());
+    }
+}
@@ -1,280 +1,1374 @@
-use regex::Regex;
+use arena::{Arena, ArenaVec};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
-#[macro_use]
-extern crate lazy_static;
+#[cfg(test)]
 #[macro_use]
 extern crate maplit;
 
-lazy_static! {
-    static ref METRIC_REGEX_NO_LABEL: Regex =
-        Regex::new(r"([a-zA-Z_:][a-zA-Z0-9_:]*)\s(-?[\d.]+(?:e-?\d+)?|NaN)").unwrap();
-    static ref METRIC_REGEX_WITH_LABEL: Regex =
-        Regex::new(r"[a-zA-Z_:][a-zA-Z0-9_:]*\{(.*)\}\s(-?[\d.]+(?:e-?\d+)?|NaN)").unwrap();
-    static ref LABELS_REGEX: Regex = Regex::new("([a-zA-Z0-9_:]*)=\"([^\"]+)\"").unwrap();
-}
+mod aggregate;
+mod anomaly;
+mod arena;
+#[cfg(feature = "polars")]
+mod dataframe;
+mod dedup;
+mod derive;
+mod diff;
+mod exposition;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod history;
+#[cfg(feature = "http-client")]
+mod http;
+mod input;
+mod integrity;
+mod join;
+mod limits;
+mod lint;
+#[cfg(feature = "serde")]
+mod newrelic;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod privacy;
+mod protobuf;
+#[cfg(feature = "serde")]
+mod query;
+mod rates;
+mod relabel;
+mod remote_write;
+mod scan;
+mod selector;
+mod slo;
+mod stats;
+#[cfg(feature = "datafusion")]
+mod table;
+mod units;
+#[cfg(feature = "wasm")]
+mod wasm;
 
-type Labels = HashMap<String, String>;
-type Value = String;
+pub use aggregate::{Aggregation, AggregationOp, OVERFLOW_LABEL};
+pub use anomaly::{Anomaly, AnomalyDetector};
+pub use dedup::{dedup_replicas, Dedup, DedupPolicy};
+pub use derive::Derivation;
+pub use diff::{
+    compare, counter_increase, diff, is_counter_reset, ChangeTracker, CompareOptions, Diff,
+};
+pub use diff::{MetadataChange, ValueChange};
+pub use exposition::render_exposition;
+pub use history::{ScrapeHistory, SeriesHistory};
+#[cfg(feature = "http-client")]
+pub use http::FetchError;
+pub use input::{openmetrics_to_text, InputFormat};
+pub use integrity::sha256_hex;
+pub use join::InfoJoin;
+pub use limits::{LimitExceeded, ParseError, ParseLimits};
+pub use lint::{lint, Issue, Severity};
+#[cfg(feature = "serde")]
+pub use newrelic::newrelic_payload;
+#[cfg(feature = "otlp")]
+pub use otlp::{otlp_metrics_request, ExportMetricsServiceRequest};
+pub use privacy::{HashAlgorithm, PrivacyConfig, REDACTED};
+pub use protobuf::{DecodeError, PROTOBUF_CONTENT_TYPE};
+pub use rates::CounterState;
+pub use relabel::{RelabelAction, RelabelConfig, Relabeler};
+pub use remote_write::remote_write_body;
+pub use selector::{LabelMatcher, MatchOp, Selector};
+pub use slo::SloThreshold;
+pub use stats::{stats, FamilyStats, LabelStats, Stats};
+#[cfg(feature = "datafusion")]
+pub use table::scrapes_table;
+pub use units::{Unit, UnitConversion, UnitConverter};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Metric {
-    labels: Option<Labels>,
-    value: Value,
+pub type Labels = HashMap<String, String>;
+pub type Value = String;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A plain gauge, counter or untyped sample
+pub struct Metric {
+    pub labels: Option<Labels>,
+    pub value: Value,
+    /// Milliseconds since the epoch, when the exposition line carries a timestamp (as
+    /// `/federate` output does)
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub timestamp: Option<i64>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Summary {
-    labels: Option<Labels>,
-    quantiles: Labels,
-    count: Value,
-    sum: Value,
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A summary, keyed by quantile
+pub struct Summary {
+    pub labels: Option<Labels>,
+    pub quantiles: Labels,
+    pub count: Value,
+    pub sum: Value,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Histogram {
-    labels: Option<HashMap<String, String>>,
-    buckets: Labels,
-    count: Value,
-    sum: Value,
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A histogram, keyed by the `le` bucket bound
+pub struct Histogram {
+    pub labels: Option<HashMap<String, String>>,
+    pub buckets: Labels,
+    pub count: Value,
+    pub sum: Value,
+    /// Quantiles estimated from the buckets, keyed like those of a summary, once asked for with
+    /// `PrometheusData::add_percentiles`
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub percentiles: Option<Labels>,
 }
 
-#[derive(Debug, PartialEq, Serialize)]
-enum MetricType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MetricType {
     Gauge,
     Histogram,
     Summary,
+    Untyped,
+}
+
+impl Clone for Box<dyn MetricLike> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
 }
 
-#[derive(Serialize)]
-struct MetricFamily {
-    metric_type: MetricType,
-    metric_name: String,
-    help: String,
-    data: Vec<Box<dyn MetricLike>>,
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// All the series sharing a single `# HELP`/`# TYPE` header
+pub struct MetricFamily {
+    pub metric_type: MetricType,
+    pub metric_name: String,
+    pub help: String,
+    pub data: Vec<Box<dyn MetricLike>>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// A parsed representation of the prometheus metrics data
 pub struct PrometheusData {
-    metrics: Vec<MetricFamily>,
+    pub metrics: Vec<MetricFamily>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// A single flattened sample, as it would appear on an exposition line
+pub struct Sample {
+    pub name: String,
+    pub labels: Labels,
+    pub value: Value,
+}
+
+impl Sample {
+    fn new(name: String, labels: &Option<Labels>, value: &str) -> Sample {
+        Sample {
+            name,
+            labels: labels.clone().unwrap_or_default(),
+            value: value.to_string(),
+        }
+    }
+
+    fn with_label(mut self, key: &str, value: &str) -> Sample {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// The sample value as a float, understanding the exposition spellings of infinities
+    pub fn float_value(&self) -> Option<f64> {
+        parse_float(&self.value)
+    }
+
+    /// Canonical `name{label="value",...}` identity of the series, with sorted labels escaped as
+    /// in exposition text
+    pub fn series_id(&self) -> String {
+        if self.labels.is_empty() {
+            return self.name.clone();
+        }
+        let mut labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, exposition::escape_label_value(v)))
+            .collect();
+        labels.sort();
+        format!("{}{{{}}}", self.name, labels.join(","))
+    }
+}
+
+/// The optional timestamp following the value of a sample line
+fn parse_timestamp(line: &str) -> Option<i64> {
+    let tail = match line.rfind('}') {
+        Some(i) => &line[i + 1..],
+        None => line.split_once(char::is_whitespace)?.1,
+    };
+    let mut tokens = tail.split_whitespace();
+    tokens.next()?;
+    tokens.next()?.parse().ok()
+}
+
+/// The metric name of a sample line
+fn sample_name(line: &str) -> &str {
+    let end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Whether a sample named `name` is part of the family `family`, histogram and summary
+/// samples carrying a suffix
+fn belongs_to(name: &str, family: &str) -> bool {
+    match name.strip_prefix(family) {
+        Some("") => true,
+        Some(suffix) => ["_bucket", "_sum", "_count", "_total", "_created"].contains(&suffix),
+        None => false,
+    }
+}
+
+/// Parse an exposition format value, including `+Inf`/`-Inf`/`NaN`. Other values take the
+/// same spellings as `str::parse`, with a faster parser since every sample goes through here.
+pub fn parse_float(s: &str) -> Option<f64> {
+    match s {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => fast_float2::parse(s).ok(),
+    }
+}
+
+/// A float spelled as on an exposition line, in the fewest digits that read back the same
+pub(crate) fn format_float(v: f64) -> Value {
+    match v {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        v if v.is_nan() => "NaN".to_string(),
+        v => {
+            let mut buffer = ryu::Buffer::new();
+            let formatted = buffer.format_finite(v);
+            // Whole numbers as exporters write them, `3` rather than `3.0`
+            formatted
+                .strip_suffix(".0")
+                .unwrap_or(formatted)
+                .to_string()
+        }
+    }
+}
+
+/// A value passed through `f`, left as is when it is not a number
+fn map_value(value: &str, f: &dyn Fn(f64) -> f64) -> Value {
+    match parse_float(value) {
+        Some(v) => format_float(f(v)),
+        None => value.to_string(),
+    }
+}
+
+/// The value and labels of a sample line
+fn parse_sample(s: &str) -> Result<(Value, Option<Labels>), String> {
+    if let Some(value) = scan::unlabeled(s) {
+        Ok((value.to_string(), None))
+    } else if let Some((raw_labels, value)) = scan::labeled(s) {
+        let mut labels: HashMap<String, String> = HashMap::new();
+        for (key, label_value) in scan::label_pairs(raw_labels) {
+            labels.insert(key.to_string(), scan::unescape(label_value).into_owned());
+        }
+        Ok((value.to_string(), Some(labels)))
+    } else {
+        Err(format!("Invalid format {}", s))
+    }
 }
 
-#[typetag::serde(tag = "type")]
-trait MetricLike {
+#[cfg_attr(feature = "serde", typetag::serde(tag = "type"))]
+/// Behaviour shared by every kind of parsed series
+pub trait MetricLike: Send + Sync {
+    /// The value and labels of a sample line, panicking on a line it cannot read
+    #[deprecated(note = "panics on malformed lines, parse with `PrometheusData::try_from_string`")]
     fn parse_from_string(s: &str) -> (Value, Option<Labels>)
     where
         Self: Sized,
     {
-        if let Some(caps) = METRIC_REGEX_NO_LABEL.captures(s) {
-            (caps[2].to_string(), None)
-        } else if let Some(caps) = METRIC_REGEX_WITH_LABEL.captures(s) {
-            let value = caps[2].to_string();
-            let mut labels: HashMap<String, String> = HashMap::new();
-            for cap in LABELS_REGEX.captures_iter(&caps[1]) {
-                labels.insert(cap[1].to_string(), cap[2].to_string());
-            }
-            (value, Some(labels))
-        } else {
-            panic!("Invalid format")
-        }
+        parse_sample(s).unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn metric_type() -> String
     where
         Self: Sized;
+
+    /// Flatten into the samples this series was parsed from
+    fn samples(&self, metric_name: &str) -> Vec<Sample>;
+
+    fn labels(&self) -> Option<&Labels>;
+
+    fn clone_box(&self) -> Box<dyn MetricLike>;
+
+    fn labels_mut(&mut self) -> &mut Option<Labels>;
+
+    /// The timestamp of a plain series, which summaries and histograms do not have
+    fn timestamp_mut(&mut self) -> Option<&mut Option<i64>> {
+        None
+    }
+
+    /// The value a series is ranked by: the sample value, or the observation count for
+    /// summaries and histograms
+    fn rank_value(&self) -> Option<f64>;
+
+    /// Turn cumulative histogram buckets into per-bucket counts, see `Histogram::bucket_counts`.
+    /// Other series have nothing to turn.
+    fn decumulate(&mut self) {}
+
+    /// Pass the measured values of a series through `f`, as unit conversions do: sample values,
+    /// sums, quantile values and bucket bounds, not counts
+    fn map_values(&mut self, f: &dyn Fn(f64) -> f64);
+
+    /// Estimate the `quantiles` of a histogram, see `Histogram::quantile`. Other series have
+    /// nothing to estimate.
+    fn add_percentiles(&mut self, _quantiles: &[f64]) {}
+
+    /// The fraction of the observations of a histogram at most `threshold`, see
+    /// `Histogram::fraction_below`. Other series have no observations.
+    fn fraction_below(&self, _threshold: f64) -> Option<f64> {
+        None
+    }
+
+    /// The bucket bounds of a histogram in order, see `Histogram::bounds`. Other series have no
+    /// buckets.
+    fn bucket_bounds(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    /// Only keep the buckets of a histogram whose bound is one of `bounds`, see
+    /// `Histogram::rebucket`. Other series have no buckets.
+    fn rebucket(&mut self, _bounds: &[f64]) {}
 }
 
 impl Metric {
-    fn from_string(s: &str) -> Metric {
-        let (value, labels) = Self::parse_from_string(s);
-        Metric {
-            labels: labels,
-            value: value,
-        }
+    fn from_string(s: &str) -> Result<Metric, String> {
+        let (value, labels) = parse_sample(s)?;
+        Ok(Metric {
+            labels,
+            value,
+            timestamp: parse_timestamp(s),
+        })
     }
 }
 
-#[typetag::serde]
+#[cfg_attr(feature = "serde", typetag::serde)]
 impl MetricLike for Metric {
+    fn clone_box(&self) -> Box<dyn MetricLike> {
+        Box::new(self.clone())
+    }
+
     fn metric_type() -> String {
         String::from("DEFAULT")
     }
+
+    fn samples(&self, metric_name: &str) -> Vec<Sample> {
+        vec![Sample::new(
+            metric_name.to_string(),
+            &self.labels,
+            &self.value,
+        )]
+    }
+
+    fn labels(&self) -> Option<&Labels> {
+        self.labels.as_ref()
+    }
+
+    fn labels_mut(&mut self) -> &mut Option<Labels> {
+        &mut self.labels
+    }
+
+    fn rank_value(&self) -> Option<f64> {
+        parse_float(&self.value)
+    }
+
+    fn map_values(&mut self, f: &dyn Fn(f64) -> f64) {
+        self.value = map_value(&self.value, f);
+    }
+
+    fn timestamp_mut(&mut self) -> Option<&mut Option<i64>> {
+        Some(&mut self.timestamp)
+    }
 }
 
-impl Summary {
-    fn from_raw(metric_name: &str, raw_lines: &Vec<&str>) -> Summary {
-        let mut sum = String::from("");
-        let mut count = String::from("");
-        let sum_prefix = format!("{}_sum", metric_name);
-        let count_prefix = format!("{}_count", metric_name);
-        let mut labels = HashMap::new();
-        let mut quantiles = HashMap::new();
+#[derive(Default)]
+/// Where a parse keeps the errors of the lines it skips, when it skips them rather than failing
+struct Skipped<'v>(Option<&'v mut Vec<String>>);
+
+impl Skipped<'_> {
+    /// What was read, or None once the error is kept as that of a skipped line
+    fn or_skip<T>(&mut self, read: Result<T, String>) -> Result<Option<T>, String> {
+        match (read, self.0.as_deref_mut()) {
+            (Ok(value), _) => Ok(Some(value)),
+            (Err(e), Some(skipped)) => {
+                skipped.push(e);
+                Ok(None)
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+}
+
+/// One summary or histogram series: its sum, count and labels, and its other samples by the
+/// value of their `quantile` or `le` label
+struct RawSeries {
+    sum: Value,
+    count: Value,
+    labels: Labels,
+    by_bound: HashMap<String, String>,
+}
+
+impl RawSeries {
+    /// Parse the lines of one series, `bound` being the label that tells its samples apart
+    fn from_raw(
+        metric_name: &str,
+        raw_lines: &[&str],
+        bound: &str,
+        arena: &Arena,
+        skipped: &mut Skipped,
+    ) -> Result<RawSeries, String> {
+        let mut sum = String::new();
+        let mut count = String::new();
+        let sum_name = arena::concat(arena, metric_name, "_sum");
+        let count_name = arena::concat(arena, metric_name, "_count");
+        // Every line repeats the labels, borrowed until the end rather than copied each time
+        let mut labels: ArenaVec<(&str, Cow<str>)> = arena::vec(arena);
+        let mut by_bound = HashMap::with_capacity(raw_lines.len());
         for raw_line in raw_lines {
-            if raw_line.starts_with(&sum_prefix) {
-                sum = Summary::parse_from_string(raw_line).0;
-            } else if raw_line.starts_with(&count_prefix) {
-                count = Summary::parse_from_string(raw_line).0;
-            } else if let Some(caps) = METRIC_REGEX_WITH_LABEL.captures(raw_line) {
-                for cap in LABELS_REGEX.captures_iter(&caps[1]) {
-                    let key = &cap[1];
-                    let value = &cap[2];
-                    match key {
-                        "quantile" => quantiles.insert(key.to_string(), value.to_string()),
-                        _ => labels.insert(key.to_string(), value.to_string()),
-                    };
+            if raw_line.starts_with(sum_name.as_str()) {
+                if let Some((value, _)) = skipped.or_skip(parse_sample(raw_line))? {
+                    sum = value;
+                }
+            } else if raw_line.starts_with(count_name.as_str()) {
+                if let Some((value, _)) = skipped.or_skip(parse_sample(raw_line))? {
+                    count = value;
+                }
+            } else if let Some((raw_labels, sample_value)) = scan::labeled(raw_line) {
+                for (key, value) in scan::label_pairs(raw_labels) {
+                    if key == bound {
+                        by_bound.insert(value.to_string(), sample_value.to_string());
+                    } else if let Some(label) = labels.iter_mut().find(|(k, _)| *k == key) {
+                        label.1 = scan::unescape(value);
+                    } else {
+                        labels.push((key, scan::unescape(value)));
+                    }
                 }
             } else {
-                panic!("Invalid format {}", raw_line)
+                skipped.or_skip::<()>(Err(format!("Invalid format {}", raw_line)))?;
             }
         }
-        Summary {
-            sum: sum,
-            count: count,
-            labels: Some(labels),
-            quantiles: quantiles,
-        }
+        Ok(RawSeries {
+            sum,
+            count,
+            labels: labels
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.into_owned()))
+                .collect(),
+            by_bound,
+        })
+    }
+}
+
+impl Summary {
+    fn from_raw(
+        metric_name: &str,
+        raw_lines: &[&str],
+        arena: &Arena,
+        skipped: &mut Skipped,
+    ) -> Result<Summary, String> {
+        let series = RawSeries::from_raw(metric_name, raw_lines, "quantile", arena, skipped)?;
+        Ok(Summary {
+            sum: series.sum,
+            count: series.count,
+            labels: Some(series.labels),
+            quantiles: series.by_bound,
+        })
     }
 }
 
-#[typetag::serde]
+#[cfg_attr(feature = "serde", typetag::serde)]
 impl MetricLike for Summary {
+    fn clone_box(&self) -> Box<dyn MetricLike> {
+        Box::new(self.clone())
+    }
+
     fn metric_type() -> String {
         String::from("SUMMARY")
     }
+
+    fn samples(&self, metric_name: &str) -> Vec<Sample> {
+        let mut quantiles: Vec<(&String, &String)> = self.quantiles.iter().collect();
+        quantiles.sort_by(|a, b| sort_bound(a.0, b.0));
+        let mut samples: Vec<Sample> = quantiles
+            .into_iter()
+            .map(|(q, v)| {
+                Sample::new(metric_name.to_string(), &self.labels, v).with_label("quantile", q)
+            })
+            .collect();
+        samples.push(Sample::new(
+            format!("{}_sum", metric_name),
+            &self.labels,
+            &self.sum,
+        ));
+        samples.push(Sample::new(
+            format!("{}_count", metric_name),
+            &self.labels,
+            &self.count,
+        ));
+        samples
+    }
+
+    fn labels(&self) -> Option<&Labels> {
+        self.labels.as_ref()
+    }
+
+    fn labels_mut(&mut self) -> &mut Option<Labels> {
+        &mut self.labels
+    }
+
+    fn rank_value(&self) -> Option<f64> {
+        parse_float(&self.count)
+    }
+
+    fn map_values(&mut self, f: &dyn Fn(f64) -> f64) {
+        for value in self.quantiles.values_mut() {
+            *value = map_value(value, f);
+        }
+        self.sum = map_value(&self.sum, f);
+    }
+}
+
+/// Order bucket bounds and quantiles numerically rather than lexically
+fn sort_bound(a: &str, b: &str) -> std::cmp::Ordering {
+    let (a, b) = (
+        parse_float(a).unwrap_or(f64::NAN),
+        parse_float(b).unwrap_or(f64::NAN),
+    );
+    a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
 }
 
 impl Histogram {
-    fn from_raw(metric_name: &str, raw_lines: &Vec<&str>) -> Histogram {
-        let mut sum = String::from("");
-        let mut count = String::from("");
-        let sum_prefix = format!("{}_sum", metric_name);
-        let count_prefix = format!("{}_count", metric_name);
-        let mut labels: HashMap<String, String> = HashMap::new();
-        let mut buckets: HashMap<String, String> = HashMap::new();
-        for raw_line in raw_lines {
-            if raw_line.starts_with(&sum_prefix) {
-                sum = Summary::parse_from_string(raw_line).0;
-            } else if raw_line.starts_with(&count_prefix) {
-                count = Summary::parse_from_string(raw_line).0;
-            } else if let Some(caps) = METRIC_REGEX_WITH_LABEL.captures(raw_line) {
-                for cap in LABELS_REGEX.captures_iter(&caps[1]) {
-                    let key = &cap[1];
-                    let value = &cap[2];
-                    match key {
-                        "le" => buckets.insert(value.to_string(), caps[2].to_string()),
-                        _ => labels.insert(key.to_string(), value.to_string()),
-                    };
+    fn from_raw(
+        metric_name: &str,
+        raw_lines: &[&str],
+        arena: &Arena,
+        skipped: &mut Skipped,
+    ) -> Result<Histogram, String> {
+        let series = RawSeries::from_raw(metric_name, raw_lines, "le", arena, skipped)?;
+        Ok(Histogram {
+            sum: series.sum,
+            count: series.count,
+            labels: Some(series.labels),
+            buckets: series.by_bound,
+            percentiles: None,
+        })
+    }
+
+    /// The `q` quantile (between 0 and 1) of the observations, interpolated linearly within
+    /// the bucket it falls in as PromQL's `histogram_quantile` does. None without observations
+    /// or a `+Inf` bucket.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let mut buckets: Vec<(f64, f64)> = self
+            .buckets
+            .iter()
+            .filter_map(|(le, v)| Some((parse_float(le)?, parse_float(v)?)))
+            .collect();
+        buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let &(last_bound, total) = buckets.last()?;
+        if last_bound != f64::INFINITY || total <= 0.0 || buckets.len() < 2 {
+            return None;
+        }
+        let rank = q * total;
+        let i = buckets.iter().position(|(_, count)| *count >= rank)?;
+        // Past the last finite bound, the best estimate is that bound
+        if i == buckets.len() - 1 {
+            return Some(buckets[i - 1].0);
+        }
+        let (end, count) = buckets[i];
+        let (start, before) = match i {
+            0 if end <= 0.0 => return Some(end),
+            0 => (0.0, 0.0),
+            _ => buckets[i - 1],
+        };
+        if count <= before {
+            return Some(start);
+        }
+        Some(start + (end - start) * (rank - before) / (count - before))
+    }
+
+    /// The fraction (between 0 and 1) of the observations at most `threshold`, interpolated
+    /// linearly within the bucket it falls in as PromQL's `histogram_fraction` does, e.g. the
+    /// attainment of a latency SLO. Past the last finite bound, the best estimate is the fraction
+    /// up to that bound. None without observations or a `+Inf` bucket.
+    pub fn fraction_below(&self, threshold: f64) -> Option<f64> {
+        let mut buckets: Vec<(f64, f64)> = self
+            .buckets
+            .iter()
+            .filter_map(|(le, v)| Some((parse_float(le)?, parse_float(v)?)))
+            .collect();
+        buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let &(last_bound, total) = buckets.last()?;
+        if last_bound != f64::INFINITY || total <= 0.0 || threshold.is_nan() {
+            return None;
+        }
+        let (mut start, mut before) = (0.0_f64.min(buckets[0].0), 0.0);
+        for (end, count) in buckets {
+            if threshold < end {
+                if end == f64::INFINITY || threshold <= start {
+                    break;
                 }
-            } else {
-                panic!("Invalid format {}", raw_line)
+                let within = (count - before) * (threshold - start) / (end - start);
+                return Some((before + within) / total);
             }
+            start = end;
+            before = count;
         }
-        Histogram {
-            sum: sum,
-            count: count,
-            labels: Some(labels),
-            buckets: buckets,
+        Some(before / total)
+    }
+
+    /// The buckets in order of their bound, each with the observations of its own range (above
+    /// the previous bound) rather than every one up to its bound. Buckets whose bound or count
+    /// is not a number are left out.
+    pub fn bucket_counts(&self) -> Vec<(f64, f64)> {
+        self.per_bucket()
+            .into_iter()
+            .map(|(_, bound, count)| (bound, count))
+            .collect()
+    }
+
+    /// `bucket_counts`, with the `le` label of every bucket
+    fn per_bucket(&self) -> Vec<(&String, f64, f64)> {
+        let mut buckets: Vec<(&String, f64, f64)> = self
+            .buckets
+            .iter()
+            .filter_map(|(le, v)| Some((le, parse_float(le)?, parse_float(v)?)))
+            .collect();
+        buckets.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        let mut previous = 0.0;
+        for bucket in buckets.iter_mut() {
+            let cumulative = bucket.2;
+            bucket.2 = (cumulative - previous).max(0.0);
+            previous = cumulative;
         }
+        buckets
     }
 }
 
-#[typetag::serde]
+#[cfg_attr(feature = "serde", typetag::serde)]
 impl MetricLike for Histogram {
+    fn clone_box(&self) -> Box<dyn MetricLike> {
+        Box::new(self.clone())
+    }
+
     fn metric_type() -> String {
         String::from("HISTOGRAM")
     }
+
+    fn samples(&self, metric_name: &str) -> Vec<Sample> {
+        let mut buckets: Vec<(&String, &String)> = self.buckets.iter().collect();
+        buckets.sort_by(|a, b| sort_bound(a.0, b.0));
+        let bucket_name = format!("{}_bucket", metric_name);
+        let mut samples: Vec<Sample> = buckets
+            .into_iter()
+            .map(|(le, v)| Sample::new(bucket_name.clone(), &self.labels, v).with_label("le", le))
+            .collect();
+        samples.push(Sample::new(
+            format!("{}_sum", metric_name),
+            &self.labels,
+            &self.sum,
+        ));
+        samples.push(Sample::new(
+            format!("{}_count", metric_name),
+            &self.labels,
+            &self.count,
+        ));
+        samples
+    }
+
+    fn labels(&self) -> Option<&Labels> {
+        self.labels.as_ref()
+    }
+
+    fn labels_mut(&mut self) -> &mut Option<Labels> {
+        &mut self.labels
+    }
+
+    fn rank_value(&self) -> Option<f64> {
+        parse_float(&self.count)
+    }
+
+    fn decumulate(&mut self) {
+        self.buckets = self
+            .per_bucket()
+            .into_iter()
+            .map(|(le, _, count)| (le.clone(), format_float(count)))
+            .collect();
+    }
+
+    fn map_values(&mut self, f: &dyn Fn(f64) -> f64) {
+        self.buckets = self
+            .buckets
+            .drain()
+            .map(|(le, count)| (map_value(&le, f), count))
+            .collect();
+        self.sum = map_value(&self.sum, f);
+        for value in self.percentiles.iter_mut().flat_map(|p| p.values_mut()) {
+            *value = map_value(value, f);
+        }
+    }
+
+    fn add_percentiles(&mut self, quantiles: &[f64]) {
+        let percentiles = quantiles
+            .iter()
+            .filter_map(|q| Some((format_float(*q), format_float(self.quantile(*q)?))))
+            .collect();
+        self.percentiles = Some(percentiles);
+    }
+
+    fn fraction_below(&self, threshold: f64) -> Option<f64> {
+        Histogram::fraction_below(self, threshold)
+    }
+
+    fn bucket_bounds(&self) -> Vec<f64> {
+        self.bounds()
+    }
+
+    fn rebucket(&mut self, bounds: &[f64]) {
+        Histogram::rebucket(self, bounds)
+    }
 }
 
 impl MetricFamily {
-    fn from_raw(raw: &Vec<&str>) -> MetricFamily {
-        let mut raw_iter = raw.iter();
-        let help = MetricFamily::metric_help_fron_raw(raw_iter.next().expect("invalid format"));
-        let (metric_name, metric_type) =
-            MetricFamily::metric_name_and_type(raw_iter.next().expect("invalid format"));
-        let mut data: Vec<Box<dyn MetricLike>> = Vec::new();
-        match metric_type {
-            MetricType::Gauge => {
-                for raw_line in raw_iter {
-                    data.push(Box::new(Metric::from_string(raw_line)))
+    /// Parse the lines of one family: optional `# HELP` and `# TYPE` lines, then the samples.
+    /// A family without `# TYPE` is untyped.
+    fn from_raw(
+        raw: &[&str],
+        arena: &Arena,
+        skipped: &mut Skipped,
+    ) -> Result<MetricFamily, String> {
+        let mut help = String::new();
+        let mut name_and_type = None;
+        let mut samples = arena::vec(arena);
+        for line in raw {
+            if line.starts_with("# HELP ") {
+                help = MetricFamily::metric_help_fron_raw(line);
+            } else if line.starts_with("# TYPE ") {
+                let read = MetricFamily::metric_name_and_type(line);
+                if let Some(read) = skipped.or_skip(read)? {
+                    name_and_type = Some(read);
                 }
+            } else if !line.starts_with('#') {
+                samples.push(*line);
             }
-            MetricType::Histogram => {
-                let count_prefix = format!("{}_count", metric_name);
-                let mut histogram_lines: Vec<&str> = Vec::new();
+        }
+        let (metric_name, metric_type) = name_and_type.unwrap_or_else(|| {
+            let name = raw
+                .iter()
+                .find(|l| l.starts_with("# HELP "))
+                .and_then(|l| l.split_whitespace().nth(2))
+                .or_else(|| samples.first().map(|l| sample_name(l)))
+                .unwrap_or_default();
+            (name.to_string(), MetricType::Untyped)
+        });
+        let raw_iter = samples.into_iter();
+        let mut data: Vec<Box<dyn MetricLike>> = Vec::new();
+        match metric_type {
+            MetricType::Gauge | MetricType::Untyped => {
                 for raw_line in raw_iter {
-                    histogram_lines.push(raw_line);
-                    if raw_line.starts_with(&count_prefix) {
-                        data.push(Box::new(Histogram::from_raw(
-                            &metric_name,
-                            &histogram_lines,
-                        )));
-                        histogram_lines = Vec::new();
+                    if let Some(metric) = skipped.or_skip(Metric::from_string(raw_line))? {
+                        data.push(Box::new(metric))
                     }
                 }
             }
-            MetricType::Summary => {
-                let count_prefix = format!("{}_count", metric_name);
-                let mut summary_lines: Vec<&str> = Vec::new();
+            MetricType::Histogram | MetricType::Summary => {
+                let count_name = arena::concat(arena, &metric_name, "_count");
+                let mut series_lines = arena::vec(arena);
                 for raw_line in raw_iter {
-                    summary_lines.push(raw_line);
-                    if raw_line.starts_with(&count_prefix) {
-                        data.push(Box::new(Summary::from_raw(&metric_name, &summary_lines)));
-                        summary_lines = Vec::new();
+                    series_lines.push(raw_line);
+                    if raw_line.starts_with(count_name.as_str()) {
+                        let series: Box<dyn MetricLike> = if metric_type == MetricType::Histogram {
+                            Box::new(Histogram::from_raw(
+                                &metric_name,
+                                &series_lines,
+                                arena,
+                                skipped,
+                            )?)
+                        } else {
+                            Box::new(Summary::from_raw(
+                                &metric_name,
+                                &series_lines,
+                                arena,
+                                skipped,
+                            )?)
+                        };
+                        data.push(series);
+                        series_lines.clear();
                     }
                 }
             }
         }
-        MetricFamily {
-            metric_type: metric_type,
-            metric_name: metric_name,
-            help: help,
-            data: data,
-        }
+        Ok(MetricFamily {
+            metric_type,
+            metric_name,
+            help,
+            data,
+        })
+    }
+
+    fn max_rank_value(&self) -> Option<f64> {
+        self.data
+            .iter()
+            .filter_map(|m| m.rank_value())
+            .filter(|v| !v.is_nan())
+            .fold(None, |max: Option<f64>, v| {
+                Some(max.map_or(v, |m| m.max(v)))
+            })
+    }
+
+    /// Flatten every series of the family into samples
+    pub fn samples(&self) -> Vec<Sample> {
+        self.data
+            .iter()
+            .flat_map(|m| m.samples(&self.metric_name))
+            .collect()
     }
 
-    fn metric_name_and_type(type_line: &str) -> (String, MetricType) {
+    fn metric_name_and_type(type_line: &str) -> Result<(String, MetricType), String> {
         let tags: Vec<&str> = type_line.split_whitespace().collect();
-        let (name, type_raw) = (tags[2], tags[3]);
+        let (name, type_raw) = match tags[..] {
+            [_, _, name, type_raw, ..] => (name, type_raw),
+            _ => return Err(format!("Invalid format {}", type_line)),
+        };
         let metric_type = match type_raw {
             "gauge" => MetricType::Gauge,
             "counter" => MetricType::Gauge,
             "histogram" => MetricType::Histogram,
             "summary" => MetricType::Summary,
-            unknown_metric => panic!("Unknown metric type {}", unknown_metric),
+            "untyped" => MetricType::Untyped,
+            unknown_metric => return Err(format!("Unknown metric type {}", unknown_metric)),
         };
 
-        (name.to_string(), metric_type)
+        Ok((name.to_string(), metric_type))
     }
 
     fn metric_help_fron_raw(help_line: &str) -> String {
         let tags: Vec<&str> = help_line.split_whitespace().collect();
-        tags[3..].join(" ").to_string()
+        tags.get(3..).unwrap_or_default().join(" ")
+    }
+}
+
+/// How a line relates to the family before it
+enum Grouping {
+    /// A comment or blank line, left out
+    Skip,
+    /// Part of the current family
+    Same,
+    /// The first line of a new family
+    New,
+}
+
+#[derive(Default)]
+/// Tells where each family starts in a sequence of lines
+struct FamilyGrouping {
+    /// The name of the current family, and whether it came from a `# HELP`/`# TYPE` line
+    family: Option<(String, bool)>,
+}
+
+impl FamilyGrouping {
+    fn next(&mut self, line: &str) -> Grouping {
+        if line.trim().is_empty() {
+            return Grouping::Skip;
+        }
+        let header = line
+            .strip_prefix("# HELP ")
+            .or_else(|| line.strip_prefix("# TYPE "))
+            .and_then(|rest| rest.split_whitespace().next());
+        let (name, has_header) = match (header, &self.family) {
+            // A header of the current family
+            (Some(name), Some((current, true))) if current == name => return Grouping::Same,
+            (Some(name), _) => (name, true),
+            // Other comments
+            (None, _) if line.starts_with('#') => return Grouping::Skip,
+            // Samples belong to the family whose header precedes them, and samples without
+            // any header to an untyped family of their own name
+            (None, Some((current, true))) if belongs_to(sample_name(line), current) => {
+                return Grouping::Same
+            }
+            (None, Some((current, false))) if current == sample_name(line) => {
+                return Grouping::Same
+            }
+            (None, _) => (sample_name(line), false),
+        };
+        self.family = Some((name.to_string(), has_header));
+        Grouping::New
+    }
+}
+
+/// The lines of each family in exposition text
+fn family_blocks(s: &str) -> Vec<Vec<&str>> {
+    let mut grouping = FamilyGrouping::default();
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    for line in scan::lines(s) {
+        match (grouping.next(line), blocks.last_mut()) {
+            (Grouping::Skip, _) => {}
+            (Grouping::Same, Some(block)) => block.push(line),
+            (Grouping::Same, None) | (Grouping::New, _) => blocks.push(vec![line]),
+        }
+    }
+    blocks
+}
+
+/// Parse the lines of each family, on the rayon thread pool with the `parallel` feature. Each
+/// thread has an arena, reset once a family is parsed. With `skip`, the lines that cannot be
+/// read are left out and their errors returned along with the families, rather than failing.
+fn parse_blocks(
+    blocks: &[Vec<&str>],
+    skip: bool,
+) -> Result<(Vec<MetricFamily>, Vec<String>), String> {
+    let parse = |arena: &mut Arena, block: &Vec<&str>| {
+        let mut errors = Vec::new();
+        let mut skipped = Skipped(if skip { Some(&mut errors) } else { None });
+        let family = MetricFamily::from_raw(block, arena, &mut skipped);
+        arena.reset();
+        family.map(|family| (family, errors))
+    };
+    #[cfg(feature = "parallel")]
+    let parsed: Result<Vec<_>, String> = {
+        use rayon::prelude::*;
+        blocks.par_iter().map_init(Arena::default, parse).collect()
+    };
+    #[cfg(not(feature = "parallel"))]
+    let parsed: Result<Vec<_>, String> = {
+        let mut arena = Arena::default();
+        blocks
+            .iter()
+            .map(|block| parse(&mut arena, block))
+            .collect()
+    };
+    let mut skipped = Vec::new();
+    let families = parsed?
+        .into_iter()
+        .map(|(family, mut errors)| {
+            skipped.append(&mut errors);
+            family
+        })
+        .collect();
+    Ok((families, skipped))
+}
+
+/// How much raw text of whole families the stream parser gathers before parsing them in
+/// parallel
+const PARALLEL_BATCH_BYTES: usize = 1 << 20;
+
+/// What a stream parser skipping malformed lines does with the error of each one
+type OnMalformed = Box<dyn FnMut(&str) + Send>;
+
+#[derive(Default)]
+/// Incremental parser, fed with chunks of exposition text as they arrive. Each family is
+/// parsed as soon as the next one starts, so only the current family is kept as raw text.
+/// With the `parallel` feature, families are parsed in parallel batches of about
+/// `PARALLEL_BATCH_BYTES` instead.
+pub struct StreamParser {
+    partial: Vec<u8>,
+    /// The lines of the families not parsed yet, the current one included, one after the
+    /// other. The buffer is reused from one family to the next rather than holding a string
+    /// per line.
+    text: String,
+    /// Where each line ends in `text`
+    line_ends: Vec<usize>,
+    /// Where each whole family ends in `line_ends`
+    family_ends: Vec<usize>,
+    grouping: FamilyGrouping,
+    metrics: Vec<MetricFamily>,
+    limits: ParseLimits,
+    /// The bytes and sample lines fed so far, held against the limits
+    payload_bytes: usize,
+    samples: usize,
+    /// Handed the error of every line skipped, when lines that cannot be read are skipped
+    on_malformed: Option<OnMalformed>,
+}
+
+impl StreamParser {
+    pub fn new() -> StreamParser {
+        StreamParser::default()
+    }
+
+    /// A parser failing as soon as the input goes over `limits`
+    pub fn with_limits(limits: ParseLimits) -> StreamParser {
+        StreamParser {
+            limits,
+            ..StreamParser::default()
+        }
+    }
+
+    /// The same parser, leaving out the lines it cannot read rather than failing on them and
+    /// handing the error of each one to `on_malformed`
+    pub fn skipping_malformed<F>(self, on_malformed: F) -> StreamParser
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        StreamParser {
+            on_malformed: Some(Box::new(on_malformed)),
+            ..self
+        }
+    }
+
+    /// Feed the next chunk of bytes, which may end in the middle of a line
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), ParseError> {
+        self.payload_bytes += chunk.len();
+        limits::check(
+            self.payload_bytes,
+            self.limits.max_payload_bytes,
+            LimitExceeded::PayloadBytes,
+        )?;
+        let mut rest = chunk;
+        while let Some(end) = memchr::memchr(b'\n', rest) {
+            if self.partial.is_empty() {
+                self.push_raw_line(&rest[..end])?;
+            } else {
+                let mut partial = std::mem::take(&mut self.partial);
+                partial.extend_from_slice(&rest[..end]);
+                self.push_raw_line(&partial)?;
+                partial.clear();
+                self.partial = partial;
+            }
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+        limits::check(
+            self.partial.len(),
+            self.limits.max_line_length,
+            LimitExceeded::LineLength,
+        )?;
+        Ok(())
+    }
+
+    fn push_raw_line(&mut self, raw: &[u8]) -> Result<(), ParseError> {
+        let line = String::from_utf8_lossy(raw);
+        self.push_line(line.strip_suffix('\r').unwrap_or(&line))
+    }
+
+    /// Feed one complete line, without its line ending
+    pub fn push_line(&mut self, line: &str) -> Result<(), ParseError> {
+        limits::check(
+            line.len(),
+            self.limits.max_line_length,
+            LimitExceeded::LineLength,
+        )?;
+        match self.grouping.next(line) {
+            Grouping::Skip => return Ok(()),
+            Grouping::Same => {}
+            Grouping::New => self.flush_family()?,
+        }
+        if !line.starts_with('#') {
+            self.samples += 1;
+            limits::check(
+                self.samples,
+                self.limits.max_samples,
+                LimitExceeded::Samples,
+            )?;
+        }
+        self.text.push_str(line);
+        self.line_ends.push(self.text.len());
+        Ok(())
+    }
+
+    fn flush_family(&mut self) -> Result<(), ParseError> {
+        if self.family_ends.last().copied().unwrap_or(0) == self.line_ends.len() {
+            return Ok(());
+        }
+        self.family_ends.push(self.line_ends.len());
+        if !cfg!(feature = "parallel") || self.text.len() >= PARALLEL_BATCH_BYTES {
+            self.parse_pending()?;
+        }
+        Ok(())
+    }
+
+    fn parse_pending(&mut self) -> Result<(), ParseError> {
+        let mut line_start = 0;
+        let mut family_start = 0;
+        let blocks: Vec<Vec<&str>> = self
+            .family_ends
+            .iter()
+            .map(|&family_end| {
+                let lines = self.line_ends[family_start..family_end]
+                    .iter()
+                    .map(|&line_end| {
+                        let line = &self.text[line_start..line_end];
+                        line_start = line_end;
+                        line
+                    })
+                    .collect();
+                family_start = family_end;
+                lines
+            })
+            .collect();
+        let skip = self.on_malformed.is_some();
+        let (families, skipped) = parse_blocks(&blocks, skip).map_err(ParseError::Malformed)?;
+        if let Some(on_malformed) = &mut self.on_malformed {
+            skipped.iter().for_each(|error| on_malformed(error));
+        }
+        self.text.clear();
+        self.line_ends.clear();
+        self.family_ends.clear();
+        if let Some(limit) = self.limits.max_series_per_family {
+            if let Some(family) = families.iter().find(|f| f.data.len() > limit) {
+                return Err(LimitExceeded::SeriesPerFamily {
+                    family: family.metric_name.clone(),
+                    limit,
+                }
+                .into());
+            }
+        }
+        self.metrics.extend(families);
+        Ok(())
+    }
+
+    /// Take the families parsed so far, to hand them on while the rest is still coming
+    pub fn take_families(&mut self) -> Vec<MetricFamily> {
+        std::mem::take(&mut self.metrics)
+    }
+
+    /// Parse whatever is left and return the parsed data
+    pub fn finish(mut self) -> Result<PrometheusData, ParseError> {
+        if !self.partial.is_empty() {
+            let partial = std::mem::take(&mut self.partial);
+            self.push_raw_line(&partial)?;
+        }
+        self.flush_family()?;
+        self.parse_pending()?;
+        Ok(PrometheusData {
+            metrics: self.metrics,
+        })
     }
 }
 
 impl PrometheusData {
-    /// Parse promethues metric data from string
+    /// Parse promethues metric data from string. With the `parallel` feature, families are
+    /// parsed on the rayon thread pool.
+    ///
+    /// # Panics
+    ///
+    /// On a line it cannot read. Text that cannot be trusted is parsed with `try_from_string`
+    /// instead.
     pub fn from_string(s: &str) -> PrometheusData {
-        let mut metrics = Vec::new();
-        let mut metric_lines = Vec::new();
-        let mut num_comment_lines = 0;
-        for line in s.lines() {
-            if line.starts_with('#') {
-                if num_comment_lines == 2 {
-                    // One set complete
-                    metrics.push(MetricFamily::from_raw(&metric_lines));
-                    metric_lines = vec![line];
-                    num_comment_lines = 1;
-                } else {
-                    num_comment_lines += 1;
-                    metric_lines.push(line);
-                }
-            } else {
-                metric_lines.push(line)
+        PrometheusData::try_from_string(s).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Parse promethues metric data from string like `from_string`, with an error naming the
+    /// first line it cannot read rather than a panic. Nothing it runs panics, malformed text
+    /// only ever makes an error.
+    pub fn try_from_string(s: &str) -> Result<PrometheusData, String> {
+        Ok(PrometheusData {
+            metrics: parse_blocks(&family_blocks(s), false)?.0,
+        })
+    }
+
+    /// Parse promethues metric data from string, refusing input with any lint error, or that
+    /// still cannot be parsed. Such a parse error is an issue on the line it names.
+    pub fn from_string_strict(s: &str) -> Result<PrometheusData, Vec<Issue>> {
+        let errors: Vec<Issue> = lint(s)
+            .into_iter()
+            .filter(|i| i.severity == Severity::Error)
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        PrometheusData::try_from_string(s).map_err(|message| {
+            // 0 when the error names no line of the text
+            let line = s
+                .lines()
+                .map(str::trim)
+                .position(|l| !l.is_empty() && message.ends_with(l))
+                .map_or(0, |i| i + 1);
+            vec![Issue {
+                line,
+                severity: Severity::Error,
+                message,
+            }]
+        })
+    }
+
+    /// Flatten every family into samples, in exposition order
+    pub fn samples(&self) -> Vec<Sample> {
+        self.metrics.iter().flat_map(|f| f.samples()).collect()
+    }
+
+    /// Sort the families, and the series within each family. Sorting by value puts the
+    /// largest series first, and orders families by their largest series.
+    pub fn sort(&mut self, by: SortBy) {
+        for family in self.metrics.iter_mut() {
+            match by {
+                SortBy::Name => family
+                    .data
+                    .sort_by_cached_key(|m| sorted_labels(m.labels())),
+                SortBy::Value => family
+                    .data
+                    .sort_by(|a, b| descending(a.rank_value(), b.rank_value())),
             }
         }
-        PrometheusData { metrics: metrics }
+        match by {
+            SortBy::Name => self
+                .metrics
+                .sort_by(|a, b| a.metric_name.cmp(&b.metric_name)),
+            SortBy::Value => self
+                .metrics
+                .sort_by(|a, b| descending(a.max_rank_value(), b.max_rank_value())),
+        }
+    }
+
+    /// Add `labels` to every series, keeping the value of labels a series already has
+    pub fn add_labels(&mut self, labels: &Labels) {
+        if labels.is_empty() {
+            return;
+        }
+        for metric in self.metrics.iter_mut().flat_map(|f| f.data.iter_mut()) {
+            let series_labels = metric.labels_mut().get_or_insert_with(Labels::new);
+            for (key, value) in labels {
+                series_labels
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    /// Give every plain sample without a timestamp this one, in milliseconds since the epoch,
+    /// e.g. the time of the scrape
+    pub fn stamp(&mut self, timestamp_ms: i64) {
+        for metric in self.metrics.iter_mut().flat_map(|f| f.data.iter_mut()) {
+            if let Some(timestamp) = metric.timestamp_mut() {
+                timestamp.get_or_insert(timestamp_ms);
+            }
+        }
+    }
+
+    /// Only keep the series for which `keep(family_name, series)` is true, dropping the
+    /// families left empty
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&str, &dyn MetricLike) -> bool,
+    {
+        for family in self.metrics.iter_mut() {
+            let name = &family.metric_name;
+            family.data.retain(|m| keep(name, m.as_ref()));
+        }
+        self.metrics.retain(|f| !f.data.is_empty());
+    }
+
+    /// Only keep the series matched by one of `include` (when not empty) and by none of
+    /// `exclude`. Selectors are matched against the family name.
+    pub fn filter(&mut self, include: &[Selector], exclude: &[Selector]) {
+        self.retain(|name, m| {
+            (include.is_empty() || include.iter().any(|s| s.matches_series(name, m.labels())))
+                && !exclude.iter().any(|s| s.matches_series(name, m.labels()))
+        })
+    }
+
+    /// Append the series of `other`, into the family of the same name when there is one.
+    /// Series are not deduplicated, label them apart (e.g. with `add_labels`) beforehand.
+    pub fn merge(&mut self, other: PrometheusData) {
+        for family in other.metrics {
+            match self
+                .metrics
+                .iter_mut()
+                .find(|f| f.metric_name == family.metric_name)
+            {
+                Some(existing) => existing.data.extend(family.data),
+                None => self.metrics.push(family),
+            }
+        }
+    }
+
+    /// Give every histogram bucket the observations of its own range rather than every one up
+    /// to its bound, as plotting and analysis tools want them. The result is no longer valid
+    /// exposition data.
+    pub fn decumulate(&mut self) {
+        for metric in self.metrics.iter_mut().flat_map(|f| f.data.iter_mut()) {
+            metric.decumulate();
+        }
+    }
+
+    /// Estimate the `quantiles` (e.g. 0.5, 0.9 and 0.99) of every histogram from its buckets,
+    /// into its `percentiles`, for dashboards to show latencies without PromQL
+    pub fn add_percentiles(&mut self, quantiles: &[f64]) {
+        if quantiles.is_empty() {
+            return;
+        }
+        for metric in self.metrics.iter_mut().flat_map(|f| f.data.iter_mut()) {
+            metric.add_percentiles(quantiles);
+        }
+    }
+
+    /// Only keep the `n` series with the largest values, across all families
+    pub fn top(&mut self, n: usize) {
+        let mut ranked: Vec<(usize, usize, Option<f64>)> = Vec::new();
+        for (i, family) in self.metrics.iter().enumerate() {
+            for (j, metric) in family.data.iter().enumerate() {
+                ranked.push((i, j, metric.rank_value()));
+            }
+        }
+        ranked.sort_by(|a, b| descending(a.2, b.2));
+        let mut keep: Vec<Vec<bool>> = self
+            .metrics
+            .iter()
+            .map(|f| vec![false; f.data.len()])
+            .collect();
+        for (i, j, _) in ranked.into_iter().take(n) {
+            keep[i][j] = true;
+        }
+        for (family, keep) in self.metrics.iter_mut().zip(keep) {
+            let mut keep = keep.into_iter();
+            family.data.retain(|_| keep.next().unwrap_or(false));
+        }
+        self.metrics.retain(|f| !f.data.is_empty());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The key series are sorted by
+pub enum SortBy {
+    Name,
+    Value,
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortBy, String> {
+        match s {
+            "name" => Ok(SortBy::Name),
+            "value" => Ok(SortBy::Value),
+            other => Err(format!(
+                "Unknown sort key {}, expected name or value",
+                other
+            )),
+        }
+    }
+}
+
+fn sorted_labels(labels: Option<&Labels>) -> Vec<(String, String)> {
+    let mut labels: Vec<(String, String)> = labels
+        .map(|l| l.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+    labels.sort();
+    labels
+}
+
+/// Largest first, with NaN and unparsable values last
+fn descending(a: Option<f64>, b: Option<f64>) -> std::cmp::Ordering {
+    let key = |v: Option<f64>| v.filter(|v| !v.is_nan());
+    match (key(a), key(b)) {
+        (Some(a), Some(b)) => b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
     }
 }
 
@@ -287,9 +1381,10 @@ mod test {
         assert_eq!(
             Metric {
                 labels: None,
-                value: String::from("205632")
+                value: String::from("205632"),
+                timestamp: None,
             },
-            Metric::from_string("go_memstats_mspan_inuse_bytes 205632")
+            Metric::from_string("go_memstats_mspan_inuse_bytes 205632").unwrap()
         );
         assert_eq!(
             Metric {
@@ -297,10 +1392,53 @@ mod test {
                     "dialer_name".to_string() => "default".to_string(),
                     "reason".to_string() => "unknown".to_string(),
                 }),
-                value: String::from("0")
+                value: String::from("0"),
+                timestamp: None,
             },
-            Metric::from_string("net_conntrack_dialer_conn_failed_total{dialer_name=\"default\",reason=\"unknown\"} 0")
-        )
+            Metric::from_string("net_conntrack_dialer_conn_failed_total{dialer_name=\"default\",reason=\"unknown\"} 0").unwrap()
+        );
+        assert_eq!(
+            Metric::from_string("up{job=\"a b\"} 1 1395066363000")
+                .unwrap()
+                .timestamp,
+            Some(1395066363000)
+        );
+        assert_eq!(Metric::from_string("up 1 -5").unwrap().timestamp, Some(-5));
+        assert_eq!(
+            Metric::from_string(r#"up{path="C:\\dir\nnext"} 1"#)
+                .unwrap()
+                .labels,
+            Some(hashmap! {"path".to_string() => "C:\\dir\nnext".to_string()})
+        );
+    }
+
+    #[test]
+    fn fallible_parsing_works() {
+        let data = PrometheusData::try_from_string("# TYPE up gauge\nup 1\n").unwrap();
+        assert_eq!(data.samples().len(), 1);
+        for (text, error) in [
+            ("up{a=\"b\" 1", "Invalid format up{a=\"b\" 1"),
+            ("# TYPE up\nup 1", "Invalid format # TYPE up"),
+            ("# TYPE up gauges\nup 1", "Unknown metric type gauges"),
+            (
+                "# TYPE rpc summary\nrpc_sum{ 1\nrpc_count 1",
+                "Invalid format rpc_sum{ 1",
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                PrometheusData::try_from_string(text).err().as_deref(),
+                Some(*error)
+            );
+        }
+
+        assert!(PrometheusData::from_string_strict("# TYPE up gauge\nup 1\n").is_ok());
+        let issues = PrometheusData::from_string_strict("up 1\nup{a=\"b\" 1")
+            .err()
+            .unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].line, 2);
     }
 
     #[test]
@@ -312,7 +1450,131 @@ go_goroutines 31
 # TYPE go_info gauge
 go_info{version=\"go1.15.5\"} 1";
         let prom_data = PrometheusData::from_string(raw_data);
-        assert_eq!(MetricType::Gauge, prom_data.metrics[0].metric_type)
+        assert_eq!(MetricType::Gauge, prom_data.metrics[0].metric_type);
+        // The last family ends with the input rather than with another header
+        assert_eq!(prom_data.metrics.len(), 2);
+        assert_eq!(prom_data.metrics[1].metric_name, "go_info");
+    }
+
+    #[test]
+    fn stream_parsing_works() {
+        let raw_data = "# HELP go_goroutines Number of goroutines that currently exist.\r
+# TYPE go_goroutines gauge\r
+go_goroutines 31\r
+# HELP go_info Information about the Go environment.
+# TYPE go_info gauge
+go_info{version=\"go1.15.5\"} 1";
+        let expected = PrometheusData::from_string(raw_data).samples();
+        for chunk_size in [1, 7, 64] {
+            let mut parser = StreamParser::new();
+            for chunk in raw_data.as_bytes().chunks(chunk_size) {
+                parser.feed(chunk).unwrap();
+            }
+            let data = parser.finish().unwrap();
+            assert_eq!(data.metrics.len(), 2);
+            assert_eq!(data.samples(), expected);
+        }
+
+        // A malformed family stops the parse with an error, in whichever chunk it ends
+        let mut parser = StreamParser::new();
+        parser.feed(b"# TYPE up gauge\nup{a=\"b\" 2\n").unwrap();
+        let error = ParseError::Malformed("Invalid format up{a=\"b\" 2".to_string());
+        assert_eq!(parser.finish().err(), Some(error));
+
+        // Unless malformed lines are skipped
+        let skipped = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let errors = skipped.clone();
+        let mut parser = StreamParser::new()
+            .skipping_malformed(move |e| errors.lock().unwrap().push(e.to_string()));
+        parser
+            .feed(b"# TYPE up gauge\nup 1\nup{a=\"b\" 2\n# TYPE x gaug\nx 3\n")
+            .unwrap();
+        let data = parser.finish().unwrap();
+        let names: Vec<_> = data.samples().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["up", "x"]);
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            ["Invalid format up{a=\"b\" 2", "Unknown metric type gaug"]
+        );
+    }
+
+    #[test]
+    fn family_order_is_kept() {
+        let raw_data: String = (0..200)
+            .map(|i| {
+                format!(
+                    "# TYPE family_{} gauge\nfamily_{}{{i=\"{}\"}} {}\n",
+                    i, i, i, i
+                )
+            })
+            .collect();
+        let expected: Vec<String> = (0..200).map(|i| format!("family_{}", i)).collect();
+        let names = |data: PrometheusData| -> Vec<String> {
+            data.metrics.into_iter().map(|f| f.metric_name).collect()
+        };
+        assert_eq!(names(PrometheusData::from_string(&raw_data)), expected);
+        let mut parser = StreamParser::new();
+        parser.feed(raw_data.as_bytes()).unwrap();
+        assert_eq!(names(parser.finish().unwrap()), expected);
+
+        // Families taken along the way come in order too, the last one with `finish`
+        let mut parser = StreamParser::new();
+        let mut taken = Vec::new();
+        for chunk in raw_data.as_bytes().chunks(100) {
+            parser.feed(chunk).unwrap();
+            taken.extend(parser.take_families());
+        }
+        taken.extend(parser.finish().unwrap().metrics);
+        assert_eq!(names(PrometheusData { metrics: taken }), expected);
+    }
+
+    #[test]
+    fn float_parsing_works() {
+        let spellings = [
+            "1",
+            "-1.5e-3",
+            "1e+06",
+            "1.458255915e9",
+            ".5",
+            "5.",
+            "+1",
+            "0",
+            "-0",
+            "1E5",
+            "inf",
+            "-infinity",
+            "nan",
+            "",
+            "1e",
+            "--1",
+            " 1",
+            "1_000",
+            "0x10",
+            "1.2.3",
+        ];
+        for s in spellings.iter() {
+            let std = s.parse::<f64>().ok();
+            assert_eq!(
+                format!("{:?}", parse_float(s)),
+                format!("{:?}", std),
+                "{:?}",
+                s
+            );
+        }
+        assert_eq!(parse_float("+Inf"), Some(f64::INFINITY));
+        assert!(parse_float("NaN").unwrap().is_nan());
+
+        for (v, formatted) in [
+            (3.0, "3"),
+            (-0.25, "-0.25"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1e21, "1e21"),
+            (f64::INFINITY, "+Inf"),
+            (f64::NEG_INFINITY, "-Inf"),
+            (f64::NAN, "NaN"),
+        ] {
+            assert_eq!(format_float(v), formatted);
+        }
     }
 
     #[test]
@@ -324,10 +1586,15 @@ prometheus_engine_query_duration_seconds{slice=\"inner_eval\",quantile=\"0.99\"}
 prometheus_engine_query_duration_seconds_sum{slice=\"inner_eval\"} 12
 prometheus_engine_query_duration_seconds_count{slice=\"inner_eval\"} 0";
         let summary = Summary::from_raw(
-            &"prometheus_engine_query_duration_seconds",
-            &raw_data.lines().collect(),
-        );
+            "prometheus_engine_query_duration_seconds",
+            &raw_data.lines().collect::<Vec<_>>(),
+            &Arena::default(),
+            &mut Skipped::default(),
+        )
+        .unwrap();
         assert_eq!(summary.sum, "12".to_string());
+        assert_eq!(summary.quantiles.len(), 3);
+        assert_eq!(summary.quantiles["0.99"], "NaN");
         assert_eq!(
             summary.labels,
             Some(hashmap! {"slice".to_string() => "inner_eval".to_string()})
@@ -349,13 +1616,190 @@ prometheus_http_request_duration_seconds_bucket{handler="/metrics",le="+Inf"} 10
 prometheus_http_request_duration_seconds_sum{handler="/metrics"} 67.48398663499978
 prometheus_http_request_duration_seconds_count{handler="/metrics"} 10871"#;
         let histogram = Histogram::from_raw(
-            &"prometheus_http_request_duration_seconds",
-            &raw_data.lines().collect(),
-        );
+            "prometheus_http_request_duration_seconds",
+            &raw_data.lines().collect::<Vec<_>>(),
+            &Arena::default(),
+            &mut Skipped::default(),
+        )
+        .unwrap();
         assert_eq!(histogram.sum, "67.48398663499978");
         assert_eq!(
             histogram.labels,
             Some(hashmap! {"handler".to_string() => "/metrics".to_string()})
         );
     }
+
+    #[test]
+    fn decumulation_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 2
+latency_bucket{le=\"1\"} 5
+latency_bucket{le=\"+Inf\"} 6
+latency_sum 3.5
+latency_count 6",
+        );
+        let histogram = Histogram::from_raw(
+            "latency",
+            &"latency_bucket{le=\"1\"} 5\nlatency_bucket{le=\"0.1\"} 2\nlatency_count 5"
+                .lines()
+                .collect::<Vec<_>>(),
+            &Arena::default(),
+            &mut Skipped::default(),
+        )
+        .unwrap();
+        assert_eq!(histogram.bucket_counts(), vec![(0.1, 2.0), (1.0, 3.0)]);
+
+        data.decumulate();
+        let values: Vec<_> = data.samples().into_iter().map(|s| s.value).collect();
+        assert_eq!(values, vec!["2", "3", "1", "3.5", "6"]);
+
+        // Counts keep the formatting of parsed values
+        let mut data = PrometheusData::from_string(
+            "# TYPE big histogram
+big_bucket{le=\"1\"} 1e21
+big_bucket{le=\"+Inf\"} 3e21
+big_sum 0
+big_count 3e21",
+        );
+        data.decumulate();
+        let values: Vec<_> = data.samples().into_iter().map(|s| s.value).collect();
+        assert_eq!(values, vec!["1e21", "2e21", "0", "3e21"]);
+    }
+
+    #[test]
+    fn percentiles_work() {
+        let mut histogram = Histogram::from_raw(
+            "latency",
+            &"latency_bucket{le=\"1\"} 2
+latency_bucket{le=\"2\"} 6
+latency_bucket{le=\"+Inf\"} 8
+latency_count 8"
+                .lines()
+                .collect::<Vec<_>>(),
+            &Arena::default(),
+            &mut Skipped::default(),
+        )
+        .unwrap();
+        assert_eq!(histogram.quantile(0.25), Some(1.0));
+        assert_eq!(histogram.quantile(0.5), Some(1.5));
+        // In the +Inf bucket
+        assert_eq!(histogram.quantile(0.99), Some(2.0));
+        assert_eq!(histogram.quantile(2.0), None);
+
+        histogram.add_percentiles(&[0.5, 0.99, 1e-7]);
+        let percentiles = hashmap! {
+            "0.5".to_string() => "1.5".to_string(),
+            "0.99".to_string() => "2".to_string(),
+            "1e-7".to_string() => "4e-7".to_string(),
+        };
+        assert_eq!(histogram.percentiles, Some(percentiles));
+    }
+
+    #[test]
+    fn federation_parsing_works() {
+        let raw_data = "# TYPE up untyped
+up{instance=\"a\",job=\"node\"} 1 1395066363000
+up{instance=\"b\",job=\"node\"} 0 1395066363000
+# TYPE http_requests_total counter
+http_requests_total{code=\"200\"} 10 1395066363000
+# HELP no_type Only help.
+no_type 3
+headerless 4
+headerless{x=\"1\"} 5";
+        let mut data = PrometheusData::from_string(raw_data);
+        let names: Vec<&str> = data
+            .metrics
+            .iter()
+            .map(|f| f.metric_name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["up", "http_requests_total", "no_type", "headerless"]
+        );
+        assert_eq!(data.metrics[0].metric_type, MetricType::Untyped);
+        assert_eq!(data.metrics[0].data.len(), 2);
+        assert_eq!(data.metrics[1].metric_type, MetricType::Gauge);
+        assert_eq!(data.metrics[2].help, "Only help.");
+        assert_eq!(data.metrics[3].data.len(), 2);
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&data.metrics[0]).unwrap();
+            assert!(json.contains("\"timestamp\":1395066363000"));
+        }
+        // Stamping keeps the timestamps samples have
+        data.stamp(7);
+        assert_eq!(
+            data.metrics[0].data[0].timestamp_mut(),
+            Some(&mut Some(1395066363000))
+        );
+        assert_eq!(data.metrics[2].data[0].timestamp_mut(), Some(&mut Some(7)));
+    }
+
+    #[test]
+    fn series_ids_work() {
+        let sample = |labels: &[(&str, &str)]| Sample {
+            name: "up".to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value: "1".to_string(),
+        };
+        assert_eq!(sample(&[]).series_id(), "up");
+        assert_eq!(
+            sample(&[("b", "2"), ("a", "1")]).series_id(),
+            "up{a=\"1\",b=\"2\"}"
+        );
+        // Without escaping both would be `up{a="x",b="y"}`
+        let quoted = sample(&[("a", "x\",b=\"y")]);
+        let split = sample(&[("a", "x"), ("b", "y")]);
+        assert_eq!(quoted.series_id(), "up{a=\"x\\\",b=\\\"y\"}");
+        assert_ne!(quoted.series_id(), split.series_id());
+        assert_eq!(
+            sample(&[("a", "c:\\\n")]).series_id(),
+            "up{a=\"c:\\\\\\n\"}"
+        );
+    }
+
+    #[test]
+    fn merge_works() {
+        let mut data = PrometheusData::from_string(
+            "# HELP up Up.
+# TYPE up gauge
+up{pod=\"a\"} 1",
+        );
+        data.merge(PrometheusData::from_string(
+            "# HELP up Up.
+# TYPE up gauge
+up{pod=\"b\"} 0
+# HELP go_goroutines Goroutines.
+# TYPE go_goroutines gauge
+go_goroutines 5",
+        ));
+        assert_eq!(data.metrics.len(), 2);
+        assert_eq!(data.metrics[0].data.len(), 2);
+        assert_eq!(data.metrics[1].metric_name, "go_goroutines");
+    }
+
+    #[test]
+    fn sort_and_top_works() {
+        let raw_data = "# HELP b_metric B.
+# TYPE b_metric gauge
+b_metric{x=\"1\"} 5
+b_metric{x=\"2\"} 50
+# HELP a_metric A.
+# TYPE a_metric gauge
+a_metric 10
+a_metric{x=\"3\"} NaN";
+        let mut prom_data = PrometheusData::from_string(raw_data);
+        prom_data.sort(SortBy::Name);
+        assert_eq!(prom_data.metrics[0].metric_name, "a_metric");
+        prom_data.sort(SortBy::Value);
+        assert_eq!(prom_data.metrics[0].metric_name, "b_metric");
+        assert_eq!(prom_data.metrics[0].data[0].rank_value(), Some(50.0));
+        prom_data.top(2);
+        let values: Vec<String> = prom_data.samples().into_iter().map(|s| s.value).collect();
+        assert_eq!(values, vec!["50", "10"]);
+    }
 }
@@ -0,0 +1,156 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(deny_unknown_fields)
+)]
+/// Bounds on what a stream parser takes in, to scrape targets that cannot be trusted without
+/// unbounded memory growth. Each one is unbounded when left out.
+pub struct ParseLimits {
+    /// Longest line, in bytes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_line_length: Option<usize>,
+    /// Most series in a family, histograms and summaries counting once per label set
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_series_per_family: Option<usize>,
+    /// Most sample lines in the whole payload
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_samples: Option<usize>,
+    /// Largest payload, in bytes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_payload_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The limit a parse went over, which stops it
+pub enum LimitExceeded {
+    LineLength(usize),
+    SeriesPerFamily { family: String, limit: usize },
+    Samples(usize),
+    PayloadBytes(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::LineLength(limit) => write!(f, "a line is longer than {} bytes", limit),
+            LimitExceeded::SeriesPerFamily { family, limit } => {
+                write!(f, "family {} has more than {} series", family, limit)
+            }
+            LimitExceeded::Samples(limit) => write!(f, "more than {} samples", limit),
+            LimitExceeded::PayloadBytes(limit) => {
+                write!(f, "the payload is larger than {} bytes", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Why a stream parser stopped
+pub enum ParseError {
+    /// The input went over one of its limits
+    Limit(LimitExceeded),
+    /// A line that cannot be read, as `PrometheusData::try_from_string` tells
+    Malformed(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Limit(e) => e.fmt(f),
+            ParseError::Malformed(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<LimitExceeded> for ParseError {
+    fn from(e: LimitExceeded) -> ParseError {
+        ParseError::Limit(e)
+    }
+}
+
+/// Fail with `exceeded` when `value` is over `limit`
+pub(crate) fn check(
+    value: usize,
+    limit: Option<usize>,
+    exceeded: fn(usize) -> LimitExceeded,
+) -> Result<(), LimitExceeded> {
+    match limit {
+        Some(limit) if value > limit => Err(exceeded(limit)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::StreamParser;
+
+    fn parse(raw: &str, limits: ParseLimits) -> Result<usize, ParseError> {
+        let mut parser = StreamParser::with_limits(limits);
+        for chunk in raw.as_bytes().chunks(8) {
+            parser.feed(chunk)?;
+        }
+        Ok(parser.finish()?.samples().len())
+    }
+
+    #[test]
+    fn parse_limits_work() {
+        let raw =
+            "# TYPE up gauge\nup{i=\"a\"} 1\nup{i=\"b\"} 1\nup{i=\"c\"} 1\n# TYPE x gauge\nx 1\n";
+        assert_eq!(parse(raw, ParseLimits::default()), Ok(4));
+        let limits = ParseLimits {
+            max_line_length: Some(15),
+            max_series_per_family: Some(3),
+            max_samples: Some(4),
+            max_payload_bytes: Some(raw.len()),
+        };
+        assert_eq!(parse(raw, limits), Ok(4));
+        let over = |limits: ParseLimits| parse(raw, limits).unwrap_err();
+        assert_eq!(
+            over(ParseLimits {
+                max_line_length: Some(14),
+                ..limits
+            }),
+            ParseError::Limit(LimitExceeded::LineLength(14))
+        );
+        assert_eq!(
+            over(ParseLimits {
+                max_series_per_family: Some(2),
+                ..limits
+            }),
+            ParseError::Limit(LimitExceeded::SeriesPerFamily {
+                family: "up".to_string(),
+                limit: 2
+            })
+        );
+        assert_eq!(
+            over(ParseLimits {
+                max_samples: Some(3),
+                ..limits
+            }),
+            ParseError::Limit(LimitExceeded::Samples(3))
+        );
+        assert_eq!(
+            over(ParseLimits {
+                max_payload_bytes: Some(raw.len() - 1),
+                ..limits
+            }),
+            ParseError::Limit(LimitExceeded::PayloadBytes(raw.len() - 1))
+        );
+        // A line never ending is caught before it is whole
+        let mut parser = StreamParser::with_limits(limits);
+        assert_eq!(
+            parser.feed(&[b'a'; 16]),
+            Err(ParseError::Limit(LimitExceeded::LineLength(15)))
+        );
+    }
+}
@@ -0,0 +1,497 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// A problem found in exposition text, with the 1-based line it was found on
+pub struct Issue {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "line {}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+/// The parts of a sample line: name, labels in order, value and optional timestamp
+pub(crate) struct SampleLine<'a> {
+    pub name: &'a str,
+    pub labels: Vec<(&'a str, Cow<'a, str>)>,
+    pub value: &'a str,
+    pub timestamp: Option<&'a str>,
+}
+
+pub(crate) fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+pub(crate) fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The unescaped quoted value at the start of `rest`, and where its closing quote is
+fn unescape_value(rest: &str, label: &str) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, other)) => {
+                    return Err(format!("invalid escape \\{} in label {}", other, label))
+                }
+                None => break,
+            },
+            '"' => return Ok((value, i)),
+            c => value.push(c),
+        }
+    }
+    Err(format!("unterminated value of label {}", label))
+}
+
+/// Split a sample line, unescaping label values
+pub(crate) fn split_sample(line: &str) -> Result<SampleLine<'_>, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or_else(|| "missing value".to_string())?;
+    let name = &line[..name_end];
+    if !is_metric_name(name) {
+        return Err(format!("invalid metric name {:?}", name));
+    }
+    let mut labels = Vec::new();
+    let mut rest = &line[name_end..];
+    if rest.starts_with('{') {
+        rest = &rest[1..];
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if let Some(after) = rest.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let eq = rest
+                .find('=')
+                .ok_or_else(|| "unterminated label set".to_string())?;
+            let label = rest[..eq].trim();
+            if !is_label_name(label) {
+                return Err(format!("invalid label name {:?}", label));
+            }
+            rest = rest[eq + 1..].trim_start();
+            if !rest.starts_with('"') {
+                return Err(format!("label {} has an unquoted value", label));
+            }
+            // Most values have no escapes, and are borrowed as they are
+            let (value, end) = match memchr::memchr2(b'"', b'\\', &rest.as_bytes()[1..]) {
+                Some(i) if rest.as_bytes()[1 + i] == b'"' => {
+                    (Cow::Borrowed(&rest[1..1 + i]), 1 + i)
+                }
+                _ => {
+                    let (value, end) = unescape_value(rest, label)?;
+                    (Cow::Owned(value), end)
+                }
+            };
+            labels.push((label, value));
+            rest = &rest[end + 1..];
+            if !rest.starts_with(',') && !rest.trim_start().starts_with('}') {
+                return Err("labels must be separated by commas".to_string());
+            }
+        }
+    }
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or_else(|| "missing value".to_string())?;
+    let timestamp = fields.next();
+    if fields.next().is_some() {
+        return Err("unexpected text after the timestamp".to_string());
+    }
+    Ok(SampleLine {
+        name,
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+struct Family {
+    name: String,
+    metric_type: String,
+    has_help: bool,
+    has_samples: bool,
+}
+
+#[derive(Default)]
+/// Observed parts of a single histogram or summary series
+struct Group {
+    line: usize,
+    buckets: Vec<(f64, f64)>,
+    has_sum: bool,
+    has_count: bool,
+}
+
+struct Linter {
+    issues: Vec<Issue>,
+    family: Option<Family>,
+    closed: HashSet<String>,
+    helps: HashSet<String>,
+    types: HashSet<String>,
+    series: HashSet<String>,
+    groups: Vec<(String, Group)>,
+}
+
+impl Linter {
+    fn error(&mut self, line: usize, message: String) {
+        self.issues.push(Issue {
+            line,
+            severity: Severity::Error,
+            message,
+        })
+    }
+
+    fn warn(&mut self, line: usize, message: String) {
+        self.issues.push(Issue {
+            line,
+            severity: Severity::Warning,
+            message,
+        })
+    }
+
+    /// Close the current family, checking the histograms and summaries it contained
+    fn finish_family(&mut self) {
+        let family = match self.family.take() {
+            Some(family) => family,
+            None => return,
+        };
+        for (key, group) in std::mem::take(&mut self.groups) {
+            if family.metric_type == "histogram" {
+                if !group.buckets.iter().any(|(le, _)| le.is_infinite()) {
+                    self.error(group.line, format!("histogram {} has no +Inf bucket", key));
+                }
+                let mut buckets = group.buckets.clone();
+                buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+                if buckets.windows(2).any(|w| w[1].1 < w[0].1) {
+                    self.error(
+                        group.line,
+                        format!("histogram {} buckets are not cumulative", key),
+                    );
+                }
+            }
+            if !group.has_sum || !group.has_count {
+                self.warn(
+                    group.line,
+                    format!("{} {} is missing _sum or _count", family.metric_type, key),
+                );
+            }
+        }
+        if family.metric_type == "counter" && !family.name.ends_with("_total") {
+            self.warn(
+                0,
+                format!("counter {} should have a _total suffix", family.name),
+            );
+        }
+        self.closed.insert(family.name);
+    }
+
+    fn start_family(&mut self, line: usize, name: &str) {
+        if self.family.as_ref().map(|f| f.name.as_str()) == Some(name) {
+            return;
+        }
+        self.finish_family();
+        if self.closed.contains(name) {
+            self.error(
+                line,
+                format!("metric family {} appears more than once", name),
+            );
+        }
+        self.family = Some(Family {
+            name: name.to_string(),
+            metric_type: "untyped".to_string(),
+            has_help: false,
+            has_samples: false,
+        });
+    }
+
+    fn comment(&mut self, line_no: usize, line: &str) {
+        let mut parts = line[1..].trim_start().splitn(3, char::is_whitespace);
+        let keyword = parts.next().unwrap_or("");
+        if keyword != "HELP" && keyword != "TYPE" {
+            return;
+        }
+        let name = match parts.next() {
+            Some(name) if is_metric_name(name) => name,
+            other => {
+                return self.error(
+                    line_no,
+                    format!(
+                        "invalid metric name {:?} in {}",
+                        other.unwrap_or(""),
+                        keyword
+                    ),
+                )
+            }
+        };
+        let rest = parts.next().unwrap_or("").trim();
+        let (seen, kind) = if keyword == "HELP" {
+            (self.helps.insert(name.to_string()), "HELP")
+        } else {
+            (self.types.insert(name.to_string()), "TYPE")
+        };
+        if !seen {
+            self.error(line_no, format!("duplicate {} for {}", kind, name));
+        }
+        self.start_family(line_no, name);
+        let family = self.family.as_mut().unwrap();
+        if keyword == "HELP" {
+            family.has_help = true;
+            return;
+        }
+        if family.has_samples {
+            self.error(line_no, format!("TYPE for {} after its samples", name));
+        }
+        match rest {
+            "counter" | "gauge" | "histogram" | "summary" | "untyped" => {
+                self.family.as_mut().unwrap().metric_type = rest.to_string()
+            }
+            other => self.error(line_no, format!("unknown metric type {:?}", other)),
+        }
+    }
+
+    /// The family a sample belongs to, given the family currently open
+    fn family_of<'a>(&self, name: &'a str) -> &'a str {
+        if let Some(family) = &self.family {
+            let suffix = name.strip_prefix(family.name.as_str());
+            let belongs = match (family.metric_type.as_str(), suffix) {
+                (_, Some("")) => family.metric_type != "histogram",
+                ("histogram", Some("_bucket")) => true,
+                ("histogram", Some("_sum")) | ("histogram", Some("_count")) => true,
+                ("summary", Some("_sum")) | ("summary", Some("_count")) => true,
+                _ => false,
+            };
+            if belongs {
+                return &name[..family.name.len()];
+            }
+        }
+        name
+    }
+
+    fn sample(&mut self, line_no: usize, line: &str) {
+        let sample = match split_sample(line) {
+            Ok(sample) => sample,
+            Err(err) => return self.error(line_no, err),
+        };
+        let family_name = self.family_of(sample.name);
+        self.start_family(line_no, family_name);
+        let family = self.family.as_mut().unwrap();
+        family.has_samples = true;
+        let metric_type = family.metric_type.clone();
+        if !family.has_help {
+            family.has_help = true;
+            self.warn(line_no, format!("metric {} has no HELP", family_name));
+        }
+
+        let mut names = HashSet::new();
+        for (label, _) in &sample.labels {
+            if !names.insert(*label) {
+                self.error(line_no, format!("duplicate label {}", label));
+            }
+            if label.starts_with("__") {
+                self.warn(
+                    line_no,
+                    format!("label {} uses the reserved __ prefix", label),
+                );
+            }
+        }
+        let value = match crate::parse_float(sample.value) {
+            Some(value) => value,
+            None => return self.error(line_no, format!("invalid value {:?}", sample.value)),
+        };
+        if let Some(ts) = sample.timestamp {
+            if ts.parse::<i64>().is_err() {
+                self.error(line_no, format!("invalid timestamp {:?}", ts));
+            }
+        }
+        let mut labels: Vec<String> = sample
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={:?}", k, v))
+            .collect();
+        labels.sort();
+        if !self
+            .series
+            .insert(format!("{}{{{}}}", sample.name, labels.join(",")))
+        {
+            self.error(line_no, format!("duplicate series {}", sample.name));
+        }
+
+        if metric_type != "histogram" && metric_type != "summary" {
+            return;
+        }
+        let special = if metric_type == "histogram" {
+            "le"
+        } else {
+            "quantile"
+        };
+        let mut key: Vec<String> = sample
+            .labels
+            .iter()
+            .filter(|(k, _)| *k != special)
+            .map(|(k, v)| format!("{}={:?}", k, v))
+            .collect();
+        key.sort();
+        let key = format!("{}{{{}}}", family_name, key.join(","));
+        let index = match self.groups.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                self.groups.push((
+                    key,
+                    Group {
+                        line: line_no,
+                        ..Group::default()
+                    },
+                ));
+                self.groups.len() - 1
+            }
+        };
+        let suffix = &sample.name[family_name.len()..];
+        let bound = sample
+            .labels
+            .iter()
+            .find(|(k, _)| *k == special)
+            .map(|(_, v)| v.clone());
+        match (suffix, bound) {
+            ("_sum", _) => self.groups[index].1.has_sum = true,
+            ("_count", _) => self.groups[index].1.has_count = true,
+            (_, Some(bound)) => match crate::parse_float(&bound) {
+                Some(bound) if metric_type == "histogram" => {
+                    self.groups[index].1.buckets.push((bound, value))
+                }
+                Some(_) => {}
+                None => self.error(line_no, format!("invalid {} {:?}", special, bound)),
+            },
+            (_, None) => self.error(
+                line_no,
+                format!("{} sample without a {} label", metric_type, special),
+            ),
+        }
+    }
+}
+
+/// Check exposition text strictly, returning every error and lint warning found
+pub fn lint(text: &str) -> Vec<Issue> {
+    let mut linter = Linter {
+        issues: Vec::new(),
+        family: None,
+        closed: HashSet::new(),
+        helps: HashSet::new(),
+        types: HashSet::new(),
+        series: HashSet::new(),
+        groups: Vec::new(),
+    };
+    let mut last_line = 0;
+    for (i, line) in text.lines().enumerate() {
+        last_line = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        } else if line.starts_with('#') {
+            linter.comment(i + 1, line);
+        } else {
+            linter.sample(i + 1, line);
+        }
+    }
+    linter.finish_family();
+    // Family level warnings are reported without a line, attach them to the end of input
+    let mut first_lines: HashMap<String, usize> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            if let Some(name) = rest.split_whitespace().next() {
+                first_lines.entry(name.to_string()).or_insert(i + 1);
+            }
+        }
+    }
+    for issue in linter.issues.iter_mut().filter(|i| i.line == 0) {
+        let name = issue.message.split_whitespace().nth(1).unwrap_or("");
+        issue.line = first_lines.get(name).copied().unwrap_or(last_line);
+    }
+    linter.issues.sort_by_key(|i| i.line);
+    linter.issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_sample_works() {
+        let sample = split_sample(r#"a_b{x="1",y="q\"uo\\te"} 1.5 1234"#).unwrap();
+        assert_eq!(sample.name, "a_b");
+        assert_eq!(
+            sample.labels,
+            vec![("x", "1".into()), ("y", "q\"uo\\te".into())]
+        );
+        assert!(matches!(sample.labels[0].1, Cow::Borrowed(_)));
+        assert_eq!(sample.value, "1.5");
+        assert_eq!(sample.timestamp, Some("1234"));
+        assert!(split_sample("1abc 1").is_err());
+        assert!(split_sample(r#"a{x=1} 1"#).is_err());
+        assert!(split_sample(r#"a{x="1"}"#).is_err());
+    }
+
+    #[test]
+    fn lint_works() {
+        let issues = lint(
+            "# HELP requests Requests.
+# TYPE requests counter
+requests{code=\"200\"} 1
+requests{code=\"200\"} 2
+# HELP latency Latency.
+# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 5
+latency_bucket{le=\"1\"} 3
+latency_sum 1
+latency_count 5
+untyped_metric abc
+# TYPE weird enum",
+        );
+        let messages: Vec<String> = issues.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 2: warning: counter requests should have a _total suffix",
+                "line 4: error: duplicate series requests",
+                "line 7: error: histogram latency{} has no +Inf bucket",
+                "line 7: error: histogram latency{} buckets are not cumulative",
+                "line 11: warning: metric untyped_metric has no HELP",
+                "line 11: error: invalid value \"abc\"",
+                "line 12: error: unknown metric type \"enum\"",
+            ]
+        );
+        assert!(lint("# HELP up Up.\n# TYPE up gauge\nup 1\n").is_empty());
+    }
+}
@@ -1,16 +1,6 @@
-use structopt::StructOpt;
-use prom2jsonrs::PrometheusData;
+mod cli;
 
-#[derive(StructOpt)]
-struct Cli {
-    // url to query for prom metrics
-    url: String,
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    cli::run().await
 }
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::from_args();
-    let resp = reqwest::blocking::get(&args.url)?.text()?;
-    println!("{}", serde_json::to_string(&PrometheusData::from_string(&resp)).unwrap());
-    Ok(())
-}
-
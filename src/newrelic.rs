@@ -0,0 +1,243 @@
+//! Scrapes as the JSON payload of the New Relic Metric API: a `common` block with the time and
+//! attributes shared by every metric, and the `metrics` array
+
+use crate::diff::{counter_increase, is_counter};
+use crate::{CounterState, Labels, MetricType, PrometheusData};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// The last value of every series and when it was seen, by series id
+type Values = HashMap<String, (i64, f64)>;
+
+/// Remember the `value` of the series `id` at `time_ms`, and give its increase since the previous
+/// value with the time of that one
+fn increase(
+    previous: &Values,
+    current: &mut Values,
+    id: String,
+    value: f64,
+    time_ms: i64,
+) -> Option<(f64, i64)> {
+    let increase = match previous.get(&id) {
+        Some(&(before, last)) if before < time_ms => Some((counter_increase(last, value), before)),
+        _ => None,
+    };
+    current.insert(id, (time_ms, value));
+    increase
+}
+
+/// A metric of the `metrics` array. Counts and summaries cover the interval from `since` to
+/// `time_ms`, and are stamped with its start as the API expects.
+fn metric(
+    name: &str,
+    kind: &str,
+    value: Value,
+    labels: &Labels,
+    since: Option<i64>,
+    time_ms: i64,
+) -> Value {
+    let mut metric = Map::new();
+    metric.insert("name".into(), name.into());
+    metric.insert("type".into(), kind.into());
+    metric.insert("value".into(), value);
+    if let Some(before) = since {
+        metric.insert("timestamp".into(), before.into());
+        metric.insert("interval.ms".into(), (time_ms - before).into());
+    }
+    if !labels.is_empty() {
+        metric.insert("attributes".into(), json!(labels));
+    }
+    Value::Object(metric)
+}
+
+/// The New Relic Metric API payload of the scrapes in `data`, taken at `time_ms`, with the
+/// attributes `common` to every metric. Gauges and untyped series become `gauge` metrics, and
+/// counters (the families named `*_total`) `count` metrics of their increase since the last value
+/// `state` has of them in `scope`. Histograms and summaries become `summary` metrics of the
+/// increase of their count and sum, with unknown `min` and `max`; buckets and quantiles are left
+/// out. Series seen for the first time get no count or summary, and values that are not finite
+/// numbers are skipped. A reset counts as an increase by the whole new value, as in `add_rates`.
+pub fn newrelic_payload(
+    data: &[&PrometheusData],
+    state: &mut CounterState,
+    scope: &str,
+    time_ms: i64,
+    common: &Labels,
+) -> Value {
+    let previous = state.scopes.remove(scope).unwrap_or_default();
+    let mut current = HashMap::new();
+    let mut metrics = Vec::new();
+    for family in data.iter().flat_map(|d| &d.metrics) {
+        let name = &family.metric_name;
+        match family.metric_type {
+            MetricType::Histogram | MetricType::Summary => {
+                for series in &family.data {
+                    let samples = series.samples(name);
+                    let find = |suffix: &str| {
+                        let sample = samples.iter().find(|s| s.name == name.clone() + suffix)?;
+                        let value = sample.float_value().filter(|v| v.is_finite())?;
+                        Some((sample, value))
+                    };
+                    let ((count, count_value), (sum, sum_value)) =
+                        match (find("_count"), find("_sum")) {
+                            (Some(count), Some(sum)) => (count, sum),
+                            _ => continue,
+                        };
+                    let count_increase = increase(
+                        &previous,
+                        &mut current,
+                        count.series_id(),
+                        count_value,
+                        time_ms,
+                    );
+                    let sum_increase =
+                        increase(&previous, &mut current, sum.series_id(), sum_value, time_ms);
+                    if let (Some((count_increase, before)), Some((sum_increase, _))) =
+                        (count_increase, sum_increase)
+                    {
+                        let value = json!({
+                            "count": count_increase,
+                            "sum": sum_increase,
+                            "min": null,
+                            "max": null,
+                        });
+                        metrics.push(metric(
+                            name,
+                            "summary",
+                            value,
+                            &count.labels,
+                            Some(before),
+                            time_ms,
+                        ));
+                    }
+                }
+            }
+            MetricType::Gauge | MetricType::Untyped => {
+                let counter = is_counter(family.metric_type, name, name);
+                for sample in family.samples() {
+                    let value = match sample.float_value().filter(|v| v.is_finite()) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    if !counter {
+                        metrics.push(metric(
+                            name,
+                            "gauge",
+                            value.into(),
+                            &sample.labels,
+                            None,
+                            time_ms,
+                        ));
+                        continue;
+                    }
+                    let id = sample.series_id();
+                    if let Some((delta, before)) =
+                        increase(&previous, &mut current, id, value, time_ms)
+                    {
+                        metrics.push(metric(
+                            name,
+                            "count",
+                            delta.into(),
+                            &sample.labels,
+                            Some(before),
+                            time_ms,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    state.scopes.insert(scope.to_string(), current);
+    let mut block = Map::new();
+    block.insert("timestamp".into(), time_ms.into());
+    if !common.is_empty() {
+        block.insert("attributes".into(), json!(common));
+    }
+    json!([{ "common": block, "metrics": metrics }])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scrape(requests: u32, count: u32, sum: f64) -> PrometheusData {
+        PrometheusData::from_string(&format!(
+            "# TYPE requests_total counter
+requests_total{{code=\"200\"}} {}
+# TYPE load gauge
+load 1.5
+# TYPE latency_seconds histogram
+latency_seconds_bucket{{le=\"1\"}} {}
+latency_seconds_bucket{{le=\"+Inf\"}} {}
+latency_seconds_sum {}
+latency_seconds_count {}
+",
+            requests, count, count, sum, count
+        ))
+    }
+
+    #[test]
+    fn newrelic_payload_works() {
+        let mut state = CounterState::new();
+        let common = hashmap! {"service.name".to_string() => "node".to_string()};
+        let first = newrelic_payload(&[&scrape(100, 4, 2.0)], &mut state, "node", 10_000, &common);
+        assert_eq!(
+            first,
+            json!([{
+                "common": {"timestamp": 10_000, "attributes": {"service.name": "node"}},
+                "metrics": [{"name": "load", "type": "gauge", "value": 1.5}],
+            }])
+        );
+
+        let second = newrelic_payload(
+            &[&scrape(160, 10, 5.0)],
+            &mut state,
+            "node",
+            30_000,
+            &Labels::new(),
+        );
+        assert_eq!(
+            second,
+            json!([{
+                "common": {"timestamp": 30_000},
+                "metrics": [
+                    {
+                        "name": "requests_total",
+                        "type": "count",
+                        "value": 60.0,
+                        "timestamp": 10_000,
+                        "interval.ms": 20_000,
+                        "attributes": {"code": "200"},
+                    },
+                    {"name": "load", "type": "gauge", "value": 1.5},
+                    {
+                        "name": "latency_seconds",
+                        "type": "summary",
+                        "value": {"count": 6.0, "sum": 3.0, "min": null, "max": null},
+                        "timestamp": 10_000,
+                        "interval.ms": 20_000,
+                    },
+                ],
+            }])
+        );
+
+        // A reset counts the whole new value, and other scopes keep their own values
+        let reset = newrelic_payload(
+            &[&scrape(20, 10, 5.0)],
+            &mut state,
+            "node",
+            40_000,
+            &Labels::new(),
+        );
+        assert_eq!(reset[0]["metrics"][0]["value"], 20.0);
+        assert_eq!(reset[0]["metrics"][2]["value"]["count"], 0.0);
+        let other = newrelic_payload(
+            &[&scrape(20, 10, 5.0)],
+            &mut state,
+            "other",
+            40_000,
+            &Labels::new(),
+        );
+        assert_eq!(other[0]["metrics"].as_array().unwrap().len(), 1);
+    }
+}
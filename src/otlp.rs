@@ -0,0 +1,235 @@
+//! Scrapes as OTLP metrics, mapped the way the OpenTelemetry specification maps Prometheus
+//! metrics
+
+use crate::{parse_float, Labels, MetricFamily, MetricLike, MetricType, PrometheusData};
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, summary_data_point, AggregationTemporality, Gauge, Histogram,
+    HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum, Summary,
+    SummaryDataPoint,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+
+pub use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+
+fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+/// The labels of a series as attributes, in order
+fn attributes(labels: Option<&Labels>) -> Vec<KeyValue> {
+    let mut labels: Vec<_> = labels.into_iter().flatten().collect();
+    labels.sort();
+    labels.into_iter().map(|(k, v)| key_value(k, v)).collect()
+}
+
+/// A count, which the exposition format spells as a float
+fn count(value: &str) -> u64 {
+    parse_float(value).filter(|v| *v >= 0.0).unwrap_or(0.0) as u64
+}
+
+/// The data point of a gauge, counter or untyped series, none when its value is not a number
+fn number_point(m: &dyn MetricLike, name: &str, time_unix_nano: u64) -> Option<NumberDataPoint> {
+    let value = m.samples(name).first()?.float_value()?;
+    Some(NumberDataPoint {
+        attributes: attributes(m.labels()),
+        time_unix_nano,
+        value: Some(number_data_point::Value::AsDouble(value)),
+        ..NumberDataPoint::default()
+    })
+}
+
+fn histogram_point(m: &dyn MetricLike, name: &str, time_unix_nano: u64) -> HistogramDataPoint {
+    let mut point = HistogramDataPoint {
+        attributes: attributes(m.labels()),
+        time_unix_nano,
+        ..HistogramDataPoint::default()
+    };
+    // Buckets come in order, counting every observation up to their bound
+    let mut cumulative = 0;
+    for sample in m.samples(name) {
+        if let Some(le) = sample.labels.get("le") {
+            let bucket = count(&sample.value);
+            match parse_float(le) {
+                Some(bound) if bound.is_finite() => {
+                    point.explicit_bounds.push(bound);
+                    point.bucket_counts.push(bucket.saturating_sub(cumulative));
+                    cumulative = bucket;
+                }
+                _ => {}
+            }
+        } else if sample.name.ends_with("_sum") {
+            point.sum = sample.float_value();
+        } else if sample.name.ends_with("_count") {
+            point.count = count(&sample.value);
+        }
+    }
+    point
+        .bucket_counts
+        .push(point.count.saturating_sub(cumulative));
+    point
+}
+
+fn summary_point(m: &dyn MetricLike, name: &str, time_unix_nano: u64) -> SummaryDataPoint {
+    let mut point = SummaryDataPoint {
+        attributes: attributes(m.labels()),
+        time_unix_nano,
+        ..SummaryDataPoint::default()
+    };
+    for sample in m.samples(name) {
+        if let Some(quantile) = sample.labels.get("quantile") {
+            if let (Some(quantile), Some(value)) = (parse_float(quantile), sample.float_value()) {
+                point
+                    .quantile_values
+                    .push(summary_data_point::ValueAtQuantile { quantile, value });
+            }
+        } else if sample.name.ends_with("_sum") {
+            point.sum = sample.float_value().unwrap_or_default();
+        } else if sample.name.ends_with("_count") {
+            point.count = count(&sample.value);
+        }
+    }
+    point
+}
+
+/// A family as an OTLP metric. Counters parse as gauges, so gauge families named `*_total`
+/// are taken for counters and become monotonic sums.
+fn otlp_metric(family: &MetricFamily, time_unix_nano: u64) -> Metric {
+    let name = &family.metric_name;
+    let series = family.data.iter().map(|m| m.as_ref());
+    let cumulative = AggregationTemporality::Cumulative as i32;
+    let data = match family.metric_type {
+        MetricType::Gauge if name.ends_with("_total") => metric::Data::Sum(Sum {
+            data_points: series
+                .filter_map(|m| number_point(m, name, time_unix_nano))
+                .collect(),
+            aggregation_temporality: cumulative,
+            is_monotonic: true,
+        }),
+        MetricType::Gauge | MetricType::Untyped => metric::Data::Gauge(Gauge {
+            data_points: series
+                .filter_map(|m| number_point(m, name, time_unix_nano))
+                .collect(),
+        }),
+        MetricType::Histogram => metric::Data::Histogram(Histogram {
+            data_points: series
+                .map(|m| histogram_point(m, name, time_unix_nano))
+                .collect(),
+            aggregation_temporality: cumulative,
+        }),
+        MetricType::Summary => metric::Data::Summary(Summary {
+            data_points: series
+                .map(|m| summary_point(m, name, time_unix_nano))
+                .collect(),
+        }),
+    };
+    Metric {
+        name: name.clone(),
+        description: family.help.clone(),
+        data: Some(data),
+        ..Metric::default()
+    }
+}
+
+/// An OTLP export request of every series of `data` observed at `time_unix_nano`, from a
+/// resource with the `resource` attributes (e.g. `service.name`). Gauges and untyped series
+/// whose value is not a number are left out.
+pub fn otlp_metrics_request(
+    data: &PrometheusData,
+    resource: &Labels,
+    time_unix_nano: u64,
+) -> ExportMetricsServiceRequest {
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: attributes(Some(resource)),
+                ..Resource::default()
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: env!("CARGO_PKG_NAME").to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    ..InstrumentationScope::default()
+                }),
+                metrics: data
+                    .metrics
+                    .iter()
+                    .map(|family| otlp_metric(family, time_unix_nano))
+                    .collect(),
+                ..ScopeMetrics::default()
+            }],
+            ..ResourceMetrics::default()
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn otlp_mapping_works() {
+        let data = PrometheusData::from_string(
+            "# HELP http_requests_total Requests.
+# TYPE http_requests_total counter
+http_requests_total{code=\"200\"} 1027
+# TYPE up gauge
+up 1
+# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 2
+latency_bucket{le=\"1\"} 5
+latency_bucket{le=\"+Inf\"} 6
+latency_sum 3.5
+latency_count 6
+# TYPE rpc summary
+rpc{quantile=\"0.5\"} 0.2
+rpc_sum 8
+rpc_count 40",
+        );
+        let resource = hashmap! {"service.name".to_string() => "api".to_string()};
+        let request = otlp_metrics_request(&data, &resource, 42);
+        let resource_metrics = &request.resource_metrics[0];
+        let resource = resource_metrics.resource.as_ref().unwrap();
+        assert_eq!(resource.attributes, vec![key_value("service.name", "api")]);
+        let metrics = &resource_metrics.scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 4);
+
+        match &metrics[0].data {
+            Some(metric::Data::Sum(sum)) => {
+                assert!(sum.is_monotonic);
+                let point = &sum.data_points[0];
+                assert_eq!(point.attributes, vec![key_value("code", "200")]);
+                assert_eq!(point.time_unix_nano, 42);
+                assert_eq!(
+                    point.value,
+                    Some(number_data_point::Value::AsDouble(1027.0))
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(metrics[0].description, "Requests.");
+        assert!(matches!(metrics[1].data, Some(metric::Data::Gauge(_))));
+        match &metrics[2].data {
+            Some(metric::Data::Histogram(histogram)) => {
+                let point = &histogram.data_points[0];
+                assert_eq!(point.explicit_bounds, vec![0.1, 1.0]);
+                assert_eq!(point.bucket_counts, vec![2, 3, 1]);
+                assert_eq!((point.count, point.sum), (6, Some(3.5)));
+            }
+            other => panic!("{:?}", other),
+        }
+        match &metrics[3].data {
+            Some(metric::Data::Summary(summary)) => {
+                let point = &summary.data_points[0];
+                assert_eq!((point.count, point.sum), (40, 8.0));
+                assert_eq!(point.quantile_values[0].value, 0.2);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Anonymizing label values, redacted or hashed, before scrapes leave the host
+
+use crate::PrometheusData;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// What redacted label values become
+pub const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            other => Err(format!(
+                "Unknown hash algorithm {}, expected sha256 or sha512",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(deny_unknown_fields))]
+/// The labels to anonymize, by name
+pub struct PrivacyConfig {
+    /// Labels whose values are replaced with `REDACTED`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub redact: Vec<String>,
+    /// Labels whose values are replaced with their hex digest, which still tells series apart
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hash: HashMap<String, HashAlgorithm>,
+    /// Prepended to values before hashing them, so that guessed values cannot be hashed to find
+    /// which one a digest is
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub salt: Option<String>,
+}
+
+pub(crate) fn hex(digest: &[u8]) -> String {
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+impl PrivacyConfig {
+    pub fn is_empty(&self) -> bool {
+        self.redact.is_empty() && self.hash.is_empty()
+    }
+
+    /// The anonymized `value` of `label`, none when the label is not anonymized
+    pub fn anonymize(&self, label: &str, value: &str) -> Option<String> {
+        if self.redact.iter().any(|l| l == label) {
+            return Some(REDACTED.to_string());
+        }
+        let salted = format!("{}{}", self.salt.as_deref().unwrap_or(""), value);
+        match self.hash.get(label)? {
+            HashAlgorithm::Sha256 => Some(hex(&Sha256::digest(salted.as_bytes()))),
+            HashAlgorithm::Sha512 => Some(hex(&Sha512::digest(salted.as_bytes()))),
+        }
+    }
+
+    /// Anonymize the labels of every series
+    pub fn apply(&self, data: &mut PrometheusData) {
+        if self.is_empty() {
+            return;
+        }
+        for metric in data.metrics.iter_mut().flat_map(|f| f.data.iter_mut()) {
+            for (label, value) in metric.labels_mut().iter_mut().flatten() {
+                if let Some(anonymized) = self.anonymize(label, value) {
+                    *value = anonymized;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn anonymization_works() {
+        let mut data = PrometheusData::from_string(
+            "logins_total{user_id=\"42\",email=\"a@example.com\",code=\"200\"} 1",
+        );
+        let privacy = PrivacyConfig {
+            redact: vec!["user_id".to_string()],
+            hash: hashmap! {"email".to_string() => HashAlgorithm::Sha256},
+            salt: None,
+        };
+        privacy.apply(&mut data);
+        let labels = data.metrics[0].data[0].labels().unwrap();
+        assert_eq!(labels["user_id"], REDACTED);
+        assert_eq!(
+            labels["email"],
+            "08168cd80dfd534ab0f10af10f1303fe00af2d43ab5c1432360d137f8197e17a"
+        );
+        assert_eq!(labels["code"], "200");
+
+        let salted = PrivacyConfig {
+            salt: Some("pepper".to_string()),
+            ..privacy
+        };
+        assert_ne!(
+            salted.anonymize("email", "a@example.com").unwrap(),
+            labels["email"]
+        );
+        assert_eq!(salted.anonymize("code", "200"), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! The protobuf exposition format, `application/vnd.google.protobuf;
+//! proto=io.prometheus.client.MetricFamily;encoding=delimited`, which some exporters still
+//! answer with when asked for it: length-delimited `MetricFamily` messages.
+
+use crate::{
+    format_float, Histogram, Labels, Metric, MetricFamily, MetricLike, MetricType, PrometheusData,
+    Summary,
+};
+use prost::Message;
+
+pub use prost::DecodeError;
+
+/// The content type of the protobuf exposition format
+pub const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+// io.prometheus.client.MetricType
+const COUNTER: i32 = 0;
+const GAUGE: i32 = 1;
+const SUMMARY: i32 = 2;
+const HISTOGRAM: i32 = 4;
+const GAUGE_HISTOGRAM: i32 = 5;
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoFamily {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    help: String,
+    #[prost(int32, tag = "3")]
+    r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    metric: Vec<ProtoMetric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoMetric {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    gauge: Option<SingleValue>,
+    #[prost(message, optional, tag = "3")]
+    counter: Option<SingleValue>,
+    #[prost(message, optional, tag = "4")]
+    summary: Option<ProtoSummary>,
+    #[prost(message, optional, tag = "5")]
+    untyped: Option<SingleValue>,
+    #[prost(message, optional, tag = "7")]
+    histogram: Option<ProtoHistogram>,
+    #[prost(int64, optional, tag = "6")]
+    timestamp_ms: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LabelPair {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+/// Gauge, Counter and Untyped, which all hold their value at tag 1
+#[derive(Clone, PartialEq, Message)]
+struct SingleValue {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoSummary {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    quantile: Vec<Quantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Quantile {
+    #[prost(double, tag = "1")]
+    quantile: f64,
+    #[prost(double, tag = "2")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProtoHistogram {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    bucket: Vec<Bucket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Bucket {
+    #[prost(uint64, tag = "1")]
+    cumulative_count: u64,
+    #[prost(double, tag = "2")]
+    upper_bound: f64,
+}
+
+fn labels(pairs: Vec<LabelPair>) -> Labels {
+    pairs.into_iter().map(|l| (l.name, l.value)).collect()
+}
+
+impl ProtoMetric {
+    fn into_metric_like(self, metric_type: MetricType) -> Box<dyn MetricLike> {
+        let labels = labels(self.label);
+        match metric_type {
+            MetricType::Histogram => {
+                let histogram = self.histogram.unwrap_or_default();
+                Box::new(Histogram {
+                    labels: Some(labels),
+                    buckets: histogram
+                        .bucket
+                        .into_iter()
+                        .map(|b| (format_float(b.upper_bound), b.cumulative_count.to_string()))
+                        .collect(),
+                    count: histogram.sample_count.to_string(),
+                    sum: format_float(histogram.sample_sum),
+                    percentiles: None,
+                })
+            }
+            MetricType::Summary => {
+                let summary = self.summary.unwrap_or_default();
+                Box::new(Summary {
+                    labels: Some(labels),
+                    quantiles: summary
+                        .quantile
+                        .into_iter()
+                        .map(|q| (format_float(q.quantile), format_float(q.value)))
+                        .collect(),
+                    count: summary.sample_count.to_string(),
+                    sum: format_float(summary.sample_sum),
+                })
+            }
+            MetricType::Gauge | MetricType::Untyped => {
+                let value = self
+                    .gauge
+                    .or(self.counter)
+                    .or(self.untyped)
+                    .unwrap_or_default();
+                Box::new(Metric {
+                    labels: Some(labels).filter(|l| !l.is_empty()),
+                    value: format_float(value.value),
+                    timestamp: self.timestamp_ms,
+                })
+            }
+        }
+    }
+}
+
+impl From<ProtoFamily> for MetricFamily {
+    fn from(family: ProtoFamily) -> MetricFamily {
+        // Counters are gauges, as in text
+        let metric_type = match family.r#type {
+            COUNTER | GAUGE => MetricType::Gauge,
+            SUMMARY => MetricType::Summary,
+            HISTOGRAM | GAUGE_HISTOGRAM => MetricType::Histogram,
+            _ => MetricType::Untyped,
+        };
+        MetricFamily {
+            metric_type,
+            metric_name: family.name,
+            help: family.help,
+            data: family
+                .metric
+                .into_iter()
+                .map(|m| m.into_metric_like(metric_type))
+                .collect(),
+        }
+    }
+}
+
+impl PrometheusData {
+    /// Parse a payload of the protobuf exposition format into the same model as text
+    pub fn from_protobuf(mut bytes: &[u8]) -> Result<PrometheusData, DecodeError> {
+        let mut metrics = Vec::new();
+        while !bytes.is_empty() {
+            metrics.push(ProtoFamily::decode_length_delimited(&mut bytes)?.into());
+        }
+        Ok(PrometheusData { metrics })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(families: &[ProtoFamily]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for family in families {
+            family.encode_length_delimited(&mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn protobuf_parsing_works() {
+        let label = |name: &str, value: &str| LabelPair {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let bytes = encode(&[
+            ProtoFamily {
+                name: "http_requests_total".to_string(),
+                help: "Requests.".to_string(),
+                r#type: COUNTER,
+                metric: vec![ProtoMetric {
+                    label: vec![label("code", "200")],
+                    counter: Some(SingleValue { value: 1027.0 }),
+                    timestamp_ms: Some(1395066363000),
+                    ..ProtoMetric::default()
+                }],
+            },
+            ProtoFamily {
+                name: "latency".to_string(),
+                help: String::new(),
+                r#type: HISTOGRAM,
+                metric: vec![ProtoMetric {
+                    label: vec![label("job", "api")],
+                    histogram: Some(ProtoHistogram {
+                        sample_count: 3,
+                        sample_sum: 0.5,
+                        bucket: vec![
+                            Bucket {
+                                cumulative_count: 2,
+                                upper_bound: 0.1,
+                            },
+                            Bucket {
+                                cumulative_count: 3,
+                                upper_bound: f64::INFINITY,
+                            },
+                        ],
+                    }),
+                    ..ProtoMetric::default()
+                }],
+            },
+        ]);
+        let from_text = PrometheusData::from_string(
+            "# HELP http_requests_total Requests.
+# TYPE http_requests_total counter
+http_requests_total{code=\"200\"} 1027 1395066363000
+# TYPE latency histogram
+latency_bucket{job=\"api\",le=\"0.1\"} 2
+latency_bucket{job=\"api\",le=\"+Inf\"} 3
+latency_sum{job=\"api\"} 0.5
+latency_count{job=\"api\"} 3",
+        );
+        let data = PrometheusData::from_protobuf(&bytes).unwrap();
+        assert_eq!(data.samples(), from_text.samples());
+        for (family, text_family) in data.metrics.iter().zip(&from_text.metrics) {
+            assert_eq!(family.metric_type, text_family.metric_type);
+            assert_eq!(family.help, text_family.help);
+        }
+        assert!(PrometheusData::from_protobuf(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{Labels, Metric, MetricFamily, MetricLike, MetricType, PrometheusData};
+use serde::Deserialize;
+use serde_json::Value as Json;
+
+#[derive(Deserialize)]
+struct Response {
+    status: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    data: Option<ResponseData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseData {
+    result_type: String,
+    result: Json,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    metric: Labels,
+    #[serde(default)]
+    value: Option<(f64, String)>,
+    #[serde(default)]
+    values: Vec<(f64, String)>,
+}
+
+fn point(labels: &Labels, (time, value): (f64, String)) -> Box<dyn MetricLike> {
+    Box::new(Metric {
+        labels: if labels.is_empty() {
+            None
+        } else {
+            Some(labels.clone())
+        },
+        value,
+        timestamp: Some((time * 1000.0).round() as i64),
+    })
+}
+
+impl PrometheusData {
+    /// Normalize a Prometheus HTTP API `/api/v1/query` or `/api/v1/query_range` response into
+    /// untyped families, one per `__name__`. Every point of a range becomes a timestamped
+    /// series; results without a name (e.g. of `rate()` or scalars) go to a family named
+    /// after `expr`.
+    pub fn from_query_response(s: &str, expr: &str) -> Result<PrometheusData, String> {
+        let response: Response =
+            serde_json::from_str(s).map_err(|e| format!("Invalid query response: {}", e))?;
+        if response.status != "success" {
+            return Err(format!(
+                "Query failed: {}",
+                response.error.unwrap_or(response.status)
+            ));
+        }
+        let data = response.data.ok_or("Query response without data")?;
+        let mut metrics: Vec<MetricFamily> = Vec::new();
+        let mut push = |name: &str, metric: Box<dyn MetricLike>| match metrics
+            .iter_mut()
+            .find(|f| f.metric_name == name)
+        {
+            Some(family) => family.data.push(metric),
+            None => metrics.push(MetricFamily {
+                metric_type: MetricType::Untyped,
+                metric_name: name.to_string(),
+                help: String::new(),
+                data: vec![metric],
+            }),
+        };
+        match data.result_type.as_str() {
+            "vector" | "matrix" => {
+                let series: Vec<Series> = serde_json::from_value(data.result)
+                    .map_err(|e| format!("Invalid query result: {}", e))?;
+                for mut series in series {
+                    let name = series
+                        .metric
+                        .remove("__name__")
+                        .unwrap_or_else(|| expr.to_string());
+                    for value in series.value.into_iter().chain(series.values) {
+                        push(&name, point(&series.metric, value));
+                    }
+                }
+            }
+            "scalar" | "string" => {
+                let value: (f64, String) = serde_json::from_value(data.result)
+                    .map_err(|e| format!("Invalid query result: {}", e))?;
+                push(expr, point(&Labels::new(), value));
+            }
+            other => return Err(format!("Unknown query result type {}", other)),
+        }
+        Ok(PrometheusData { metrics })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_response_parsing_works() {
+        let data = PrometheusData::from_query_response(
+            r#"{"status": "success", "data": {"resultType": "vector", "result": [
+                {"metric": {"__name__": "up", "job": "api"}, "value": [1435781451.781, "1"]},
+                {"metric": {"__name__": "up", "job": "node"}, "value": [1435781451.781, "0"]},
+                {"metric": {"job": "api"}, "value": [1435781451.781, "0.5"]}
+            ]}}"#,
+            "rate(x[5m])",
+        )
+        .unwrap();
+        assert_eq!(data.metrics.len(), 2);
+        assert_eq!(data.metrics[0].metric_name, "up");
+        assert_eq!(data.metrics[0].data.len(), 2);
+        assert_eq!(data.metrics[1].metric_name, "rate(x[5m])");
+        let json = serde_json::to_string(&data.metrics[0].data[0]).unwrap();
+        assert!(json.contains("\"timestamp\":1435781451781"));
+
+        let data = PrometheusData::from_query_response(
+            r#"{"status": "success", "data": {"resultType": "matrix", "result": [
+                {"metric": {"__name__": "up"}, "values": [[1, "1"], [2, "0"]]}
+            ]}}"#,
+            "up",
+        )
+        .unwrap();
+        assert_eq!(data.samples().len(), 2);
+        assert_eq!(data.metrics[0].data[1].labels(), None);
+
+        let data = PrometheusData::from_query_response(
+            r#"{"status": "success", "data": {"resultType": "scalar", "result": [1, "2"]}}"#,
+            "1+1",
+        )
+        .unwrap();
+        assert_eq!(data.samples()[0].value, "2");
+
+        assert!(PrometheusData::from_query_response(
+            r#"{"status": "error", "errorType": "bad_data", "error": "parse error"}"#,
+            "x"
+        )
+        .is_err());
+    }
+}
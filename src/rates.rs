@@ -0,0 +1,119 @@
+//! Per-second rates of counters between conversions, from their last-seen values which can be
+//! saved and loaded again so that rates and resets carry over process restarts
+
+use crate::diff::{counter_increase, is_counter};
+use crate::{format_float, Metric, MetricFamily, MetricLike, MetricType, PrometheusData};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The last value of every counter series and when it was seen, by scope (e.g. the target the
+/// series were scraped from)
+pub struct CounterState {
+    pub(crate) scopes: HashMap<String, HashMap<String, (i64, f64)>>,
+}
+
+impl CounterState {
+    pub fn new() -> CounterState {
+        CounterState::default()
+    }
+}
+
+impl PrometheusData {
+    /// Add the per-second rate of every counter series (of the families named `*_total`) since
+    /// the last value `state` has of it in `scope`, as a series of the gauge family
+    /// `<family>_rate`, then remember the values of `time_ms` instead. A reset counts as an
+    /// increase by the whole new value as in Prometheus. Series seen for the first time get no
+    /// rate, and those gone from the scope are forgotten.
+    pub fn add_rates(&mut self, state: &mut CounterState, scope: &str, time_ms: i64) {
+        let previous = state.scopes.remove(scope).unwrap_or_default();
+        let mut current = HashMap::new();
+        let mut families = Vec::new();
+        for family in &self.metrics {
+            let name = &family.metric_name;
+            // Only plain counters, not the buckets, counts and sums of histograms and summaries
+            if !is_counter(family.metric_type, name, name) {
+                continue;
+            }
+            let mut series: Vec<Box<dyn MetricLike>> = Vec::new();
+            for sample in family.samples() {
+                let value = match sample.float_value().filter(|v| v.is_finite()) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let id = sample.series_id();
+                match previous.get(&id) {
+                    Some(&(before, last)) if before < time_ms => {
+                        let seconds = (time_ms - before) as f64 / 1000.0;
+                        series.push(Box::new(Metric {
+                            labels: Some(sample.labels).filter(|l| !l.is_empty()),
+                            value: format_float(counter_increase(last, value) / seconds),
+                            timestamp: None,
+                        }));
+                    }
+                    _ => {}
+                }
+                current.insert(id, (time_ms, value));
+            }
+            if !series.is_empty() {
+                families.push(MetricFamily {
+                    metric_type: MetricType::Gauge,
+                    help: format!("Per-second increase of {} since the previous scrape", name),
+                    metric_name: format!("{}_rate", name),
+                    data: series,
+                });
+            }
+        }
+        state.scopes.insert(scope.to_string(), current);
+        self.metrics.extend(families);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rates_work() {
+        let scrape = |requests: u32| {
+            PrometheusData::from_string(&format!(
+                "# TYPE requests_total counter
+requests_total{{code=\"200\"}} {}
+# TYPE load gauge
+load 1",
+                requests
+            ))
+        };
+        let rates = |data: &PrometheusData| -> Vec<String> {
+            data.metrics
+                .iter()
+                .filter(|f| f.metric_name.ends_with("_rate"))
+                .flat_map(|f| f.samples())
+                .map(|s| format!("{} {}", s.series_id(), s.value))
+                .collect()
+        };
+        let mut state = CounterState::new();
+        let mut first = scrape(100);
+        first.add_rates(&mut state, "node", 0);
+        assert!(rates(&first).is_empty());
+
+        let mut second = scrape(160);
+        second.add_rates(&mut state, "node", 20_000);
+        assert_eq!(rates(&second), vec!["requests_total_rate{code=\"200\"} 3"]);
+        // Other scopes keep their own values
+        let mut other = scrape(0);
+        other.add_rates(&mut state, "other", 20_000);
+        assert!(rates(&other).is_empty());
+
+        // Carried over through a copy of the state, as one saved and loaded again
+        let mut restored = state.clone();
+        let mut reset = scrape(20);
+        reset.add_rates(&mut restored, "node", 30_000);
+        assert_eq!(rates(&reset), vec!["requests_total_rate{code=\"200\"} 2"]);
+        let mut same_time = scrape(40);
+        same_time.add_rates(&mut restored, "node", 30_000);
+        assert!(rates(&same_time).is_empty());
+    }
+}
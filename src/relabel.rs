@@ -0,0 +1,230 @@
+use crate::{Labels, PrometheusData};
+use regex::Regex;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum RelabelAction {
+    #[default]
+    Replace,
+    Keep,
+    Drop,
+    LabelMap,
+    LabelDrop,
+    LabelKeep,
+}
+
+#[cfg(any(feature = "serde", test))]
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+#[cfg(any(feature = "serde", test))]
+fn default_regex() -> String {
+    "(.*)".to_string()
+}
+
+#[cfg(any(feature = "serde", test))]
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(deny_unknown_fields))]
+/// A Prometheus `metric_relabel_configs` style rule. `__name__` can be used as a source
+/// label but not as a target.
+pub struct RelabelConfig {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source_labels: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(default = "default_separator"))]
+    pub separator: String,
+    #[cfg_attr(feature = "serde", serde(default = "default_regex"))]
+    pub regex: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub target_label: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default = "default_replacement"))]
+    pub replacement: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub action: RelabelAction,
+}
+
+struct Rule {
+    config: RelabelConfig,
+    regex: Regex,
+}
+
+/// A compiled list of relabel rules
+pub struct Relabeler {
+    rules: Vec<Rule>,
+}
+
+impl Relabeler {
+    pub fn new(configs: &[RelabelConfig]) -> Result<Relabeler, String> {
+        let mut rules = Vec::new();
+        for config in configs {
+            let regex = Regex::new(&format!("^(?:{})$", config.regex))
+                .map_err(|e| format!("Invalid relabel regex {}: {}", config.regex, e))?;
+            match (config.action, &config.target_label) {
+                (RelabelAction::Replace, None) => {
+                    return Err("replace relabeling needs a target_label".to_string())
+                }
+                (_, Some(label)) if label == "__name__" => {
+                    return Err("relabeling cannot change __name__".to_string())
+                }
+                _ => {}
+            }
+            rules.push(Rule {
+                config: config.clone(),
+                regex,
+            })
+        }
+        Ok(Relabeler { rules })
+    }
+
+    /// Apply the rules to the labels of a series, returning false if it has to be dropped
+    pub fn relabel(&self, name: &str, labels: &mut Labels) -> bool {
+        for rule in &self.rules {
+            let config = &rule.config;
+            let source: Vec<&str> = config
+                .source_labels
+                .iter()
+                .map(|l| match l.as_str() {
+                    "__name__" => name,
+                    l => labels.get(l).map(|v| v.as_str()).unwrap_or(""),
+                })
+                .collect();
+            let source = source.join(&config.separator);
+            match config.action {
+                RelabelAction::Keep if !rule.regex.is_match(&source) => return false,
+                RelabelAction::Drop if rule.regex.is_match(&source) => return false,
+                RelabelAction::Replace => {
+                    if let Some(caps) = rule.regex.captures(&source) {
+                        let mut value = String::new();
+                        caps.expand(&config.replacement, &mut value);
+                        let target = config.target_label.clone().unwrap_or_default();
+                        if value.is_empty() {
+                            labels.remove(&target);
+                        } else {
+                            labels.insert(target, value);
+                        }
+                    }
+                }
+                RelabelAction::LabelMap => {
+                    let mapped: Vec<(String, String)> = labels
+                        .iter()
+                        .filter_map(|(k, v)| {
+                            rule.regex.captures(k).map(|caps| {
+                                let mut key = String::new();
+                                caps.expand(&config.replacement, &mut key);
+                                (key, v.clone())
+                            })
+                        })
+                        .collect();
+                    labels.extend(mapped);
+                }
+                RelabelAction::LabelDrop => labels.retain(|k, _| !rule.regex.is_match(k)),
+                RelabelAction::LabelKeep => labels.retain(|k, _| rule.regex.is_match(k)),
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Relabel every series, dropping those a rule asked to drop
+    pub fn apply(&self, data: &mut PrometheusData) {
+        if self.rules.is_empty() {
+            return;
+        }
+        for family in data.metrics.iter_mut() {
+            let name = &family.metric_name;
+            family.data.retain_mut(|m| {
+                let labels = m.labels_mut();
+                let mut relabeled = labels.clone().unwrap_or_default();
+                if !self.relabel(name, &mut relabeled) {
+                    return false;
+                }
+                if labels.is_some() || !relabeled.is_empty() {
+                    *labels = Some(relabeled);
+                }
+                true
+            });
+        }
+        data.metrics.retain(|f| !f.data.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(fields: &[(&str, &str)], source_labels: &[&str]) -> RelabelConfig {
+        let mut config = RelabelConfig {
+            source_labels: source_labels.iter().map(|s| s.to_string()).collect(),
+            separator: default_separator(),
+            regex: default_regex(),
+            target_label: None,
+            replacement: default_replacement(),
+            action: RelabelAction::Replace,
+        };
+        for (key, value) in fields {
+            match *key {
+                "regex" => config.regex = value.to_string(),
+                "target_label" => config.target_label = Some(value.to_string()),
+                "replacement" => config.replacement = value.to_string(),
+                "action" => {
+                    config.action = match *value {
+                        "keep" => RelabelAction::Keep,
+                        "drop" => RelabelAction::Drop,
+                        "labeldrop" => RelabelAction::LabelDrop,
+                        "labelmap" => RelabelAction::LabelMap,
+                        _ => RelabelAction::Replace,
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        config
+    }
+
+    #[test]
+    fn relabeling_works() {
+        let mut data = PrometheusData::from_string(
+            "# HELP up Up.
+# TYPE up gauge
+up{instance=\"a:9100\",pod_name=\"p\",job=\"node\"} 1
+up{instance=\"b:9100\",job=\"drop-me\"} 1
+# HELP go_goroutines Goroutines.
+# TYPE go_goroutines gauge
+go_goroutines 5",
+        );
+        let relabeler = Relabeler::new(&[
+            config(
+                &[("regex", "(.*):\\d+"), ("target_label", "host")],
+                &["instance"],
+            ),
+            config(&[("regex", "drop-me"), ("action", "drop")], &["job"]),
+            config(&[("regex", "go_.*"), ("action", "drop")], &["__name__"]),
+            config(&[("regex", "pod_(.*)"), ("action", "labelmap")], &[]),
+            config(&[("regex", "pod_.*"), ("action", "labeldrop")], &[]),
+        ])
+        .unwrap();
+        relabeler.apply(&mut data);
+        assert_eq!(data.metrics.len(), 1);
+        assert_eq!(data.metrics[0].data.len(), 1);
+        assert_eq!(
+            data.metrics[0].data[0].labels(),
+            Some(&hashmap! {
+                "instance".to_string() => "a:9100".to_string(),
+                "host".to_string() => "a".to_string(),
+                "job".to_string() => "node".to_string(),
+                "name".to_string() => "p".to_string(),
+            })
+        );
+        assert!(Relabeler::new(&[config(&[], &["job"])]).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::PrometheusData;
+use prost::Message;
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Encode every sample as a Prometheus remote-write `WriteRequest` stamped with
+/// `timestamp_ms`, as the snappy-compressed protobuf body a remote-write endpoint expects.
+/// Samples whose value is not a number are skipped.
+pub fn remote_write_body(data: &PrometheusData, timestamp_ms: i64) -> Vec<u8> {
+    let timeseries = data
+        .samples()
+        .into_iter()
+        .filter_map(|sample| {
+            let value = sample.float_value()?;
+            let mut labels: Vec<Label> = sample
+                .labels
+                .into_iter()
+                .map(|(name, value)| Label { name, value })
+                .collect();
+            labels.push(Label {
+                name: "__name__".to_string(),
+                value: sample.name,
+            });
+            // Remote-write wants the labels sorted by name
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            Some(TimeSeries {
+                labels,
+                samples: vec![Sample {
+                    value,
+                    timestamp: timestamp_ms,
+                }],
+            })
+        })
+        .collect();
+    let request = WriteRequest { timeseries };
+    snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .expect("snappy compression of an in-memory buffer")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_write_encoding_works() {
+        let data = PrometheusData::from_string(
+            "# HELP up Up.
+# TYPE up gauge
+up{job=\"api\",instance=\"a\"} 1
+up{job=\"db\"} NaN",
+        );
+        let body = remote_write_body(&data, 1700000000000);
+        let raw = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let request = WriteRequest::decode(raw.as_slice()).unwrap();
+        assert_eq!(request.timeseries.len(), 2);
+        let names: Vec<&str> = request.timeseries[0]
+            .labels
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["__name__", "instance", "job"]);
+        assert_eq!(request.timeseries[0].samples[0].value, 1.0);
+        assert_eq!(request.timeseries[0].samples[0].timestamp, 1700000000000);
+        assert!(request.timeseries[1].samples[0].value.is_nan());
+    }
+}
@@ -0,0 +1,348 @@
+//! Byte-oriented reading of sample lines. This reads lines exactly the way the regexes it
+//! replaces did, `([a-zA-Z_:][a-zA-Z0-9_:]*)\s(-?[\d.]+(?:e-?\d+)?|NaN)` for unlabeled
+//! samples, `[a-zA-Z_:][a-zA-Z0-9_:]*\{(.*)\}\s(-?[\d.]+(?:e-?\d+)?|NaN)` for labeled ones and
+//! `([a-zA-Z0-9_:]*)="([^"]+)"` for each label, quirks included: both are searched anywhere in
+//! the line, the value stops at the first character out of the pattern and labels with an
+//! empty value are left out.
+
+use memchr::{memchr, memchr3, memchr3_iter, memchr_iter, memrchr};
+use std::borrow::Cow;
+
+/// The lines of `s`, split the way `str::lines` does
+pub(crate) fn lines(s: &str) -> Lines<'_> {
+    Lines { rest: s }
+}
+
+pub(crate) struct Lines<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let line = match memchr(b'\n', self.rest.as_bytes()) {
+            Some(end) => {
+                let line = &self.rest[..end];
+                self.rest = &self.rest[end + 1..];
+                line.strip_suffix('\r').unwrap_or(line)
+            }
+            None => std::mem::take(&mut self.rest),
+        };
+        Some(line)
+    }
+}
+
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b':'
+}
+
+/// The length of the whitespace character at `i`, or 0
+fn whitespace_len(line: &str, i: usize) -> usize {
+    match line.as_bytes()[i] {
+        b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r' => 1,
+        b if b < 0x80 || !line.is_char_boundary(i) => 0,
+        _ => match line[i..].chars().next() {
+            Some(c) if c.is_whitespace() => c.len_utf8(),
+            _ => 0,
+        },
+    }
+}
+
+/// The value starting at `i`: a number such as `-1.5e-3`, or `NaN`
+fn value_at(line: &str, i: usize) -> Option<&str> {
+    let bytes = &line.as_bytes()[i..];
+    let mut end = usize::from(bytes.first() == Some(&b'-'));
+    let digits = end;
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b'.') {
+        end += 1;
+    }
+    if end > digits {
+        if bytes.get(end) == Some(&b'e') {
+            let mut exponent = end + 1;
+            if bytes.get(exponent) == Some(&b'-') {
+                exponent += 1;
+            }
+            let exponent_digits = exponent;
+            while exponent < bytes.len() && bytes[exponent].is_ascii_digit() {
+                exponent += 1;
+            }
+            if exponent > exponent_digits {
+                end = exponent;
+            }
+        }
+        Some(&line[i..i + end])
+    } else if bytes.starts_with(b"NaN") {
+        Some(&line[i..i + 3])
+    } else {
+        None
+    }
+}
+
+/// The value following whitespace at `i`
+fn value_after_whitespace(line: &str, i: usize) -> Option<&str> {
+    match whitespace_len(line, i) {
+        0 => None,
+        len => value_at(line, i + len),
+    }
+}
+
+/// Whether the run of name characters ending before `end` can start a name, not being all
+/// digits
+fn named_before(bytes: &[u8], end: usize) -> bool {
+    bytes[..end]
+        .iter()
+        .rev()
+        .take_while(|&&b| is_name_byte(b))
+        .any(|b| !b.is_ascii_digit())
+}
+
+/// The value of the first `name value` in the line
+pub(crate) fn unlabeled(line: &str) -> Option<&str> {
+    let bytes = line.as_bytes();
+    // Only spaces, tabs and carriage returns can be whitespace in most lines, which can then
+    // be jumped to
+    if line.is_ascii() && memchr3(b'\n', b'\x0b', b'\x0c', bytes).is_none() {
+        return memchr3_iter(b' ', b'\t', b'\r', bytes)
+            .filter(|&i| named_before(bytes, i))
+            .find_map(|i| value_at(line, i + 1));
+    }
+    // Whether the run of name characters before `i` can start a name
+    let mut named = false;
+    for (i, &b) in bytes.iter().enumerate() {
+        if is_name_byte(b) {
+            named |= !b.is_ascii_digit();
+            continue;
+        }
+        if named {
+            if let Some(value) = value_after_whitespace(line, i) {
+                return Some(value);
+            }
+        }
+        named = false;
+    }
+    None
+}
+
+/// The label section and the value of the first `name{labels} value` in the line, the label
+/// section running to the last `}` followed by a value
+pub(crate) fn labeled(line: &str) -> Option<(&str, &str)> {
+    let bytes = line.as_bytes();
+    // Any later brace would only have fewer closing braces to end at
+    let open = memchr_iter(b'{', bytes).find(|&i| named_before(bytes, i))?;
+    let mut end = bytes.len();
+    while let Some(close) = memrchr(b'}', &bytes[open + 1..end]) {
+        let close = open + 1 + close;
+        if close + 1 < bytes.len() {
+            if let Some(value) = value_after_whitespace(line, close + 1) {
+                return Some((&line[open + 1..close], value));
+            }
+        }
+        end = close;
+    }
+    None
+}
+
+/// The `name="value"` pairs of a label section
+pub(crate) fn label_pairs(labels: &str) -> LabelPairs<'_> {
+    LabelPairs { labels, next: 0 }
+}
+
+pub(crate) struct LabelPairs<'a> {
+    labels: &'a str,
+    next: usize,
+}
+
+impl<'a> Iterator for LabelPairs<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.labels.as_bytes();
+        while self.next <= bytes.len() {
+            let start = self.next;
+            let mut key_end = start;
+            while key_end < bytes.len() && is_name_byte(bytes[key_end]) {
+                key_end += 1;
+            }
+            if bytes[key_end..].starts_with(b"=\"") {
+                let value_start = key_end + 2;
+                if let Some(len) = memchr(b'"', &bytes[value_start..]) {
+                    if len > 0 {
+                        self.next = value_start + len + 1;
+                        return Some((
+                            &self.labels[start..key_end],
+                            &self.labels[value_start..value_start + len],
+                        ));
+                    }
+                }
+            }
+            // Starting anywhere else in the same key would fail the same way
+            self.next = key_end + 1;
+        }
+        None
+    }
+}
+
+/// A label value with its `\\`, `\"` and `\n` escapes undone. Most values have none, and are
+/// borrowed as they are.
+pub(crate) fn unescape(value: &str) -> Cow<'_, str> {
+    let first = match memchr(b'\\', value.as_bytes()) {
+        Some(first) => first,
+        None => return Cow::Borrowed(value),
+    };
+    let mut unescaped = String::with_capacity(value.len());
+    unescaped.push_str(&value[..first]);
+    let mut chars = value[first..].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(escaped @ ('\\' | '"')) => unescaped.push(escaped),
+            // Anything else is not an escape, and kept
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use regex::Regex;
+
+    type Read = (Option<String>, Option<(String, Vec<(String, String)>)>);
+
+    /// Both readings of a line, the way the regexes did
+    fn with_regexes(regexes: &[Regex; 3], line: &str) -> Read {
+        let [no_label, with_label, labels] = regexes;
+        (
+            no_label.captures(line).map(|caps| caps[2].to_string()),
+            with_label.captures(line).map(|caps| {
+                let pairs = labels
+                    .captures_iter(&caps[1])
+                    .map(|cap| (cap[1].to_string(), cap[2].to_string()))
+                    .collect();
+                (caps[2].to_string(), pairs)
+            }),
+        )
+    }
+
+    fn with_scanning(line: &str) -> Read {
+        (
+            unlabeled(line).map(str::to_string),
+            labeled(line).map(|(labels, value)| {
+                let pairs = label_pairs(labels)
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                (value.to_string(), pairs)
+            }),
+        )
+    }
+
+    #[test]
+    fn line_splitting_works() {
+        for s in [
+            "",
+            "\n",
+            "a",
+            "a\n",
+            "a\r\nb\r",
+            "a\n\n\r\nb\rc\n",
+            "\r\n\r",
+        ]
+        .iter()
+        {
+            assert_eq!(lines(s).collect::<Vec<_>>(), s.lines().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn scanning_matches_regexes() {
+        let regexes = [
+            Regex::new(r"([a-zA-Z_:][a-zA-Z0-9_:]*)\s(-?[\d.]+(?:e-?\d+)?|NaN)").unwrap(),
+            Regex::new(r"[a-zA-Z_:][a-zA-Z0-9_:]*\{(.*)\}\s(-?[\d.]+(?:e-?\d+)?|NaN)").unwrap(),
+            Regex::new("([a-zA-Z0-9_:]*)=\"([^\"]+)\"").unwrap(),
+        ];
+        let lines = [
+            "up 1",
+            "up 1 1700000000000",
+            "http_requests_total{method=\"post\",code=\"200\"} 1027 1395066363000",
+            "msdos_file_access_time_seconds{path=\"C:\\\\DIR\\\\FILE.TXT\",error=\"Cannot find file:\\n\\\"FILE.TXT\\\"\"} 1.458255915e9",
+            "metric_without_timestamp_and_labels 12.47",
+            "something_weird{problem=\"division by zero\"} +Inf -3982045",
+            "rpc_duration_seconds{quantile=\"0.99\"} 76656",
+            "http_request_duration_seconds_bucket{le=\"+Inf\"} 144320",
+            "process_max_fds 1.048576e+06",
+            "temperature -1.5e-3",
+            "ratio NaN",
+            "ratio{a=\"b\"} NaN 1700000000000",
+            "ratio{a=\"b\"} 1e5 1700000000000",
+            "empty{a=\"\",b=\"c\"} 3",
+            "trailing{a=\"b\",} 5",
+            "spaced{a=\"x 1\"} 2",
+            "braces{a=\"}\"} 1",
+            "braces{a=\"b\"} 1} 2",
+            "nested{x{a=\"b\"} 1",
+            "9starts_with_digit 1",
+            "f9 1",
+            "{a=\"b\"} 1",
+            "nolabels{} 4",
+            "two  spaces 1",
+            "tab\t-0.5",
+            "unicode{a=\"é 1\"} 2",
+            "nbsp\u{a0}1",
+            "=\"v\" 1",
+            "k{=\"v\",9=\"w\",a-b=\"c\"} 1",
+            "unterminated{a=\"b} 1",
+            "value_only",
+            "",
+            "# HELP up Whether the target is up",
+        ];
+        for line in lines.iter() {
+            assert_eq!(
+                with_scanning(line),
+                with_regexes(&regexes, line),
+                "{:?}",
+                line
+            );
+        }
+
+        // And lines made up of the characters that matter, in any order
+        let alphabet: Vec<char> = "ab9_:{}=\",. \t\r\x0b-eNa\u{e9}".chars().collect();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..20_000 {
+            let mut line = String::from("m");
+            for _ in 0..(seed % 24) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                line.push(alphabet[(seed >> 32) as usize % alphabet.len()]);
+            }
+            assert_eq!(
+                with_scanning(&line),
+                with_regexes(&regexes, &line),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn unescaping_works() {
+        assert!(matches!(unescape("/metrics"), Cow::Borrowed("/metrics")));
+        assert!(matches!(unescape(""), Cow::Borrowed("")));
+        assert_eq!(unescape("C:\\\\dir\\nnext"), "C:\\dir\nnext");
+        assert_eq!(unescape("\\\"quoted\\\""), "\"quoted\"");
+        assert_eq!(unescape("\\d+ and \\"), "\\d+ and \\");
+    }
+}
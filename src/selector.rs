@@ -0,0 +1,231 @@
+use crate::{Labels, Sample};
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    RegexNoMatch,
+}
+
+#[derive(Debug, Clone)]
+/// A single `label="value"` style matcher of a selector
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Default)]
+/// A PromQL style series selector, e.g. `http_requests_total{job="api",code!~"2.."}`
+pub struct Selector {
+    pub metric: Option<String>,
+    pub matchers: Vec<LabelMatcher>,
+}
+
+impl LabelMatcher {
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Result<LabelMatcher, String> {
+        let regex = match op {
+            MatchOp::RegexMatch | MatchOp::RegexNoMatch => Some(
+                Regex::new(&format!("^(?:{})$", value))
+                    .map_err(|e| format!("Invalid regex {}: {}", value, e))?,
+            ),
+            _ => None,
+        };
+        Ok(LabelMatcher {
+            name: name.to_string(),
+            op,
+            value: value.to_string(),
+            regex,
+        })
+    }
+
+    /// Whether a label value satisfies the matcher, a missing label being the empty string
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        let value = value.unwrap_or("");
+        match (self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::RegexMatch, Some(re)) => re.is_match(value),
+            (MatchOp::RegexNoMatch, Some(re)) => !re.is_match(value),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, sample: &Sample) -> bool {
+        self.matches_series(&sample.name, Some(&sample.labels))
+    }
+
+    /// Match a series given its name and labels
+    pub fn matches_series(&self, name: &str, labels: Option<&Labels>) -> bool {
+        if let Some(metric) = &self.metric {
+            if name != metric {
+                return false;
+            }
+        }
+        self.matchers.iter().all(|m| {
+            if m.name == "__name__" {
+                m.matches(Some(name))
+            } else {
+                m.matches(labels.and_then(|l| l.get(&m.name)).map(|v| v.as_str()))
+            }
+        })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Selector, String> {
+        let s = s.trim();
+        let (metric, rest) = match s.find('{') {
+            Some(idx) => (s[..idx].trim(), &s[idx..]),
+            None => (s, ""),
+        };
+        let mut selector = Selector {
+            metric: if metric.is_empty() {
+                None
+            } else {
+                Some(metric.to_string())
+            },
+            matchers: Vec::new(),
+        };
+        if rest.is_empty() {
+            return Ok(selector);
+        }
+        if !rest.ends_with('}') {
+            return Err(format!("Invalid selector {}: missing closing brace", s));
+        }
+        let mut chars = rest[1..rest.len() - 1].chars().peekable();
+        loop {
+            while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+                chars.next();
+            }
+            if chars.peek().is_none() {
+                break;
+            }
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' || c == ':' {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            let op = match (chars.next(), chars.peek()) {
+                (Some('='), Some('~')) => MatchOp::RegexMatch,
+                (Some('='), _) => MatchOp::Equal,
+                (Some('!'), Some('=')) => MatchOp::NotEqual,
+                (Some('!'), Some('~')) => MatchOp::RegexNoMatch,
+                _ => return Err(format!("Invalid selector {}: expected a matcher", s)),
+            };
+            if op != MatchOp::Equal {
+                chars.next();
+            }
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.next() != Some('"') {
+                return Err(format!("Invalid selector {}: expected a quoted value", s));
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some(c) => value.push(c),
+                        None => return Err(format!("Invalid selector {}: unterminated value", s)),
+                    },
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => return Err(format!("Invalid selector {}: unterminated value", s)),
+                }
+            }
+            if name.is_empty() {
+                return Err(format!("Invalid selector {}: missing label name", s));
+            }
+            selector
+                .matchers
+                .push(LabelMatcher::new(&name, op, &value)?);
+        }
+        Ok(selector)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(metric) = &self.metric {
+            write!(f, "{}", metric)?;
+        }
+        if !self.matchers.is_empty() || self.metric.is_none() {
+            let matchers: Vec<String> = self
+                .matchers
+                .iter()
+                .map(|m| {
+                    let op = match m.op {
+                        MatchOp::Equal => "=",
+                        MatchOp::NotEqual => "!=",
+                        MatchOp::RegexMatch => "=~",
+                        MatchOp::RegexNoMatch => "!~",
+                    };
+                    let value = m.value.replace('\\', "\\\\").replace('"', "\\\"");
+                    format!("{}{}\"{}\"", m.name, op, value)
+                })
+                .collect();
+            write!(f, "{{{}}}", matchers.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(name: &str, labels: &[(&str, &str)]) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value: "1".to_string(),
+        }
+    }
+
+    #[test]
+    fn selector_parsing_works() {
+        let selector: Selector = r#"up{job="api", code!~"5..",path=~"/a\"b"}"#.parse().unwrap();
+        assert_eq!(selector.metric, Some("up".to_string()));
+        assert_eq!(selector.matchers.len(), 3);
+        assert_eq!(selector.matchers[1].op, MatchOp::RegexNoMatch);
+        assert_eq!(selector.matchers[2].value, "/a\"b");
+        assert_eq!(
+            selector.to_string(),
+            r#"up{job="api",code!~"5..",path=~"/a\"b"}"#
+        );
+        assert!("{job=api}".parse::<Selector>().is_err());
+        assert!("{job=\"api\"".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn selector_matching_works() {
+        let selector: Selector = r#"{job="api",code=~"2.."}"#.parse().unwrap();
+        assert!(selector.matches(&sample("up", &[("job", "api"), ("code", "200")])));
+        assert!(!selector.matches(&sample("up", &[("job", "api"), ("code", "500")])));
+        assert!(!selector.matches(&sample("up", &[("job", "api")])));
+        let selector: Selector = r#"up{instance!="a"}"#.parse().unwrap();
+        assert!(selector.matches(&sample("up", &[])));
+        assert!(!selector.matches(&sample("down", &[])));
+    }
+}
@@ -0,0 +1,150 @@
+//! SLO attainment of histograms: the fraction of their observations under a threshold, e.g. the
+//! requests served within 300ms, as gauges computed at conversion time
+
+use crate::PrometheusData;
+use crate::{format_float, parse_float, Metric, MetricFamily, MetricLike, MetricType};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(try_from = "String"))]
+/// A histogram family and the bound its observations should stay under, e.g.
+/// `http_request_duration_seconds=0.3`
+pub struct SloThreshold {
+    pub family: String,
+    pub threshold: f64,
+}
+
+impl FromStr for SloThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SloThreshold, String> {
+        let invalid = || format!("Invalid SLO threshold {}, expected `family=threshold`", s);
+        let (family, threshold) = s.split_once('=').ok_or_else(invalid)?;
+        let family = family.trim();
+        let threshold = parse_float(threshold.trim())
+            .filter(|t| !t.is_nan())
+            .ok_or_else(invalid)?;
+        if family.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SloThreshold {
+            family: family.to_string(),
+            threshold,
+        })
+    }
+}
+
+impl TryFrom<String> for SloThreshold {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<SloThreshold, String> {
+        s.parse()
+    }
+}
+
+impl PrometheusData {
+    /// Add the fraction of the observations of every series of the histogram family of `slo`
+    /// at most its threshold (see `Histogram::fraction_below`) as a series of the gauge family
+    /// `<family>_slo_attainment`, with the labels of the histogram series and a `threshold` one.
+    /// Thresholds of the same family share that family; series without observations are left out.
+    pub fn add_slo_attainment(&mut self, slo: &SloThreshold) {
+        let threshold = format_float(slo.threshold);
+        let series: Vec<Box<dyn MetricLike>> = self
+            .metrics
+            .iter()
+            .filter(|f| f.metric_name == slo.family && f.metric_type == MetricType::Histogram)
+            .flat_map(|f| f.data.iter())
+            .filter_map(|histogram| {
+                let fraction = histogram.fraction_below(slo.threshold)?;
+                let mut labels = histogram.labels().cloned().unwrap_or_default();
+                labels.insert("threshold".to_string(), threshold.clone());
+                Some(Box::new(Metric {
+                    labels: Some(labels),
+                    value: format_float(fraction),
+                    timestamp: None,
+                }) as Box<dyn MetricLike>)
+            })
+            .collect();
+        if series.is_empty() {
+            return;
+        }
+        let name = format!("{}_slo_attainment", slo.family);
+        match self.metrics.iter_mut().find(|f| f.metric_name == name) {
+            Some(family) => family.data.extend(series),
+            None => self.metrics.push(MetricFamily {
+                metric_type: MetricType::Gauge,
+                help: format!(
+                    "Fraction of the observations of {} under a threshold",
+                    slo.family
+                ),
+                metric_name: name,
+                data: series,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Histogram;
+
+    #[test]
+    fn slo_attainment_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE latency histogram
+latency_bucket{path=\"/a\",le=\"0.1\"} 2
+latency_bucket{path=\"/a\",le=\"0.5\"} 6
+latency_bucket{path=\"/a\",le=\"1\"} 8
+latency_bucket{path=\"/a\",le=\"+Inf\"} 10
+latency_sum{path=\"/a\"} 4
+latency_count{path=\"/a\"} 10
+latency_bucket{path=\"/b\",le=\"0.1\"} 0
+latency_bucket{path=\"/b\",le=\"0.5\"} 0
+latency_bucket{path=\"/b\",le=\"1\"} 0
+latency_bucket{path=\"/b\",le=\"+Inf\"} 0
+latency_sum{path=\"/b\"} 0
+latency_count{path=\"/b\"} 0",
+        );
+        let histogram = Histogram {
+            labels: None,
+            buckets: data.metrics[0].samples()[..4]
+                .iter()
+                .map(|s| (s.labels["le"].clone(), s.value.clone()))
+                .collect(),
+            count: "10".to_string(),
+            sum: "4".to_string(),
+            percentiles: None,
+        };
+        assert_eq!(histogram.fraction_below(0.3), Some(0.4));
+        assert_eq!(histogram.fraction_below(0.05), Some(0.1));
+        assert_eq!(histogram.fraction_below(1.0), Some(0.8));
+        assert_eq!(histogram.fraction_below(5.0), Some(0.8));
+        assert_eq!(histogram.fraction_below(-1.0), Some(0.0));
+        assert_eq!(histogram.fraction_below(f64::INFINITY), Some(1.0));
+
+        data.add_slo_attainment(&"latency=0.3".parse().unwrap());
+        data.add_slo_attainment(&"latency = 1".parse().unwrap());
+        data.add_slo_attainment(&"missing=1".parse().unwrap());
+        assert_eq!(data.metrics.len(), 2);
+        let samples: Vec<String> = data.metrics[1]
+            .samples()
+            .iter()
+            .map(|s| format!("{} {}", s.series_id(), s.value))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                "latency_slo_attainment{path=\"/a\",threshold=\"0.3\"} 0.4",
+                "latency_slo_attainment{path=\"/a\",threshold=\"1\"} 0.8",
+            ]
+        );
+
+        assert!("latency".parse::<SloThreshold>().is_err());
+        assert!("latency=fast".parse::<SloThreshold>().is_err());
+        assert!("=0.3".parse::<SloThreshold>().is_err());
+    }
+}
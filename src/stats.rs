@@ -0,0 +1,110 @@
+use crate::{MetricType, PrometheusData};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FamilyStats {
+    pub name: String,
+    pub metric_type: MetricType,
+    pub series: usize,
+    /// Approximate size of the family's sample lines in exposition format
+    pub bytes: usize,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LabelStats {
+    pub name: String,
+    pub values: usize,
+    pub series: usize,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+/// Size and cardinality figures of a scrape
+pub struct Stats {
+    pub families: usize,
+    pub series: usize,
+    /// Families by descending series count
+    pub per_family: Vec<FamilyStats>,
+    /// Label names by descending number of distinct values
+    pub labels: Vec<LabelStats>,
+}
+
+/// Count families, series and label values of a scrape
+pub fn stats(data: &PrometheusData) -> Stats {
+    let mut per_family = Vec::new();
+    let mut label_values: HashMap<String, (HashSet<String>, usize)> = HashMap::new();
+    let mut series = 0;
+    for family in &data.metrics {
+        let samples = family.samples();
+        let mut bytes = 0;
+        for sample in &samples {
+            bytes += sample.series_id().len() + sample.value.len() + 2;
+            for (name, value) in &sample.labels {
+                let entry = label_values.entry(name.clone()).or_default();
+                entry.0.insert(value.clone());
+                entry.1 += 1;
+            }
+        }
+        series += samples.len();
+        per_family.push(FamilyStats {
+            name: family.metric_name.clone(),
+            metric_type: family.metric_type,
+            series: samples.len(),
+            bytes,
+        });
+    }
+    per_family.sort_by(|a, b| b.series.cmp(&a.series).then(a.name.cmp(&b.name)));
+    let mut labels: Vec<LabelStats> = label_values
+        .into_iter()
+        .map(|(name, (values, series))| LabelStats {
+            name,
+            values: values.len(),
+            series,
+        })
+        .collect();
+    labels.sort_by(|a, b| b.values.cmp(&a.values).then(a.name.cmp(&b.name)));
+    Stats {
+        families: data.metrics.len(),
+        series,
+        per_family,
+        labels,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stats_works() {
+        let data = PrometheusData::from_string(
+            "# HELP a A.
+# TYPE a gauge
+a{pod=\"1\",job=\"x\"} 1
+a{pod=\"2\",job=\"x\"} 2
+# HELP h H.
+# TYPE h histogram
+h_bucket{pod=\"1\",le=\"1\"} 1
+h_bucket{pod=\"1\",le=\"+Inf\"} 1
+h_sum{pod=\"1\"} 1
+h_count{pod=\"1\"} 1",
+        );
+        let stats = stats(&data);
+        assert_eq!(stats.families, 2);
+        assert_eq!(stats.series, 6);
+        assert_eq!(stats.per_family[0].name, "h");
+        assert_eq!(stats.per_family[0].series, 4);
+        assert_eq!(
+            stats.per_family[1].bytes,
+            2 * "a{job=\"x\",pod=\"1\"} 1\n".len()
+        );
+        assert_eq!(stats.labels[0].name, "le");
+        assert_eq!(stats.labels[1].name, "pod");
+        assert_eq!(stats.labels[1].values, 2);
+        assert_eq!(stats.labels[1].series, 6);
+    }
+}
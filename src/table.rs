@@ -0,0 +1,96 @@
+//! Scrapes as a DataFusion table, to query them with SQL
+
+use crate::PrometheusData;
+use datafusion::arrow::array::{
+    Array, ArrayRef, Float64Builder, MapBuilder, StringBuilder, TimestampMillisecondBuilder,
+};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::MemTable;
+use datafusion::error::Result;
+use std::sync::Arc;
+
+/// A table of every sample of `scrapes`, each one given with the time it was taken at in
+/// milliseconds since the epoch if known (as archived documents are). Its columns are
+/// `scraped_at` (a timestamp), `name`, `labels` (a map, queried as `labels['job']`) and
+/// `value` (a float, null when it is not a number).
+pub fn scrapes_table<'a, I>(scrapes: I) -> Result<MemTable>
+where
+    I: IntoIterator<Item = (Option<i64>, &'a PrometheusData)>,
+{
+    let mut scraped_at = TimestampMillisecondBuilder::new();
+    let mut names = StringBuilder::new();
+    let mut labels = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+    let mut values = Float64Builder::new();
+    for (time, data) in scrapes {
+        for sample in data.samples() {
+            scraped_at.append_option(time);
+            names.append_value(&sample.name);
+            let mut pairs: Vec<_> = sample.labels.iter().collect();
+            pairs.sort();
+            for (key, value) in pairs {
+                labels.keys().append_value(key);
+                labels.values().append_value(value);
+            }
+            labels.append(true)?;
+            values.append_option(sample.float_value());
+        }
+    }
+    let labels = labels.finish();
+    let schema = Arc::new(Schema::new(vec![
+        Field::new(
+            "scraped_at",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            true,
+        ),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("labels", labels.data_type().clone(), false),
+        Field::new("value", DataType::Float64, true),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(scraped_at.finish()),
+        Arc::new(names.finish()),
+        Arc::new(labels),
+        Arc::new(values.finish()),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    MemTable::try_new(schema, vec![vec![batch]])
+}
+
+impl PrometheusData {
+    /// The samples of this scrape as a table, see `scrapes_table`
+    pub fn to_table(&self) -> Result<MemTable> {
+        scrapes_table([(None, self)])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::{Float64Array, StringArray};
+    use datafusion::prelude::SessionContext;
+
+    #[tokio::test]
+    async fn sql_over_scrapes_works() {
+        let old = PrometheusData::from_string("up{job=\"api\"} 0\nup{job=\"node\"} 1");
+        let new = PrometheusData::from_string("up{job=\"api\"} 1\nup{job=\"node\"} 1");
+        let ctx = SessionContext::new();
+        let table = scrapes_table([(Some(1000), &old), (Some(2000), &new)]).unwrap();
+        ctx.register_table("scrapes", Arc::new(table)).unwrap();
+        let batches = ctx
+            .sql(
+                "SELECT name, value FROM scrapes WHERE labels['job'] = 'api' \
+                 ORDER BY scraped_at",
+            )
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let batch = &batches[0];
+        let names = batch.column(0).as_any().downcast_ref::<StringArray>();
+        assert_eq!(names.unwrap().value(1), "up");
+        let values = batch.column(1).as_any().downcast_ref::<Float64Array>();
+        assert_eq!(values.unwrap().values(), &[0.0, 1.0]);
+    }
+}
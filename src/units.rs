@@ -0,0 +1,298 @@
+//! Declarative unit conversions, scaling the values of families and renaming them after their
+//! new unit
+
+use crate::PrometheusData;
+use regex::Regex;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(try_from = "String"))]
+pub enum Unit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+    Bytes,
+    Kilobytes,
+    Kibibytes,
+    Megabytes,
+    Mebibytes,
+    Gigabytes,
+    Gibibytes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dimension {
+    Time,
+    Size,
+}
+
+impl Unit {
+    /// The suffix of the names of families in this unit
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Unit::Nanoseconds => "nanoseconds",
+            Unit::Microseconds => "microseconds",
+            Unit::Milliseconds => "milliseconds",
+            Unit::Seconds => "seconds",
+            Unit::Minutes => "minutes",
+            Unit::Hours => "hours",
+            Unit::Bytes => "bytes",
+            Unit::Kilobytes => "kilobytes",
+            Unit::Kibibytes => "kibibytes",
+            Unit::Megabytes => "megabytes",
+            Unit::Mebibytes => "mebibytes",
+            Unit::Gigabytes => "gigabytes",
+            Unit::Gibibytes => "gibibytes",
+        }
+    }
+
+    /// The size of the unit in nanoseconds or bytes, which are whole and exact as floats
+    fn scale(self) -> (Dimension, f64) {
+        match self {
+            Unit::Nanoseconds => (Dimension::Time, 1.0),
+            Unit::Microseconds => (Dimension::Time, 1e3),
+            Unit::Milliseconds => (Dimension::Time, 1e6),
+            Unit::Seconds => (Dimension::Time, 1e9),
+            Unit::Minutes => (Dimension::Time, 60e9),
+            Unit::Hours => (Dimension::Time, 3600e9),
+            Unit::Bytes => (Dimension::Size, 1.0),
+            Unit::Kilobytes => (Dimension::Size, 1e3),
+            Unit::Kibibytes => (Dimension::Size, 1024.0),
+            Unit::Megabytes => (Dimension::Size, 1e6),
+            Unit::Mebibytes => (Dimension::Size, 1024.0 * 1024.0),
+            Unit::Gigabytes => (Dimension::Size, 1e9),
+            Unit::Gibibytes => (Dimension::Size, 1024.0 * 1024.0 * 1024.0),
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    /// A unit by the suffix of its families or its symbol, e.g. `milliseconds` or `ms`
+    fn from_str(s: &str) -> Result<Unit, String> {
+        let units = [
+            Unit::Nanoseconds,
+            Unit::Microseconds,
+            Unit::Milliseconds,
+            Unit::Seconds,
+            Unit::Minutes,
+            Unit::Hours,
+            Unit::Bytes,
+            Unit::Kilobytes,
+            Unit::Kibibytes,
+            Unit::Megabytes,
+            Unit::Mebibytes,
+            Unit::Gigabytes,
+            Unit::Gibibytes,
+        ];
+        if let Some(unit) = units.iter().find(|u| u.suffix() == s) {
+            return Ok(*unit);
+        }
+        match s {
+            "ns" => Ok(Unit::Nanoseconds),
+            "us" | "µs" => Ok(Unit::Microseconds),
+            "ms" => Ok(Unit::Milliseconds),
+            "s" => Ok(Unit::Seconds),
+            "min" => Ok(Unit::Minutes),
+            "h" => Ok(Unit::Hours),
+            "B" => Ok(Unit::Bytes),
+            "KB" | "kB" => Ok(Unit::Kilobytes),
+            "KiB" => Ok(Unit::Kibibytes),
+            "MB" => Ok(Unit::Megabytes),
+            "MiB" => Ok(Unit::Mebibytes),
+            "GB" => Ok(Unit::Gigabytes),
+            "GiB" => Ok(Unit::Gibibytes),
+            other => Err(format!("Unknown unit {}", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for Unit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Unit, String> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(deny_unknown_fields))]
+/// Convert the values of families from one unit to another
+pub struct UnitConversion {
+    pub from: Unit,
+    pub to: Unit,
+    /// Regex of the names of the families to convert, which then need not be named after the
+    /// unit. By default the families named `*_<from>` or `*_<from>_total` are converted.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metrics: Option<String>,
+}
+
+impl FromStr for UnitConversion {
+    type Err = String;
+
+    /// A conversion of the families named after a unit, e.g. `seconds:milliseconds`
+    fn from_str(s: &str) -> Result<UnitConversion, String> {
+        let (from, to) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid conversion {}, expected `from:to`", s))?;
+        Ok(UnitConversion {
+            from: from.parse()?,
+            to: to.parse()?,
+            metrics: None,
+        })
+    }
+}
+
+struct Rule {
+    from: Unit,
+    to: Unit,
+    metrics: Option<Regex>,
+}
+
+impl Rule {
+    fn matches(&self, name: &str) -> bool {
+        match &self.metrics {
+            Some(regex) => regex.is_match(name),
+            None => {
+                let base = name.strip_suffix("_total").unwrap_or(name);
+                base.strip_suffix(self.from.suffix())
+                    .is_some_and(|rest| rest.ends_with('_'))
+            }
+        }
+    }
+
+    /// The name of a converted family: the unit suffix replaced, or added when there is none,
+    /// before any `_total`
+    fn rename(&self, name: &str) -> String {
+        let (base, total) = match name.strip_suffix("_total") {
+            Some(base) => (base, "_total"),
+            None => (name, ""),
+        };
+        let base = base
+            .strip_suffix(self.from.suffix())
+            .and_then(|rest| rest.strip_suffix('_'))
+            .unwrap_or(base);
+        format!("{}_{}{}", base, self.to.suffix(), total)
+    }
+
+    /// Multiplying by whole factors and dividing by whole divisors, so that `0.25` seconds are
+    /// `250` milliseconds rather than `250.00000000000003`
+    fn convert(&self, value: f64) -> f64 {
+        let (from, to) = (self.from.scale().1, self.to.scale().1);
+        if from >= to {
+            value * (from / to)
+        } else {
+            value / (to / from)
+        }
+    }
+}
+
+/// A compiled list of unit conversions
+pub struct UnitConverter {
+    rules: Vec<Rule>,
+}
+
+impl UnitConverter {
+    pub fn new(conversions: &[UnitConversion]) -> Result<UnitConverter, String> {
+        let mut rules = Vec::new();
+        for conversion in conversions {
+            if conversion.from.scale().0 != conversion.to.scale().0 {
+                return Err(format!(
+                    "Cannot convert {} to {}",
+                    conversion.from.suffix(),
+                    conversion.to.suffix()
+                ));
+            }
+            let metrics = match &conversion.metrics {
+                Some(metrics) => Some(
+                    Regex::new(&format!("^(?:{})$", metrics))
+                        .map_err(|e| format!("Invalid unit conversion regex {}: {}", metrics, e))?,
+                ),
+                None => None,
+            };
+            rules.push(Rule {
+                from: conversion.from,
+                to: conversion.to,
+                metrics,
+            });
+        }
+        Ok(UnitConverter { rules })
+    }
+
+    /// Convert the families matched by a conversion, the first one only: their sample values,
+    /// sums, quantile values and bucket bounds, not their counts. They are renamed after the new
+    /// unit.
+    pub fn apply(&self, data: &mut PrometheusData) {
+        for family in data.metrics.iter_mut() {
+            let rule = match self.rules.iter().find(|r| r.matches(&family.metric_name)) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            family.metric_name = rule.rename(&family.metric_name);
+            for metric in family.data.iter_mut() {
+                metric.map_values(&|v| rule.convert(v));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unit_conversion_works() {
+        let mut data = PrometheusData::from_string(
+            "# TYPE cpu_seconds_total counter
+cpu_seconds_total 1.5
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.25\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 3
+latency_seconds_sum 0.3
+latency_seconds_count 3
+# TYPE process_resident_memory_bytes gauge
+process_resident_memory_bytes 3145728
+# TYPE uptime_seconds_ago gauge
+uptime_seconds_ago 5
+# TYPE heap gauge
+heap 2097152",
+        );
+        let converter = UnitConverter::new(&[
+            "s:ms".parse().unwrap(),
+            "bytes:MiB".parse().unwrap(),
+            UnitConversion {
+                from: Unit::Bytes,
+                to: Unit::Kibibytes,
+                metrics: Some("heap".to_string()),
+            },
+        ])
+        .unwrap();
+        converter.apply(&mut data);
+        let samples: Vec<String> = data
+            .samples()
+            .into_iter()
+            .map(|s| format!("{}{:?} {}", s.name, s.labels, s.value))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                "cpu_milliseconds_total{} 1500",
+                "latency_milliseconds_bucket{\"le\": \"250\"} 2",
+                "latency_milliseconds_bucket{\"le\": \"+Inf\"} 3",
+                "latency_milliseconds_sum{} 300",
+                "latency_milliseconds_count{} 3",
+                "process_resident_memory_mebibytes{} 3",
+                "uptime_seconds_ago{} 5",
+                "heap_kibibytes{} 2048",
+            ]
+        );
+        assert!(UnitConverter::new(&["s:MiB".parse().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! The parser for JavaScript, packaged for npm with `wasm-pack`
+
+use crate::PrometheusData;
+use wasm_bindgen::prelude::*;
+
+/// Parse exposition text into the JSON the command line tool prints
+#[wasm_bindgen(js_name = parseToJson)]
+pub fn parse_to_json(text: &str) -> Result<String, JsError> {
+    // Panics abort WebAssembly, so malformed text is an error thrown to JavaScript instead
+    parse(text).map_err(|e| JsError::new(&e))
+}
+
+fn parse(text: &str) -> Result<String, String> {
+    let data = PrometheusData::try_from_string(text)?;
+    serde_json::to_string(&data).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_to_json_works() {
+        let json = parse_to_json("# TYPE up gauge\nup{job=\"a\"} 1\n").unwrap();
+        let data: PrometheusData = serde_json::from_str(&json).unwrap();
+        assert_eq!(data.samples().len(), 1);
+
+        // `JsError` needs a JavaScript host, which native tests do not have
+        assert_eq!(
+            parse("up{a=\"b\" 1"),
+            Err("Invalid format up{a=\"b\" 1".to_string())
+        );
+    }
+}